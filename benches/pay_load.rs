@@ -0,0 +1,94 @@
+//! `cargo bench --bench pay_load`; compare runs with `--save-baseline` and
+//! `--baseline`.
+
+#![allow(clippy::upper_case_acronyms)]
+
+// The service is a binary crate, so the encoder modules are compiled in here.
+#[path = "../src/emvo_compiled.rs"]
+mod emvo_compiled;
+#[path = "../src/emvo_qrcode.rs"]
+mod emvo_qrcode;
+#[path = "../src/emvo_types.rs"]
+mod emvo_types;
+
+use std::convert::TryFrom;
+
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+
+use emvo_compiled::CompiledPayLoad;
+use emvo_qrcode::*;
+use emvo_types::*;
+
+fn dynamic_emvqr() -> EMVQR {
+    // PromptPay credit transfer to a mobile number
+    let mut merchant_account = EMVTemplate::new(TagID::new(29)).unwrap();
+    merchant_account
+        .set_field(
+            TagID::new(0),
+            Box::new(EMVAlphanumbericSpecial::try_from("A000000677010111".to_string()).unwrap()),
+            16,
+        )
+        .unwrap();
+    merchant_account
+        .set_field(
+            TagID::new(1),
+            Box::new(EMVNumeric::try_from("0066809729900".to_string()).unwrap()),
+            13,
+        )
+        .unwrap();
+    let mut additional_data = AdditionalDataFieldTemplate::default();
+    additional_data
+        .set_reference_label("INV0001".to_string())
+        .unwrap();
+    additional_data
+        .set_terminal_label("POS01".to_string())
+        .unwrap();
+
+    let mut emvo = EMVQR::default();
+    emvo.set_payload_format_indicator("02".to_string()).unwrap();
+    emvo.set_point_types(DYNAMIC_POINT).unwrap();
+    emvo.set_merchant_account_information(TagID::new(29), Box::new(merchant_account))
+        .unwrap();
+    emvo.set_merchant_category_code("5311".to_string()).unwrap();
+    emvo.set_transaction_currency("764").unwrap();
+    emvo.set_transaction_amount("50.25".to_string()).unwrap();
+    emvo.set_country_code("TH").unwrap();
+    emvo.set_merchant_name("test".to_string()).unwrap();
+    emvo.set_merchant_city("Bangkok".to_string()).unwrap();
+    emvo.set_postal_code("10240".to_string()).unwrap();
+    emvo.set_additional_data_field_template(additional_data);
+    emvo
+}
+
+fn pay_load(c: &mut Criterion) {
+    let emvo = dynamic_emvqr();
+    c.bench_function("to_pay_load", |b| {
+        b.iter(|| black_box(&emvo).to_pay_load().unwrap())
+    });
+    let mut buffer = String::with_capacity(emvo.encoded_len().unwrap());
+    c.bench_function("encode_into", |b| {
+        b.iter(|| {
+            buffer.clear();
+            black_box(&emvo).encode_into(&mut buffer).unwrap();
+        })
+    });
+    let pay_load = emvo.to_pay_load().unwrap();
+    let compiled = CompiledPayLoad::compile(dynamic_emvqr()).unwrap();
+    assert_eq!(
+        compiled.pay_load("50.25", Some("INV0001")).unwrap(),
+        pay_load
+    );
+    c.bench_function("compiled_pay_load", |b| {
+        b.iter(|| {
+            compiled
+                .pay_load(black_box("50.25"), black_box(Some("INV0001")))
+                .unwrap()
+        })
+    });
+    c.bench_function("parse_pay_load", |b| {
+        b.iter(|| parse_pay_load(black_box(&pay_load)).unwrap())
+    });
+}
+
+criterion_group!(benches, pay_load);
+criterion_main!(benches);
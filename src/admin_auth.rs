@@ -0,0 +1,95 @@
+#![allow(dead_code)]
+
+use std::future::{ready, Ready};
+
+use actix_web::dev::Payload;
+use actix_web::http::header::AUTHORIZATION;
+use actix_web::web::Data;
+use actix_web::{FromRequest, HttpRequest};
+use log::error;
+use sha2::{Digest, Sha256};
+
+use crate::apis::PromptPayServiceError;
+
+pub const BEARER_PREFIX: &str = "Bearer ";
+
+/// The operator token guarding the admin routes (merchant registry,
+/// webhooks, customer registration).
+pub enum AdminAuth {
+    Token(Vec<u8>),
+    /// No token configured: every admin request is rejected.
+    Disabled,
+}
+
+/// Extractor for admin handlers. Fails with 401 unless the request carries
+/// `Authorization: Bearer <ADMIN_API_TOKEN>`.
+pub struct Admin;
+
+impl AdminAuth {
+    /// Reads `ADMIN_API_TOKEN`.
+    pub fn from_env() -> Self {
+        match std::env::var("ADMIN_API_TOKEN") {
+            Ok(token) if !token.is_empty() => AdminAuth::new(&token),
+            _ => AdminAuth::Disabled,
+        }
+    }
+
+    pub fn new(token: &str) -> Self {
+        AdminAuth::Token(Sha256::digest(token.as_bytes()).to_vec())
+    }
+
+    /// Compares digests so the comparison time does not depend on how much
+    /// of the token was guessed right.
+    pub fn verify(&self, authorization: Option<&str>) -> bool {
+        match self {
+            AdminAuth::Disabled => false,
+            AdminAuth::Token(digest) => bearer_token(authorization)
+                .map(|token| Sha256::digest(token.as_bytes()).as_slice() == digest.as_slice())
+                .unwrap_or(false),
+        }
+    }
+}
+
+pub fn bearer_token(authorization: Option<&str>) -> Option<&str> {
+    authorization
+        .and_then(|value| value.strip_prefix(BEARER_PREFIX))
+        .filter(|token| !token.is_empty())
+}
+
+pub fn authorization_header(req: &HttpRequest) -> Option<&str> {
+    req.headers()
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+}
+
+impl FromRequest for Admin {
+    type Error = PromptPayServiceError;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _payload: &mut Payload) -> Self::Future {
+        let authorized = req
+            .app_data::<Data<AdminAuth>>()
+            .map(|auth| auth.verify(authorization_header(req)))
+            .unwrap_or(false);
+        if !authorized {
+            error!("Admin request rejected: {} {}", req.method(), req.path());
+            return ready(Err(PromptPayServiceError::Unauthorized));
+        }
+        ready(Ok(Admin))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_admin_auth_verify() {
+        let auth = AdminAuth::new("s3cret");
+        assert!(auth.verify(Some("Bearer s3cret")));
+        assert!(!auth.verify(Some("Bearer s3cre")));
+        assert!(!auth.verify(Some("s3cret")));
+        assert!(!auth.verify(None));
+        assert!(!AdminAuth::Disabled.verify(Some("Bearer s3cret")));
+    }
+}
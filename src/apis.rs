@@ -1,30 +1,226 @@
 #![allow(dead_code)]
 
-use actix_web::{error, HttpRequest, HttpResponse, post, Responder};
+use std::convert::TryFrom;
+
+use actix_multipart::Multipart;
 use actix_web::body::BoxBody;
-use actix_web::http::header::ContentType;
+use actix_web::http::header::{
+    CacheControl, CacheDirective, ContentEncoding, ContentType, ETag, EntityTag, Header,
+    HeaderName, HeaderValue, IfNoneMatch,
+};
 use actix_web::http::StatusCode;
-use actix_web::web::Json;
-use base64::Engine;
+use actix_web::web::{Data, Json, Path, Payload, Query};
+use actix_web::{
+    delete, error, get, post, put, web, HttpMessage, HttpRequest, HttpResponse, Responder,
+};
 use base64::engine::general_purpose;
+use base64::Engine;
 use derive_more::{Display, Error};
+use futures_util::StreamExt;
 use log::{error, info};
-use qrcode_generator::QrCodeEcc;
 use serde::{Deserialize, Serialize};
 
+use crate::admin_auth::{authorization_header, bearer_token, Admin};
+use crate::bank_notifications::{
+    BankNotificationRq, BankWebhookAuth, NotificationOutcome, BANK_SIGNATURE_HEADER,
+};
+use crate::card_networks::{card_merchant_accounts, CardMerchantAccount};
+use crate::emvo_document::EMVQRDocument;
 use crate::emvo_qrcode::*;
-use crate::prompt_pay::{BAHT, CUSTOMER_PRESENTED, MerchantPromptPayCreditTransfer, THAI};
-
+use crate::emvo_types::{EMVAlphanumbericSpecial, EMVNumeric, EMVOError};
+use crate::merchant_registry::{
+    validate_merchant_id, CreateMerchantRq, MerchantProfile, MerchantQrCodeRq,
+};
+use crate::paynow::{PayNowProfile, PayNowProxyType};
+use crate::prompt_pay::{
+    MerchantPromptPayCreditTransfer, BAHT, CUSTOMER_PRESENTED, ID_PROMPT_PAY_CREDIT_TRANSFER,
+    MAX_LENGTH_MOBILE_NO, THAI,
+};
+use crate::prompt_pay_ota::{generate_ota, CreateCustomerRq, CustomerQrCodeRs, OtaPolicy};
+use crate::qr_signature::QRSigningKeys;
+use crate::qrcode_batch::{
+    build_pdf, build_zip, generate_batch, parse_csv, BatchFormat, BatchMerchantRq, MAX_BATCH_ROWS,
+};
+use crate::qrcode_cache::{QRCodeCache, QRCodeCacheKey, QRCodeRenderOptions};
+use crate::qris::validate_pay_load;
+use crate::reconciliation::{
+    parse_bank_csv, parse_camt054, CsvStatementFormat, ReportFormat, StatementFormat,
+};
+use crate::slip_verification::{parse_slip_qr, slip_payloads_from_bytes, VerifySlipRq};
+use crate::storage::{unix_timestamp, Storage};
+use crate::transactions::{
+    generate_reference, status_event_stream, transaction_ttl_from_env, validate_transaction_ttl,
+    TransactionStatus,
+};
+use crate::webhooks::{
+    check_webhook_destination, current_trace_context, generate_secret, MerchantWebhookRq,
+    MerchantWebhookRs,
+};
 
 //use qrcode::QrCode;
 //use image::{Luma, ImageBuffer};
 
+/// Payment scheme of the QR the generate API returns.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum QRScheme {
+    #[default]
+    PromptPay,
+    PayNow,
+}
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Default, Deserialize, Serialize)]
 pub struct GenerateQrCodeRq {
     pub transaction_amount: f32,
+    #[serde(default)]
     pub mobile_number: String,
     pub merchant_name: String,
+    /// Card network merchant IDs accepted alongside PromptPay.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub merchant_accounts: Vec<CardMerchantAccount>,
+    #[serde(default)]
+    pub scheme: QRScheme,
+    /// PayNow: pay a Unique Entity Number instead of `mobile_number`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub uen: Option<String>,
+    /// PayNow: whether the payer may change the amount.
+    #[serde(default)]
+    pub amount_editable: bool,
+    /// PayNow: last day the QR is accepted, `YYYYMMDD`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expiry_date: Option<String>,
+}
+
+/// An existing (usually bank-issued static) payload to turn into a dynamic QR.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct ConvertQrCodeRq {
+    pub pay_load: String,
+    pub transaction_amount: f32,
+    #[serde(default)]
+    pub reference: Option<String>,
+}
+
+/// A raw EMVCo payload, the body of `/emvco/decode` and the result of
+/// `/emvco/encode`.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct EMVQRPayLoad {
+    pub pay_load: String,
+}
+
+/// A decoded payload together with its JSON document model.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct EMVQRDocumentRs {
+    pub pay_load: String,
+    pub document: EMVQRDocument,
+}
+
+const MAX_LENGTH_MERCHANT_NAME: usize = 25;
+pub const TRANSACTION_REFERENCE_HEADER: &str = "x-transaction-reference";
+const MAX_BATCH_BODY_BYTES: usize = 16 * 1024 * 1024;
+
+impl ReconciliationQuery {
+    fn csv_format(&self) -> Result<CsvStatementFormat, String> {
+        let mut format = CsvStatementFormat::default();
+        if let Some(delimiter) = self.delimiter {
+            if !delimiter.is_ascii() {
+                return Err("delimiter must be an ASCII character".to_string());
+            }
+            format.delimiter = delimiter as u8;
+        }
+        if let Some(column) = &self.amount_column {
+            format.amount_column = column.clone();
+        }
+        if let Some(columns) = &self.reference_columns {
+            format.reference_columns = columns.split(',').map(|c| c.trim().to_string()).collect();
+        }
+        if self.bank_reference_column.is_some() {
+            format.bank_reference_column = self.bank_reference_column.clone();
+        }
+        if self.date_column.is_some() {
+            format.date_column = self.date_column.clone();
+        }
+        if self.currency_column.is_some() {
+            format.currency_column = self.currency_column.clone();
+        }
+        Ok(format)
+    }
+}
+
+impl GenerateQrCodeRq {
+    /// Checks the request up front, so bad input is reported to the caller
+    /// instead of panicking inside the EMV setters.
+    pub fn validate(&self) -> Result<(), EMVOError> {
+        if self.mobile_number.is_empty()
+            || self.mobile_number.len() > MAX_LENGTH_MOBILE_NO
+            || EMVNumeric::try_from(self.mobile_number.clone()).is_err()
+        {
+            return Err(EMVOError::new("mobile_number must be 1 to 13 digits"));
+        }
+        if self.merchant_name.is_empty()
+            || EMVAlphanumbericSpecial::try_from(self.merchant_name.clone()).is_err()
+        {
+            return Err(EMVOError::new(
+                "merchant_name is empty or has invalid characters",
+            ));
+        }
+        if self.merchant_name.len() > MAX_LENGTH_MERCHANT_NAME {
+            return Err(EMVOError::new("merchant_name longer than 25 characters"));
+        }
+        if !self.transaction_amount.is_finite() || self.transaction_amount < 0.0 {
+            return Err(EMVOError::new("transaction_amount must not be negative"));
+        }
+        card_merchant_accounts(&self.merchant_accounts)?;
+        Ok(())
+    }
+
+    /// Amount 0 leaves the amount to the payer, as with PromptPay.
+    pub fn paynow_profile(&self) -> Result<PayNowProfile, EMVOError> {
+        if !self.merchant_accounts.is_empty() {
+            return Err(EMVOError::new(
+                "merchant_accounts is only supported by promptpay",
+            ));
+        }
+        let (proxy_type, proxy_value) = match &self.uen {
+            Some(uen) => (PayNowProxyType::Uen, uen.clone()),
+            None => (PayNowProxyType::Mobile, self.mobile_number.clone()),
+        };
+        Ok(PayNowProfile {
+            proxy_type,
+            proxy_value,
+            merchant_name: self.merchant_name.clone(),
+            transaction_amount: (self.transaction_amount != 0.0)
+                .then(|| self.transaction_amount.to_string()),
+            amount_editable: self.amount_editable,
+            expiry_date: self.expiry_date.clone(),
+        })
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct BatchQrCodeQuery {
+    #[serde(default)]
+    pub format: BatchFormat,
+}
+
+/// The CSV column options describe the bank's export; unset ones keep the
+/// `CsvStatementFormat` defaults. `reference_columns` is comma separated.
+#[derive(Debug, Deserialize)]
+pub struct ReconciliationQuery {
+    #[serde(default)]
+    pub format: StatementFormat,
+    #[serde(default)]
+    pub output: ReportFormat,
+    pub delimiter: Option<char>,
+    pub amount_column: Option<String>,
+    pub reference_columns: Option<String>,
+    pub bank_reference_column: Option<String>,
+    pub date_column: Option<String>,
+    pub currency_column: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct NotificationExceptionsQuery {
+    pub outcome: Option<NotificationOutcome>,
 }
 
 #[derive(Debug, Display, Error)]
@@ -37,6 +233,15 @@ pub enum PromptPayServiceError {
 
     #[display(fmt = "timeout")]
     Timeout,
+
+    #[display(fmt = "not found")]
+    NotFound,
+
+    #[display(fmt = "conflict")]
+    Conflict,
+
+    #[display(fmt = "unauthorized")]
+    Unauthorized,
 }
 impl error::ResponseError for PromptPayServiceError {
     fn status_code(&self) -> StatusCode {
@@ -44,6 +249,9 @@ impl error::ResponseError for PromptPayServiceError {
             PromptPayServiceError::InternalError => StatusCode::INTERNAL_SERVER_ERROR,
             PromptPayServiceError::BadClientData => StatusCode::BAD_REQUEST,
             PromptPayServiceError::Timeout => StatusCode::GATEWAY_TIMEOUT,
+            PromptPayServiceError::NotFound => StatusCode::NOT_FOUND,
+            PromptPayServiceError::Conflict => StatusCode::CONFLICT,
+            PromptPayServiceError::Unauthorized => StatusCode::UNAUTHORIZED,
         }
     }
 
@@ -55,10 +263,24 @@ impl error::ResponseError for PromptPayServiceError {
 }
 pub struct QRCodeResponse {
     pub qrcode_base64: String,
+    pub etag: Option<String>,
 }
 impl Responder for QRCodeResponse {
     type Body = BoxBody;
-    fn respond_to(self, _req: &HttpRequest) -> HttpResponse<Self::Body> {
+    fn respond_to(self, req: &HttpRequest) -> HttpResponse<Self::Body> {
+        if let Some(etag) = self.etag {
+            let etag = EntityTag::new_strong(etag);
+            if is_not_modified(req, &etag) {
+                return HttpResponse::NotModified()
+                    .insert_header(ETag(etag))
+                    .finish();
+            }
+            return HttpResponse::Ok()
+                .append_header(("Content-Transfer-Encoding", "base64"))
+                .insert_header(ETag(etag))
+                .content_type("image/jpg")
+                .body(self.qrcode_base64);
+        }
         HttpResponse::Ok()
             .append_header(("Content-Transfer-Encoding", "base64"))
             .content_type("image/jpg")
@@ -67,59 +289,1401 @@ impl Responder for QRCodeResponse {
 }
 impl QRCodeResponse {
     fn create_response(qrcode_generator: String) -> QRCodeResponse {
-        return QRCodeResponse {
+        QRCodeResponse {
             qrcode_base64: qrcode_generator,
-        };
+            etag: None,
+        }
+    }
+    fn create_cached_response(qrcode_generator: String, etag: String) -> QRCodeResponse {
+        QRCodeResponse {
+            qrcode_base64: qrcode_generator,
+            etag: Some(etag),
+        }
     }
 }
 
+fn is_not_modified(req: &HttpRequest, etag: &EntityTag) -> bool {
+    match IfNoneMatch::parse(req) {
+        Ok(IfNoneMatch::Any) => true,
+        Ok(IfNoneMatch::Items(items)) => items.iter().any(|item| item.weak_eq(etag)),
+        Err(_) => false,
+    }
+}
+
+pub fn generate_prompt_pay_pay_load(
+    req: &GenerateQrCodeRq,
+) -> Result<String, PromptPayServiceError> {
+    if let Err(e) = req.validate() {
+        error!("Bad request: {}", e);
+        return Err(PromptPayServiceError::BadClientData);
+    }
+    let mut merchant_prompt_pay = MerchantPromptPayCreditTransfer::default();
+    merchant_prompt_pay
+        .set_promptpay_presented_type(CUSTOMER_PRESENTED)
+        .map_err(bad_request)?;
+    merchant_prompt_pay
+        .set_mobile_number(&req.mobile_number)
+        .map_err(bad_request)?;
+
+    let mut builder = EMVQR::builder()
+        .point_of_initiation_method(STATIC_POINT)
+        .transaction_currency(BAHT)
+        .transaction_amount(req.transaction_amount.to_string())
+        .merchant_name(req.merchant_name.clone())
+        .merchant_category_code("5311")
+        .merchant_account_information(ID_PROMPT_PAY_CREDIT_TRANSFER, Box::new(merchant_prompt_pay))
+        .merchant_city("Bangkok")
+        .postal_code("10240")
+        .country_code(THAI);
+    for (tag_id, merchant_id) in
+        card_merchant_accounts(&req.merchant_accounts).map_err(bad_request)?
+    {
+        builder = builder.merchant_account_information(tag_id, merchant_id);
+    }
+    builder
+        .build()
+        .map(|emvo| emvo.into_pay_load())
+        .map_err(bad_request)
+}
+
+/// The payload of the requested scheme.
+pub fn generate_pay_load(req: &GenerateQrCodeRq) -> Result<String, PromptPayServiceError> {
+    match req.scheme {
+        QRScheme::PromptPay => generate_prompt_pay_pay_load(req),
+        QRScheme::PayNow => req
+            .paynow_profile()
+            .and_then(|profile| profile.generate_pay_load())
+            .map_err(bad_request),
+    }
+}
+
+pub fn render_qrcode_png_base64(
+    pay_load: &str,
+    options: QRCodeRenderOptions,
+) -> Result<String, PromptPayServiceError> {
+    let png = qrcode_generator::to_png_to_vec_from_str(pay_load, options.ecc, options.size);
+    let result: Vec<u8> = png.map_err(|e| {
+        error!("QRCode render error: {}", e);
+        PromptPayServiceError::InternalError
+    })?;
+    Ok(general_purpose::STANDARD.encode(&result))
+}
+
+fn cached_qr_code_response(
+    http_req: &HttpRequest,
+    pay_load: String,
+    cache: &QRCodeCache,
+) -> Result<QRCodeResponse, PromptPayServiceError> {
+    let key = QRCodeCacheKey::new(pay_load, QRCodeRenderOptions::default());
+
+    // The ETag only depends on the cache key, so a client that already holds
+    // this image doesn't need it rendered or even looked up.
+    let etag = key.etag();
+    if is_not_modified(http_req, &EntityTag::new_strong(etag.clone())) {
+        return Ok(QRCodeResponse::create_cached_response(String::new(), etag));
+    }
+
+    let cached = cache.get_or_render(key, render_qrcode_png_base64)?;
+    info!("QRCode served, ETag {}", cached.etag);
+    Ok(QRCodeResponse::create_cached_response(
+        cached.qrcode_base64.clone(),
+        cached.etag.clone(),
+    ))
+}
+
 #[post("/promptpay/qrcode")]
 pub async fn qr_code_tag30(
+    http_req: HttpRequest,
     req: Json<GenerateQrCodeRq>,
+    cache: Data<QRCodeCache>,
 ) -> Result<QRCodeResponse, PromptPayServiceError> {
-    let mut emvo = EMVQR::default();
-    let result = emvo.set_payload_format_indicator("02".to_string());
+    let pay_load = generate_pay_load(&req)?;
+    cached_qr_code_response(&http_req, pay_load, &cache)
+}
 
-    return if result.is_ok() {
-        emvo.set_point_types(STATIC_POINT)
-            .expect("TODO: panic message");
+#[get("/promptpay/qrcode")]
+pub async fn qr_code_tag30_get(
+    http_req: HttpRequest,
+    req: Query<GenerateQrCodeRq>,
+    cache: Data<QRCodeCache>,
+) -> Result<QRCodeResponse, PromptPayServiceError> {
+    let pay_load = generate_pay_load(&req)?;
+    cached_qr_code_response(&http_req, pay_load, &cache)
+}
 
-        let mut merchant_prompt_pay = MerchantPromptPayCreditTransfer::default();
-        //let mobile_number = req.mobile_number.as_ref().unwrap(); //String::from("0809729900");
+#[post("/promptpay/qrcode/dynamic")]
+pub async fn qr_code_to_dynamic(
+    http_req: HttpRequest,
+    req: Json<ConvertQrCodeRq>,
+    cache: Data<QRCodeCache>,
+) -> Result<QRCodeResponse, PromptPayServiceError> {
+    let pay_load = convert_to_dynamic(
+        &req.pay_load,
+        req.transaction_amount,
+        req.reference.as_deref(),
+    )
+    .map_err(bad_request)?;
+    cached_qr_code_response(&http_req, pay_load, &cache)
+}
+
+/// Encodes a JSON document (see `EMVQRDocument`) into a payload with its CRC.
+#[post("/emvco/encode")]
+pub async fn emvco_encode(req: Json<EMVQRDocument>) -> Result<HttpResponse, PromptPayServiceError> {
+    let pay_load = req.to_pay_load().map_err(bad_request)?;
+    Ok(HttpResponse::Ok().json(EMVQRPayLoad { pay_load }))
+}
 
-        merchant_prompt_pay.set_promptpay_presented_type(CUSTOMER_PRESENTED);
-        merchant_prompt_pay.set_mobile_number(&req.0.mobile_number);
-        emvo.set_point_types(STATIC_POINT).expect("Point Type Error");
-        emvo.set_transaction_currency(BAHT);
-        emvo.set_transaction_amount(req.0.transaction_amount.to_string());
-        emvo.set_merchant_name(req.0.merchant_name);
-        emvo.set_merchant_category_code("5311".to_string());
-        emvo.set_merchant_account_information("29", Box::new(merchant_prompt_pay));
-        emvo.set_merchant_city("Bangkok".to_string());
-        emvo.set_postal_code("10240".to_string());
-        emvo.set_country_code(THAI);
+#[post("/emvco/decode")]
+pub async fn emvco_decode(req: Json<EMVQRPayLoad>) -> Result<HttpResponse, PromptPayServiceError> {
+    let document = EMVQRDocument::from_pay_load(&req.pay_load).map_err(bad_request)?;
+    Ok(HttpResponse::Ok().json(EMVQRDocumentRs {
+        pay_load: req.into_inner().pay_load,
+        document,
+    }))
+}
 
-        // info!("Payload: {:?}", emvo.generate_pay_load());
+/// Tells whether a payload (e.g. scanned off a merchant sticker) carries a
+/// valid signature from one of our QR signing keys.
+#[post("/emvco/verify-signature")]
+pub async fn emvco_verify_signature(
+    req: Json<EMVQRPayLoad>,
+    keys: Data<QRSigningKeys>,
+) -> Result<HttpResponse, PromptPayServiceError> {
+    let verification = keys.verify_pay_load(&req.pay_load).map_err(bad_request)?;
+    Ok(HttpResponse::Ok().json(verification))
+}
 
-        let result = emvo.generate_pay_load();
+#[post("/qris/validate")]
+pub async fn qris_validate(req: Json<EMVQRPayLoad>) -> Result<HttpResponse, PromptPayServiceError> {
+    let validation = validate_pay_load(&req.pay_load).map_err(bad_request)?;
+    Ok(HttpResponse::Ok().json(validation))
+}
 
-        if let Ok(result) = result {
-            let data = result;
+#[post("/promptpay/qrcode/batch")]
+pub async fn qr_code_tag30_batch(
+    http_req: HttpRequest,
+    query: Query<BatchQrCodeQuery>,
+    payload: Payload,
+) -> Result<HttpResponse, PromptPayServiceError> {
+    let (rows, parse_errors) = if http_req.content_type() == "multipart/form-data" {
+        let mut multipart = Multipart::new(http_req.headers(), payload);
+        let csv = read_first_multipart_file(&mut multipart).await?;
+        parse_csv(&csv)
+    } else {
+        let body = payload
+            .to_bytes_limited(MAX_BATCH_BODY_BYTES)
+            .await
+            .map_err(|_| PromptPayServiceError::BadClientData)?
+            .map_err(|_| PromptPayServiceError::BadClientData)?;
+        if http_req.content_type() == "text/csv" {
+            parse_csv(&body)
+        } else {
+            let merchants: Vec<BatchMerchantRq> = serde_json::from_slice(&body).map_err(|e| {
+                error!("Bad batch request: {}", e);
+                PromptPayServiceError::BadClientData
+            })?;
+            (
+                merchants
+                    .into_iter()
+                    .enumerate()
+                    .map(|(i, m)| (i + 1, m))
+                    .collect(),
+                vec![],
+            )
+        }
+    };
+    if rows.len() + parse_errors.len() > MAX_BATCH_ROWS {
+        error!("Batch too large: {} rows", rows.len() + parse_errors.len());
+        return Err(PromptPayServiceError::BadClientData);
+    }
 
-            let result: Vec<u8> =
-                qrcode_generator::to_png_to_vec_from_str(data, QrCodeEcc::Low, 320).unwrap();
-            let str_b64 = general_purpose::STANDARD.encode(&result);
-            info!("QRCode: {}", str_b64);
+    // Rendering up to MAX_BATCH_ROWS images is CPU bound, keep it off the
+    // worker thread
+    let format = query.format;
+    let body = web::block(move || {
+        let mut batch = generate_batch(rows);
+        batch.errors.extend(parse_errors);
+        batch.errors.sort_by_key(|e| e.row);
+        info!(
+            "Batch generated {} QR code(s), {} error(s)",
+            batch.qr_codes.len(),
+            batch.errors.len()
+        );
+        let options = QRCodeRenderOptions::default();
+        match format {
+            BatchFormat::Pdf => build_pdf(batch, options.ecc),
+            format => build_zip(batch, format, options),
+        }
+    })
+    .await??;
 
+    let (content_type, file_name) = match format {
+        BatchFormat::Pdf => ("application/pdf", "qrcodes.pdf"),
+        _ => ("application/zip", "qrcodes.zip"),
+    };
+    Ok(HttpResponse::Ok()
+        .content_type(content_type)
+        .append_header((
+            "Content-Disposition",
+            format!("attachment; filename=\"{}\"", file_name),
+        ))
+        .body(body))
+}
 
-            Ok(QRCodeResponse::create_response(str_b64))
-        } else {
-            //HttpResponse::BadRequest().finish()
-            error!("Bad request");
-            Err(PromptPayServiceError::BadClientData)
+async fn read_first_multipart_file(
+    multipart: &mut Multipart,
+) -> Result<Vec<u8>, PromptPayServiceError> {
+    let mut data: Vec<u8> = vec![];
+    if let Some(field) = multipart.next().await {
+        let mut field = field.map_err(|_| PromptPayServiceError::BadClientData)?;
+        while let Some(chunk) = field.next().await {
+            let chunk = chunk.map_err(|_| PromptPayServiceError::BadClientData)?;
+            if data.len() + chunk.len() > MAX_BATCH_BODY_BYTES {
+                return Err(PromptPayServiceError::BadClientData);
+            }
+            data.extend_from_slice(&chunk);
         }
+        Ok(data)
     } else {
-        //HttpResponse::InternalServerError().finish()
-        Err(PromptPayServiceError::InternalError)
+        Err(PromptPayServiceError::BadClientData)
+    }
+}
+
+fn bad_request(e: impl std::fmt::Display) -> PromptPayServiceError {
+    error!("Bad request: {}", e);
+    PromptPayServiceError::BadClientData
+}
+
+#[post("/merchants")]
+pub async fn create_merchant(
+    _admin: Admin,
+    req: Json<CreateMerchantRq>,
+    storage: Data<Storage>,
+) -> Result<HttpResponse, PromptPayServiceError> {
+    let req = req.into_inner();
+    validate_merchant_id(&req.id).map_err(bad_request)?;
+    req.profile.validate().map_err(bad_request)?;
+    let merchant = web::block(move || storage.insert_merchant(&req.id, &req.profile)).await??;
+    Ok(HttpResponse::Created().json(merchant))
+}
+
+#[get("/merchants")]
+pub async fn list_merchants(
+    _admin: Admin,
+    storage: Data<Storage>,
+) -> Result<HttpResponse, PromptPayServiceError> {
+    let merchants = web::block(move || storage.list_merchants()).await??;
+    Ok(HttpResponse::Ok().json(merchants))
+}
+
+#[get("/merchants/{id}")]
+pub async fn get_merchant(
+    _admin: Admin,
+    id: Path<String>,
+    storage: Data<Storage>,
+) -> Result<HttpResponse, PromptPayServiceError> {
+    let merchant = web::block(move || storage.get_merchant(&id)).await??;
+    Ok(HttpResponse::Ok().json(merchant))
+}
+
+#[put("/merchants/{id}")]
+pub async fn update_merchant(
+    _admin: Admin,
+    id: Path<String>,
+    req: Json<MerchantProfile>,
+    storage: Data<Storage>,
+) -> Result<HttpResponse, PromptPayServiceError> {
+    req.validate().map_err(bad_request)?;
+    let merchant = web::block(move || storage.update_merchant(&id, &req)).await??;
+    Ok(HttpResponse::Ok().json(merchant))
+}
+
+#[delete("/merchants/{id}")]
+pub async fn delete_merchant(
+    _admin: Admin,
+    id: Path<String>,
+    storage: Data<Storage>,
+) -> Result<HttpResponse, PromptPayServiceError> {
+    web::block(move || storage.delete_merchant(&id)).await??;
+    Ok(HttpResponse::NoContent().finish())
+}
+
+/// Sets the URL that receives the merchant's signed transaction events. A
+/// secret is generated when none is given; it is only returned here.
+#[put("/merchants/{id}/webhook")]
+pub async fn set_merchant_webhook(
+    _admin: Admin,
+    id: Path<String>,
+    req: Json<MerchantWebhookRq>,
+    storage: Data<Storage>,
+) -> Result<HttpResponse, PromptPayServiceError> {
+    let req = req.into_inner();
+    req.validate().map_err(bad_request)?;
+    check_webhook_destination(&req.url)
+        .await
+        .map_err(bad_request)?;
+    let secret = req.secret.unwrap_or_else(generate_secret);
+    let url = req.url;
+    let webhook = {
+        let secret = secret.clone();
+        web::block(move || storage.set_merchant_webhook(&id, &url, &secret)).await??
+    };
+    Ok(HttpResponse::Ok().json(MerchantWebhookRs { webhook, secret }))
+}
+
+#[get("/merchants/{id}/webhook")]
+pub async fn get_merchant_webhook(
+    _admin: Admin,
+    id: Path<String>,
+    storage: Data<Storage>,
+) -> Result<HttpResponse, PromptPayServiceError> {
+    let webhook = web::block(move || storage.get_merchant_webhook(&id)).await??;
+    Ok(HttpResponse::Ok().json(webhook))
+}
+
+#[delete("/merchants/{id}/webhook")]
+pub async fn delete_merchant_webhook(
+    _admin: Admin,
+    id: Path<String>,
+    storage: Data<Storage>,
+) -> Result<HttpResponse, PromptPayServiceError> {
+    web::block(move || storage.delete_merchant_webhook(&id)).await??;
+    Ok(HttpResponse::NoContent().finish())
+}
+
+/// Static QRs are served from the render cache. A transaction amount makes
+/// the QR dynamic: it is recorded as a pending transaction under its tag 62
+/// reference label, returned in the `X-Transaction-Reference` header. It
+/// expires after the request's `ttl_seconds`, else the merchant's TTL.
+#[post("/merchants/{id}/qrcode")]
+pub async fn merchant_qr_code(
+    _admin: Admin,
+    http_req: HttpRequest,
+    id: Path<String>,
+    req: Json<MerchantQrCodeRq>,
+    storage: Data<Storage>,
+    cache: Data<QRCodeCache>,
+    keys: Data<QRSigningKeys>,
+) -> Result<HttpResponse, PromptPayServiceError> {
+    let merchant_id = id.into_inner();
+    let merchant = {
+        let storage = storage.clone();
+        let merchant_id = merchant_id.clone();
+        web::block(move || storage.get_merchant(&merchant_id)).await??
     };
+    let transaction_amount = match req.transaction_amount {
+        Some(amount) => amount,
+        None => {
+            let pay_load = merchant
+                .profile
+                .generate_pay_load(None, req.reference.as_deref())
+                .and_then(|pay_load| keys.sign_pay_load(&pay_load))
+                .map_err(bad_request)?;
+            return Ok(cached_qr_code_response(&http_req, pay_load, &cache)?.respond_to(&http_req));
+        }
+    };
+
+    let ttl_seconds = req
+        .ttl_seconds
+        .or(merchant.profile.transaction_ttl_seconds)
+        .unwrap_or_else(transaction_ttl_from_env);
+    validate_transaction_ttl(ttl_seconds).map_err(bad_request)?;
+    let reference = req.reference.clone().unwrap_or_else(generate_reference);
+    let pay_load = merchant
+        .profile
+        .generate_pay_load(Some(transaction_amount), Some(&reference))
+        .and_then(|pay_load| keys.sign_pay_load(&pay_load))
+        .map_err(bad_request)?;
+    let qrcode_base64 = render_qrcode_png_base64(&pay_load, QRCodeRenderOptions::default())?;
+    let transaction = web::block(move || {
+        storage.insert_transaction(
+            &reference,
+            &merchant_id,
+            transaction_amount as f64,
+            &pay_load,
+            ttl_seconds,
+        )
+    })
+    .await??;
+    info!(
+        "Transaction {} created for merchant {}",
+        transaction.reference, transaction.merchant_id
+    );
+
+    let mut response = QRCodeResponse::create_response(qrcode_base64).respond_to(&http_req);
+    response.headers_mut().insert(
+        HeaderName::from_static(TRANSACTION_REFERENCE_HEADER),
+        HeaderValue::from_str(&transaction.reference).map_err(bad_request)?,
+    );
+    Ok(response)
+}
+
+#[post("/customers")]
+pub async fn create_customer(
+    _admin: Admin,
+    req: Json<CreateCustomerRq>,
+    storage: Data<Storage>,
+) -> Result<HttpResponse, PromptPayServiceError> {
+    let req = req.into_inner();
+    req.validate().map_err(bad_request)?;
+    let customer = web::block(move || storage.insert_customer(&req)).await??;
+    Ok(HttpResponse::Created().json(customer))
+}
+
+/// A customer presented QR for the wallet to show, carrying the OTA of the
+/// current time step. It is accepted until `expires_at`. The customer
+/// authenticates with `Authorization: Bearer <access_token>`.
+#[post("/customers/{id}/qrcode")]
+pub async fn customer_qr_code(
+    http_req: HttpRequest,
+    id: Path<String>,
+    storage: Data<Storage>,
+    policy: Data<OtaPolicy>,
+) -> Result<HttpResponse, PromptPayServiceError> {
+    let access_token = bearer_token(authorization_header(&http_req))
+        .ok_or(PromptPayServiceError::Unauthorized)?
+        .to_string();
+    let customer = web::block(move || storage.authenticate_customer(&id, &access_token)).await??;
+    let counter = policy.counter(unix_timestamp());
+    let ota = generate_ota(customer.ota_secret.as_bytes(), counter);
+    let pay_load = customer.generate_pay_load(&ota).map_err(bad_request)?;
+    Ok(HttpResponse::Ok().json(CustomerQrCodeRs {
+        qrcode_base64: render_qrcode_png_base64(&pay_load, QRCodeRenderOptions::default())?,
+        pay_load,
+        expires_at: policy.expires_at(counter),
+    }))
+}
+
+/// Merchant side check of a scanned customer presented QR. Each OTA is
+/// accepted once, and the customer is only named when it is.
+#[post("/promptpay/ota/verify")]
+pub async fn verify_customer_ota(
+    req: Json<EMVQRPayLoad>,
+    storage: Data<Storage>,
+    policy: Data<OtaPolicy>,
+) -> Result<HttpResponse, PromptPayServiceError> {
+    let verification =
+        web::block(move || storage.verify_customer_ota(&req.pay_load, &policy, unix_timestamp()))
+            .await??;
+    Ok(HttpResponse::Ok().json(verification))
+}
+
+#[get("/transactions/{reference}")]
+pub async fn get_transaction(
+    reference: Path<String>,
+    storage: Data<Storage>,
+) -> Result<HttpResponse, PromptPayServiceError> {
+    let transaction = web::block(move || storage.get_transaction(&reference)).await??;
+    Ok(HttpResponse::Ok().json(transaction))
+}
+
+/// Pushes the transaction's status as Server-Sent Events until it is paid,
+/// expired or cancelled, so displays can flip to "Paid" without polling.
+#[get("/transactions/{reference}/events")]
+pub async fn transaction_events(
+    reference: Path<String>,
+    storage: Data<Storage>,
+) -> Result<HttpResponse, PromptPayServiceError> {
+    // Subscribe before reading so no change between the two is missed
+    let receiver = storage.transaction_events().subscribe();
+    let transaction = {
+        let storage = storage.clone();
+        web::block(move || storage.get_transaction(&reference)).await??
+    };
+    Ok(HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .insert_header(CacheControl(vec![CacheDirective::NoCache]))
+        // Keeps the Compress middleware from buffering the stream
+        .insert_header(ContentEncoding::Identity)
+        .streaming(status_event_stream(storage, receiver, transaction)))
+}
+
+#[post("/transactions/{reference}/cancel")]
+pub async fn cancel_transaction(
+    _admin: Admin,
+    reference: Path<String>,
+    storage: Data<Storage>,
+) -> Result<HttpResponse, PromptPayServiceError> {
+    let transaction = web::block(move || {
+        storage.transition_transaction(&reference, TransactionStatus::Cancelled)
+    })
+    .await??;
+    Ok(HttpResponse::Ok().json(transaction))
+}
+
+/// Payment confirmations from the bank, authenticated by an HMAC signature
+/// over the raw body (or by the mTLS listener). Always answers 200 once the
+/// notification is recorded, exceptions included, so the bank stops retrying.
+#[post("/bank/notifications")]
+pub async fn bank_notification(
+    http_req: HttpRequest,
+    body: web::Bytes,
+    auth: Data<BankWebhookAuth>,
+    storage: Data<Storage>,
+) -> Result<HttpResponse, PromptPayServiceError> {
+    let signature = http_req
+        .headers()
+        .get(BANK_SIGNATURE_HEADER)
+        .and_then(|v| v.to_str().ok());
+    if !auth.verify(signature, &body) {
+        error!("Bank notification rejected: invalid signature");
+        return Err(PromptPayServiceError::Unauthorized);
+    }
+    let rq: BankNotificationRq = serde_json::from_slice(&body).map_err(bad_request)?;
+    let trace_context = current_trace_context();
+    let notification =
+        web::block(move || storage.ingest_bank_notification(&rq, &trace_context)).await??;
+    Ok(HttpResponse::Ok().json(notification))
+}
+
+/// `?outcome=LATE_PAYMENT` lists the payments waiting for refund review.
+#[get("/bank/notifications/exceptions")]
+pub async fn list_notification_exceptions(
+    _admin: Admin,
+    query: Query<NotificationExceptionsQuery>,
+    storage: Data<Storage>,
+) -> Result<HttpResponse, PromptPayServiceError> {
+    let exceptions =
+        web::block(move || storage.list_notification_exceptions(query.outcome)).await??;
+    Ok(HttpResponse::Ok().json(exceptions))
+}
+
+/// Reconciles a bank statement (camt.054 XML or CSV in the body) against the
+/// issued dynamic QRs and reports matched, unmatched, over- and under-paid
+/// entries as JSON or CSV.
+#[post("/reconciliation")]
+pub async fn reconcile_statement(
+    _admin: Admin,
+    query: Query<ReconciliationQuery>,
+    body: web::Bytes,
+    storage: Data<Storage>,
+) -> Result<HttpResponse, PromptPayServiceError> {
+    let entries = match query.format {
+        StatementFormat::Camt054 => parse_camt054(&body),
+        StatementFormat::Csv => query
+            .csv_format()
+            .and_then(|format| parse_bank_csv(&body, &format)),
+    }
+    .map_err(bad_request)?;
+    let report = web::block(move || storage.reconcile(entries)).await??;
+    match query.output {
+        ReportFormat::Json => Ok(HttpResponse::Ok().json(report)),
+        ReportFormat::Csv => Ok(HttpResponse::Ok()
+            .content_type("text/csv")
+            .body(report.to_csv()?)),
+    }
+}
+
+/// Verifies a bank slip verification QR given as JSON `{"payload": ..}`,
+/// plain text, a PNG/JPEG slip image or a multipart upload, and reports
+/// whether the bank has notified us of that transfer.
+#[post("/slip/verify")]
+pub async fn verify_slip(
+    http_req: HttpRequest,
+    payload: Payload,
+    storage: Data<Storage>,
+) -> Result<HttpResponse, PromptPayServiceError> {
+    let body = if http_req.content_type() == "multipart/form-data" {
+        let mut multipart = Multipart::new(http_req.headers(), payload);
+        read_first_multipart_file(&mut multipart).await?
+    } else {
+        payload
+            .to_bytes_limited(MAX_BATCH_BODY_BYTES)
+            .await
+            .map_err(|_| PromptPayServiceError::BadClientData)?
+            .map_err(|_| PromptPayServiceError::BadClientData)?
+            .to_vec()
+    };
+    let json_payload = if http_req.content_type() == "application/json" {
+        let rq: VerifySlipRq = serde_json::from_slice(&body).map_err(bad_request)?;
+        Some(rq.payload)
+    } else {
+        None
+    };
+    // Decoding an image and locating its QR codes is CPU bound, keep it off
+    // the worker thread
+    let slip = web::block(move || {
+        let candidates = match json_payload {
+            Some(payload) => vec![payload],
+            None => slip_payloads_from_bytes(&body).map_err(bad_request)?,
+        };
+        // a slip image may carry other QR codes next to the verification QR
+        let mut result = Err(EMVOError::new("No slip payload"));
+        for candidate in candidates {
+            result = parse_slip_qr(&candidate);
+            if result.is_ok() {
+                break;
+            }
+        }
+        result.map_err(bad_request)
+    })
+    .await??;
+    let verification = web::block(move || storage.verify_slip(slip)).await??;
+    Ok(HttpResponse::Ok().json(verification))
+}
+
+#[get("/webhooks/dead-letters")]
+pub async fn list_dead_letters(
+    _admin: Admin,
+    storage: Data<Storage>,
+) -> Result<HttpResponse, PromptPayServiceError> {
+    let dead_letters = web::block(move || storage.list_dead_letters()).await??;
+    Ok(HttpResponse::Ok().json(dead_letters))
+}
+
+#[post("/webhooks/dead-letters/{id}/retry")]
+pub async fn retry_dead_letter(
+    _admin: Admin,
+    id: Path<i64>,
+    storage: Data<Storage>,
+) -> Result<HttpResponse, PromptPayServiceError> {
+    let delivery = web::block(move || storage.retry_dead_letter(*id)).await??;
+    Ok(HttpResponse::Ok().json(delivery))
+}
+
+#[cfg(test)]
+mod test {
+    use actix_web::http::header;
+    use actix_web::{test, App};
+
+    use super::*;
+    use crate::admin_auth::AdminAuth;
+    use crate::bank_notifications::sign_payload;
+
+    const ADMIN_TOKEN: &str = "test-admin-token";
+
+    fn admin_header() -> (header::HeaderName, String) {
+        (header::AUTHORIZATION, format!("Bearer {}", ADMIN_TOKEN))
+    }
+
+    #[actix_web::test]
+    async fn test_qr_code_paynow_scheme() {
+        let app = test::init_service(
+            App::new()
+                .app_data(Data::new(QRCodeCache::new(1024 * 1024)))
+                .service(qr_code_tag30),
+        )
+        .await;
+        let mut rq = serde_json::json!({
+            "scheme": "paynow",
+            "uen": "201403121W",
+            "merchant_name": "COMPANY ABC",
+            "transaction_amount": 10.5,
+            "expiry_date": "20261231"
+        });
+        let req = test::TestRequest::post()
+            .uri("/promptpay/qrcode")
+            .set_json(&rq)
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::OK);
+
+        rq["uen"] = serde_json::json!("not a uen");
+        let req = test::TestRequest::post()
+            .uri("/promptpay/qrcode")
+            .set_json(&rq)
+            .to_request();
+        assert_eq!(
+            test::call_service(&app, req).await.status(),
+            StatusCode::BAD_REQUEST
+        );
+        let rq: GenerateQrCodeRq = serde_json::from_value(rq).unwrap();
+        assert_eq!(
+            rq.paynow_profile().unwrap().transaction_amount.as_deref(),
+            Some("10.5")
+        );
+    }
+
+    #[actix_web::test]
+    async fn test_qr_code_tag30_if_none_match() {
+        let cache = Data::new(QRCodeCache::new(1024 * 1024));
+        let app = test::init_service(
+            App::new()
+                .app_data(cache.clone())
+                .service(qr_code_tag30)
+                .service(qr_code_tag30_get),
+        )
+        .await;
+        let rq = GenerateQrCodeRq {
+            transaction_amount: 50.0,
+            mobile_number: "0809729900".to_string(),
+            merchant_name: "test".to_string(),
+            ..Default::default()
+        };
+
+        let req = test::TestRequest::post()
+            .uri("/promptpay/qrcode")
+            .set_json(&rq)
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let etag = resp.headers().get(header::ETAG).unwrap().clone();
+
+        let req = test::TestRequest::get()
+            .uri("/promptpay/qrcode?transaction_amount=50&mobile_number=0809729900&merchant_name=test")
+            .insert_header((header::IF_NONE_MATCH, etag))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::NOT_MODIFIED);
+        assert_eq!(cache.len(), 1);
+    }
+
+    #[actix_web::test]
+    async fn test_qr_code_to_dynamic() {
+        let cache = Data::new(QRCodeCache::new(1024 * 1024));
+        let app = test::init_service(
+            App::new()
+                .app_data(cache.clone())
+                .service(qr_code_to_dynamic),
+        )
+        .await;
+        let mut static_pay_load = "00020101021129370016A000000677010114011300008097299005204531153037645802TH5904test6007Bangkok6304".to_string();
+        static_pay_load.push_str(&crc16(&static_pay_load));
+
+        let req = test::TestRequest::post()
+            .uri("/promptpay/qrcode/dynamic")
+            .set_json(serde_json::json!({"pay_load": static_pay_load, "transaction_amount": 50}))
+            .to_request();
+        let body = test::call_and_read_body(&app, req).await;
+        let png = general_purpose::STANDARD.decode(&body).unwrap();
+        let pay_load = crate::slip_verification::slip_payloads_from_bytes(&png)
+            .unwrap()
+            .remove(0);
+        assert!(pay_load.starts_with("000201010212"));
+        assert!(pay_load.contains("540250"));
+
+        let req = test::TestRequest::post()
+            .uri("/promptpay/qrcode/dynamic")
+            .set_json(serde_json::json!({"pay_load": "000201010211", "transaction_amount": 50}))
+            .to_request();
+        assert_eq!(
+            test::call_service(&app, req).await.status(),
+            StatusCode::BAD_REQUEST
+        );
+    }
+
+    #[actix_web::test]
+    async fn test_qris_validate() {
+        let app = test::init_service(App::new().service(qris_validate)).await;
+        let mut pay_load = "00020101021126670017ID.CO.BANKXYZ.WWW011993600012345678901230212000123456789\
+                            0303UMI51440014ID.CO.QRIS.WWW0215ID10200211817450303UMI5204581253033605802ID\
+                            5913WARUNG BU SRI6007JAKARTA610510110".to_string();
+        pay_load.push_str("6304");
+        pay_load.push_str(&crc16(&pay_load));
+        let req = test::TestRequest::post()
+            .uri("/qris/validate")
+            .set_json(serde_json::json!({ "pay_load": pay_load }))
+            .to_request();
+        let validation: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(validation["compliant"], true);
+
+        let req = test::TestRequest::post()
+            .uri("/qris/validate")
+            .set_json(serde_json::json!({ "pay_load": "00020101021163041234" }))
+            .to_request();
+        assert_eq!(
+            test::call_service(&app, req).await.status(),
+            StatusCode::BAD_REQUEST
+        );
+    }
+
+    #[actix_web::test]
+    async fn test_emvco_encode_decode() {
+        let app = test::init_service(App::new().service(emvco_encode).service(emvco_decode)).await;
+        let mut pay_load = "00020101021129370016A000000677010111011300668123456785303764540550.255802TH5904test6007Bangkok62110507INV00016503123".to_string();
+        pay_load.push_str("6304");
+        pay_load.push_str(&crc16(&pay_load));
+
+        let req = test::TestRequest::post()
+            .uri("/emvco/decode")
+            .set_json(serde_json::json!({ "pay_load": pay_load }))
+            .to_request();
+        let decoded: EMVQRDocumentRs = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(decoded.pay_load, pay_load);
+        assert_eq!(
+            decoded.document.transaction_amount.as_deref(),
+            Some("50.25")
+        );
+
+        let req = test::TestRequest::post()
+            .uri("/emvco/encode")
+            .set_json(&decoded.document)
+            .to_request();
+        let encoded: EMVQRPayLoad = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(encoded.pay_load, pay_load);
+
+        let req = test::TestRequest::post()
+            .uri("/emvco/encode")
+            .set_json(serde_json::json!({"unknown": {"63": "ABCD"}}))
+            .to_request();
+        assert_eq!(
+            test::call_service(&app, req).await.status(),
+            StatusCode::BAD_REQUEST
+        );
+    }
+
+    #[actix_web::test]
+    async fn test_qr_code_tag30_batch() {
+        let app = test::init_service(App::new().service(qr_code_tag30_batch)).await;
+
+        let req = test::TestRequest::post()
+            .uri("/promptpay/qrcode/batch?format=pdf")
+            .set_json(serde_json::json!([
+                {"merchant_id": "M001", "mobile_number": "0809729900", "merchant_name": "test", "transaction_amount": 50},
+                {"merchant_id": "M002", "mobile_number": "not a number", "merchant_name": "test", "transaction_amount": 50}
+            ]))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(
+            resp.headers().get(header::CONTENT_TYPE).unwrap(),
+            "application/pdf"
+        );
+        let body = test::read_body(resp).await;
+        assert!(body.starts_with(b"%PDF"));
+
+        let req = test::TestRequest::post()
+            .uri("/promptpay/qrcode/batch")
+            .insert_header((header::CONTENT_TYPE, "text/csv"))
+            .set_payload("merchant_id,mobile_number,merchant_name,transaction_amount\nM001,0809729900,test,50\n")
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(
+            resp.headers().get(header::CONTENT_TYPE).unwrap(),
+            "application/zip"
+        );
+    }
+
+    #[actix_web::test]
+    async fn test_merchant_webhook_requires_admin_and_public_https() {
+        let app = test::init_service(
+            App::new()
+                .app_data(Data::new(Storage::open_in_memory().unwrap()))
+                .app_data(Data::new(AdminAuth::new(ADMIN_TOKEN)))
+                .service(set_merchant_webhook)
+                .service(list_dead_letters),
+        )
+        .await;
+        let webhook = |url: &str| serde_json::json!({ "url": url });
+
+        let req = test::TestRequest::put()
+            .uri("/merchants/M001/webhook")
+            .set_json(webhook("https://8.8.8.8/hook"))
+            .to_request();
+        assert_eq!(
+            test::call_service(&app, req).await.status(),
+            StatusCode::UNAUTHORIZED
+        );
+        let req = test::TestRequest::get()
+            .uri("/webhooks/dead-letters")
+            .to_request();
+        assert_eq!(
+            test::call_service(&app, req).await.status(),
+            StatusCode::UNAUTHORIZED
+        );
+        for url in ["http://8.8.8.8/hook", "https://169.254.169.254/hook"] {
+            let req = test::TestRequest::put()
+                .uri("/merchants/M001/webhook")
+                .insert_header(admin_header())
+                .set_json(webhook(url))
+                .to_request();
+            assert_eq!(
+                test::call_service(&app, req).await.status(),
+                StatusCode::BAD_REQUEST
+            );
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_merchant_registry_qr_code() {
+        let app = test::init_service(
+            App::new()
+                .app_data(Data::new(Storage::open_in_memory().unwrap()))
+                .app_data(Data::new(QRCodeCache::new(1024 * 1024)))
+                .app_data(Data::new(QRSigningKeys::default()))
+                .app_data(Data::new(AdminAuth::new(ADMIN_TOKEN)))
+                .service(create_merchant)
+                .service(get_merchant)
+                .service(merchant_qr_code),
+        )
+        .await;
+        let merchant = serde_json::json!({
+            "id": "M001",
+            "merchant_name": "test",
+            "merchant_city": "Bangkok",
+            "merchant_category_code": "5311",
+            "proxy_type": "mobile_number",
+            "proxy_value": "0809729900"
+        });
+
+        let req = test::TestRequest::post()
+            .uri("/merchants")
+            .set_json(&merchant)
+            .to_request();
+        assert_eq!(
+            test::call_service(&app, req).await.status(),
+            StatusCode::UNAUTHORIZED
+        );
+        let req = test::TestRequest::post()
+            .uri("/merchants")
+            .insert_header(admin_header())
+            .set_json(&merchant)
+            .to_request();
+        assert_eq!(
+            test::call_service(&app, req).await.status(),
+            StatusCode::CREATED
+        );
+        let req = test::TestRequest::post()
+            .uri("/merchants")
+            .insert_header(admin_header())
+            .set_json(&merchant)
+            .to_request();
+        assert_eq!(
+            test::call_service(&app, req).await.status(),
+            StatusCode::CONFLICT
+        );
+
+        let req = test::TestRequest::post()
+            .uri("/merchants/M001/qrcode")
+            .set_json(serde_json::json!({"transaction_amount": 50, "reference": "INV0001"}))
+            .to_request();
+        assert_eq!(
+            test::call_service(&app, req).await.status(),
+            StatusCode::UNAUTHORIZED
+        );
+        let req = test::TestRequest::post()
+            .uri("/merchants/M001/qrcode")
+            .insert_header(admin_header())
+            .set_json(serde_json::json!({"transaction_amount": 50, "reference": "INV0001"}))
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::OK);
+        let req = test::TestRequest::post()
+            .uri("/merchants/M999/qrcode")
+            .insert_header(admin_header())
+            .set_json(serde_json::json!({"transaction_amount": 50}))
+            .to_request();
+        assert_eq!(
+            test::call_service(&app, req).await.status(),
+            StatusCode::NOT_FOUND
+        );
+    }
+
+    #[actix_web::test]
+    async fn test_dynamic_qr_code_records_transaction() {
+        let app = test::init_service(
+            App::new()
+                .app_data(Data::new(Storage::open_in_memory().unwrap()))
+                .app_data(Data::new(QRCodeCache::new(1024 * 1024)))
+                .app_data(Data::new(
+                    QRSigningKeys::parse(&format!("K1:{}", "11".repeat(32)), None).unwrap(),
+                ))
+                .app_data(Data::new(AdminAuth::new(ADMIN_TOKEN)))
+                .service(create_merchant)
+                .service(merchant_qr_code)
+                .service(get_transaction)
+                .service(emvco_verify_signature)
+                .service(cancel_transaction),
+        )
+        .await;
+        let req = test::TestRequest::post()
+            .uri("/merchants")
+            .insert_header(admin_header())
+            .set_json(serde_json::json!({
+                "id": "M001",
+                "merchant_name": "test",
+                "merchant_city": "Bangkok",
+                "merchant_category_code": "5311",
+                "proxy_type": "mobile_number",
+                "proxy_value": "0809729900"
+            }))
+            .to_request();
+        test::call_service(&app, req).await;
+
+        let req = test::TestRequest::post()
+            .uri("/merchants/M001/qrcode")
+            .insert_header(admin_header())
+            .set_json(serde_json::json!({"transaction_amount": 50.25, "reference": "INV0001"}))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(
+            resp.headers().get(TRANSACTION_REFERENCE_HEADER).unwrap(),
+            "INV0001"
+        );
+        let req = test::TestRequest::post()
+            .uri("/merchants/M001/qrcode")
+            .insert_header(admin_header())
+            .set_json(serde_json::json!({"transaction_amount": 10, "reference": "INV0001"}))
+            .to_request();
+        assert_eq!(
+            test::call_service(&app, req).await.status(),
+            StatusCode::CONFLICT
+        );
+
+        let req = test::TestRequest::get()
+            .uri("/transactions/INV0001")
+            .to_request();
+        let transaction: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(transaction["status"], "PENDING");
+        assert_eq!(transaction["amount"], 50.25);
+        assert_eq!(transaction["merchant_id"], "M001");
+
+        let req = test::TestRequest::post()
+            .uri("/emvco/verify-signature")
+            .set_json(serde_json::json!({ "pay_load": transaction["pay_load"] }))
+            .to_request();
+        let verification: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(verification["status"], "VALID");
+        assert_eq!(verification["key_id"], "K1");
+
+        // the reference is printed in the QR, so it is no credential
+        let req = test::TestRequest::post()
+            .uri("/transactions/INV0001/cancel")
+            .to_request();
+        assert_eq!(
+            test::call_service(&app, req).await.status(),
+            StatusCode::UNAUTHORIZED
+        );
+        let req = test::TestRequest::post()
+            .uri("/transactions/INV0001/cancel")
+            .insert_header(admin_header())
+            .to_request();
+        let transaction: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(transaction["status"], "CANCELLED");
+        let req = test::TestRequest::post()
+            .uri("/transactions/INV0001/cancel")
+            .insert_header(admin_header())
+            .to_request();
+        assert_eq!(
+            test::call_service(&app, req).await.status(),
+            StatusCode::CONFLICT
+        );
+    }
+
+    #[actix_web::test]
+    async fn test_customer_presented_qr_code_ota() {
+        let app = test::init_service(
+            App::new()
+                .app_data(Data::new(Storage::open_in_memory().unwrap()))
+                .app_data(Data::new(OtaPolicy::default()))
+                .app_data(Data::new(AdminAuth::new(ADMIN_TOKEN)))
+                .service(create_customer)
+                .service(customer_qr_code)
+                .service(verify_customer_ota),
+        )
+        .await;
+        let customer_rq = serde_json::json!({
+            "id": "C001",
+            "proxy_type": "mobile_number",
+            "proxy_value": "0809729900"
+        });
+        let req = test::TestRequest::post()
+            .uri("/customers")
+            .set_json(&customer_rq)
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+
+        let req = test::TestRequest::post()
+            .uri("/customers")
+            .insert_header(admin_header())
+            .set_json(&customer_rq)
+            .to_request();
+        let customer: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(customer["ota_secret"].as_str().unwrap().len(), 32);
+        let access_token = customer["access_token"].as_str().unwrap();
+
+        let wrong_token = format!("Bearer {}", customer["ota_secret"].as_str().unwrap());
+        for (uri, authorization) in [
+            ("/customers/C001/qrcode", None),
+            ("/customers/C001/qrcode", Some(wrong_token)),
+            (
+                "/customers/C002/qrcode",
+                Some(format!("Bearer {}", access_token)),
+            ),
+        ] {
+            let mut req = test::TestRequest::post().uri(uri);
+            if let Some(authorization) = authorization {
+                req = req.insert_header((header::AUTHORIZATION, authorization));
+            }
+            let resp = test::call_service(&app, req.to_request()).await;
+            assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+        }
+
+        let req = test::TestRequest::post()
+            .uri("/customers/C001/qrcode")
+            .insert_header((header::AUTHORIZATION, format!("Bearer {}", access_token)))
+            .to_request();
+        let qr_code: CustomerQrCodeRs = test::call_and_read_body_json(&app, req).await;
+        assert!(qr_code.expires_at > unix_timestamp());
+
+        for (valid, customer_id) in [
+            (true, serde_json::json!("C001")),
+            (false, serde_json::Value::Null),
+        ] {
+            let req = test::TestRequest::post()
+                .uri("/promptpay/ota/verify")
+                .set_json(serde_json::json!({ "pay_load": qr_code.pay_load }))
+                .to_request();
+            let verification: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+            assert_eq!(verification["valid"], valid);
+            assert_eq!(verification["customer_id"], customer_id);
+        }
+    }
+
+    /// Replays the local bank stand-in feed: one payment, one short payment,
+    /// one unknown reference and a redelivery of the first payment.
+    #[actix_web::test]
+    async fn test_bank_notifications_mark_transactions_paid() {
+        let secret = b"bank-secret";
+        let storage = Storage::open_in_memory().unwrap();
+        storage
+            .insert_transaction("INV0001", "M001", 50.25, "000201", 60)
+            .unwrap();
+        storage
+            .insert_transaction("INV0002", "M001", 20.0, "000201", 60)
+            .unwrap();
+        let app = test::init_service(
+            App::new()
+                .app_data(Data::new(storage))
+                .app_data(Data::new(BankWebhookAuth::Hmac(secret.to_vec())))
+                .app_data(Data::new(AdminAuth::new(ADMIN_TOKEN)))
+                .service(bank_notification)
+                .service(list_notification_exceptions)
+                .service(get_transaction),
+        )
+        .await;
+
+        let feed: Vec<serde_json::Value> =
+            serde_json::from_str(include_str!("../testdata/bank_notifications.json")).unwrap();
+        let mut outcomes = vec![];
+        for notification in feed {
+            let body = serde_json::to_vec(&notification).unwrap();
+            let req = test::TestRequest::post()
+                .uri("/bank/notifications")
+                .insert_header((header::CONTENT_TYPE, "application/json"))
+                .insert_header((BANK_SIGNATURE_HEADER, sign_payload(secret, &body)))
+                .set_payload(body)
+                .to_request();
+            let resp: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+            outcomes.push(resp["outcome"].as_str().unwrap().to_string());
+        }
+        assert_eq!(
+            outcomes,
+            vec!["MATCHED", "AMOUNT_MISMATCH", "UNKNOWN_REFERENCE", "MATCHED"]
+        );
+
+        let req = test::TestRequest::get()
+            .uri("/transactions/INV0001")
+            .to_request();
+        let transaction: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(transaction["status"], "PAID");
+        let req = test::TestRequest::get()
+            .uri("/transactions/INV0002")
+            .to_request();
+        let transaction: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(transaction["status"], "PENDING");
+
+        // exceptions carry the payer's name and account
+        let req = test::TestRequest::get()
+            .uri("/bank/notifications/exceptions")
+            .to_request();
+        assert_eq!(
+            test::call_service(&app, req).await.status(),
+            StatusCode::UNAUTHORIZED
+        );
+        let req = test::TestRequest::get()
+            .uri("/bank/notifications/exceptions")
+            .insert_header(admin_header())
+            .to_request();
+        let exceptions: Vec<serde_json::Value> = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(exceptions.len(), 2);
+
+        let req = test::TestRequest::post()
+            .uri("/bank/notifications")
+            .insert_header((BANK_SIGNATURE_HEADER, "sha256=00"))
+            .set_payload("{}")
+            .to_request();
+        assert_eq!(
+            test::call_service(&app, req).await.status(),
+            StatusCode::UNAUTHORIZED
+        );
+    }
+
+    #[actix_web::test]
+    async fn test_transaction_events_push_status_changes() {
+        let storage = Data::new(Storage::open_in_memory().unwrap());
+        storage
+            .insert_transaction("INV0001", "M001", 10.0, "000201", 60)
+            .unwrap();
+        storage
+            .insert_transaction("INV0002", "M001", 10.0, "000201", 1)
+            .unwrap();
+        let app = test::init_service(
+            App::new()
+                .app_data(storage.clone())
+                .service(transaction_events),
+        )
+        .await;
+
+        let req = test::TestRequest::get()
+            .uri("/transactions/INV0001/events")
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(
+            resp.headers().get(header::CONTENT_TYPE).unwrap(),
+            "text/event-stream"
+        );
+        {
+            let storage = storage.clone();
+            actix_web::rt::spawn(async move {
+                actix_web::rt::time::sleep(std::time::Duration::from_millis(50)).await;
+                storage
+                    .transition_transaction("INV0001", TransactionStatus::Paid)
+                    .unwrap();
+            });
+        }
+        let body = actix_web::body::to_bytes(resp.into_body()).await.unwrap();
+        let body = std::str::from_utf8(&body).unwrap();
+        let events: Vec<&str> = body.split("\n\n").filter(|e| !e.is_empty()).collect();
+        assert_eq!(events.len(), 2);
+        assert!(events[0].contains("\"status\":\"PENDING\""));
+        assert!(events[1].starts_with("event: status\ndata: "));
+        assert!(events[1].contains("\"status\":\"PAID\""));
+
+        // Nobody reads INV0002, the stream itself expires it
+        let req = test::TestRequest::get()
+            .uri("/transactions/INV0002/events")
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        let body = actix_web::body::to_bytes(resp.into_body()).await.unwrap();
+        let body = std::str::from_utf8(&body).unwrap();
+        assert!(body.contains("\"status\":\"EXPIRED\""));
+
+        let req = test::TestRequest::get()
+            .uri("/transactions/UNKNOWN/events")
+            .to_request();
+        assert_eq!(
+            test::call_service(&app, req).await.status(),
+            StatusCode::NOT_FOUND
+        );
+    }
+
+    #[actix_web::test]
+    async fn test_reconcile_bank_csv() {
+        let storage = Storage::open_in_memory().unwrap();
+        storage
+            .insert_transaction("INV0001", "M001", 50.25, "000201", 60)
+            .unwrap();
+        let app = test::init_service(
+            App::new()
+                .app_data(Data::new(storage))
+                .app_data(Data::new(AdminAuth::new(ADMIN_TOKEN)))
+                .service(reconcile_statement),
+        )
+        .await;
+
+        let statement = "date,transaction_id,ref1,credit\n2024-06-10,B1,INV0001,50.25\n";
+        let req = test::TestRequest::post()
+            .uri("/reconciliation?format=csv&amount_column=credit&reference_columns=ref1")
+            .set_payload(statement)
+            .to_request();
+        assert_eq!(
+            test::call_service(&app, req).await.status(),
+            StatusCode::UNAUTHORIZED
+        );
+        let req = test::TestRequest::post()
+            .uri("/reconciliation?format=csv&amount_column=credit&reference_columns=ref1")
+            .insert_header(admin_header())
+            .set_payload("date,transaction_id,ref1,credit\n2024-06-10,B1,INV0001,50.25\n2024-06-10,B2,X1,10\n")
+            .to_request();
+        let report: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(report["summary"]["matched"], 1);
+        assert_eq!(report["summary"]["unmatched"], 1);
+        assert_eq!(report["matched"][0]["merchant_id"], "M001");
+
+        let req = test::TestRequest::post()
+            .uri("/reconciliation?format=camt054&output=csv")
+            .insert_header(admin_header())
+            .set_payload(&include_bytes!("../testdata/camt054.xml")[..])
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(
+            resp.headers().get(header::CONTENT_TYPE).unwrap(),
+            "text/csv"
+        );
+        let body = test::read_body(resp).await;
+        assert!(body.starts_with(b"category,"));
+
+        let req = test::TestRequest::post()
+            .uri("/reconciliation")
+            .insert_header(admin_header())
+            .set_payload("<Document><Ntry>")
+            .to_request();
+        assert_eq!(
+            test::call_service(&app, req).await.status(),
+            StatusCode::BAD_REQUEST
+        );
+    }
+
+    #[actix_web::test]
+    async fn test_verify_slip() {
+        let storage = Storage::open_in_memory().unwrap();
+        storage
+            .insert_transaction("INV0001", "M001", 50.25, "000201", 60)
+            .unwrap();
+        storage
+            .ingest_bank_notification(
+                &BankNotificationRq {
+                    transaction_id: "014242082547BPM04988".to_string(),
+                    amount: 50.25,
+                    ref1: "INV0001".to_string(),
+                    ref2: None,
+                    ref3: None,
+                    payer_name: None,
+                    payer_account: None,
+                    paid_at: 1718000000,
+                },
+                &Default::default(),
+            )
+            .unwrap();
+        let app =
+            test::init_service(App::new().app_data(Data::new(storage)).service(verify_slip)).await;
+
+        let mut slip = "0041000600000101030040220014242082547BPM049885102TH9104".to_string();
+        slip.push_str(&crc16(&slip));
+        let png =
+            qrcode_generator::to_png_to_vec_from_str(&slip, qrcode_generator::QrCodeEcc::Low, 320)
+                .unwrap();
+        let req = test::TestRequest::post()
+            .uri("/slip/verify")
+            .insert_header((header::CONTENT_TYPE, "image/png"))
+            .set_payload(png)
+            .to_request();
+        let rs: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(rs["sending_bank"], "KBANK");
+        assert_eq!(rs["transaction_reference"], "014242082547BPM04988");
+        assert_eq!(rs["payment_received"], true);
+
+        let mut unknown = "0041000600000101030140220014242082547BPM000015102TH9104".to_string();
+        unknown.push_str(&crc16(&unknown));
+        let req = test::TestRequest::post()
+            .uri("/slip/verify")
+            .set_json(serde_json::json!({ "payload": unknown }))
+            .to_request();
+        let rs: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(rs["sending_bank"], "SCB");
+        assert_eq!(rs["payment_received"], false);
+
+        let req = test::TestRequest::post()
+            .uri("/slip/verify")
+            .insert_header((header::CONTENT_TYPE, "text/plain"))
+            .set_payload(slip.replace("BPM", "BPX"))
+            .to_request();
+        assert_eq!(
+            test::call_service(&app, req).await.status(),
+            StatusCode::BAD_REQUEST
+        );
+    }
 }
@@ -0,0 +1,491 @@
+#![allow(dead_code)]
+
+use std::fs::File;
+use std::io::{self, BufReader};
+use std::sync::Arc;
+
+use hmac::{Hmac, Mac};
+use log::{error, info, warn};
+use opentelemetry::global;
+use opentelemetry::metrics::Counter;
+use opentelemetry::KeyValue;
+use rusqlite::{params, Connection, OptionalExtension, TransactionBehavior};
+use rustls::server::WebPkiClientVerifier;
+use rustls::{RootCertStore, ServerConfig};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+
+use crate::apis::PromptPayServiceError;
+use crate::storage::{unix_timestamp, Storage};
+use crate::transactions::{
+    expire_overdue_transaction, select_transaction, to_satang, update_transaction_status,
+    Transaction, TransactionStatus,
+};
+use crate::webhooks::{insert_transaction_webhook, TraceContext, TRANSACTION_PAID_EVENT};
+
+pub const BANK_NOTIFICATION_SCHEMA: &str = "CREATE TABLE IF NOT EXISTS bank_notifications (
+    transaction_id TEXT PRIMARY KEY NOT NULL,
+    reference TEXT NOT NULL,
+    amount_satang INTEGER NOT NULL,
+    notification TEXT NOT NULL,
+    outcome TEXT NOT NULL,
+    detail TEXT,
+    received_at INTEGER NOT NULL
+);
+CREATE INDEX IF NOT EXISTS bank_notifications_outcome ON bank_notifications (outcome, received_at)";
+
+pub const BANK_SIGNATURE_HEADER: &str = "x-bank-signature";
+const BANK_SIGNATURE_PREFIX: &str = "sha256=";
+
+/// Payment confirmation pushed by the bank. `ref1` carries the tag 62
+/// reference label of the dynamic QR; `paid_at` is in unix seconds.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct BankNotificationRq {
+    pub transaction_id: String,
+    pub amount: f64,
+    pub ref1: String,
+    pub ref2: Option<String>,
+    pub ref3: Option<String>,
+    pub payer_name: Option<String>,
+    pub payer_account: Option<String>,
+    pub paid_at: i64,
+}
+
+/// How a notification was matched. Everything but `Matched` is an exception
+/// left for an operator to resolve.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum NotificationOutcome {
+    Matched,
+    UnknownReference,
+    AmountMismatch,
+    NotPending,
+    /// Paid after the QR expired, held for refund review.
+    LatePayment,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct BankNotification {
+    pub transaction_id: String,
+    pub reference: String,
+    pub outcome: NotificationOutcome,
+    pub detail: Option<String>,
+    pub received_at: i64,
+    pub notification: BankNotificationRq,
+}
+
+/// How the bank proves its identity. Plain HTTP listeners check the HMAC
+/// signature; the mTLS listener has already verified the client certificate.
+#[derive(Clone)]
+pub enum BankWebhookAuth {
+    Hmac(Vec<u8>),
+    MutualTls,
+    Disabled,
+}
+
+impl NotificationOutcome {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            NotificationOutcome::Matched => "MATCHED",
+            NotificationOutcome::UnknownReference => "UNKNOWN_REFERENCE",
+            NotificationOutcome::AmountMismatch => "AMOUNT_MISMATCH",
+            NotificationOutcome::NotPending => "NOT_PENDING",
+            NotificationOutcome::LatePayment => "LATE_PAYMENT",
+        }
+    }
+
+    fn parse(s: &str) -> Option<Self> {
+        match s {
+            "MATCHED" => Some(NotificationOutcome::Matched),
+            "UNKNOWN_REFERENCE" => Some(NotificationOutcome::UnknownReference),
+            "AMOUNT_MISMATCH" => Some(NotificationOutcome::AmountMismatch),
+            "NOT_PENDING" => Some(NotificationOutcome::NotPending),
+            "LATE_PAYMENT" => Some(NotificationOutcome::LatePayment),
+            _ => None,
+        }
+    }
+}
+
+impl BankWebhookAuth {
+    /// Reads `BANK_WEBHOOK_HMAC_SECRET`. Without it the plain HTTP endpoint
+    /// rejects every notification.
+    pub fn from_env() -> Self {
+        match std::env::var("BANK_WEBHOOK_HMAC_SECRET") {
+            Ok(secret) if !secret.is_empty() => BankWebhookAuth::Hmac(secret.into_bytes()),
+            _ => BankWebhookAuth::Disabled,
+        }
+    }
+
+    pub fn verify(&self, signature: Option<&str>, body: &[u8]) -> bool {
+        match self {
+            BankWebhookAuth::MutualTls => true,
+            BankWebhookAuth::Disabled => false,
+            BankWebhookAuth::Hmac(secret) => signature
+                .and_then(|s| s.strip_prefix(BANK_SIGNATURE_PREFIX))
+                .and_then(|s| hex::decode(s).ok())
+                .map(|expected| {
+                    let mut mac = Hmac::<Sha256>::new_from_slice(secret)
+                        .expect("HMAC accepts keys of any length");
+                    mac.update(body);
+                    mac.verify_slice(&expected).is_ok()
+                })
+                .unwrap_or(false),
+        }
+    }
+}
+
+/// `sha256=<hex>` HMAC-SHA256 of `body`, as sent by the bank in
+/// `X-Bank-Signature` and by us on merchant webhooks.
+pub fn sign_payload(secret: &[u8], body: &[u8]) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret).expect("HMAC accepts keys of any length");
+    mac.update(body);
+    format!(
+        "{}{}",
+        BANK_SIGNATURE_PREFIX,
+        hex::encode(mac.finalize().into_bytes())
+    )
+}
+
+/// Builds the TLS config for the bank-only listener from
+/// `BANK_WEBHOOK_TLS_CERT`, `BANK_WEBHOOK_TLS_KEY` and `BANK_WEBHOOK_CLIENT_CA`
+/// (PEM files). Returns `None` when mTLS is not configured.
+pub fn mtls_server_config_from_env() -> io::Result<Option<ServerConfig>> {
+    let (cert, key, ca) = match (
+        std::env::var("BANK_WEBHOOK_TLS_CERT"),
+        std::env::var("BANK_WEBHOOK_TLS_KEY"),
+        std::env::var("BANK_WEBHOOK_CLIENT_CA"),
+    ) {
+        (Ok(cert), Ok(key), Ok(ca)) => (cert, key, ca),
+        _ => return Ok(None),
+    };
+    let invalid =
+        |e: &dyn std::fmt::Display| io::Error::new(io::ErrorKind::InvalidData, e.to_string());
+
+    let certs = rustls_pemfile::certs(&mut BufReader::new(File::open(cert)?))
+        .collect::<Result<Vec<_>, _>>()?;
+    let key = rustls_pemfile::private_key(&mut BufReader::new(File::open(key)?))?
+        .ok_or_else(|| invalid(&"no private key found"))?;
+    let mut roots = RootCertStore::empty();
+    for ca_cert in rustls_pemfile::certs(&mut BufReader::new(File::open(ca)?)) {
+        roots.add(ca_cert?).map_err(|e| invalid(&e))?;
+    }
+
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let verifier = WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider.clone())
+        .build()
+        .map_err(|e| invalid(&e))?;
+    let config = ServerConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()
+        .map_err(|e| invalid(&e))?
+        .with_client_cert_verifier(verifier)
+        .with_single_cert(certs, key)
+        .map_err(|e| invalid(&e))?;
+    Ok(Some(config))
+}
+
+/// Payments for expired QRs (stale printed receipts, slow payers) are never
+/// applied; they are flagged for refund review instead.
+fn not_pending_outcome(
+    rq: &BankNotificationRq,
+    transaction: &Transaction,
+) -> (NotificationOutcome, Option<String>) {
+    if transaction.status != TransactionStatus::Expired {
+        return (
+            NotificationOutcome::NotPending,
+            Some(format!("transaction is {}", transaction.status)),
+        );
+    }
+    let detail = if rq.paid_at > transaction.expires_at {
+        format!(
+            "paid at {} after expiry at {}, refund review",
+            rq.paid_at, transaction.expires_at
+        )
+    } else {
+        format!(
+            "paid at {} but notified after expiry at {}, refund review",
+            rq.paid_at, transaction.expires_at
+        )
+    };
+    (NotificationOutcome::LatePayment, Some(detail))
+}
+
+fn notification_counter() -> Counter<u64> {
+    global::meter("prompt_pay_service")
+        .u64_counter("bank_notifications")
+        .with_description("Bank payment notifications by match outcome")
+        .init()
+}
+
+fn notification_from_row(row: &rusqlite::Row) -> rusqlite::Result<BankNotification> {
+    let outcome: String = row.get(2)?;
+    let notification: String = row.get(5)?;
+    let conversion_error = |index, e: Box<dyn std::error::Error + Send + Sync>| {
+        rusqlite::Error::FromSqlConversionFailure(index, rusqlite::types::Type::Text, e)
+    };
+    Ok(BankNotification {
+        transaction_id: row.get(0)?,
+        reference: row.get(1)?,
+        outcome: NotificationOutcome::parse(&outcome)
+            .ok_or_else(|| conversion_error(2, format!("unknown outcome {}", outcome).into()))?,
+        detail: row.get(3)?,
+        received_at: row.get(4)?,
+        notification: serde_json::from_str(&notification)
+            .map_err(|e| conversion_error(5, e.into()))?,
+    })
+}
+
+const NOTIFICATION_COLUMNS: &str =
+    "transaction_id, reference, outcome, detail, received_at, notification";
+
+fn select_bank_notification(
+    conn: &Connection,
+    transaction_id: &str,
+) -> Result<Option<BankNotification>, PromptPayServiceError> {
+    Ok(conn
+        .query_row(
+            &format!(
+                "SELECT {} FROM bank_notifications WHERE transaction_id = ?1",
+                NOTIFICATION_COLUMNS
+            ),
+            params![transaction_id],
+            notification_from_row,
+        )
+        .optional()?)
+}
+
+impl Storage {
+    /// Matches a bank notification against the pending transaction named by
+    /// `ref1` and marks it paid. Redelivered notifications (same bank
+    /// `transaction_id`) return the outcome recorded the first time. A match
+    /// queues the merchant's `transaction.paid` webhook.
+    ///
+    /// Everything happens in one SQL transaction: the notification is only
+    /// recorded together with the status change and its webhook, so a failed
+    /// ingest leaves nothing behind and the bank's retry is matched again.
+    pub fn ingest_bank_notification(
+        &self,
+        rq: &BankNotificationRq,
+        trace_context: &TraceContext,
+    ) -> Result<BankNotification, PromptPayServiceError> {
+        let amount_satang = to_satang(rq.amount);
+        let notification_json = serde_json::to_string(rq).map_err(|e| {
+            error!("Bank notification encode error: {}", e);
+            PromptPayServiceError::InternalError
+        })?;
+
+        let (notification, changed) = {
+            let mut conn = self.connection();
+            let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+            if let Some(existing) = select_bank_notification(&tx, &rq.transaction_id)? {
+                return Ok(existing);
+            }
+
+            let mut changed = None;
+            let (outcome, detail) = match select_transaction(&tx, &rq.ref1)? {
+                None => (NotificationOutcome::UnknownReference, None),
+                Some(transaction)
+                    if transaction.status == TransactionStatus::Pending
+                        && transaction.expires_at <= unix_timestamp() =>
+                {
+                    let expired = expire_overdue_transaction(&tx, &rq.ref1)?;
+                    let outcome = not_pending_outcome(rq, &expired);
+                    changed = Some(expired);
+                    outcome
+                }
+                Some(transaction) if transaction.status != TransactionStatus::Pending => {
+                    not_pending_outcome(rq, &transaction)
+                }
+                Some(transaction) if transaction.amount_satang != amount_satang => (
+                    NotificationOutcome::AmountMismatch,
+                    Some(format!(
+                        "expected {:.2} received {:.2}",
+                        transaction.amount, rq.amount
+                    )),
+                ),
+                Some(_) => {
+                    let paid = update_transaction_status(&tx, &rq.ref1, TransactionStatus::Paid)?;
+                    insert_transaction_webhook(&tx, TRANSACTION_PAID_EVENT, &paid, trace_context)?;
+                    changed = Some(paid);
+                    (NotificationOutcome::Matched, None)
+                }
+            };
+
+            let notification = BankNotification {
+                transaction_id: rq.transaction_id.clone(),
+                reference: rq.ref1.clone(),
+                outcome,
+                detail,
+                received_at: unix_timestamp(),
+                notification: rq.clone(),
+            };
+            let inserted = tx.execute(
+                "INSERT INTO bank_notifications (transaction_id, reference, amount_satang, notification, outcome, detail, received_at)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)
+                 ON CONFLICT (transaction_id) DO NOTHING",
+                params![
+                    notification.transaction_id,
+                    notification.reference,
+                    amount_satang,
+                    notification_json,
+                    outcome.as_str(),
+                    notification.detail,
+                    notification.received_at
+                ],
+            )?;
+            if inserted == 0 {
+                // Recorded by another connection first: keep its outcome and
+                // roll back ours
+                return select_bank_notification(&tx, &rq.transaction_id)?
+                    .ok_or(PromptPayServiceError::InternalError);
+            }
+            tx.commit()?;
+            (notification, changed)
+        };
+
+        if let Some(transaction) = &changed {
+            self.transaction_events().publish(transaction);
+        }
+        let outcome = notification.outcome;
+        notification_counter().add(1, &[KeyValue::new("outcome", outcome.as_str())]);
+        if outcome == NotificationOutcome::Matched {
+            info!("Transaction {} paid by bank notification", rq.ref1);
+        } else {
+            warn!(
+                "Bank notification {} for {} recorded as {}",
+                rq.transaction_id,
+                rq.ref1,
+                outcome.as_str()
+            );
+        }
+        Ok(notification)
+    }
+
+    pub fn get_bank_notification(
+        &self,
+        transaction_id: &str,
+    ) -> Result<Option<BankNotification>, PromptPayServiceError> {
+        select_bank_notification(&self.connection(), transaction_id)
+    }
+
+    /// Notifications that were not matched, optionally of one outcome only.
+    pub fn list_notification_exceptions(
+        &self,
+        outcome: Option<NotificationOutcome>,
+    ) -> Result<Vec<BankNotification>, PromptPayServiceError> {
+        let conn = self.connection();
+        let mut stmt = conn.prepare(&format!(
+            "SELECT {} FROM bank_notifications WHERE outcome != ?1 AND (?2 IS NULL OR outcome = ?2)
+             ORDER BY received_at, transaction_id",
+            NOTIFICATION_COLUMNS
+        ))?;
+        let rows = stmt.query_map(
+            params![
+                NotificationOutcome::Matched.as_str(),
+                outcome.map(|o| o.as_str())
+            ],
+            notification_from_row,
+        )?;
+        Ok(rows.collect::<rusqlite::Result<Vec<_>>>()?)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_hmac_signature() {
+        let auth = BankWebhookAuth::Hmac(b"secret".to_vec());
+        let body = br#"{"transaction_id":"B1"}"#;
+        let signature = sign_payload(b"secret", body);
+        assert!(auth.verify(Some(&signature), body));
+        assert!(!auth.verify(Some(&signature), b"{}"));
+        assert!(!auth.verify(Some(&sign_payload(b"other", body)), body));
+        assert!(!auth.verify(None, body));
+        assert!(!BankWebhookAuth::Disabled.verify(Some(&signature), body));
+        assert!(BankWebhookAuth::MutualTls.verify(None, body));
+    }
+
+    #[test]
+    fn test_late_payment_is_flagged_for_refund_review() {
+        let storage = Storage::open_in_memory().unwrap();
+        let transaction = storage
+            .insert_transaction("INV0001", "M001", 10.0, "000201", 0)
+            .unwrap();
+        let rq = BankNotificationRq {
+            transaction_id: "BANK1".to_string(),
+            amount: 10.0,
+            ref1: "INV0001".to_string(),
+            ref2: None,
+            ref3: None,
+            payer_name: None,
+            payer_account: None,
+            paid_at: transaction.expires_at + 60,
+        };
+        let notification = storage
+            .ingest_bank_notification(&rq, &TraceContext::new())
+            .unwrap();
+        assert_eq!(notification.outcome, NotificationOutcome::LatePayment);
+        assert_eq!(
+            storage.get_transaction("INV0001").unwrap().status,
+            TransactionStatus::Expired
+        );
+        assert_eq!(
+            storage
+                .list_notification_exceptions(Some(NotificationOutcome::LatePayment))
+                .unwrap()
+                .len(),
+            1
+        );
+        assert!(storage
+            .list_notification_exceptions(Some(NotificationOutcome::AmountMismatch))
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn test_failed_ingest_is_rolled_back_and_retried() {
+        let storage = Storage::open_in_memory().unwrap();
+        storage
+            .insert_transaction("INV0001", "M001", 10.0, "000201", 60)
+            .unwrap();
+        let rq = BankNotificationRq {
+            transaction_id: "BANK1".to_string(),
+            amount: 10.0,
+            ref1: "INV0001".to_string(),
+            ref2: None,
+            ref3: None,
+            payer_name: None,
+            payer_account: None,
+            paid_at: unix_timestamp(),
+        };
+        // The webhook cannot be queued, so neither the payment nor the
+        // notification may be recorded
+        storage
+            .connection()
+            .execute_batch("ALTER TABLE webhook_deliveries RENAME TO webhook_deliveries_off")
+            .unwrap();
+        assert!(storage
+            .ingest_bank_notification(&rq, &TraceContext::new())
+            .is_err());
+        assert_eq!(
+            storage.get_transaction("INV0001").unwrap().status,
+            TransactionStatus::Pending
+        );
+        assert!(storage.get_bank_notification("BANK1").unwrap().is_none());
+
+        storage
+            .connection()
+            .execute_batch("ALTER TABLE webhook_deliveries_off RENAME TO webhook_deliveries")
+            .unwrap();
+        let notification = storage
+            .ingest_bank_notification(&rq, &TraceContext::new())
+            .unwrap();
+        assert_eq!(notification.outcome, NotificationOutcome::Matched);
+        let redelivered = storage
+            .ingest_bank_notification(&rq, &TraceContext::new())
+            .unwrap();
+        assert_eq!(redelivered.outcome, NotificationOutcome::Matched);
+        assert_eq!(redelivered.received_at, notification.received_at);
+    }
+}
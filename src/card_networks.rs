@@ -0,0 +1,163 @@
+#![allow(dead_code)]
+
+use std::convert::TryFrom;
+use std::ops::RangeInclusive;
+
+use serde::{Deserialize, Serialize};
+
+use crate::emvo_types::*;
+
+// EMVCo reserved primitive Merchant Account Information IDs, two per network
+pub const ID_VISA_RANGE_START: TagID = TagID::new(2); // (O) 02-03 Visa
+pub const ID_MASTERCARD_RANGE_START: TagID = TagID::new(4); // (O) 04-05 Mastercard
+pub const ID_DISCOVER_RANGE_START: TagID = TagID::new(9); // (O) 09-10 Discover
+pub const ID_AMEX_RANGE_START: TagID = TagID::new(11); // (O) 11-12 Amex
+pub const ID_JCB_RANGE_START: TagID = TagID::new(13); // (O) 13-14 JCB
+pub const ID_UNIONPAY_RANGE_START: TagID = TagID::new(15); // (O) 15-16 UnionPay
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CardNetwork {
+    Visa,
+    Mastercard,
+    Discover,
+    Amex,
+    Jcb,
+    Unionpay,
+}
+
+/// A card network merchant ID to put next to the PromptPay account, e.g.
+/// `{"network": "visa", "merchant_id": "4000001234567899"}`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CardMerchantAccount {
+    pub network: CardNetwork,
+    pub merchant_id: String,
+}
+
+impl CardNetwork {
+    /// The two IDs reserved for the network.
+    pub fn tag_ids(self) -> [TagID; 2] {
+        let start = match self {
+            CardNetwork::Visa => ID_VISA_RANGE_START,
+            CardNetwork::Mastercard => ID_MASTERCARD_RANGE_START,
+            CardNetwork::Discover => ID_DISCOVER_RANGE_START,
+            CardNetwork::Amex => ID_AMEX_RANGE_START,
+            CardNetwork::Jcb => ID_JCB_RANGE_START,
+            CardNetwork::Unionpay => ID_UNIONPAY_RANGE_START,
+        };
+        [start, TagID::new(start.value() + 1)]
+    }
+
+    /// Allowed lengths, leading digits and whether the Luhn check digit
+    /// applies to the network's merchant PAN.
+    fn rules(self) -> (RangeInclusive<usize>, &'static [&'static str], bool) {
+        match self {
+            CardNetwork::Visa => (16..=16, &["4"], true),
+            CardNetwork::Mastercard => (16..=16, &["5", "2"], true),
+            CardNetwork::Discover => (16..=19, &["6"], true),
+            CardNetwork::Amex => (15..=15, &["34", "37"], true),
+            CardNetwork::Jcb => (16..=19, &["35"], true),
+            // UnionPay merchant PANs do not all carry a Luhn check digit
+            CardNetwork::Unionpay => (16..=19, &["62"], false),
+        }
+    }
+
+    pub fn validate(self, merchant_id: &str) -> Result<(), EMVOError> {
+        let (lengths, prefixes, luhn) = self.rules();
+        let valid = lengths.contains(&merchant_id.len())
+            && merchant_id.chars().all(|c| c.is_ascii_digit())
+            && prefixes
+                .iter()
+                .any(|prefix| merchant_id.starts_with(prefix))
+            && (!luhn || luhn_valid(merchant_id));
+        if !valid {
+            let msg = format!("{:?} Merchant Id Invalid", self);
+            return Err(EMVOError::new(msg.as_str()));
+        }
+        Ok(())
+    }
+}
+
+fn luhn_valid(digits: &str) -> bool {
+    let sum: u32 = digits
+        .chars()
+        .rev()
+        .filter_map(|c| c.to_digit(10))
+        .enumerate()
+        .map(|(index, digit)| match (index % 2, digit * 2) {
+            (0, _) => digit,
+            (_, doubled) if doubled > 9 => doubled - 9,
+            (_, doubled) => doubled,
+        })
+        .sum();
+    sum.is_multiple_of(10)
+}
+
+pub type MerchantAccountDataObject = (TagID, Box<dyn EMVOData>);
+
+/// Validates the accounts and assigns each one the next free ID of its
+/// network, so a network can appear at most twice.
+pub fn card_merchant_accounts(
+    accounts: &[CardMerchantAccount],
+) -> Result<Vec<MerchantAccountDataObject>, EMVOError> {
+    let mut data_objects: Vec<MerchantAccountDataObject> = vec![];
+    for account in accounts {
+        account.network.validate(&account.merchant_id)?;
+        let tag_id = IntoIterator::into_iter(account.network.tag_ids())
+            .find(|tag_id| data_objects.iter().all(|(id, _)| id != tag_id))
+            .ok_or_else(|| {
+                let msg = format!("More Than Two {:?} Merchant Accounts", account.network);
+                EMVOError::new(msg.as_str())
+            })?;
+        let merchant_id = EMVAlphanumbericSpecial::try_from(account.merchant_id.clone())?;
+        data_objects.push((tag_id, Box::new(merchant_id)));
+    }
+    Ok(data_objects)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn account(network: CardNetwork, merchant_id: &str) -> CardMerchantAccount {
+        CardMerchantAccount {
+            network,
+            merchant_id: merchant_id.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_card_network_validation() {
+        assert!(CardNetwork::Visa.validate("4111111111111111").is_ok());
+        assert!(CardNetwork::Visa.validate("4111111111111112").is_err());
+        assert!(CardNetwork::Visa.validate("5555555555554444").is_err());
+        assert!(CardNetwork::Mastercard.validate("5555555555554444").is_ok());
+        assert!(CardNetwork::Mastercard.validate("2223003122003222").is_ok());
+        assert!(CardNetwork::Amex.validate("378282246310005").is_ok());
+        assert!(CardNetwork::Amex.validate("37828224631000").is_err());
+        assert!(CardNetwork::Discover.validate("6011111111111117").is_ok());
+        assert!(CardNetwork::Jcb.validate("3530111333300000").is_ok());
+        assert!(CardNetwork::Unionpay.validate("6212345678901234").is_ok());
+        assert!(CardNetwork::Unionpay.validate("621234567890123A").is_err());
+    }
+
+    #[test]
+    fn test_card_merchant_accounts() {
+        let data_objects = card_merchant_accounts(&[
+            account(CardNetwork::Visa, "4111111111111111"),
+            account(CardNetwork::Mastercard, "5555555555554444"),
+            account(CardNetwork::Visa, "4012888888881881"),
+        ])
+        .unwrap();
+        let ids: Vec<TagID> = data_objects.iter().map(|(id, _)| *id).collect();
+        assert_eq!(ids, [TagID::new(2), TagID::new(4), TagID::new(3)]);
+        assert_eq!(data_objects[2].1.value(), "4012888888881881");
+
+        let three_visas = [
+            account(CardNetwork::Visa, "4111111111111111"),
+            account(CardNetwork::Visa, "4111111111111111"),
+            account(CardNetwork::Visa, "4111111111111111"),
+        ];
+        assert!(card_merchant_accounts(&three_visas).is_err());
+    }
+}
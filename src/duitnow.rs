@@ -0,0 +1,265 @@
+#![allow(dead_code)]
+
+use std::convert::TryFrom;
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+use crate::emvo_qrcode::*;
+use crate::emvo_types::*;
+
+pub const MYR: CurrencyCode = "458";
+pub const MALAYSIA: CountryCode = "MY";
+
+pub const ID_DUITNOW: TagID = TagID::new(26);
+/// PayNet's application identifier for DuitNow QR.
+pub const DUITNOW_AID: &str = "A0000006150001";
+
+pub const ID_DUITNOW_AID: TagID = TagID::new(0);
+pub const ID_DUITNOW_ACQUIRER_ID: TagID = TagID::new(1);
+pub const ID_DUITNOW_MERCHANT_ID: TagID = TagID::new(2);
+
+const MAX_LENGTH_ACQUIRER_ID: usize = 11;
+const MAX_LENGTH_MERCHANT_ID: usize = 25;
+
+/// The DuitNow merchant account information template (ID "26").
+pub struct MerchantDuitNow {
+    template: EMVTemplate,
+}
+
+/// A DuitNow QR: what a Malaysian store supplies on top of the defaults
+/// (MYR, MY), and what a DuitNow payload decodes into. DuitNow requires the
+/// reference label (62/05) and the purpose of transaction (62/08).
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DuitNowProfile {
+    /// PayNet participant code of the acquiring bank.
+    pub acquirer_id: String,
+    /// Merchant ID assigned by the acquirer.
+    pub merchant_id: String,
+    pub merchant_category_code: String,
+    pub merchant_name: String,
+    pub merchant_city: String,
+    #[serde(default)]
+    pub postal_code: Option<String>,
+    #[serde(default)]
+    pub transaction_amount: Option<String>,
+    pub reference_label: String,
+    pub purpose_of_transaction: String,
+}
+
+impl Default for MerchantDuitNow {
+    fn default() -> Self {
+        let mut template = EMVTemplate::new(ID_DUITNOW).unwrap();
+        template
+            .set_field(
+                ID_DUITNOW_AID,
+                Box::new(EMVAlphanumbericSpecial::try_from(DUITNOW_AID.to_string()).unwrap()),
+                16,
+            )
+            .unwrap();
+        MerchantDuitNow { template }
+    }
+}
+
+impl MerchantDuitNow {
+    pub fn new(acquirer_id: &str, merchant_id: &str) -> Result<Self, EMVOError> {
+        let mut duitnow = MerchantDuitNow::default();
+        duitnow.set_acquirer_id(acquirer_id)?;
+        duitnow.set_merchant_id(merchant_id)?;
+        Ok(duitnow)
+    }
+    pub fn set_acquirer_id(&mut self, acquirer_id: &str) -> Result<(), EMVOError> {
+        self.template.set_field(
+            ID_DUITNOW_ACQUIRER_ID,
+            Box::new(EMVAlphanumbericSpecial::try_from(acquirer_id.to_string())?),
+            MAX_LENGTH_ACQUIRER_ID,
+        )
+    }
+    pub fn set_merchant_id(&mut self, merchant_id: &str) -> Result<(), EMVOError> {
+        self.template.set_field(
+            ID_DUITNOW_MERCHANT_ID,
+            Box::new(EMVAlphanumbericSpecial::try_from(merchant_id.to_string())?),
+            MAX_LENGTH_MERCHANT_ID,
+        )
+    }
+}
+
+impl EMVOData for MerchantDuitNow {
+    fn len(&self) -> usize {
+        self.template.len()
+    }
+    fn valid(&self) -> bool {
+        self.template.valid()
+    }
+    fn value(&self) -> Data {
+        self.template.value()
+    }
+    fn type_name(&self) -> &str {
+        TYPE_NAME_TEMPLATE
+    }
+    fn encode_into(&self, out: &mut dyn fmt::Write) -> fmt::Result {
+        self.template.encode_into(out)
+    }
+}
+
+fn find(objects: &[(TagID, Data)], tag_id: TagID) -> Option<&str> {
+    objects
+        .iter()
+        .find(|(id, _)| *id == tag_id)
+        .map(|(_, value)| value.as_str())
+}
+
+fn required<'a>(
+    objects: &'a [(TagID, Data)],
+    tag_id: TagID,
+    name: &str,
+) -> Result<&'a str, EMVOError> {
+    find(objects, tag_id).ok_or_else(|| {
+        let msg = format!("DuitNow {} Missing", name);
+        EMVOError::new(msg.as_str())
+    })
+}
+
+impl DuitNowProfile {
+    pub fn generate_pay_load(&self) -> Result<String, EMVOError> {
+        let duitnow = MerchantDuitNow::new(&self.acquirer_id, &self.merchant_id)?;
+        let mut additional_data = AdditionalDataFieldTemplate::default();
+        additional_data.set_reference_label(self.reference_label.clone())?;
+        additional_data.set_purpose_of_transaction(self.purpose_of_transaction.clone())?;
+        let point_type = match self.transaction_amount {
+            Some(_) => DYNAMIC_POINT,
+            None => STATIC_POINT,
+        };
+        let mut builder = EMVQR::builder()
+            .payload_format_indicator("01")
+            .point_of_initiation_method(point_type)
+            .merchant_account_information(ID_DUITNOW, Box::new(duitnow))
+            .merchant_category_code(self.merchant_category_code.clone())
+            .transaction_currency(MYR)
+            .country_code(MALAYSIA)
+            .merchant_name(self.merchant_name.clone())
+            .merchant_city(self.merchant_city.clone())
+            .additional_data_field_template(additional_data);
+        if let Some(amount) = &self.transaction_amount {
+            builder = builder.transaction_amount(amount.clone());
+        }
+        if let Some(postal_code) = &self.postal_code {
+            builder = builder.postal_code(postal_code.clone());
+        }
+        Ok(builder.build()?.into_pay_load())
+    }
+
+    /// Decodes a DuitNow payload (CRC checked). Fails when the payload is
+    /// not a DuitNow QR in MYR for Malaysia, or lacks a field DuitNow
+    /// requires.
+    pub fn from_pay_load(pay_load: &str) -> Result<Self, EMVOError> {
+        let objects = parse_pay_load(pay_load)?;
+        let account = parse_data_objects(required(&objects, ID_DUITNOW, "Merchant Account")?)?;
+        if find(&account, ID_DUITNOW_AID) != Some(DUITNOW_AID) {
+            return Err(EMVOError::new("DuitNow AID Invalid"));
+        }
+        if find(&objects, ID_TRANSACTION_CURRENCY) != Some(MYR) {
+            return Err(EMVOError::new("DuitNow Transaction Currency Must Be 458"));
+        }
+        if find(&objects, ID_COUNTRY_CODE) != Some(MALAYSIA) {
+            return Err(EMVOError::new("DuitNow Country Code Must Be MY"));
+        }
+        let additional_data = parse_data_objects(required(
+            &objects,
+            ID_ADDITIONAL_DATA_FIELD_TEMPLATE,
+            "Additional Data",
+        )?)?;
+        Ok(DuitNowProfile {
+            acquirer_id: required(&account, ID_DUITNOW_ACQUIRER_ID, "Acquirer Id")?.to_string(),
+            merchant_id: required(&account, ID_DUITNOW_MERCHANT_ID, "Merchant Id")?.to_string(),
+            merchant_category_code: required(
+                &objects,
+                ID_MERCHANT_CATEGORY_CODE,
+                "Merchant Category Code",
+            )?
+            .to_string(),
+            merchant_name: required(&objects, ID_MERCHANT_NAME, "Merchant Name")?.to_string(),
+            merchant_city: required(&objects, ID_MERCHANT_CITY, "Merchant City")?.to_string(),
+            postal_code: find(&objects, ID_POSTAL_CODE).map(str::to_string),
+            transaction_amount: find(&objects, ID_TRANSACTION_AMOUNT).map(str::to_string),
+            reference_label: required(
+                &additional_data,
+                ADDITIONAL_ID_REFERENCE_LABEL,
+                "Reference Label",
+            )?
+            .to_string(),
+            purpose_of_transaction: required(
+                &additional_data,
+                ADDITIONAL_ID_PURPOSE_TRANSACTION,
+                "Purpose Of Transaction",
+            )?
+            .to_string(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn with_crc(data: &str) -> String {
+        let data = format!("{}6304", data);
+        format!("{}{}", data, crc16(&data))
+    }
+
+    fn profile() -> DuitNowProfile {
+        DuitNowProfile {
+            acquirer_id: "890053".to_string(),
+            merchant_id: "M0001234".to_string(),
+            merchant_category_code: "5812".to_string(),
+            merchant_name: "KEDAI MAKAN ALI".to_string(),
+            merchant_city: "Kuala Lumpur".to_string(),
+            postal_code: Some("50450".to_string()),
+            transaction_amount: Some("25.90".to_string()),
+            reference_label: "INV0042".to_string(),
+            purpose_of_transaction: "Food".to_string(),
+        }
+    }
+
+    #[test]
+    fn test_duitnow_pay_load() {
+        let sample = with_crc(
+            "00020101021226400014A000000615000101068900530208M0001234\
+             520458125303458540525.905802MY5915KEDAI MAKAN ALI\
+             6012Kuala Lumpur61055045062190507INV00420804Food",
+        );
+        assert_eq!(profile().generate_pay_load().unwrap(), sample);
+        assert_eq!(DuitNowProfile::from_pay_load(&sample).unwrap(), profile());
+    }
+
+    #[test]
+    fn test_duitnow_static_round_trip() {
+        let profile = DuitNowProfile {
+            postal_code: None,
+            transaction_amount: None,
+            ..profile()
+        };
+        let pay_load = profile.generate_pay_load().unwrap();
+        assert!(pay_load.starts_with("000201010211"));
+        assert_eq!(DuitNowProfile::from_pay_load(&pay_load).unwrap(), profile);
+    }
+
+    #[test]
+    fn test_duitnow_parse_rejects_other_schemes() {
+        let mut objects = parse_pay_load(&profile().generate_pay_load().unwrap()).unwrap();
+        upsert_data_object(&mut objects, ID_TRANSACTION_CURRENCY, "764".to_string());
+        let pay_load = encode_pay_load(&objects).unwrap();
+        assert!(DuitNowProfile::from_pay_load(&pay_load).is_err());
+
+        objects.retain(|(tag_id, _)| *tag_id != ID_ADDITIONAL_DATA_FIELD_TEMPLATE);
+        upsert_data_object(&mut objects, ID_TRANSACTION_CURRENCY, MYR.to_string());
+        let pay_load = encode_pay_load(&objects).unwrap();
+        assert!(DuitNowProfile::from_pay_load(&pay_load).is_err());
+
+        let paynow = with_crc(
+            "00020101021126380009SG.PAYNOW010100211+65912345670301052040000\
+             53037025802SG5912HAWKER STALL6009Singapore",
+        );
+        assert!(DuitNowProfile::from_pay_load(&paynow).is_err());
+    }
+}
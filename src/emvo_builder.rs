@@ -0,0 +1,322 @@
+#![allow(dead_code)]
+
+use std::fmt;
+use std::marker::PhantomData;
+
+use crate::emvo_qrcode::*;
+use crate::emvo_types::*;
+
+/// Type state of a mandatory data object that has not been set yet.
+pub struct Missing;
+/// Type state of a mandatory data object that has been set.
+pub struct Present;
+
+/// Fluent builder for [`EMVQR`]. The type parameters track the mandatory
+/// data objects (merchant account information, IDs 52, 53, 58, 59 and 60),
+/// so `build` only exists once all of them have been given.
+/// Setters never panic; the first invalid value is reported by `build`.
+pub struct EMVQRBuilder<Account, Category, Currency, Country, Name, City> {
+    emvo: EMVQR,
+    error: Option<EMVOError>,
+    state: PhantomData<(Account, Category, Currency, Country, Name, City)>,
+}
+
+/// An [`EMVQR`] that passed validation, together with its encoded payload.
+/// It can only be read, so the payload always matches the data objects.
+pub struct ValidEMVQR {
+    emvo: EMVQR,
+    pay_load: String,
+}
+
+impl EMVQR {
+    pub fn builder() -> EMVQRBuilder<Missing, Missing, Missing, Missing, Missing, Missing> {
+        EMVQRBuilder {
+            emvo: EMVQR::default(),
+            error: None,
+            state: PhantomData,
+        }
+        .payload_format_indicator("02")
+    }
+}
+
+impl<Account, Category, Currency, Country, Name, City>
+    EMVQRBuilder<Account, Category, Currency, Country, Name, City>
+{
+    fn into_state<A, B, C, D, E, F>(self) -> EMVQRBuilder<A, B, C, D, E, F> {
+        EMVQRBuilder {
+            emvo: self.emvo,
+            error: self.error,
+            state: PhantomData,
+        }
+    }
+
+    fn record(&mut self, result: Result<(), EMVOError>) {
+        if let Err(e) = result {
+            self.error.get_or_insert(e);
+        }
+    }
+
+    /// Defaults to "02", like every other payload this service generates.
+    pub fn payload_format_indicator(mut self, data: impl Into<Data>) -> Self {
+        let result = self.emvo.set_payload_format_indicator(data.into());
+        self.record(result);
+        self
+    }
+    pub fn point_of_initiation_method(mut self, point_type: PointType) -> Self {
+        let result = self.emvo.set_point_types(point_type);
+        self.record(result);
+        self
+    }
+    pub fn transaction_amount(mut self, data: impl Into<Data>) -> Self {
+        let result = self.emvo.set_transaction_amount(data.into());
+        self.record(result);
+        self
+    }
+    pub fn tip_or_convenience_indicator(mut self, data: impl Into<Data>) -> Self {
+        let result = self.emvo.set_tip_convenience_indicator(data.into());
+        self.record(result);
+        self
+    }
+    pub fn value_of_convenience_fee_fixed(mut self, data: impl Into<Data>) -> Self {
+        let result = self.emvo.set_value_of_convenience_fee_fixed(data.into());
+        self.record(result);
+        self
+    }
+    pub fn value_of_convenience_fee_percentage(mut self, data: impl Into<Data>) -> Self {
+        let result = self
+            .emvo
+            .set_value_of_convenience_fee_percentage(data.into());
+        self.record(result);
+        self
+    }
+    pub fn postal_code(mut self, data: impl Into<Data>) -> Self {
+        let result = self.emvo.set_postal_code(data.into());
+        self.record(result);
+        self
+    }
+    pub fn additional_data_field_template(mut self, template: AdditionalDataFieldTemplate) -> Self {
+        self.emvo.set_additional_data_field_template(template);
+        self
+    }
+    pub fn merchant_information_language_template(
+        mut self,
+        template: MerchantInformationLanguageTemplate,
+    ) -> Self {
+        self.emvo
+            .set_merchant_information_language_template(template);
+        self
+    }
+    pub fn unreserved_template(mut self, template: UnreservedTemplate) -> Self {
+        self.emvo.set_unreserved_template(template);
+        self
+    }
+    pub fn rfu(mut self, tag_id: TagID, data: impl Into<Data>) -> Self {
+        let result = self.emvo.set_rfu(tag_id, data.into());
+        self.record(result);
+        self
+    }
+
+    /// Adds a merchant account (IDs 02-51); may be called once per account.
+    pub fn merchant_account_information(
+        mut self,
+        tag_id: TagID,
+        data: Box<dyn EMVOData>,
+    ) -> EMVQRBuilder<Present, Category, Currency, Country, Name, City> {
+        let result = self.emvo.set_merchant_account_information(tag_id, data);
+        self.record(result);
+        self.into_state()
+    }
+    pub fn merchant_category_code(
+        mut self,
+        data: impl Into<Data>,
+    ) -> EMVQRBuilder<Account, Present, Currency, Country, Name, City> {
+        let result = self.emvo.set_merchant_category_code(data.into());
+        self.record(result);
+        self.into_state()
+    }
+    pub fn transaction_currency(
+        mut self,
+        currency: CurrencyCode,
+    ) -> EMVQRBuilder<Account, Category, Present, Country, Name, City> {
+        let result = self.emvo.set_transaction_currency(currency);
+        self.record(result);
+        self.into_state()
+    }
+    pub fn country_code(
+        mut self,
+        country: CountryCode,
+    ) -> EMVQRBuilder<Account, Category, Currency, Present, Name, City> {
+        let result = self.emvo.set_country_code(country);
+        self.record(result);
+        self.into_state()
+    }
+    pub fn merchant_name(
+        mut self,
+        data: impl Into<Data>,
+    ) -> EMVQRBuilder<Account, Category, Currency, Country, Present, City> {
+        let result = self.emvo.set_merchant_name(data.into());
+        self.record(result);
+        self.into_state()
+    }
+    pub fn merchant_city(
+        mut self,
+        data: impl Into<Data>,
+    ) -> EMVQRBuilder<Account, Category, Currency, Country, Name, Present> {
+        let result = self.emvo.set_merchant_city(data.into());
+        self.record(result);
+        self.into_state()
+    }
+}
+
+impl EMVQRBuilder<Present, Present, Present, Present, Present, Present> {
+    /// Checks the conditional data objects and encodes the payload.
+    pub fn build(self) -> Result<ValidEMVQR, EMVOError> {
+        if let Some(e) = self.error {
+            return Err(e);
+        }
+        let mut emvo = self.emvo;
+        let pay_load = emvo.generate_pay_load()?;
+        let mut tip = None;
+        let mut fee_fixed = false;
+        let mut fee_percentage = false;
+        for (tag_id, value) in parse_pay_load(&pay_load)? {
+            match tag_id {
+                ID_TIP_OR_CONVENIENCE_INDICATOR => tip = Some(value),
+                ID_VALUE_OF_CONVENIENCE_FEE_FIXED => fee_fixed = true,
+                ID_VALUE_OF_CONVENIENCE_FEE_PERCENTAGE => fee_percentage = true,
+                _ => {}
+            }
+        }
+        // EMVCo 4.7.5: "02" needs a fixed fee, "03" a percentage, and the
+        // fees are only present together with their indicator
+        let valid = match tip.as_deref() {
+            None => !fee_fixed && !fee_percentage,
+            Some("01") => !fee_fixed && !fee_percentage,
+            Some("02") => fee_fixed && !fee_percentage,
+            Some("03") => !fee_fixed && fee_percentage,
+            Some(_) => false,
+        };
+        if !valid {
+            return Err(EMVOError::new("Tip Or Convenience Indicator Invalid"));
+        }
+        Ok(ValidEMVQR { emvo, pay_load })
+    }
+}
+
+impl ValidEMVQR {
+    pub fn pay_load(&self) -> &str {
+        &self.pay_load
+    }
+    pub fn emvqr(&self) -> &EMVQR {
+        &self.emvo
+    }
+    pub fn into_pay_load(self) -> String {
+        self.pay_load
+    }
+}
+
+impl fmt::Display for ValidEMVQR {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.pay_load)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::prompt_pay::*;
+
+    fn prompt_pay() -> Box<MerchantPromptPayCreditTransfer> {
+        let mut merchant_prompt_pay = MerchantPromptPayCreditTransfer::default();
+        merchant_prompt_pay
+            .set_promptpay_presented_type(CUSTOMER_PRESENTED)
+            .unwrap();
+        merchant_prompt_pay
+            .set_mobile_number(&"0809729900".to_string())
+            .unwrap();
+        Box::new(merchant_prompt_pay)
+    }
+
+    #[test]
+    fn test_builder_pay_load() {
+        let qr = EMVQR::builder()
+            .point_of_initiation_method(STATIC_POINT)
+            .transaction_currency(BAHT)
+            .transaction_amount("50")
+            .merchant_name("test")
+            .merchant_category_code("5311")
+            .merchant_account_information(ID_PROMPT_PAY_CREDIT_TRANSFER, prompt_pay())
+            .merchant_city("Bangkok")
+            .postal_code("10240")
+            .country_code(THAI)
+            .build()
+            .unwrap();
+        // same data objects as test_merchant_prompt_pay_credit_transfer_pay_load
+        assert_eq!(qr.pay_load(), "00020201021129370016A000000677010114011300008097299005204531153037645402505802TH5904test6007Bangkok610510240630443DC");
+        assert_eq!(qr.to_string(), qr.emvqr().to_pay_load().unwrap());
+    }
+
+    #[test]
+    fn test_builder_errors() {
+        let builder = || {
+            EMVQR::builder()
+                .merchant_account_information(ID_PROMPT_PAY_CREDIT_TRANSFER, prompt_pay())
+                .merchant_category_code("5311")
+                .transaction_currency(BAHT)
+                .country_code(THAI)
+                .merchant_city("Bangkok")
+        };
+        assert!(builder().merchant_name("test").build().is_ok());
+        assert!(builder().merchant_name("ร้าน").build().is_err());
+        assert!(builder()
+            .merchant_name("a name that is longer than 25")
+            .build()
+            .is_err());
+        assert!(builder()
+            .merchant_name("test")
+            .transaction_amount("-1")
+            .build()
+            .is_err());
+        assert!(builder()
+            .merchant_name("test")
+            .merchant_account_information(ID_MERCHANT_CATEGORY_CODE, prompt_pay())
+            .build()
+            .is_err());
+        assert!(builder()
+            .merchant_name("test")
+            .tip_or_convenience_indicator("02")
+            .build()
+            .is_err());
+        assert!(builder()
+            .merchant_name("test")
+            .tip_or_convenience_indicator("02")
+            .value_of_convenience_fee_fixed("10.00")
+            .build()
+            .is_ok());
+        assert!(builder()
+            .merchant_name("test")
+            .value_of_convenience_fee_percentage("3")
+            .build()
+            .is_err());
+    }
+
+    #[test]
+    fn test_setters_report_invalid_values() {
+        let mut emvo = EMVQR::default();
+        assert!(emvo
+            .set_transaction_amount("1234567890.12".to_string())
+            .is_ok());
+        assert!(emvo
+            .set_transaction_amount("12345678901.23".to_string())
+            .is_err());
+        assert!(emvo.set_transaction_amount("5O".to_string()).is_err());
+        assert!(emvo.set_merchant_category_code("53A1".to_string()).is_err());
+        assert!(emvo.set_transaction_currency("7640").is_err());
+        assert!(emvo.set_country_code("THA").is_err());
+        assert!(emvo.set_merchant_city("ร้าน".to_string()).is_err());
+        assert!(emvo.set_postal_code("".to_string()).is_err());
+        assert!(emvo
+            .set_tip_convenience_indicator("123".to_string())
+            .is_err());
+    }
+}
@@ -0,0 +1,248 @@
+#![allow(dead_code)]
+
+use std::fmt;
+use std::fmt::Write;
+
+use crc::Digest;
+
+use crate::emvo_qrcode::*;
+use crate::emvo_types::*;
+
+const MAX_LENGTH_TRANSACTION_AMOUNT: usize = 13;
+const MAX_LENGTH_REFERENCE_LABEL: usize = 25;
+
+/// A merchant's dynamic QR with everything but the transaction amount (ID 54)
+/// and the Reference Label (ID 62, 05) encoded ahead of time. The CRC state
+/// after the constant prefix (IDs 00-53) is kept, so generating a payload
+/// only encodes the amount and reference and runs the CRC over the rest.
+pub struct CompiledPayLoad {
+    prefix: String,
+    prefix_crc: Digest<'static, u16>,
+    // IDs 55-61
+    middle: String,
+    // Additional Data Field Template children before and after ID 05
+    additional_data: Option<(String, String)>,
+    // IDs 64-99 and the CRC ID and length
+    suffix: String,
+}
+
+impl CompiledPayLoad {
+    /// Validates and encodes `emvo` once, as a dynamic QR. Any amount or
+    /// reference label it has is left out.
+    pub fn compile(mut emvo: EMVQR) -> Result<Self, EMVOError> {
+        emvo.set_point_types(DYNAMIC_POINT)?;
+        let mut prefix = String::new();
+        let mut middle = String::new();
+        let mut additional_data = None;
+        let mut suffix = String::new();
+        for data_object in emvo.data_objects() {
+            let tag_id = data_object.tag_id();
+            if tag_id == ID_TRANSACTION_AMOUNT {
+                continue;
+            }
+            if tag_id == ID_ADDITIONAL_DATA_FIELD_TEMPLATE {
+                let value = data_object.value()?;
+                let (mut head, mut tail) = (String::new(), String::new());
+                for (child_id, child) in parse_data_objects(&value[4..])? {
+                    let section = match child_id.cmp(&ADDITIONAL_ID_REFERENCE_LABEL) {
+                        std::cmp::Ordering::Less => &mut head,
+                        std::cmp::Ordering::Equal => continue,
+                        std::cmp::Ordering::Greater => &mut tail,
+                    };
+                    write!(section, "{}{:02}{}", child_id, child.chars().count(), child)?;
+                }
+                additional_data = Some((head, tail));
+                continue;
+            }
+            let section = if tag_id < ID_TRANSACTION_AMOUNT {
+                &mut prefix
+            } else if tag_id < ID_ADDITIONAL_DATA_FIELD_TEMPLATE {
+                &mut middle
+            } else {
+                &mut suffix
+            };
+            data_object.encode_into(section)?;
+        }
+        write!(suffix, "{}04", ID_CRC)?;
+        let mut prefix_crc = crc16_digest();
+        prefix_crc.update(prefix.as_bytes());
+        Ok(CompiledPayLoad {
+            prefix,
+            prefix_crc,
+            middle,
+            additional_data,
+            suffix,
+        })
+    }
+
+    /// Writes the payload for one sale. Only `amount` and `reference` are
+    /// checked.
+    pub fn encode_into<W: fmt::Write>(
+        &self,
+        out: &mut W,
+        amount: &str,
+        reference: Option<&str>,
+    ) -> Result<(), EMVOError> {
+        validate_amount(ID_TRANSACTION_AMOUNT, amount)?;
+        if amount.len() > MAX_LENGTH_TRANSACTION_AMOUNT {
+            return Err(EMVOError::new("Transaction Amount Invalid"));
+        }
+        if let Some(reference) = reference {
+            if reference.is_empty()
+                || reference.len() > MAX_LENGTH_REFERENCE_LABEL
+                || !is_alphanumeric_special(reference)
+            {
+                return Err(EMVOError::new("Reference Label Invalid"));
+            }
+        }
+        let (head, tail) = match &self.additional_data {
+            Some((head, tail)) => (head.as_str(), tail.as_str()),
+            None => ("", ""),
+        };
+        let reference_length = reference.map_or(0, |reference| 4 + reference.len());
+        let additional_data_length = head.chars().count() + reference_length + tail.chars().count();
+        if additional_data_length > 99 {
+            let msg = format!(
+                "Tag Id {} Data Length Invalid",
+                ID_ADDITIONAL_DATA_FIELD_TEMPLATE
+            );
+            return Err(EMVOError::new(msg.as_str()));
+        }
+        // nothing is written until every check has passed
+        out.write_str(&self.prefix)?;
+        let mut writer = CrcWriter::new(out, self.prefix_crc.clone());
+        write!(
+            writer,
+            "{}{:02}{}",
+            ID_TRANSACTION_AMOUNT,
+            amount.len(),
+            amount
+        )?;
+        writer.write_str(&self.middle)?;
+        if additional_data_length > 0 {
+            write!(
+                writer,
+                "{}{:02}{}",
+                ID_ADDITIONAL_DATA_FIELD_TEMPLATE, additional_data_length, head
+            )?;
+            if let Some(reference) = reference {
+                write!(
+                    writer,
+                    "{}{:02}{}",
+                    ADDITIONAL_ID_REFERENCE_LABEL,
+                    reference.len(),
+                    reference
+                )?;
+            }
+            writer.write_str(tail)?;
+        }
+        writer.write_str(&self.suffix)?;
+        writer.finish()?;
+        Ok(())
+    }
+
+    pub fn pay_load(&self, amount: &str, reference: Option<&str>) -> Result<String, EMVOError> {
+        let (head, tail) = self
+            .additional_data
+            .as_ref()
+            .map_or((0, 0), |(head, tail)| (head.len(), tail.len()));
+        let mut pay_load = String::with_capacity(
+            self.prefix.len()
+                + self.middle.len()
+                + self.suffix.len()
+                + head
+                + tail
+                + amount.len()
+                + reference.map_or(0, str::len)
+                + 16,
+        );
+        self.encode_into(&mut pay_load, amount, reference)?;
+        Ok(pay_load)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::convert::TryFrom;
+
+    use super::*;
+
+    fn emvqr(amount: Option<&str>, reference: Option<&str>) -> EMVQR {
+        let mut merchant_account = EMVTemplate::new(TagID::new(29)).unwrap();
+        merchant_account
+            .set_field(
+                MERCHANT_ACCOUNT_INFORMATION_ID_GLOBALLY_UNIQUE_IDENTIFIER,
+                Box::new(EMVString::try_from("A000000677010111".to_string()).unwrap()),
+                16,
+            )
+            .unwrap();
+        merchant_account
+            .set_field(
+                TagID::new(1),
+                Box::new(EMVNumeric::try_from("0066809729900".to_string()).unwrap()),
+                13,
+            )
+            .unwrap();
+        let mut additional_data = AdditionalDataFieldTemplate::default();
+        additional_data.set_store_label("S01".to_string()).unwrap();
+        additional_data
+            .set_terminal_label("POS01".to_string())
+            .unwrap();
+        if let Some(reference) = reference {
+            additional_data
+                .set_reference_label(reference.to_string())
+                .unwrap();
+        }
+        let mut emvo = EMVQR::default();
+        emvo.set_payload_format_indicator("02".to_string()).unwrap();
+        emvo.set_point_types(DYNAMIC_POINT).unwrap();
+        emvo.set_merchant_account_information(TagID::new(29), Box::new(merchant_account))
+            .unwrap();
+        emvo.set_merchant_category_code("5311".to_string()).unwrap();
+        emvo.set_transaction_currency("764").unwrap();
+        if let Some(amount) = amount {
+            emvo.set_transaction_amount(amount.to_string()).unwrap();
+        }
+        emvo.set_country_code("TH").unwrap();
+        emvo.set_merchant_name("test".to_string()).unwrap();
+        emvo.set_merchant_city("Bangkok".to_string()).unwrap();
+        emvo.set_additional_data_field_template(additional_data);
+        emvo
+    }
+
+    #[test]
+    fn test_compiled_pay_load() {
+        let compiled = CompiledPayLoad::compile(emvqr(Some("1"), Some("OLD"))).unwrap();
+        for (amount, reference) in [("50.25", Some("INV0001")), ("7", None)] {
+            assert_eq!(
+                compiled.pay_load(amount, reference).unwrap(),
+                emvqr(Some(amount), reference).to_pay_load().unwrap()
+            );
+        }
+
+        assert!(compiled.pay_load("-1", None).is_err());
+        assert!(compiled.pay_load("12345678901234", None).is_err());
+        assert!(compiled.pay_load("10", Some("ร้าน")).is_err());
+        assert!(compiled.pay_load("10", Some(&"X".repeat(26))).is_err());
+    }
+
+    #[test]
+    fn test_encode_into_writes_nothing_on_error() {
+        let mut emvo = emvqr(Some("1"), None);
+        let mut additional_data = AdditionalDataFieldTemplate::default();
+        additional_data.set_bill_number("B".repeat(25)).unwrap();
+        additional_data.set_store_label("S".repeat(25)).unwrap();
+        additional_data.set_terminal_label("T".repeat(25)).unwrap();
+        emvo.set_additional_data_field_template(additional_data);
+        let compiled = CompiledPayLoad::compile(emvo).unwrap();
+
+        // 87 characters of labels fit, another 29 for the reference do not
+        let mut out = String::new();
+        assert!(compiled
+            .encode_into(&mut out, "10", Some(&"R".repeat(25)))
+            .is_err());
+        assert_eq!(out, "");
+        compiled.encode_into(&mut out, "10", None).unwrap();
+        assert!(out.contains("6287"));
+    }
+}
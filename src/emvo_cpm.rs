@@ -0,0 +1,726 @@
+#![allow(dead_code)]
+
+use base64::engine::general_purpose;
+use base64::Engine;
+
+use crate::emvo_types::*;
+
+// EMVCo QR Code Specification for Payment Systems, Consumer-Presented Mode.
+// Tags are BER-TLV, up to three bytes, kept as their big-endian value.
+pub const CPM_PAYLOAD_FORMAT_INDICATOR: &str = "CPV01";
+
+pub const CPM_TAG_PAYLOAD_FORMAT_INDICATOR: u32 = 0x85; // (M) "CPV01"
+pub const CPM_TAG_APPLICATION_TEMPLATE: u32 = 0x61; // (M) One per application
+pub const CPM_TAG_COMMON_DATA_TEMPLATE: u32 = 0x62; // (O)
+pub const CPM_TAG_APPLICATION_SPECIFIC_TRANSPARENT_TEMPLATE: u32 = 0x63; // (O)
+pub const CPM_TAG_COMMON_DATA_TRANSPARENT_TEMPLATE: u32 = 0x64; // (O)
+
+// Application Template (61)
+pub const CPM_TAG_ADF_NAME: u32 = 0x4F; // (M) AID, 5-16 bytes
+pub const CPM_TAG_APPLICATION_LABEL: u32 = 0x50; // (O) ans 1-16
+pub const CPM_TAG_TRACK_2_EQUIVALENT_DATA: u32 = 0x57; // (O) up to 19 bytes
+pub const CPM_TAG_APPLICATION_PAN: u32 = 0x5A; // (O) cn up to 10 bytes
+pub const CPM_TAG_CARDHOLDER_NAME: u32 = 0x5F20; // (O) ans 2-26
+pub const CPM_TAG_LANGUAGE_PREFERENCE: u32 = 0x5F2D; // (O) an 2-8
+
+// Application Specific Transparent Template (63)
+pub const CPM_TAG_ISSUER_APPLICATION_DATA: u32 = 0x9F10; // (O) up to 32 bytes
+pub const CPM_TAG_APPLICATION_CRYPTOGRAM: u32 = 0x9F26; // (M) 8 bytes
+pub const CPM_TAG_CRYPTOGRAM_INFORMATION_DATA: u32 = 0x9F27; // (M) 1 byte
+pub const CPM_TAG_APPLICATION_TRANSACTION_COUNTER: u32 = 0x9F36; // (M) 2 bytes
+pub const CPM_TAG_UNPREDICTABLE_NUMBER: u32 = 0x9F37; // (O) 4 bytes
+pub const CPM_TAG_APPLICATION_INTERCHANGE_PROFILE: u32 = 0x82; // (O) 2 bytes
+
+/// Templates nested deeper than this are rejected when parsing, so a crafted
+/// payload cannot exhaust the stack. CPM itself nests three levels.
+pub const BER_TLV_MAX_DEPTH: usize = 8;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum BerValue {
+    Primitive(Vec<u8>),
+    Constructed(Vec<BerTlv>),
+}
+
+/// A BER-TLV data object. Whether it is constructed follows from bit 6 of
+/// the first tag byte.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BerTlv {
+    pub tag: u32,
+    pub value: BerValue,
+}
+
+/// Cryptogram data of an application, carried in its Application Specific
+/// Transparent Template (63).
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct CPMCryptogramData {
+    pub application_cryptogram: Vec<u8>,
+    pub cryptogram_information_data: u8,
+    pub application_transaction_counter: u16,
+    pub unpredictable_number: Option<Vec<u8>>,
+    pub issuer_application_data: Option<Vec<u8>>,
+    pub application_interchange_profile: Option<Vec<u8>>,
+    pub other_data_objects: Vec<BerTlv>,
+    /// Child tags in the order they were decoded; see [`CPMApplication`].
+    pub tag_order: Vec<u32>,
+}
+
+/// One Application Template (61). The PAN and track 2 equivalent data are
+/// digit strings; track 2 uses `D` as field separator. Children are encoded
+/// in `tag_order`, filled in when decoding so a scanned template re-encodes
+/// unchanged, and children it does not list follow in the default order.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct CPMApplication {
+    pub aid: Vec<u8>,
+    pub application_label: Option<String>,
+    pub track_2_equivalent_data: Option<String>,
+    pub application_pan: Option<String>,
+    pub cardholder_name: Option<String>,
+    pub language_preference: Option<String>,
+    pub cryptogram_data: Option<CPMCryptogramData>,
+    pub other_data_objects: Vec<BerTlv>,
+    pub tag_order: Vec<u32>,
+}
+
+/// A consumer-presented QR (pay-code) as shown by a wallet: the payload
+/// format indicator, one Application Template per application and any
+/// common data templates, base64 encoded.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct ConsumerPresentedQR {
+    pub applications: Vec<CPMApplication>,
+    pub other_data_objects: Vec<BerTlv>,
+}
+
+impl BerTlv {
+    pub fn primitive(tag: u32, value: Vec<u8>) -> Self {
+        BerTlv {
+            tag,
+            value: BerValue::Primitive(value),
+        }
+    }
+    pub fn constructed(tag: u32, children: Vec<BerTlv>) -> Self {
+        BerTlv {
+            tag,
+            value: BerValue::Constructed(children),
+        }
+    }
+
+    fn tag_bytes(tag: u32) -> Vec<u8> {
+        let bytes = tag.to_be_bytes();
+        let start = bytes.iter().position(|b| *b != 0).unwrap_or(3);
+        bytes[start..].to_vec()
+    }
+    pub fn is_constructed_tag(tag: u32) -> bool {
+        BerTlv::tag_bytes(tag)[0] & 0x20 != 0
+    }
+
+    pub fn encode_into(&self, out: &mut Vec<u8>) -> Result<(), EMVOError> {
+        let value = match &self.value {
+            BerValue::Primitive(value) => value.clone(),
+            BerValue::Constructed(children) => {
+                let mut value = vec![];
+                for child in children {
+                    child.encode_into(&mut value)?;
+                }
+                value
+            }
+        };
+        out.extend(BerTlv::tag_bytes(self.tag));
+        match value.len() {
+            length if length < 0x80 => out.push(length as u8),
+            length if length <= 0xFF => out.extend([0x81, length as u8]),
+            length if length <= 0xFFFF => {
+                out.push(0x82);
+                out.extend((length as u16).to_be_bytes());
+            }
+            _ => {
+                let msg = format!("Tag {:X} Data Length Invalid", self.tag);
+                return Err(EMVOError::new(msg.as_str()));
+            }
+        }
+        out.extend(value);
+        Ok(())
+    }
+
+    /// Parses consecutive data objects, descending into constructed ones up
+    /// to [`BER_TLV_MAX_DEPTH`] levels.
+    pub fn parse_list(data: &[u8]) -> Result<Vec<BerTlv>, EMVOError> {
+        BerTlv::parse_list_at(data, 1)
+    }
+
+    fn parse_list_at(mut data: &[u8], depth: usize) -> Result<Vec<BerTlv>, EMVOError> {
+        if depth > BER_TLV_MAX_DEPTH {
+            return Err(EMVOError::new("BER-TLV Nested Too Deep"));
+        }
+        let mut objects = vec![];
+        while !data.is_empty() {
+            let (object, rest) = BerTlv::parse(data, depth)?;
+            objects.push(object);
+            data = rest;
+        }
+        Ok(objects)
+    }
+
+    fn parse(data: &[u8], depth: usize) -> Result<(BerTlv, &[u8]), EMVOError> {
+        let truncated = || EMVOError::new("BER-TLV Data Truncated");
+        let mut bytes = data.iter().copied();
+        let first = bytes.next().ok_or_else(truncated)?;
+        let mut tag = u32::from(first);
+        let mut tag_len = 1;
+        if first & 0x1F == 0x1F {
+            loop {
+                let next = bytes.next().ok_or_else(truncated)?;
+                tag = tag << 8 | u32::from(next);
+                tag_len += 1;
+                if next & 0x80 == 0 {
+                    break;
+                }
+                if tag_len == 3 {
+                    return Err(EMVOError::new("BER-TLV Tag Too Long"));
+                }
+            }
+        }
+        let (length, length_len) = match bytes.next().ok_or_else(truncated)? {
+            length if length < 0x80 => (usize::from(length), 1),
+            0x81 => (usize::from(bytes.next().ok_or_else(truncated)?), 2),
+            0x82 => {
+                let high = bytes.next().ok_or_else(truncated)?;
+                let low = bytes.next().ok_or_else(truncated)?;
+                (usize::from(u16::from_be_bytes([high, low])), 3)
+            }
+            _ => return Err(EMVOError::new("BER-TLV Length Invalid")),
+        };
+        let start = tag_len + length_len;
+        let value = data.get(start..start + length).ok_or_else(truncated)?;
+        let value = if first & 0x20 != 0 {
+            BerValue::Constructed(BerTlv::parse_list_at(value, depth + 1)?)
+        } else {
+            BerValue::Primitive(value.to_vec())
+        };
+        Ok((BerTlv { tag, value }, &data[start + length..]))
+    }
+
+    fn primitive_value(&self) -> Result<&[u8], EMVOError> {
+        match &self.value {
+            BerValue::Primitive(value) => Ok(value),
+            BerValue::Constructed(_) => {
+                let msg = format!("Tag {:X} Must Be Primitive", self.tag);
+                Err(EMVOError::new(msg.as_str()))
+            }
+        }
+    }
+    fn children(&self) -> Result<&[BerTlv], EMVOError> {
+        match &self.value {
+            BerValue::Constructed(children) => Ok(children),
+            BerValue::Primitive(_) => {
+                let msg = format!("Tag {:X} Must Be A Template", self.tag);
+                Err(EMVOError::new(msg.as_str()))
+            }
+        }
+    }
+}
+
+fn check_length(tag: u32, value: &[u8], min: usize, max: usize) -> Result<(), EMVOError> {
+    if value.len() < min || value.len() > max {
+        let msg = format!("Tag {:X} Data Length Invalid", tag);
+        return Err(EMVOError::new(msg.as_str()));
+    }
+    Ok(())
+}
+
+fn text(tag: u32, value: &str, min: usize, max: usize) -> Result<BerTlv, EMVOError> {
+    check_length(tag, value.as_bytes(), min, max)?;
+    // ans in BER-TLV data objects is any printable ASCII character
+    if !value.bytes().all(|b| (0x20..=0x7E).contains(&b)) {
+        let msg = format!("Tag {:X} Data Invalid", tag);
+        return Err(EMVOError::new(msg.as_str()));
+    }
+    Ok(BerTlv::primitive(tag, value.as_bytes().to_vec()))
+}
+
+/// Decodes a text data object, held to the same rules as [`text`].
+fn parse_text(object: &BerTlv, min: usize, max: usize) -> Result<String, EMVOError> {
+    let value = String::from_utf8(object.primitive_value()?.to_vec()).map_err(|_| {
+        let msg = format!("Tag {:X} Data Invalid", object.tag);
+        EMVOError::new(msg.as_str())
+    })?;
+    text(object.tag, &value, min, max)?;
+    Ok(value)
+}
+
+/// Compressed numeric: digits (and `D` separators) packed two per byte,
+/// right padded with `F`.
+fn compressed_numeric(
+    tag: u32,
+    digits: &str,
+    separator: bool,
+    max_bytes: usize,
+) -> Result<BerTlv, EMVOError> {
+    let valid = digits
+        .chars()
+        .all(|c| c.is_ascii_digit() || (separator && c == 'D'));
+    if digits.is_empty() || !valid {
+        let msg = format!("Tag {:X} Data Invalid", tag);
+        return Err(EMVOError::new(msg.as_str()));
+    }
+    let mut nibbles = digits.to_string();
+    if nibbles.len() % 2 == 1 {
+        nibbles.push('F');
+    }
+    let value = hex::decode(nibbles).expect("digits, D and F are hex");
+    check_length(tag, &value, 1, max_bytes)?;
+    Ok(BerTlv::primitive(tag, value))
+}
+
+/// Decodes compressed numeric data, accepting only what
+/// [`compressed_numeric`] would have encoded.
+fn parse_compressed_numeric(
+    object: &BerTlv,
+    separator: bool,
+    max_bytes: usize,
+) -> Result<String, EMVOError> {
+    let value = object.primitive_value()?;
+    let nibbles = hex::encode_upper(value);
+    let digits = nibbles.trim_end_matches('F');
+    if compressed_numeric(object.tag, digits, separator, max_bytes)?.primitive_value()? != value {
+        let msg = format!("Tag {:X} Data Invalid", object.tag);
+        return Err(EMVOError::new(msg.as_str()));
+    }
+    Ok(digits.to_string())
+}
+
+/// Puts children in `order`, matching each listed tag to the first child
+/// with it not yet placed; children left over keep their order at the end.
+fn ordered_children(children: Vec<BerTlv>, order: &[u32]) -> Vec<BerTlv> {
+    let mut remaining: Vec<Option<BerTlv>> = children.into_iter().map(Some).collect();
+    let mut ordered = Vec::with_capacity(remaining.len());
+    for tag in order {
+        if let Some(child) = remaining
+            .iter_mut()
+            .find(|child| child.as_ref().is_some_and(|child| child.tag == *tag))
+            .and_then(Option::take)
+        {
+            ordered.push(child);
+        }
+    }
+    ordered.extend(remaining.into_iter().flatten());
+    ordered
+}
+
+impl CPMCryptogramData {
+    fn to_template(&self) -> Result<BerTlv, EMVOError> {
+        check_length(
+            CPM_TAG_APPLICATION_CRYPTOGRAM,
+            &self.application_cryptogram,
+            8,
+            8,
+        )?;
+        let mut children = vec![
+            BerTlv::primitive(
+                CPM_TAG_APPLICATION_CRYPTOGRAM,
+                self.application_cryptogram.clone(),
+            ),
+            BerTlv::primitive(
+                CPM_TAG_CRYPTOGRAM_INFORMATION_DATA,
+                vec![self.cryptogram_information_data],
+            ),
+            BerTlv::primitive(
+                CPM_TAG_APPLICATION_TRANSACTION_COUNTER,
+                self.application_transaction_counter.to_be_bytes().to_vec(),
+            ),
+        ];
+        let optional = [
+            (
+                CPM_TAG_UNPREDICTABLE_NUMBER,
+                &self.unpredictable_number,
+                4,
+                4,
+            ),
+            (
+                CPM_TAG_ISSUER_APPLICATION_DATA,
+                &self.issuer_application_data,
+                1,
+                32,
+            ),
+            (
+                CPM_TAG_APPLICATION_INTERCHANGE_PROFILE,
+                &self.application_interchange_profile,
+                2,
+                2,
+            ),
+        ];
+        for (tag, value, min, max) in IntoIterator::into_iter(optional) {
+            if let Some(value) = value {
+                check_length(tag, value, min, max)?;
+                children.push(BerTlv::primitive(tag, value.clone()));
+            }
+        }
+        children.extend(self.other_data_objects.iter().cloned());
+        Ok(BerTlv::constructed(
+            CPM_TAG_APPLICATION_SPECIFIC_TRANSPARENT_TEMPLATE,
+            ordered_children(children, &self.tag_order),
+        ))
+    }
+
+    fn from_template(template: &BerTlv) -> Result<Self, EMVOError> {
+        let mut data = CPMCryptogramData::default();
+        let mut cryptogram = false;
+        let mut information_data = false;
+        let mut counter = false;
+        for object in template.children()? {
+            match object.tag {
+                CPM_TAG_APPLICATION_CRYPTOGRAM => {
+                    let value = object.primitive_value()?;
+                    check_length(object.tag, value, 8, 8)?;
+                    data.application_cryptogram = value.to_vec();
+                    cryptogram = true;
+                }
+                CPM_TAG_CRYPTOGRAM_INFORMATION_DATA => {
+                    let value = object.primitive_value()?;
+                    check_length(object.tag, value, 1, 1)?;
+                    data.cryptogram_information_data = value[0];
+                    information_data = true;
+                }
+                CPM_TAG_APPLICATION_TRANSACTION_COUNTER => {
+                    let value = object.primitive_value()?;
+                    check_length(object.tag, value, 2, 2)?;
+                    data.application_transaction_counter = u16::from_be_bytes([value[0], value[1]]);
+                    counter = true;
+                }
+                CPM_TAG_UNPREDICTABLE_NUMBER => {
+                    let value = object.primitive_value()?;
+                    check_length(object.tag, value, 4, 4)?;
+                    data.unpredictable_number = Some(value.to_vec())
+                }
+                CPM_TAG_ISSUER_APPLICATION_DATA => {
+                    let value = object.primitive_value()?;
+                    check_length(object.tag, value, 1, 32)?;
+                    data.issuer_application_data = Some(value.to_vec())
+                }
+                CPM_TAG_APPLICATION_INTERCHANGE_PROFILE => {
+                    let value = object.primitive_value()?;
+                    check_length(object.tag, value, 2, 2)?;
+                    data.application_interchange_profile = Some(value.to_vec())
+                }
+                _ => data.other_data_objects.push(object.clone()),
+            }
+            data.tag_order.push(object.tag);
+        }
+        if !(cryptogram && information_data && counter) {
+            return Err(EMVOError::new("Cryptogram Data Incomplete"));
+        }
+        Ok(data)
+    }
+}
+
+impl CPMApplication {
+    pub fn new(aid: Vec<u8>) -> Self {
+        CPMApplication {
+            aid,
+            ..CPMApplication::default()
+        }
+    }
+
+    fn to_template(&self) -> Result<BerTlv, EMVOError> {
+        check_length(CPM_TAG_ADF_NAME, &self.aid, 5, 16)?;
+        let mut children = vec![BerTlv::primitive(CPM_TAG_ADF_NAME, self.aid.clone())];
+        if let Some(label) = &self.application_label {
+            children.push(text(CPM_TAG_APPLICATION_LABEL, label, 1, 16)?);
+        }
+        if let Some(track_2) = &self.track_2_equivalent_data {
+            children.push(compressed_numeric(
+                CPM_TAG_TRACK_2_EQUIVALENT_DATA,
+                track_2,
+                true,
+                19,
+            )?);
+        }
+        if let Some(pan) = &self.application_pan {
+            children.push(compressed_numeric(CPM_TAG_APPLICATION_PAN, pan, false, 10)?);
+        }
+        if let Some(name) = &self.cardholder_name {
+            children.push(text(CPM_TAG_CARDHOLDER_NAME, name, 2, 26)?);
+        }
+        if let Some(language) = &self.language_preference {
+            children.push(text(CPM_TAG_LANGUAGE_PREFERENCE, language, 2, 8)?);
+        }
+        children.extend(self.other_data_objects.iter().cloned());
+        if let Some(cryptogram_data) = &self.cryptogram_data {
+            children.push(cryptogram_data.to_template()?);
+        }
+        Ok(BerTlv::constructed(
+            CPM_TAG_APPLICATION_TEMPLATE,
+            ordered_children(children, &self.tag_order),
+        ))
+    }
+
+    fn from_template(template: &BerTlv) -> Result<Self, EMVOError> {
+        let mut application = CPMApplication::default();
+        for object in template.children()? {
+            match object.tag {
+                CPM_TAG_ADF_NAME => application.aid = object.primitive_value()?.to_vec(),
+                CPM_TAG_APPLICATION_LABEL => {
+                    application.application_label = Some(parse_text(object, 1, 16)?)
+                }
+                CPM_TAG_TRACK_2_EQUIVALENT_DATA => {
+                    application.track_2_equivalent_data =
+                        Some(parse_compressed_numeric(object, true, 19)?)
+                }
+                CPM_TAG_APPLICATION_PAN => {
+                    application.application_pan = Some(parse_compressed_numeric(object, false, 10)?)
+                }
+                CPM_TAG_CARDHOLDER_NAME => {
+                    application.cardholder_name = Some(parse_text(object, 2, 26)?)
+                }
+                CPM_TAG_LANGUAGE_PREFERENCE => {
+                    application.language_preference = Some(parse_text(object, 2, 8)?)
+                }
+                CPM_TAG_APPLICATION_SPECIFIC_TRANSPARENT_TEMPLATE => {
+                    application.cryptogram_data = Some(CPMCryptogramData::from_template(object)?)
+                }
+                _ => application.other_data_objects.push(object.clone()),
+            }
+            application.tag_order.push(object.tag);
+        }
+        check_length(CPM_TAG_ADF_NAME, &application.aid, 5, 16)?;
+        Ok(application)
+    }
+}
+
+impl ConsumerPresentedQR {
+    pub fn new(application: CPMApplication) -> Self {
+        ConsumerPresentedQR {
+            applications: vec![application],
+            other_data_objects: vec![],
+        }
+    }
+
+    pub fn to_bytes(&self) -> Result<Vec<u8>, EMVOError> {
+        if self.applications.is_empty() {
+            return Err(EMVOError::new("Application Template Missing"));
+        }
+        let mut bytes = vec![];
+        BerTlv::primitive(
+            CPM_TAG_PAYLOAD_FORMAT_INDICATOR,
+            CPM_PAYLOAD_FORMAT_INDICATOR.as_bytes().to_vec(),
+        )
+        .encode_into(&mut bytes)?;
+        for application in &self.applications {
+            application.to_template()?.encode_into(&mut bytes)?;
+        }
+        for object in &self.other_data_objects {
+            object.encode_into(&mut bytes)?;
+        }
+        Ok(bytes)
+    }
+
+    /// The base64 text that goes into the QR code.
+    pub fn to_pay_load(&self) -> Result<String, EMVOError> {
+        Ok(general_purpose::STANDARD.encode(self.to_bytes()?))
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, EMVOError> {
+        let mut objects = BerTlv::parse_list(bytes)?.into_iter();
+        match objects.next() {
+            Some(object)
+                if object.tag == CPM_TAG_PAYLOAD_FORMAT_INDICATOR
+                    && object.primitive_value()? == CPM_PAYLOAD_FORMAT_INDICATOR.as_bytes() => {}
+            _ => return Err(EMVOError::new("Payload Format Indicator Invalid")),
+        }
+        let mut qr = ConsumerPresentedQR::default();
+        for object in objects {
+            match object.tag {
+                CPM_TAG_APPLICATION_TEMPLATE => qr
+                    .applications
+                    .push(CPMApplication::from_template(&object)?),
+                _ => qr.other_data_objects.push(object),
+            }
+        }
+        if qr.applications.is_empty() {
+            return Err(EMVOError::new("Application Template Missing"));
+        }
+        Ok(qr)
+    }
+
+    pub fn from_pay_load(pay_load: &str) -> Result<Self, EMVOError> {
+        let bytes = general_purpose::STANDARD
+            .decode(pay_load.trim())
+            .map_err(|_| EMVOError::new("Consumer Presented QR Is Not Base64"))?;
+        ConsumerPresentedQR::from_bytes(&bytes)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn application() -> CPMApplication {
+        CPMApplication {
+            application_label: Some("Product1".to_string()),
+            track_2_equivalent_data: Some("4761739001010119D22122011143804400".to_string()),
+            application_pan: Some("4761739001010119".to_string()),
+            cardholder_name: Some("CARDHOLDER/EMV".to_string()),
+            language_preference: Some("thenfr".to_string()),
+            cryptogram_data: Some(CPMCryptogramData {
+                application_cryptogram: hex::decode("584FD385FA234BCC").unwrap(),
+                cryptogram_information_data: 0x40,
+                application_transaction_counter: 1,
+                unpredictable_number: Some(hex::decode("6D58EF13").unwrap()),
+                issuer_application_data: Some(hex::decode("06010A03000000").unwrap()),
+                application_interchange_profile: Some(hex::decode("1800").unwrap()),
+                ..CPMCryptogramData::default()
+            }),
+            ..CPMApplication::new(hex::decode("A0000000031010").unwrap())
+        }
+    }
+
+    #[test]
+    fn test_cpm_minimal_encoding() {
+        let qr =
+            ConsumerPresentedQR::new(CPMApplication::new(hex::decode("A0000000031010").unwrap()));
+        assert_eq!(
+            hex::encode_upper(qr.to_bytes().unwrap()),
+            "8505435056303161094F07A0000000031010"
+        );
+        assert_eq!(qr.to_pay_load().unwrap(), "hQVDUFYwMWEJTwegAAAAAxAQ");
+    }
+
+    #[test]
+    fn test_cpm_round_trip() {
+        let mut qr = ConsumerPresentedQR::new(application());
+        qr.applications.push(CPMApplication {
+            application_label: Some("PromptPay".to_string()),
+            ..CPMApplication::new(hex::decode("A000000677010114").unwrap())
+        });
+        qr.other_data_objects.push(BerTlv::constructed(
+            CPM_TAG_COMMON_DATA_TEMPLATE,
+            vec![BerTlv::primitive(0x9F1A, vec![0x07, 0x64])],
+        ));
+        let pay_load = qr.to_pay_load().unwrap();
+        assert!(pay_load.starts_with("hQVDUFYwMWF"));
+        let mut decoded = ConsumerPresentedQR::from_pay_load(&pay_load).unwrap();
+        assert_eq!(decoded.to_pay_load().unwrap(), pay_load);
+        for application in decoded.applications.iter_mut() {
+            application.tag_order.clear();
+            if let Some(cryptogram_data) = application.cryptogram_data.as_mut() {
+                cryptogram_data.tag_order.clear();
+            }
+        }
+        assert_eq!(decoded, qr);
+        assert_eq!(
+            decoded.applications[0].track_2_equivalent_data.as_deref(),
+            Some("4761739001010119D22122011143804400")
+        );
+    }
+
+    #[test]
+    fn test_cpm_errors() {
+        let mut invalid = application();
+        invalid.application_pan = Some("47617390010101190000123".to_string());
+        assert!(ConsumerPresentedQR::new(invalid).to_bytes().is_err());
+        let mut invalid = application();
+        invalid.aid = vec![0xA0, 0x00];
+        assert!(ConsumerPresentedQR::new(invalid).to_bytes().is_err());
+        assert!(ConsumerPresentedQR::default().to_bytes().is_err());
+
+        let bytes = ConsumerPresentedQR::new(application()).to_bytes().unwrap();
+        assert!(ConsumerPresentedQR::from_bytes(&bytes[..bytes.len() - 1]).is_err());
+        assert!(ConsumerPresentedQR::from_bytes(&bytes[7..]).is_err());
+        assert!(
+            ConsumerPresentedQR::from_bytes(&hex::decode("8505435056303162").unwrap()).is_err()
+        );
+        assert!(ConsumerPresentedQR::from_pay_load("not base64!").is_err());
+    }
+
+    #[test]
+    fn test_cpm_keeps_decoded_order() {
+        // 9F08 after the transparent template, 5A before 57
+        let template = BerTlv::constructed(
+            CPM_TAG_APPLICATION_TEMPLATE,
+            vec![
+                BerTlv::primitive(CPM_TAG_ADF_NAME, hex::decode("A0000000031010").unwrap()),
+                BerTlv::primitive(
+                    CPM_TAG_APPLICATION_PAN,
+                    hex::decode("4761739001010119").unwrap(),
+                ),
+                BerTlv::primitive(
+                    CPM_TAG_TRACK_2_EQUIVALENT_DATA,
+                    hex::decode("4761D2212F").unwrap(),
+                ),
+                BerTlv::constructed(
+                    CPM_TAG_APPLICATION_SPECIFIC_TRANSPARENT_TEMPLATE,
+                    vec![
+                        BerTlv::primitive(CPM_TAG_APPLICATION_TRANSACTION_COUNTER, vec![0, 1]),
+                        BerTlv::primitive(CPM_TAG_CRYPTOGRAM_INFORMATION_DATA, vec![0x40]),
+                        BerTlv::primitive(CPM_TAG_APPLICATION_CRYPTOGRAM, vec![1; 8]),
+                    ],
+                ),
+                BerTlv::primitive(0x9F08, vec![0x00, 0x02]),
+            ],
+        );
+        let mut bytes = hex::decode("85054350563031").unwrap();
+        template.encode_into(&mut bytes).unwrap();
+        let decoded = ConsumerPresentedQR::from_bytes(&bytes).unwrap();
+        assert_eq!(
+            decoded.applications[0].track_2_equivalent_data.as_deref(),
+            Some("4761D2212")
+        );
+        assert_eq!(decoded.to_bytes().unwrap(), bytes);
+    }
+
+    #[test]
+    fn test_cpm_decode_rejects_what_encode_rejects() {
+        let decode = |object: BerTlv| {
+            let mut bytes = hex::decode("85054350563031").unwrap();
+            BerTlv::constructed(
+                CPM_TAG_APPLICATION_TEMPLATE,
+                vec![
+                    BerTlv::primitive(CPM_TAG_ADF_NAME, hex::decode("A0000000031010").unwrap()),
+                    object,
+                ],
+            )
+            .encode_into(&mut bytes)
+            .unwrap();
+            ConsumerPresentedQR::from_bytes(&bytes)
+        };
+        let cryptogram = |unpredictable_number: Vec<u8>| {
+            BerTlv::constructed(
+                CPM_TAG_APPLICATION_SPECIFIC_TRANSPARENT_TEMPLATE,
+                vec![
+                    BerTlv::primitive(CPM_TAG_APPLICATION_CRYPTOGRAM, vec![1; 8]),
+                    BerTlv::primitive(CPM_TAG_CRYPTOGRAM_INFORMATION_DATA, vec![0x40]),
+                    BerTlv::primitive(CPM_TAG_APPLICATION_TRANSACTION_COUNTER, vec![0, 1]),
+                    BerTlv::primitive(CPM_TAG_UNPREDICTABLE_NUMBER, unpredictable_number),
+                ],
+            )
+        };
+        assert!(decode(cryptogram(vec![1; 4])).is_ok());
+        assert!(decode(cryptogram(vec![1; 3])).is_err());
+        assert!(decode(BerTlv::primitive(CPM_TAG_APPLICATION_PAN, vec![0x47, 0x61])).is_ok());
+        assert!(decode(BerTlv::primitive(CPM_TAG_APPLICATION_PAN, vec![0x4A, 0x61])).is_err());
+        assert!(decode(BerTlv::primitive(CPM_TAG_APPLICATION_PAN, vec![0x47, 0xD1])).is_err());
+        assert!(decode(BerTlv::primitive(CPM_TAG_APPLICATION_PAN, vec![0x47, 0xFF])).is_err());
+        assert!(decode(BerTlv::primitive(
+            CPM_TAG_TRACK_2_EQUIVALENT_DATA,
+            vec![0x47, 0xD1]
+        ))
+        .is_ok());
+        assert!(decode(BerTlv::primitive(CPM_TAG_APPLICATION_LABEL, vec![0x07])).is_err());
+    }
+
+    #[test]
+    fn test_ber_tlv_nesting_is_capped() {
+        let mut object = BerTlv::primitive(0x9F1A, vec![0x07, 0x64]);
+        for _ in 0..BER_TLV_MAX_DEPTH - 1 {
+            object = BerTlv::constructed(CPM_TAG_COMMON_DATA_TEMPLATE, vec![object]);
+        }
+        let mut bytes = vec![];
+        object.encode_into(&mut bytes).unwrap();
+        assert!(BerTlv::parse_list(&bytes).is_ok());
+
+        let mut bytes = vec![];
+        BerTlv::constructed(CPM_TAG_COMMON_DATA_TEMPLATE, vec![object])
+            .encode_into(&mut bytes)
+            .unwrap();
+        assert!(BerTlv::parse_list(&bytes).is_err());
+    }
+}
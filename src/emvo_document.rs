@@ -0,0 +1,748 @@
+#![allow(dead_code)]
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::convert::TryFrom;
+
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::emvo_qrcode::*;
+use crate::emvo_types::*;
+use crate::prompt_pay::*;
+
+/// JSON model of an EMV QR payload. Known data objects have named fields,
+/// anything else is kept in `unknown` keyed by its two-digit ID, so a
+/// document encodes to a payload and decodes back to the same document.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct EMVQRDocument {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub payload_format_indicator: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub point_of_initiation_method: Option<String>,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub merchant_account_information: BTreeMap<TagID, MerchantAccountInformation>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub merchant_category_code: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub transaction_currency: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub transaction_amount: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tip_or_convenience_indicator: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub value_of_convenience_fee_fixed: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub value_of_convenience_fee_percentage: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub country_code: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub merchant_name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub merchant_city: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub postal_code: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub additional_data_field_template: Option<AdditionalDataDocument>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub merchant_information_language_template: Option<LanguageTemplateDocument>,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub unknown: BTreeMap<TagID, String>,
+}
+
+/// A merchant account (IDs 02-51), e.g. `{"promptpay_credit_transfer": {..}}`.
+/// `template` holds the children of any other template, `value` a primitive
+/// or anything that does not parse as a template.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MerchantAccountInformation {
+    PromptpayCreditTransfer(PromptPayCreditTransferDocument),
+    PromptpayBillPayment(PromptPayBillPaymentDocument),
+    Template(BTreeMap<TagID, String>),
+    Value(String),
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PromptPayCreditTransferDocument {
+    pub aid: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mobile_number: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub national_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub e_wallet_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bank_account: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ota: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PromptPayBillPaymentDocument {
+    pub aid: String,
+    pub biller_id: String,
+    pub ref1: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ref2: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AdditionalDataDocument {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bill_number: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mobile_number: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub store_label: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub loyalty_number: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reference_label: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub customer_label: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub terminal_label: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub purpose_of_transaction: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub additional_consumer_data_request: Option<String>,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub unknown: BTreeMap<TagID, String>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LanguageTemplateDocument {
+    pub language_preference: String,
+    pub merchant_name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub merchant_city: Option<String>,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub unknown: BTreeMap<TagID, String>,
+}
+
+#[derive(Clone, Copy)]
+enum DataFormat {
+    Numeric,
+    AlphanumericSpecial,
+    String,
+}
+
+/// Format and maximum length of the primitive top level data objects.
+fn field_format(tag_id: TagID) -> (DataFormat, usize) {
+    match tag_id {
+        ID_PAYLOAD_FORMAT_INDICATOR | ID_POINT_OF_INITIATION_METHOD => (DataFormat::Numeric, 2),
+        ID_MERCHANT_CATEGORY_CODE => (DataFormat::Numeric, 4),
+        ID_TRANSACTION_CURRENCY => (DataFormat::Numeric, 3),
+        ID_TIP_OR_CONVENIENCE_INDICATOR => (DataFormat::Numeric, 2),
+        ID_TRANSACTION_AMOUNT | ID_VALUE_OF_CONVENIENCE_FEE_FIXED => {
+            (DataFormat::AlphanumericSpecial, 13)
+        }
+        ID_VALUE_OF_CONVENIENCE_FEE_PERCENTAGE => (DataFormat::AlphanumericSpecial, 5),
+        ID_COUNTRY_CODE => (DataFormat::AlphanumericSpecial, 2),
+        ID_MERCHANT_NAME => (DataFormat::AlphanumericSpecial, 25),
+        ID_MERCHANT_CITY => (DataFormat::AlphanumericSpecial, 15),
+        ID_POSTAL_CODE => (DataFormat::AlphanumericSpecial, 10),
+        _ => (DataFormat::String, 99),
+    }
+}
+
+fn data_object(
+    tag_id: TagID,
+    value: &str,
+    format: DataFormat,
+    length: usize,
+) -> Result<EMVQRFieldDataObject, EMVOError> {
+    let data: Box<dyn EMVOData> = match format {
+        DataFormat::Numeric => Box::new(EMVNumeric::try_from(value.to_string())?),
+        DataFormat::AlphanumericSpecial => {
+            Box::new(EMVAlphanumbericSpecial::try_from(value.to_string())?)
+        }
+        DataFormat::String => Box::new(EMVString::try_from(value.to_string())?),
+    };
+    let field = EMVQRFieldDataObject::new(tag_id, data, length);
+    field.value()?;
+    Ok(field)
+}
+
+/// Decodes `value` with `decode` only if encoding the result gives `value`
+/// back, so nothing is lost by modelling it.
+fn decode_exact<T>(
+    value: &str,
+    decode: impl Fn(&str) -> Result<T, EMVOError>,
+    encode: impl Fn(&T) -> Result<String, EMVOError>,
+) -> Option<T> {
+    let decoded = decode(value).ok()?;
+    match encode(&decoded) {
+        Ok(encoded) if encoded == value => Some(decoded),
+        _ => None,
+    }
+}
+
+impl EMVQRDocument {
+    fn named_fields(&self) -> [(TagID, &Option<String>); 12] {
+        [
+            (ID_PAYLOAD_FORMAT_INDICATOR, &self.payload_format_indicator),
+            (
+                ID_POINT_OF_INITIATION_METHOD,
+                &self.point_of_initiation_method,
+            ),
+            (ID_MERCHANT_CATEGORY_CODE, &self.merchant_category_code),
+            (ID_TRANSACTION_CURRENCY, &self.transaction_currency),
+            (ID_TRANSACTION_AMOUNT, &self.transaction_amount),
+            (
+                ID_TIP_OR_CONVENIENCE_INDICATOR,
+                &self.tip_or_convenience_indicator,
+            ),
+            (
+                ID_VALUE_OF_CONVENIENCE_FEE_FIXED,
+                &self.value_of_convenience_fee_fixed,
+            ),
+            (
+                ID_VALUE_OF_CONVENIENCE_FEE_PERCENTAGE,
+                &self.value_of_convenience_fee_percentage,
+            ),
+            (ID_COUNTRY_CODE, &self.country_code),
+            (ID_MERCHANT_NAME, &self.merchant_name),
+            (ID_MERCHANT_CITY, &self.merchant_city),
+            (ID_POSTAL_CODE, &self.postal_code),
+        ]
+    }
+
+    fn named_field_mut(&mut self, tag_id: TagID) -> Option<&mut Option<String>> {
+        match tag_id {
+            ID_PAYLOAD_FORMAT_INDICATOR => Some(&mut self.payload_format_indicator),
+            ID_POINT_OF_INITIATION_METHOD => Some(&mut self.point_of_initiation_method),
+            ID_MERCHANT_CATEGORY_CODE => Some(&mut self.merchant_category_code),
+            ID_TRANSACTION_CURRENCY => Some(&mut self.transaction_currency),
+            ID_TRANSACTION_AMOUNT => Some(&mut self.transaction_amount),
+            ID_TIP_OR_CONVENIENCE_INDICATOR => Some(&mut self.tip_or_convenience_indicator),
+            ID_VALUE_OF_CONVENIENCE_FEE_FIXED => Some(&mut self.value_of_convenience_fee_fixed),
+            ID_VALUE_OF_CONVENIENCE_FEE_PERCENTAGE => {
+                Some(&mut self.value_of_convenience_fee_percentage)
+            }
+            ID_COUNTRY_CODE => Some(&mut self.country_code),
+            ID_MERCHANT_NAME => Some(&mut self.merchant_name),
+            ID_MERCHANT_CITY => Some(&mut self.merchant_city),
+            ID_POSTAL_CODE => Some(&mut self.postal_code),
+            _ => None,
+        }
+    }
+
+    /// Decodes a payload (CRC checked). Data objects that would not encode
+    /// back byte-for-byte through their named field are put in `unknown`.
+    pub fn from_pay_load(pay_load: &str) -> Result<Self, EMVOError> {
+        let mut document = EMVQRDocument::default();
+        for (tag_id, value) in parse_pay_load(pay_load)? {
+            let (format, length) = field_format(tag_id);
+            if let Some(field) = document.named_field_mut(tag_id) {
+                let exact = data_object(tag_id, &value, format, length)
+                    .and_then(|data_object| data_object.value())
+                    .map(|encoded| encoded[4..] == value)
+                    .unwrap_or(false);
+                if exact {
+                    *field = Some(value);
+                    continue;
+                }
+            } else if tag_id.is_between(
+                ID_MERCHANT_ACCOUNT_INFORMATION_RANGE_START,
+                ID_MERCHANT_ACCOUNT_INFORMATION_RANGE_END,
+            ) {
+                document
+                    .merchant_account_information
+                    .insert(tag_id, MerchantAccountInformation::decode(tag_id, &value));
+                continue;
+            } else if tag_id == ID_ADDITIONAL_DATA_FIELD_TEMPLATE {
+                document.additional_data_field_template =
+                    decode_exact(&value, AdditionalDataDocument::decode, |template| {
+                        AdditionalDataFieldTemplate::try_from(template)
+                            .and_then(|template| template.template().to_list_string())
+                    });
+                if document.additional_data_field_template.is_some() {
+                    continue;
+                }
+            } else if tag_id == ID_MERCHANT_INFORMATION_LANGUAGE_TEMPLATE {
+                document.merchant_information_language_template =
+                    decode_exact(&value, LanguageTemplateDocument::decode, |template| {
+                        MerchantInformationLanguageTemplate::try_from(template)
+                            .map(|template| template.value())
+                    });
+                if document.merchant_information_language_template.is_some() {
+                    continue;
+                }
+            }
+            document.unknown.insert(tag_id, value);
+        }
+        Ok(document)
+    }
+
+    pub fn to_pay_load(&self) -> Result<String, EMVOError> {
+        EMVQR::try_from(self)?.generate_pay_load()
+    }
+}
+
+impl TryFrom<&EMVQRDocument> for EMVQR {
+    type Error = EMVOError;
+
+    fn try_from(document: &EMVQRDocument) -> Result<Self, Self::Error> {
+        let mut emvo = EMVQR::default();
+        let mut tag_ids = BTreeSet::new();
+        for (tag_id, value) in document.named_fields().iter() {
+            if let Some(value) = value {
+                let (format, length) = field_format(*tag_id);
+                emvo.set_field_data_object(data_object(*tag_id, value, format, length)?)?;
+                tag_ids.insert(*tag_id);
+            }
+        }
+        for (tag_id, merchant_account) in &document.merchant_account_information {
+            if !tag_id.is_between(
+                ID_MERCHANT_ACCOUNT_INFORMATION_RANGE_START,
+                ID_MERCHANT_ACCOUNT_INFORMATION_RANGE_END,
+            ) {
+                let msg = format!("Tag Id {} Is Not Merchant Account Information", tag_id);
+                return Err(EMVOError::new(msg.as_str()));
+            }
+            emvo.set_field_data_object(merchant_account.to_field_data_object(*tag_id)?)?;
+            tag_ids.insert(*tag_id);
+        }
+        if let Some(template) = &document.additional_data_field_template {
+            emvo.set_additional_data_field_template(AdditionalDataFieldTemplate::try_from(
+                template,
+            )?);
+            tag_ids.insert(ID_ADDITIONAL_DATA_FIELD_TEMPLATE);
+        }
+        if let Some(template) = &document.merchant_information_language_template {
+            emvo.set_merchant_information_language_template(
+                MerchantInformationLanguageTemplate::try_from(template)?,
+            );
+            tag_ids.insert(ID_MERCHANT_INFORMATION_LANGUAGE_TEMPLATE);
+        }
+        for (tag_id, value) in &document.unknown {
+            if !tag_ids.insert(*tag_id) {
+                let msg = format!("Tag Id {} Is Set Twice", tag_id);
+                return Err(EMVOError::new(msg.as_str()));
+            }
+            emvo.set_field_data_object(data_object(*tag_id, value, DataFormat::String, 99)?)?;
+        }
+        Ok(emvo)
+    }
+}
+
+impl MerchantAccountInformation {
+    /// Picks the most specific model that encodes back to `value` exactly.
+    fn decode(tag_id: TagID, value: &str) -> Self {
+        if tag_id == ID_PROMPT_PAY_CREDIT_TRANSFER {
+            if let Some(document) =
+                decode_exact(value, PromptPayCreditTransferDocument::decode, |document| {
+                    MerchantPromptPayCreditTransfer::try_from(document)
+                        .map(|template| template.value())
+                })
+            {
+                return MerchantAccountInformation::PromptpayCreditTransfer(document);
+            }
+        }
+        if tag_id == ID_PROMPT_PAY_BILL_PAYMENT {
+            if let Some(document) =
+                decode_exact(value, PromptPayBillPaymentDocument::decode, |document| {
+                    document.to_template()?.to_list_string()
+                })
+            {
+                return MerchantAccountInformation::PromptpayBillPayment(document);
+            }
+        }
+        if let Ok(template) = EMVTemplate::new(tag_id) {
+            let decoded = decode_exact(
+                value,
+                |value| Ok(parse_data_objects(value)?.into_iter().collect()),
+                |children: &BTreeMap<TagID, String>| {
+                    children_template(template.tag_id(), children)?.to_list_string()
+                },
+            );
+            if let Some(children) = decoded {
+                return MerchantAccountInformation::Template(children);
+            }
+        }
+        MerchantAccountInformation::Value(value.to_string())
+    }
+
+    fn to_field_data_object(&self, tag_id: TagID) -> Result<EMVQRFieldDataObject, EMVOError> {
+        let data: Box<dyn EMVOData> = match self {
+            MerchantAccountInformation::PromptpayCreditTransfer(document) => {
+                if tag_id != ID_PROMPT_PAY_CREDIT_TRANSFER {
+                    return Err(EMVOError::new("PromptPay Credit Transfer Is Tag Id 29"));
+                }
+                Box::new(MerchantPromptPayCreditTransfer::try_from(document)?)
+            }
+            MerchantAccountInformation::PromptpayBillPayment(document) => {
+                if tag_id != ID_PROMPT_PAY_BILL_PAYMENT {
+                    return Err(EMVOError::new("PromptPay Bill Payment Is Tag Id 30"));
+                }
+                Box::new(document.to_template()?)
+            }
+            MerchantAccountInformation::Template(children) => {
+                Box::new(children_template(tag_id, children)?)
+            }
+            MerchantAccountInformation::Value(value) => {
+                return data_object(tag_id, value, DataFormat::String, 99);
+            }
+        };
+        Ok(EMVQRFieldDataObject::new(tag_id, data, 99))
+    }
+}
+
+fn children_template(
+    tag_id: TagID,
+    children: &BTreeMap<TagID, String>,
+) -> Result<EMVTemplate, EMVOError> {
+    let mut template = EMVTemplate::new(tag_id)?;
+    for (child_id, value) in children {
+        template.set(data_object(*child_id, value, DataFormat::String, 99)?)?;
+    }
+    Ok(template)
+}
+
+impl PromptPayCreditTransferDocument {
+    fn decode(value: &str) -> Result<Self, EMVOError> {
+        let mut document = PromptPayCreditTransferDocument::default();
+        for (tag_id, value) in parse_data_objects(value)? {
+            let field = match tag_id {
+                ID_PROMPT_PAY_CREDIT_TRANSFER_AID => {
+                    document.aid = value;
+                    continue;
+                }
+                ID_PROMPT_PAY_CREDIT_TRANSFER_MOBILE_NUMBER => &mut document.mobile_number,
+                ID_PROMPT_PAY_CREDIT_TRANSFER_NATIONAL_ID => &mut document.national_id,
+                ID_PROMPT_PAY_CREDIT_TRANSFER_EWALLET_ID => &mut document.e_wallet_id,
+                ID_PROMPT_PAY_CREDIT_TRANSFER_BANK_ACCOUNT => &mut document.bank_account,
+                ID_PROMPT_PAY_CREDIT_TRANSFER_OTA => &mut document.ota,
+                _ => {
+                    let msg = format!("Tag Id {} Is Not PromptPay Credit Transfer", tag_id);
+                    return Err(EMVOError::new(msg.as_str()));
+                }
+            };
+            *field = Some(value);
+        }
+        Ok(document)
+    }
+}
+
+impl TryFrom<&PromptPayCreditTransferDocument> for MerchantPromptPayCreditTransfer {
+    type Error = EMVOError;
+
+    fn try_from(document: &PromptPayCreditTransferDocument) -> Result<Self, Self::Error> {
+        let presented_type = promptpay_presented_type(&document.aid)
+            .ok_or_else(|| EMVOError::new("PromptPay AID Invalid"))?;
+        let mut template = MerchantPromptPayCreditTransfer::default();
+        template.set_promptpay_presented_type(presented_type)?;
+        for (proxy_type, value) in [
+            (ProxyType::MobileNumber, &document.mobile_number),
+            (ProxyType::NationalId, &document.national_id),
+            (ProxyType::EWalletId, &document.e_wallet_id),
+            (ProxyType::BankAccount, &document.bank_account),
+        ]
+        .iter()
+        {
+            if let Some(value) = value {
+                template.set_proxy(*proxy_type, value)?;
+            }
+        }
+        if let Some(ota) = &document.ota {
+            template.set_ota(ota)?;
+        }
+        Ok(template)
+    }
+}
+
+impl PromptPayBillPaymentDocument {
+    fn decode(value: &str) -> Result<Self, EMVOError> {
+        let mut document = PromptPayBillPaymentDocument::default();
+        for (tag_id, value) in parse_data_objects(value)? {
+            match tag_id {
+                ID_PROMPT_PAY_BILL_PAYMENT_AID => document.aid = value,
+                ID_PROMPT_PAY_BILL_PAYMENT_BILLER_ID => document.biller_id = value,
+                ID_PROMPT_PAY_BILL_PAYMENT_REF1 => document.ref1 = value,
+                ID_PROMPT_PAY_BILL_PAYMENT_REF2 => document.ref2 = Some(value),
+                _ => {
+                    let msg = format!("Tag Id {} Is Not PromptPay Bill Payment", tag_id);
+                    return Err(EMVOError::new(msg.as_str()));
+                }
+            }
+        }
+        Ok(document)
+    }
+
+    fn to_template(&self) -> Result<EMVTemplate, EMVOError> {
+        let mut template = EMVTemplate::new(ID_PROMPT_PAY_BILL_PAYMENT)?;
+        for (tag_id, value, length) in [
+            (ID_PROMPT_PAY_BILL_PAYMENT_AID, Some(&self.aid), 16),
+            (
+                ID_PROMPT_PAY_BILL_PAYMENT_BILLER_ID,
+                Some(&self.biller_id),
+                15,
+            ),
+            (ID_PROMPT_PAY_BILL_PAYMENT_REF1, Some(&self.ref1), 20),
+            (ID_PROMPT_PAY_BILL_PAYMENT_REF2, self.ref2.as_ref(), 20),
+        ]
+        .iter()
+        {
+            if let Some(value) = value {
+                template.set(data_object(
+                    *tag_id,
+                    value,
+                    DataFormat::AlphanumericSpecial,
+                    *length,
+                )?)?;
+            }
+        }
+        Ok(template)
+    }
+}
+
+impl AdditionalDataDocument {
+    fn decode(value: &str) -> Result<Self, EMVOError> {
+        let mut document = AdditionalDataDocument::default();
+        for (tag_id, value) in parse_data_objects(value)? {
+            let field = match tag_id {
+                ADDITIONAL_ID_BILL_NUMBER => &mut document.bill_number,
+                ADDITIONAL_ID_MOBILE_NUMBER => &mut document.mobile_number,
+                ADDITIONAL_ID_STORE_LABEL => &mut document.store_label,
+                ADDITIONAL_ID_LOYALTY_NUMBER => &mut document.loyalty_number,
+                ADDITIONAL_ID_REFERENCE_LABEL => &mut document.reference_label,
+                ADDITIONAL_ID_CUSTOMER_LABEL => &mut document.customer_label,
+                ADDITIONAL_ID_TERMINAL_LABEL => &mut document.terminal_label,
+                ADDITIONAL_ID_PURPOSE_TRANSACTION => &mut document.purpose_of_transaction,
+                ADDITIONAL_ID_ADDITIONAL_CONSUMER_DATA_REQUEST => {
+                    &mut document.additional_consumer_data_request
+                }
+                _ => {
+                    document.unknown.insert(tag_id, value);
+                    continue;
+                }
+            };
+            *field = Some(value);
+        }
+        Ok(document)
+    }
+}
+
+impl TryFrom<&AdditionalDataDocument> for AdditionalDataFieldTemplate {
+    type Error = EMVOError;
+
+    fn try_from(document: &AdditionalDataDocument) -> Result<Self, Self::Error> {
+        let mut template = AdditionalDataFieldTemplate::default();
+        if let Some(value) = &document.bill_number {
+            template.set_bill_number(value.clone())?;
+        }
+        if let Some(value) = &document.mobile_number {
+            template.set_mobile_number(value.clone())?;
+        }
+        if let Some(value) = &document.store_label {
+            template.set_store_label(value.clone())?;
+        }
+        if let Some(value) = &document.loyalty_number {
+            template.set_loyalty_number(value.clone())?;
+        }
+        if let Some(value) = &document.reference_label {
+            template.set_reference_label(value.clone())?;
+        }
+        if let Some(value) = &document.customer_label {
+            template.set_customer_label(value.clone())?;
+        }
+        if let Some(value) = &document.terminal_label {
+            template.set_terminal_label(value.clone())?;
+        }
+        if let Some(value) = &document.purpose_of_transaction {
+            template.set_purpose_of_transaction(value.clone())?;
+        }
+        if let Some(value) = &document.additional_consumer_data_request {
+            template.set_additional_consumer_data_request(value.clone())?;
+        }
+        for (tag_id, value) in &document.unknown {
+            template.set_data_object(*tag_id, value.clone())?;
+        }
+        Ok(template)
+    }
+}
+
+impl LanguageTemplateDocument {
+    fn decode(value: &str) -> Result<Self, EMVOError> {
+        let mut document = LanguageTemplateDocument::default();
+        for (tag_id, value) in parse_data_objects(value)? {
+            match tag_id {
+                MERCHANT_INFORMATION_ID_LANGUAGE_PREFERENCE => document.language_preference = value,
+                MERCHANT_INFORMATION_ID_MERCHANT_NAME => document.merchant_name = value,
+                MERCHANT_INFORMATION_ID_MERCHANT_CITY => document.merchant_city = Some(value),
+                _ => {
+                    document.unknown.insert(tag_id, value);
+                }
+            }
+        }
+        Ok(document)
+    }
+}
+
+impl TryFrom<&LanguageTemplateDocument> for MerchantInformationLanguageTemplate {
+    type Error = EMVOError;
+
+    fn try_from(document: &LanguageTemplateDocument) -> Result<Self, Self::Error> {
+        let mut template = MerchantInformationLanguageTemplate::default();
+        template.set_language_preference(document.language_preference.clone())?;
+        template.set_merchant_name(document.merchant_name.clone())?;
+        if let Some(merchant_city) = &document.merchant_city {
+            template.set_merchant_city(merchant_city.clone())?;
+        }
+        for (tag_id, value) in &document.unknown {
+            template.set_data_object(*tag_id, value.clone())?;
+        }
+        if !template.valid() {
+            return Err(EMVOError::new("Language Template Invalid"));
+        }
+        Ok(template)
+    }
+}
+
+// The typed EMV structures serialize through their JSON documents
+
+impl Serialize for EMVQR {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let pay_load = self.to_pay_load().map_err(serde::ser::Error::custom)?;
+        EMVQRDocument::from_pay_load(&pay_load)
+            .map_err(serde::ser::Error::custom)?
+            .serialize(serializer)
+    }
+}
+impl<'de> Deserialize<'de> for EMVQR {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let document = EMVQRDocument::deserialize(deserializer)?;
+        EMVQR::try_from(&document).map_err(de::Error::custom)
+    }
+}
+
+impl Serialize for MerchantPromptPayCreditTransfer {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let value = self.to_list_string().map_err(serde::ser::Error::custom)?;
+        PromptPayCreditTransferDocument::decode(&value)
+            .map_err(serde::ser::Error::custom)?
+            .serialize(serializer)
+    }
+}
+impl<'de> Deserialize<'de> for MerchantPromptPayCreditTransfer {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let document = PromptPayCreditTransferDocument::deserialize(deserializer)?;
+        MerchantPromptPayCreditTransfer::try_from(&document).map_err(de::Error::custom)
+    }
+}
+
+impl Serialize for AdditionalDataFieldTemplate {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let value = self.to_list_string().map_err(serde::ser::Error::custom)?;
+        AdditionalDataDocument::decode(&value)
+            .map_err(serde::ser::Error::custom)?
+            .serialize(serializer)
+    }
+}
+impl<'de> Deserialize<'de> for AdditionalDataFieldTemplate {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let document = AdditionalDataDocument::deserialize(deserializer)?;
+        AdditionalDataFieldTemplate::try_from(&document).map_err(de::Error::custom)
+    }
+}
+
+impl Serialize for MerchantInformationLanguageTemplate {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let value = self.to_list_string().map_err(serde::ser::Error::custom)?;
+        LanguageTemplateDocument::decode(&value)
+            .map_err(serde::ser::Error::custom)?
+            .serialize(serializer)
+    }
+}
+impl<'de> Deserialize<'de> for MerchantInformationLanguageTemplate {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let document = LanguageTemplateDocument::deserialize(deserializer)?;
+        MerchantInformationLanguageTemplate::try_from(&document).map_err(de::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn pay_load(data: &str) -> String {
+        let mut pay_load = format!("{}6304", data);
+        pay_load.push_str(&crc16(&pay_load));
+        pay_load
+    }
+
+    #[test]
+    fn test_document_round_trip() {
+        let pay_load = pay_load(concat!(
+            "000201",
+            "010212",
+            "29370016A00000067701011101130066812345678",
+            "5303764",
+            "540550.25",
+            "5802TH",
+            "5904test",
+            "6007Bangkok",
+            "62110507INV0001",
+            "64170002TH0107ร้านค้า",
+            "6503123",
+            "8104000A",
+        ));
+        let document = EMVQRDocument::from_pay_load(&pay_load).unwrap();
+        assert_eq!(document.transaction_amount.as_deref(), Some("50.25"));
+        assert_eq!(
+            document.unknown.get(&TagID::new(65)).map(String::as_str),
+            Some("123")
+        );
+        assert_eq!(
+            document
+                .additional_data_field_template
+                .as_ref()
+                .unwrap()
+                .reference_label
+                .as_deref(),
+            Some("INV0001")
+        );
+        match &document.merchant_account_information[&ID_PROMPT_PAY_CREDIT_TRANSFER] {
+            MerchantAccountInformation::PromptpayCreditTransfer(credit_transfer) => {
+                assert_eq!(credit_transfer.aid, "A000000677010111");
+                assert_eq!(
+                    credit_transfer.mobile_number.as_deref(),
+                    Some("0066812345678")
+                );
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+        assert_eq!(document.to_pay_load().unwrap(), pay_load);
+
+        let json = serde_json::to_string(&document).unwrap();
+        let from_json: EMVQRDocument = serde_json::from_str(&json).unwrap();
+        assert_eq!(from_json, document);
+
+        let emvo: EMVQR = serde_json::from_str(&json).unwrap();
+        assert_eq!(serde_json::to_string(&emvo).unwrap(), json);
+    }
+
+    #[test]
+    fn test_document_errors() {
+        let json = r#"{"merchant_name": "test", "unknown": {"59": "again"}}"#;
+        let document: EMVQRDocument = serde_json::from_str(json).unwrap();
+        assert!(document.to_pay_load().is_err());
+        assert!(serde_json::from_str::<EMVQRDocument>(r#"{"unknown": {"5": "x"}}"#).is_err());
+        assert!(serde_json::from_str::<EMVQRDocument>(r#"{"merchant": "x"}"#).is_err());
+        let json = r#"{"merchant_account_information": {"52": {"value": "x"}}}"#;
+        let document: EMVQRDocument = serde_json::from_str(json).unwrap();
+        assert!(document.to_pay_load().is_err());
+    }
+
+    #[test]
+    fn test_serialize_reports_encoding_error() {
+        let error = serde_json::to_string(&MerchantInformationLanguageTemplate::default())
+            .unwrap_err()
+            .to_string();
+        assert!(error.contains("Language Preference"), "{}", error);
+    }
+}
@@ -1,68 +1,70 @@
 #![allow(dead_code)]
 
-use std::collections::HashMap;
+use std::collections::BTreeMap;
 use std::convert::TryFrom;
+use std::fmt;
+use std::fmt::Write;
 use std::vec::Vec;
 
-use crc::{Algorithm, Crc};
-use log::info;
+use crc::{Algorithm, Crc, Digest};
 
 use crate::emvo_types::*;
 
-pub const ID_PAYLOAD_FORMAT_INDICATOR: TagID = "00"; // (M) Payload Format Indicator
-pub const ID_POINT_OF_INITIATION_METHOD: TagID = "01"; // (O) Point of Initiation Method
-pub const ID_MERCHANT_ACCOUNT_INFORMATION_RANGE_START: TagID = "02"; // (M) 2-51 Merchant Account Information
-pub const ID_MERCHANT_ACCOUNT_INFORMATION_RANGE_END: TagID = "51"; // (M) 2-51 Merchant Account Information
-pub const ID_MERCHANT_CATEGORY_CODE: TagID = "52"; // (M) Merchant Category Code
-pub const ID_TRANSACTION_CURRENCY: TagID = "53"; // (M) Transaction Currency
-pub const ID_TRANSACTION_AMOUNT: TagID = "54"; // (C) Transaction Amount
-pub const ID_TIP_OR_CONVENIENCE_INDICATOR: TagID = "55"; // (O) Tip or Convenience Indicator
-pub const ID_VALUE_OF_CONVENIENCE_FEE_FIXED: TagID = "56"; // (C) Value of Convenience Fee Fixed
-pub const ID_VALUE_OF_CONVENIENCE_FEE_PERCENTAGE: TagID = "57"; // (C) Value of Convenience Fee Percentage
-pub const ID_COUNTRY_CODE: TagID = "58"; // (M) Country Code
-pub const ID_MERCHANT_NAME: TagID = "59"; // (M) Merchant Name
-pub const ID_MERCHANT_CITY: TagID = "60"; // (M) Merchant City
-pub const ID_POSTAL_CODE: TagID = "61"; // (O) Postal Code
-pub const ID_ADDITIONAL_DATA_FIELD_TEMPLATE: TagID = "62"; // (O) Additional Data Field Template
-pub const ID_CRC: TagID = "63"; // (M) CRC
-pub const ID_MERCHANT_INFORMATION_LANGUAGE_TEMPLATE: TagID = "64"; // (O) Merchant Information— Language Template
-pub const ID_RFU_FOR_EMVCO_RANGE_START: TagID = "65"; // (O) 65-79 RFU for EMVCo
-pub const ID_RFU_FOR_EMVCO_RANGE_END: TagID = "79"; // (O) 65-79 RFU for EMVCo
-pub const ID_UNRESERVED_TEMPLATES_RANGE_START: TagID = "80"; // (O) 80-99 Unreserved Templates
-pub const ID_UNRESERVED_TEMPLATES_RANGE_END: TagID = "99"; // (O) 80-99 Unreserved Templates
+pub const ID_PAYLOAD_FORMAT_INDICATOR: TagID = TagID::new(0); // (M) Payload Format Indicator
+pub const ID_POINT_OF_INITIATION_METHOD: TagID = TagID::new(1); // (O) Point of Initiation Method
+pub const ID_MERCHANT_ACCOUNT_INFORMATION_RANGE_START: TagID = TagID::new(2); // (M) 2-51 Merchant Account Information
+pub const ID_MERCHANT_ACCOUNT_INFORMATION_RANGE_END: TagID = TagID::new(51); // (M) 2-51 Merchant Account Information
+pub const ID_MERCHANT_ACCOUNT_INFORMATION_TEMPLATE_RANGE_START: TagID = TagID::new(26); // (O) 26-51 Merchant Account Information Templates
+pub const ID_MERCHANT_CATEGORY_CODE: TagID = TagID::new(52); // (M) Merchant Category Code
+pub const ID_TRANSACTION_CURRENCY: TagID = TagID::new(53); // (M) Transaction Currency
+pub const ID_TRANSACTION_AMOUNT: TagID = TagID::new(54); // (C) Transaction Amount
+pub const ID_TIP_OR_CONVENIENCE_INDICATOR: TagID = TagID::new(55); // (O) Tip or Convenience Indicator
+pub const ID_VALUE_OF_CONVENIENCE_FEE_FIXED: TagID = TagID::new(56); // (C) Value of Convenience Fee Fixed
+pub const ID_VALUE_OF_CONVENIENCE_FEE_PERCENTAGE: TagID = TagID::new(57); // (C) Value of Convenience Fee Percentage
+pub const ID_COUNTRY_CODE: TagID = TagID::new(58); // (M) Country Code
+pub const ID_MERCHANT_NAME: TagID = TagID::new(59); // (M) Merchant Name
+pub const ID_MERCHANT_CITY: TagID = TagID::new(60); // (M) Merchant City
+pub const ID_POSTAL_CODE: TagID = TagID::new(61); // (O) Postal Code
+pub const ID_ADDITIONAL_DATA_FIELD_TEMPLATE: TagID = TagID::new(62); // (O) Additional Data Field Template
+pub const ID_CRC: TagID = TagID::new(63); // (M) CRC
+pub const ID_MERCHANT_INFORMATION_LANGUAGE_TEMPLATE: TagID = TagID::new(64); // (O) Merchant Information— Language Template
+pub const ID_RFU_FOR_EMVCO_RANGE_START: TagID = TagID::new(65); // (O) 65-79 RFU for EMVCo
+pub const ID_RFU_FOR_EMVCO_RANGE_END: TagID = TagID::new(79); // (O) 65-79 RFU for EMVCo
+pub const ID_UNRESERVED_TEMPLATES_RANGE_START: TagID = TagID::new(80); // (O) 80-99 Unreserved Templates
+pub const ID_UNRESERVED_TEMPLATES_RANGE_END: TagID = TagID::new(99); // (O) 80-99 Unreserved Templates
 
 // Data Object ID Allocation in Merchant Account Information Template ...
-pub const MERCHANT_ACCOUNT_INFORMATION_ID_GLOBALLY_UNIQUE_IDENTIFIER: TagID = "00";
-pub const MERCHANT_ACCOUNT_INFORMATION_ID_PAYMENT_NETWORK_SPECIFIC_START: TagID = "01"; // (O) 03-99 RFU for EMVCo
-pub const MERCHANT_ACCOUNT_INFORMATION_ID_PAYMENT_NETWORK_SPECIFIC_END: TagID = "99"; // (O) 03-99 RFU for EMVCo
-
-pub const ADDITIONAL_ID_BILL_NUMBER: TagID = "01"; // (O) Bill Number
-pub const ADDITIONAL_ID_MOBILE_NUMBER: TagID = "02"; // (O) Mobile Number
-pub const ADDITIONAL_ID_STORE_LABEL: TagID = "03"; // (O) Store Label
-pub const ADDITIONAL_ID_LOYALTY_NUMBER: TagID = "04"; // (O) Loyalty Number
-pub const ADDITIONAL_ID_REFERENCE_LABEL: TagID = "05"; // (O) Reference Label
-pub const ADDITIONAL_ID_CUSTOMER_LABEL: TagID = "06"; // (O) Customer Label
-pub const ADDITIONAL_ID_TERMINAL_LABEL: TagID = "07"; // (O) Terminal Label
-pub const ADDITIONAL_ID_PURPOSE_TRANSACTION: TagID = "08"; // (O) Purpose Transaction
-pub const ADDITIONAL_ID_ADDITIONAL_CONSUMER_DATA_REQUEST: TagID = "09"; // (O) Additional Consumer Data Request
-pub const ADDITIONAL_ID_RFUFOR_EMVCO_RANGE_START: TagID = "10"; // (O) RFU for EMVCo
-pub const ADDITIONAL_ID_RFUFOR_EMVCO_RANGE_END: TagID = "49"; // (O) RFU for EMVCo
-pub const ADDITIONAL_ID_PAYMENT_SYSTEM_SPECIFIC_TEMPLATES_RANGE_START: TagID = "50"; // (O) Payment System Specific Templates
-pub const ADDITIONAL_ID_PAYMENT_SYSTEM_SPECIFIC_TEMPLATES_RANGE_END: TagID = "99"; // (O) Payment System Specific Templates
+pub const MERCHANT_ACCOUNT_INFORMATION_ID_GLOBALLY_UNIQUE_IDENTIFIER: TagID = TagID::new(0);
+pub const MERCHANT_ACCOUNT_INFORMATION_ID_PAYMENT_NETWORK_SPECIFIC_START: TagID = TagID::new(1); // (O) 03-99 RFU for EMVCo
+pub const MERCHANT_ACCOUNT_INFORMATION_ID_PAYMENT_NETWORK_SPECIFIC_END: TagID = TagID::new(99); // (O) 03-99 RFU for EMVCo
+
+pub const ADDITIONAL_ID_BILL_NUMBER: TagID = TagID::new(1); // (O) Bill Number
+pub const ADDITIONAL_ID_MOBILE_NUMBER: TagID = TagID::new(2); // (O) Mobile Number
+pub const ADDITIONAL_ID_STORE_LABEL: TagID = TagID::new(3); // (O) Store Label
+pub const ADDITIONAL_ID_LOYALTY_NUMBER: TagID = TagID::new(4); // (O) Loyalty Number
+pub const ADDITIONAL_ID_REFERENCE_LABEL: TagID = TagID::new(5); // (O) Reference Label
+pub const ADDITIONAL_ID_CUSTOMER_LABEL: TagID = TagID::new(6); // (O) Customer Label
+pub const ADDITIONAL_ID_TERMINAL_LABEL: TagID = TagID::new(7); // (O) Terminal Label
+pub const ADDITIONAL_ID_PURPOSE_TRANSACTION: TagID = TagID::new(8); // (O) Purpose Transaction
+pub const ADDITIONAL_ID_ADDITIONAL_CONSUMER_DATA_REQUEST: TagID = TagID::new(9); // (O) Additional Consumer Data Request
+pub const ADDITIONAL_ID_RFUFOR_EMVCO_RANGE_START: TagID = TagID::new(10); // (O) RFU for EMVCo
+pub const ADDITIONAL_ID_RFUFOR_EMVCO_RANGE_END: TagID = TagID::new(49); // (O) RFU for EMVCo
+pub const ADDITIONAL_ID_PAYMENT_SYSTEM_SPECIFIC_TEMPLATES_RANGE_START: TagID = TagID::new(50); // (O) Payment System Specific Templates
+pub const ADDITIONAL_ID_PAYMENT_SYSTEM_SPECIFIC_TEMPLATES_RANGE_END: TagID = TagID::new(99); // (O) Payment System Specific Templates
 
 // Data Objects for Merchant Information—Language Template (ID "64")
 
-pub const MERCHANT_INFORMATION_ID_LANGUAGE_PREFERENCE: TagID = "00"; // (M) Language Preference
-pub const MERCHANT_INFORMATION_ID_MERCHANT_NAME: TagID = "01"; // (M) Merchant Name
-pub const MERCHANT_INFORMATION_ID_MERCHANT_CITY: TagID = "02"; // (O) Merchant City
-pub const MERCHANT_INFORMATION_ID_RFUFOR_EMVCO_RANGE_START: TagID = "03"; // (O) 03-99 RFU for EMVCo
-pub const MERCHANT_INFORMATION_ID_RFUFOR_EMVCO_RANGE_END: TagID = "99"; // (O) 03-99 RFU for EMVCo
+pub const MERCHANT_INFORMATION_ID_LANGUAGE_PREFERENCE: TagID = TagID::new(0); // (M) Language Preference
+pub const MERCHANT_INFORMATION_ID_MERCHANT_NAME: TagID = TagID::new(1); // (M) Merchant Name
+pub const MERCHANT_INFORMATION_ID_MERCHANT_CITY: TagID = TagID::new(2); // (O) Merchant City
+pub const MERCHANT_INFORMATION_ID_RFUFOR_EMVCO_RANGE_START: TagID = TagID::new(3); // (O) 03-99 RFU for EMVCo
+pub const MERCHANT_INFORMATION_ID_RFUFOR_EMVCO_RANGE_END: TagID = TagID::new(99); // (O) 03-99 RFU for EMVCo
 
 // Data Object ID Allocation in Merchant Account Information Template ...
 
-pub const UNRESERVED_TEMPLATE_ID_GLOBALLY_UNIQUE_IDENTIFIER: TagID = "00";
-pub const UNRESERVED_TEMPLATE_ID_CONTEXT_SPECIFIC_DATA_START: TagID = "01"; // (O) 03-99 RFU for EMVCo
-pub const UNRESERVED_TEMPLATE_ID_CONTEXT_SPECIFIC_DATA_END: TagID = "99"; // (O) 03-99 RFU for EMVCo
+pub const UNRESERVED_TEMPLATE_ID_GLOBALLY_UNIQUE_IDENTIFIER: TagID = TagID::new(0);
+pub const UNRESERVED_TEMPLATE_ID_CONTEXT_SPECIFIC_DATA_START: TagID = TagID::new(1); // (O) 01-99 Context Specific Data
+pub const UNRESERVED_TEMPLATE_ID_CONTEXT_SPECIFIC_DATA_END: TagID = TagID::new(99); // (O) 01-99 Context Specific Data
 
 pub const STATIC_POINT: PointType = 0;
 pub const DYNAMIC_POINT: PointType = 1;
@@ -76,28 +78,30 @@ pub struct EMVQRFieldDataObject {
 pub struct EMVQFieldDataObjectListBuilder<'a> {
     fields: Vec<&'a EMVQRFieldDataObject>,
 }
-/*
+/// A template data object (IDs 26-51, 62, 64 and 80-99): an ordered list of
+/// child data objects whose encoding is the template's value.
+pub struct EMVTemplate {
+    tag_id: TagID,
+    fields: Vec<EMVQRFieldDataObject>,
+}
 pub struct AdditionalDataFieldTemplate {
-    bill_number: Option<EMVQRFieldDataObject>,
-    mobile_number: Option<EMVQRFieldDataObject>,
-    storage_label: Option<EMVQRFieldDataObject>,
-    loyalty: Option<EMVQRFieldDataObject>,
-    reference_label: Option<EMVQRFieldDataObject>,
-    customer_label: Option<EMVQRFieldDataObject>,
-    terminal_label: Option<EMVQRFieldDataObject>,
-    purpose_of_transaction: Option<EMVQRFieldDataObject>,
-    additional_consumer_data: Option<EMVQRFieldDataObject>,
-    rfu_emvcos: Option<Vec<EMVQRFieldDataObject>>,
-    payment_system_templates: Option<Vec<EMVQRFieldDataObject>>,
-}
-*/
+    template: EMVTemplate,
+}
+pub struct MerchantInformationLanguageTemplate {
+    template: EMVTemplate,
+}
+/// Unreserved Template (IDs 80-99): a Globally Unique Identifier (ID 00)
+/// naming whoever defines the context specific data in IDs 01-99.
+pub struct UnreservedTemplate {
+    template: EMVTemplate,
+}
 
 //#[derive(Debug, Clone)]
 #[derive(Default)]
 pub struct EMVQR {
     payload_format_indicator: Option<EMVQRFieldDataObject>,
     point_of_initiation_method: Option<EMVQRFieldDataObject>,
-    merchant_account_information: Option<HashMap<TagID, Box<dyn EMVOData>>>,
+    merchant_account_information: Option<BTreeMap<TagID, EMVQRFieldDataObject>>,
     merchant_category_code: Option<EMVQRFieldDataObject>,
     transaction_currency: Option<EMVQRFieldDataObject>,
     transaction_amount: Option<EMVQRFieldDataObject>,
@@ -108,10 +112,10 @@ pub struct EMVQR {
     merchant_name: Option<EMVQRFieldDataObject>,
     merchant_city: Option<EMVQRFieldDataObject>,
     postal_code: Option<EMVQRFieldDataObject>,
-    additional_data_field_template: Option<Box<dyn EMVOData>>,
+    additional_data_field_template: Option<EMVQRFieldDataObject>,
     crc: Option<EMVQRFieldDataObject>,
-    merchant_information_language_template: Option<Box<dyn EMVOData>>,
-    rfu_for_emvcos: Option<Vec<EMVQRFieldDataObject>>,
+    merchant_information_language_template: Option<EMVQRFieldDataObject>,
+    other_data_objects: BTreeMap<TagID, EMVQRFieldDataObject>,
 }
 //////////
 
@@ -124,43 +128,77 @@ impl EMVQRFieldDataObject {
         }
     }
 
-    pub fn value(&self) -> Result<String, EMVOError> {
-        return if !self.data.valid() {
+    /// A data object whose value and length have been checked, so a bad
+    /// value is reported by the setter rather than on encoding.
+    pub fn checked(
+        tag_id: TagID,
+        data: Box<dyn EMVOData>,
+        length: usize,
+    ) -> Result<Self, EMVOError> {
+        let field = EMVQRFieldDataObject::new(tag_id, data, length);
+        field.value_length()?;
+        Ok(field)
+    }
+
+    pub fn tag_id(&self) -> TagID {
+        self.tag_id
+    }
+
+    /// Length written after the ID, without checking the data. Numeric data
+    /// is zero padded to the full length.
+    fn padded_len(&self) -> usize {
+        match self.data.type_name() {
+            TYPE_NAME_NUMERIC => self.length,
+            _ => self.data.len(),
+        }
+    }
+
+    /// Checks the data, nested templates included, and returns the length
+    /// written after the ID.
+    fn value_length(&self) -> Result<usize, EMVOError> {
+        if !self.data.valid() {
             let msg = format!("Tag Id {} Data Invalid", self.tag_id);
-            Err(EMVOError::new(msg.as_str()))
-        } else {
-            if !self.data.value().is_empty() {
-                if self.data.len() > self.length {
-                    let msg = format!("Tag Id {} Data Length Invalid", self.tag_id);
-                    return Err(EMVOError::new(msg.as_str()));
-                }
-                let data_type_name = self.data.type_name();
-                let result = match data_type_name {
-                    TYPE_NAME_NUMERIC => {
-                        //numeric
-                        format!(
-                            "{}{:0>2}{}",
-                            self.tag_id,
-                            self.length,
-                            format!("{:0>width$}", self.data.value(), width = self.length).as_str()
-                        )
-                    }
-                    _ => {
-                        //default
-                        format!(
-                            "{}{:0>2}{}",
-                            self.tag_id,
-                            self.data.len(),
-                            self.data.value()
-                        )
-                    }
-                };
-                Ok(String::from(result))
-            } else {
-                let msg = format!("Tag Id {} Data Is Empty", self.tag_id);
-                Err(EMVOError::new(msg.as_str()))
-            }
-        };
+            return Err(EMVOError::new(msg.as_str()));
+        }
+        let length = self.data.len();
+        if length == 0 {
+            let msg = format!("Tag Id {} Data Is Empty", self.tag_id);
+            return Err(EMVOError::new(msg.as_str()));
+        }
+        if length > self.length {
+            let msg = format!("Tag Id {} Data Length Invalid", self.tag_id);
+            return Err(EMVOError::new(msg.as_str()));
+        }
+        Ok(self.padded_len())
+    }
+
+    /// Length in characters of the encoded ID, length and value, checking
+    /// the data once.
+    pub fn encoded_len(&self) -> Result<usize, EMVOError> {
+        Ok(4 + self.value_length()?)
+    }
+
+    /// Checks the data once, writes it and returns the characters written.
+    pub fn encode_into(&self, out: &mut dyn fmt::Write) -> Result<usize, EMVOError> {
+        let length = self.encoded_len()?;
+        self.write_into(out)?;
+        Ok(length)
+    }
+
+    /// Writes a data object that `encoded_len` already checked.
+    fn write_into(&self, out: &mut dyn fmt::Write) -> fmt::Result {
+        let length = self.padded_len();
+        write!(out, "{}{:02}", self.tag_id, length)?;
+        for _ in self.data.len()..length {
+            out.write_char('0')?;
+        }
+        self.data.encode_into(out)
+    }
+
+    pub fn value(&self) -> Result<String, EMVOError> {
+        let mut value = String::with_capacity(self.encoded_len()?);
+        self.write_into(&mut value)?;
+        Ok(value)
     }
 }
 impl<'a> EMVQFieldDataObjectListBuilder<'a> {
@@ -179,52 +217,440 @@ impl<'a> EMVQFieldDataObjectListBuilder<'a> {
         self.fields.push(field_object);
     }
     pub fn to_string(&self) -> Result<String, EMVOError> {
-        let mut data: String = String::from("");
-        for (_pos, item) in self.fields.iter().enumerate() {
-            let result = item.value();
-            match result {
-                Ok(d) => {
-                    if d.len() > 0 {
-                        data.push_str(d.as_str());
-                    }
-                }
-                Err(er) => {
-                    return Err(er);
-                }
-            }
+        let mut length = 0;
+        for item in self.fields.iter() {
+            length += item.encoded_len()?;
+        }
+        let mut data = String::with_capacity(length);
+        for item in self.fields.iter() {
+            item.write_into(&mut data)?;
         }
         Ok(data)
     }
 }
-/*
-impl AdditionalDataFieldTemplate {
-    pub fn new() -> Self {
+/// Allowed child IDs of each template, per EMV QRCPS.
+fn template_child_id_range(tag_id: TagID) -> Result<(TagID, TagID), EMVOError> {
+    if tag_id.is_between(
+        ID_MERCHANT_ACCOUNT_INFORMATION_TEMPLATE_RANGE_START,
+        ID_MERCHANT_ACCOUNT_INFORMATION_RANGE_END,
+    ) {
+        Ok((
+            MERCHANT_ACCOUNT_INFORMATION_ID_GLOBALLY_UNIQUE_IDENTIFIER,
+            MERCHANT_ACCOUNT_INFORMATION_ID_PAYMENT_NETWORK_SPECIFIC_END,
+        ))
+    } else if tag_id == ID_ADDITIONAL_DATA_FIELD_TEMPLATE {
+        Ok((
+            ADDITIONAL_ID_BILL_NUMBER,
+            ADDITIONAL_ID_PAYMENT_SYSTEM_SPECIFIC_TEMPLATES_RANGE_END,
+        ))
+    } else if tag_id == ID_MERCHANT_INFORMATION_LANGUAGE_TEMPLATE {
+        Ok((
+            MERCHANT_INFORMATION_ID_LANGUAGE_PREFERENCE,
+            MERCHANT_INFORMATION_ID_RFUFOR_EMVCO_RANGE_END,
+        ))
+    } else if tag_id.is_between(
+        ID_UNRESERVED_TEMPLATES_RANGE_START,
+        ID_UNRESERVED_TEMPLATES_RANGE_END,
+    ) {
+        Ok((
+            UNRESERVED_TEMPLATE_ID_GLOBALLY_UNIQUE_IDENTIFIER,
+            UNRESERVED_TEMPLATE_ID_CONTEXT_SPECIFIC_DATA_END,
+        ))
+    } else {
+        let msg = format!("Tag Id {} Is Not A Template", tag_id);
+        Err(EMVOError::new(msg.as_str()))
+    }
+}
+
+impl EMVTemplate {
+    pub fn new(tag_id: TagID) -> Result<Self, EMVOError> {
+        template_child_id_range(tag_id)?;
+        Ok(EMVTemplate {
+            tag_id,
+            fields: Vec::new(),
+        })
+    }
+    pub fn tag_id(&self) -> TagID {
+        self.tag_id
+    }
+    /// Adds or replaces the child with the field's ID, keeping children in ID
+    /// order.
+    pub fn set(&mut self, field: EMVQRFieldDataObject) -> Result<(), EMVOError> {
+        let (start, end) = template_child_id_range(self.tag_id)?;
+        let child_id = field.tag_id();
+        if !child_id.is_between(start, end) {
+            let msg = format!(
+                "Tag Id {} Not Allowed In Template {}",
+                child_id, self.tag_id
+            );
+            return Err(EMVOError::new(msg.as_str()));
+        }
+        match self
+            .fields
+            .binary_search_by(|item| item.tag_id().cmp(&child_id))
+        {
+            Ok(index) => self.fields[index] = field,
+            Err(index) => self.fields.insert(index, field),
+        }
+        Ok(())
+    }
+    pub fn set_field(
+        &mut self,
+        tag_id: TagID,
+        data: Box<dyn EMVOData>,
+        length: usize,
+    ) -> Result<(), EMVOError> {
+        self.set(EMVQRFieldDataObject::new(tag_id, data, length))
+    }
+    pub fn remove(&mut self, tag_id: TagID) {
+        self.fields.retain(|item| item.tag_id() != tag_id);
+    }
+    pub fn get(&self, tag_id: TagID) -> Option<&EMVQRFieldDataObject> {
+        self.fields.iter().find(|item| item.tag_id() == tag_id)
+    }
+    pub fn is_empty(&self) -> bool {
+        self.fields.is_empty()
+    }
+    /// Length in characters of the children, checking each of them.
+    fn list_len(&self) -> Result<usize, EMVOError> {
+        if self.fields.is_empty() {
+            let msg = format!("Tag Id {} Template Is Empty", self.tag_id);
+            return Err(EMVOError::new(msg.as_str()));
+        }
+        let mut length = 0;
+        for field in self.fields.iter() {
+            length += field.encoded_len()?;
+        }
+        if length > 99 {
+            let msg = format!("Tag Id {} Data Length Invalid", self.tag_id);
+            return Err(EMVOError::new(msg.as_str()));
+        }
+        Ok(length)
+    }
+    /// Writes children that `list_len` already checked.
+    fn write_list_into(&self, out: &mut dyn fmt::Write) -> fmt::Result {
+        for field in self.fields.iter() {
+            field.write_into(out)?;
+        }
+        Ok(())
+    }
+    pub fn to_list_string(&self) -> Result<String, EMVOError> {
+        let mut data = String::with_capacity(self.list_len()?);
+        self.write_list_into(&mut data)?;
+        Ok(data)
+    }
+    /// The template as a top level (or nested) data object.
+    pub fn into_field_data_object(self) -> EMVQRFieldDataObject {
+        EMVQRFieldDataObject::new(self.tag_id, Box::new(self), 99)
+    }
+}
+impl EMVOData for EMVTemplate {
+    /// Unchecked, so a parent sizing its value does not validate the
+    /// children again; `valid` does that.
+    fn len(&self) -> usize {
+        self.fields.iter().map(|field| 4 + field.padded_len()).sum()
+    }
+    fn valid(&self) -> bool {
+        self.list_len().is_ok()
+    }
+    fn value(&self) -> Data {
+        self.to_list_string().unwrap_or_default()
+    }
+    fn type_name(&self) -> &str {
+        TYPE_NAME_TEMPLATE
+    }
+    fn encode_into(&self, out: &mut dyn fmt::Write) -> fmt::Result {
+        self.write_list_into(out)
+    }
+}
+
+impl Default for AdditionalDataFieldTemplate {
+    fn default() -> Self {
         AdditionalDataFieldTemplate {
-            bill_number: None,
-            mobile_number: None,
-            storage_label: None,
-            loyalty: None,
-            reference_label: None,
-            customer_label: None,
-            terminal_label: None,
-            purpose_of_transaction: None,
-            additional_consumer_data: None,
-            rfu_emvcos: None,
-            payment_system_templates: None,
+            template: EMVTemplate::new(ID_ADDITIONAL_DATA_FIELD_TEMPLATE).unwrap(),
+        }
+    }
+}
+impl AdditionalDataFieldTemplate {
+    /// The encoded sub-tags, or why they cannot be encoded.
+    pub fn to_list_string(&self) -> Result<String, EMVOError> {
+        self.template.to_list_string()
+    }
+    fn set_label(&mut self, tag_id: TagID, data: Data, length: usize) -> Result<(), EMVOError> {
+        if data.is_empty() || data.len() > length {
+            let msg = format!("Tag Id {} Data Length Invalid", tag_id);
+            return Err(EMVOError::new(msg.as_str()));
+        }
+        self.template.set_field(
+            tag_id,
+            Box::new(EMVAlphanumbericSpecial::try_from(data)?),
+            length,
+        )
+    }
+    pub fn set_bill_number(&mut self, bill_number: Data) -> Result<(), EMVOError> {
+        self.set_label(ADDITIONAL_ID_BILL_NUMBER, bill_number, 25)
+    }
+    pub fn set_mobile_number(&mut self, mobile_number: Data) -> Result<(), EMVOError> {
+        self.set_label(ADDITIONAL_ID_MOBILE_NUMBER, mobile_number, 25)
+    }
+    pub fn set_store_label(&mut self, store_label: Data) -> Result<(), EMVOError> {
+        self.set_label(ADDITIONAL_ID_STORE_LABEL, store_label, 25)
+    }
+    pub fn set_loyalty_number(&mut self, loyalty_number: Data) -> Result<(), EMVOError> {
+        self.set_label(ADDITIONAL_ID_LOYALTY_NUMBER, loyalty_number, 25)
+    }
+    pub fn set_reference_label(&mut self, reference_label: Data) -> Result<(), EMVOError> {
+        self.set_label(ADDITIONAL_ID_REFERENCE_LABEL, reference_label, 25)
+    }
+    pub fn set_customer_label(&mut self, customer_label: Data) -> Result<(), EMVOError> {
+        self.set_label(ADDITIONAL_ID_CUSTOMER_LABEL, customer_label, 25)
+    }
+    pub fn set_terminal_label(&mut self, terminal_label: Data) -> Result<(), EMVOError> {
+        self.set_label(ADDITIONAL_ID_TERMINAL_LABEL, terminal_label, 25)
+    }
+    pub fn set_purpose_of_transaction(&mut self, purpose: Data) -> Result<(), EMVOError> {
+        self.set_label(ADDITIONAL_ID_PURPOSE_TRANSACTION, purpose, 25)
+    }
+    pub fn set_additional_consumer_data_request(&mut self, request: Data) -> Result<(), EMVOError> {
+        self.set_label(ADDITIONAL_ID_ADDITIONAL_CONSUMER_DATA_REQUEST, request, 3)
+    }
+    /// Sets an RFU or payment system specific data object (IDs 10-99).
+    pub fn set_data_object(&mut self, tag_id: TagID, data: Data) -> Result<(), EMVOError> {
+        if tag_id < ADDITIONAL_ID_RFUFOR_EMVCO_RANGE_START {
+            let msg = format!("Tag Id {} Has A Setter Of Its Own", tag_id);
+            return Err(EMVOError::new(msg.as_str()));
         }
+        self.template
+            .set_field(tag_id, Box::new(EMVString::try_from(data)?), 99)
+    }
+    pub fn template(&self) -> &EMVTemplate {
+        &self.template
+    }
+}
+impl EMVOData for AdditionalDataFieldTemplate {
+    fn len(&self) -> usize {
+        self.template.len()
+    }
+    fn valid(&self) -> bool {
+        self.template.valid()
+    }
+    fn value(&self) -> Data {
+        self.template.value()
+    }
+    fn type_name(&self) -> &str {
+        TYPE_NAME_TEMPLATE
+    }
+    fn encode_into(&self, out: &mut dyn fmt::Write) -> fmt::Result {
+        self.template.encode_into(out)
+    }
+}
+
+impl Default for MerchantInformationLanguageTemplate {
+    fn default() -> Self {
+        MerchantInformationLanguageTemplate {
+            template: EMVTemplate::new(ID_MERCHANT_INFORMATION_LANGUAGE_TEMPLATE).unwrap(),
+        }
+    }
+}
+impl MerchantInformationLanguageTemplate {
+    pub fn set_language_preference(&mut self, language: Data) -> Result<(), EMVOError> {
+        self.template.set_field(
+            MERCHANT_INFORMATION_ID_LANGUAGE_PREFERENCE,
+            Box::new(EMVAlphanumbericSpecial::try_from(language)?),
+            2,
+        )
+    }
+    pub fn set_merchant_name(&mut self, merchant_name: Data) -> Result<(), EMVOError> {
+        self.template.set_field(
+            MERCHANT_INFORMATION_ID_MERCHANT_NAME,
+            Box::new(EMVString::try_from(merchant_name)?),
+            25,
+        )
+    }
+    pub fn set_merchant_city(&mut self, merchant_city: Data) -> Result<(), EMVOError> {
+        self.template.set_field(
+            MERCHANT_INFORMATION_ID_MERCHANT_CITY,
+            Box::new(EMVString::try_from(merchant_city)?),
+            15,
+        )
+    }
+    /// Sets an RFU data object (IDs 03-99).
+    pub fn set_data_object(&mut self, tag_id: TagID, data: Data) -> Result<(), EMVOError> {
+        if tag_id < MERCHANT_INFORMATION_ID_RFUFOR_EMVCO_RANGE_START {
+            let msg = format!("Tag Id {} Has A Setter Of Its Own", tag_id);
+            return Err(EMVOError::new(msg.as_str()));
+        }
+        self.template
+            .set_field(tag_id, Box::new(EMVString::try_from(data)?), 99)
+    }
+    fn check_mandatory(&self) -> Result<(), EMVOError> {
+        if self
+            .template
+            .get(MERCHANT_INFORMATION_ID_LANGUAGE_PREFERENCE)
+            .is_none()
+            || self
+                .template
+                .get(MERCHANT_INFORMATION_ID_MERCHANT_NAME)
+                .is_none()
+        {
+            return Err(EMVOError::new(
+                "Language Template requires Language Preference and Merchant Name",
+            ));
+        }
+        Ok(())
+    }
+    /// The encoded sub-tags, or why they cannot be encoded.
+    pub fn to_list_string(&self) -> Result<String, EMVOError> {
+        self.check_mandatory()?;
+        self.template.to_list_string()
+    }
+}
+impl EMVOData for MerchantInformationLanguageTemplate {
+    fn len(&self) -> usize {
+        self.template.len()
+    }
+    fn valid(&self) -> bool {
+        self.check_mandatory().is_ok() && self.template.valid()
+    }
+    fn value(&self) -> Data {
+        self.to_list_string().unwrap_or_default()
+    }
+    fn type_name(&self) -> &str {
+        TYPE_NAME_TEMPLATE
+    }
+    fn encode_into(&self, out: &mut dyn fmt::Write) -> fmt::Result {
+        self.template.encode_into(out)
+    }
+}
+
+impl UnreservedTemplate {
+    pub fn new(tag_id: TagID, globally_unique_identifier: Data) -> Result<Self, EMVOError> {
+        if !tag_id.is_between(
+            ID_UNRESERVED_TEMPLATES_RANGE_START,
+            ID_UNRESERVED_TEMPLATES_RANGE_END,
+        ) {
+            let msg = format!("Tag Id {} Is Not An Unreserved Template", tag_id);
+            return Err(EMVOError::new(msg.as_str()));
+        }
+        if globally_unique_identifier.is_empty() || globally_unique_identifier.len() > 32 {
+            return Err(EMVOError::new("Globally Unique Identifier Length Invalid"));
+        }
+        let mut template = EMVTemplate::new(tag_id)?;
+        template.set_field(
+            UNRESERVED_TEMPLATE_ID_GLOBALLY_UNIQUE_IDENTIFIER,
+            Box::new(EMVAlphanumbericSpecial::try_from(
+                globally_unique_identifier,
+            )?),
+            32,
+        )?;
+        Ok(UnreservedTemplate { template })
+    }
+    pub fn tag_id(&self) -> TagID {
+        self.template.tag_id()
+    }
+    /// Sets context specific data (IDs 01-99).
+    pub fn set_context_specific_data(
+        &mut self,
+        tag_id: TagID,
+        data: Data,
+    ) -> Result<(), EMVOError> {
+        if tag_id < UNRESERVED_TEMPLATE_ID_CONTEXT_SPECIFIC_DATA_START {
+            let msg = format!("Tag Id {} Is Not Context Specific Data", tag_id);
+            return Err(EMVOError::new(msg.as_str()));
+        }
+        self.template
+            .set_field(tag_id, Box::new(EMVString::try_from(data)?), 99)
+    }
+    pub fn template(&self) -> &EMVTemplate {
+        &self.template
+    }
+}
+impl EMVOData for UnreservedTemplate {
+    fn len(&self) -> usize {
+        self.template.len()
+    }
+    fn valid(&self) -> bool {
+        self.template.valid()
+    }
+    fn value(&self) -> Data {
+        self.template.value()
+    }
+    fn type_name(&self) -> &str {
+        TYPE_NAME_TEMPLATE
+    }
+    fn encode_into(&self, out: &mut dyn fmt::Write) -> fmt::Result {
+        self.template.encode_into(out)
+    }
+}
+
+// CRC-16/CCITT-FALSE as required by EMV QRCPS
+const CRC_ALGORITHM: Algorithm<u16> = Algorithm {
+    width: 16,
+    poly: 0x1021,
+    init: 0xffff,
+    refin: false,
+    refout: false,
+    xorout: 0x0000,
+    check: 0x29B1,
+    residue: 0x0000,
+};
+
+static CRC: Crc<u16> = Crc::<u16>::new(&CRC_ALGORITHM);
+
+/// CRC of everything up to and including the CRC tag and length, as four
+/// uppercase hex digits.
+pub fn crc16(data: &str) -> String {
+    format!("{:04X}", CRC.checksum(data.as_bytes()))
+}
+
+/// A CRC digest to feed a payload into piece by piece.
+pub fn crc16_digest() -> Digest<'static, u16> {
+    CRC.digest()
+}
+
+/// Amounts and fixed fees are unsigned decimals such as `50` or `50.25`.
+pub fn validate_amount(tag_id: TagID, data: &str) -> Result<(), EMVOError> {
+    let mut parts = data.splitn(2, '.');
+    let whole = parts.next().unwrap_or_default();
+    let valid = !whole.is_empty()
+        && whole.chars().all(|c| c.is_ascii_digit())
+        && parts
+            .next()
+            .is_none_or(|fraction| fraction.chars().all(|c| c.is_ascii_digit()));
+    if !valid {
+        let msg = format!("Tag Id {} Amount Invalid", tag_id);
+        return Err(EMVOError::new(msg.as_str()));
+    }
+    Ok(())
+}
+
+/// Passes writes through while feeding them to the CRC, then appends the
+/// CRC in `finish`.
+pub struct CrcWriter<'a, W: fmt::Write> {
+    out: &'a mut W,
+    digest: Digest<'static, u16>,
+}
+impl<'a, W: fmt::Write> CrcWriter<'a, W> {
+    pub fn new(out: &'a mut W, digest: Digest<'static, u16>) -> Self {
+        CrcWriter { out, digest }
+    }
+    pub fn finish(self) -> fmt::Result {
+        write!(self.out, "{:04X}", self.digest.finalize())
+    }
+}
+impl<W: fmt::Write> fmt::Write for CrcWriter<'_, W> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.digest.update(s.as_bytes());
+        self.out.write_str(s)
     }
 }
-*/
 
 impl EMVQR {
     pub fn set_payload_format_indicator(&mut self, data: Data) -> Result<(), EMVOError> {
-        if data.is_empty() {
-            return Err(EMVOError::new("Data is empty"));
+        if data.len() != 2 {
+            return Err(EMVOError::new("Payload Format Indicator Length Invalid"));
         }
-        if data.eq("01") {
-            return Err(EMVOError::new("Data Invalid"));
-        }
-        let numeric = EMVNumeric::try_from(data).unwrap();
+        let numeric = EMVNumeric::try_from(data)?;
         let box_numeric = Box::new(numeric);
         let field = EMVQRFieldDataObject::new(ID_PAYLOAD_FORMAT_INDICATOR, box_numeric, 2);
         //self.add_payload_format_indicator(field);
@@ -250,192 +676,387 @@ impl EMVQR {
             EMVNumeric::try_from(String::from("12")).unwrap()
         }
     }
-    pub fn set_merchant_account_information(&mut self, tag_id: TagID, data: Box<dyn EMVOData>) {
-        if tag_id.is_between(
+    pub fn set_merchant_account_information(
+        &mut self,
+        tag_id: TagID,
+        data: Box<dyn EMVOData>,
+    ) -> Result<(), EMVOError> {
+        if !tag_id.is_between(
             ID_MERCHANT_ACCOUNT_INFORMATION_RANGE_START,
             ID_MERCHANT_ACCOUNT_INFORMATION_RANGE_END,
         ) {
-            if self.merchant_account_information.is_none() {
-                self.merchant_account_information = Some(HashMap::new());
-            }
-            self.merchant_account_information
-                .as_mut()
-                .unwrap()
-                .insert(tag_id, data);
+            let msg = format!("Tag Id {} Is Not Merchant Account Information", tag_id);
+            return Err(EMVOError::new(msg.as_str()));
+        }
+        if self.merchant_account_information.is_none() {
+            self.merchant_account_information = Some(BTreeMap::new());
         }
+        self.merchant_account_information
+            .as_mut()
+            .unwrap()
+            .insert(tag_id, EMVQRFieldDataObject::new(tag_id, data, 99));
+        Ok(())
     }
-    pub fn set_merchant_category_code(&mut self, merchant_category_code: Data) {
-        self.merchant_category_code = Some(EMVQRFieldDataObject::new(
+    pub fn set_merchant_category_code(
+        &mut self,
+        merchant_category_code: Data,
+    ) -> Result<(), EMVOError> {
+        self.merchant_category_code = Some(EMVQRFieldDataObject::checked(
             ID_MERCHANT_CATEGORY_CODE,
-            Box::new(EMVNumeric::try_from(merchant_category_code).unwrap()),
+            Box::new(EMVNumeric::try_from(merchant_category_code)?),
             4,
-        ));
+        )?);
+        Ok(())
     }
-    pub fn set_transaction_currency(&mut self, transaction_currency: CurrencyCode) {
-        self.transaction_currency = Some(EMVQRFieldDataObject::new(
+    pub fn set_transaction_currency(
+        &mut self,
+        transaction_currency: CurrencyCode,
+    ) -> Result<(), EMVOError> {
+        self.transaction_currency = Some(EMVQRFieldDataObject::checked(
             ID_TRANSACTION_CURRENCY,
-            Box::new(EMVNumeric::try_from(transaction_currency.to_string()).unwrap()),
+            Box::new(EMVNumeric::try_from(transaction_currency.to_string())?),
             3,
-        ));
+        )?);
+        Ok(())
     }
-    pub fn set_transaction_amount(&mut self, transaction_amount: Data) {
-        self.transaction_amount = Some(EMVQRFieldDataObject::new(
+    pub fn set_transaction_amount(&mut self, transaction_amount: Data) -> Result<(), EMVOError> {
+        validate_amount(ID_TRANSACTION_AMOUNT, &transaction_amount)?;
+        self.transaction_amount = Some(EMVQRFieldDataObject::checked(
             ID_TRANSACTION_AMOUNT,
-            Box::new(EMVAlphanumbericSpecial::try_from(transaction_amount).unwrap()),
-            14,
-        ));
+            Box::new(EMVAlphanumbericSpecial::try_from(transaction_amount)?),
+            13,
+        )?);
+        Ok(())
     }
-    pub fn set_tip_convenience_indicator(&mut self, tip: Data) {
-        self.transaction_amount = Some(EMVQRFieldDataObject::new(
+    pub fn set_tip_convenience_indicator(&mut self, tip: Data) -> Result<(), EMVOError> {
+        self.tip_or_convenience_indicator = Some(EMVQRFieldDataObject::checked(
             ID_TIP_OR_CONVENIENCE_INDICATOR,
-            Box::new(EMVNumeric::try_from(tip).unwrap()),
+            Box::new(EMVNumeric::try_from(tip)?),
             2,
-        ));
+        )?);
+        Ok(())
     }
-    pub fn set_value_of_convenience_fee_fixed(&mut self, value_of_convenience_fee_fixed: Data) {
-        self.value_of_convenience_fee_fixed = Some(EMVQRFieldDataObject::new(
+    pub fn set_value_of_convenience_fee_fixed(
+        &mut self,
+        value_of_convenience_fee_fixed: Data,
+    ) -> Result<(), EMVOError> {
+        validate_amount(
+            ID_VALUE_OF_CONVENIENCE_FEE_FIXED,
+            &value_of_convenience_fee_fixed,
+        )?;
+        self.value_of_convenience_fee_fixed = Some(EMVQRFieldDataObject::checked(
             ID_VALUE_OF_CONVENIENCE_FEE_FIXED,
-            Box::new(EMVAlphanumbericSpecial::try_from(value_of_convenience_fee_fixed).unwrap()),
+            Box::new(EMVAlphanumbericSpecial::try_from(
+                value_of_convenience_fee_fixed,
+            )?),
             13,
-        ));
+        )?);
+        Ok(())
     }
     pub fn set_value_of_convenience_fee_percentage(
         &mut self,
         value_of_convenience_fee_percentage: Data,
-    ) {
-        self.value_of_convenience_fee_percentage = Some(EMVQRFieldDataObject::new(
+    ) -> Result<(), EMVOError> {
+        validate_amount(
+            ID_VALUE_OF_CONVENIENCE_FEE_PERCENTAGE,
+            &value_of_convenience_fee_percentage,
+        )?;
+        self.value_of_convenience_fee_percentage = Some(EMVQRFieldDataObject::checked(
             ID_VALUE_OF_CONVENIENCE_FEE_PERCENTAGE,
-            Box::new(
-                EMVAlphanumbericSpecial::try_from(value_of_convenience_fee_percentage).unwrap(),
-            ),
+            Box::new(EMVAlphanumbericSpecial::try_from(
+                value_of_convenience_fee_percentage,
+            )?),
             5,
-        ));
+        )?);
+        Ok(())
     }
-    pub fn set_country_code(&mut self, country_code: CountryCode) {
-        self.country_code = Some(EMVQRFieldDataObject::new(
+    pub fn set_country_code(&mut self, country_code: CountryCode) -> Result<(), EMVOError> {
+        self.country_code = Some(EMVQRFieldDataObject::checked(
             ID_COUNTRY_CODE,
-            Box::new(EMVAlphanumbericSpecial::try_from(country_code.to_string()).unwrap()),
+            Box::new(EMVAlphanumbericSpecial::try_from(country_code.to_string())?),
             2,
-        ))
+        )?);
+        Ok(())
     }
-    pub fn set_merchant_name(&mut self, merchant_name: Data) {
-        self.merchant_name = Some(EMVQRFieldDataObject::new(
+    pub fn set_merchant_name(&mut self, merchant_name: Data) -> Result<(), EMVOError> {
+        self.merchant_name = Some(EMVQRFieldDataObject::checked(
             ID_MERCHANT_NAME,
-            Box::new(EMVAlphanumbericSpecial::try_from(merchant_name).unwrap()),
+            Box::new(EMVAlphanumbericSpecial::try_from(merchant_name)?),
             25,
-        ))
+        )?);
+        Ok(())
     }
-    pub fn set_merchant_city(&mut self, merchant_city: Data) {
-        self.merchant_city = Some(EMVQRFieldDataObject::new(
+    pub fn set_merchant_city(&mut self, merchant_city: Data) -> Result<(), EMVOError> {
+        self.merchant_city = Some(EMVQRFieldDataObject::checked(
             ID_MERCHANT_CITY,
-            Box::new(EMVAlphanumbericSpecial::try_from(merchant_city).unwrap()),
+            Box::new(EMVAlphanumbericSpecial::try_from(merchant_city)?),
             15,
-        ))
+        )?);
+        Ok(())
     }
-    pub fn set_postal_code(&mut self, postal_code: Data) {
-        self.postal_code = Some(EMVQRFieldDataObject::new(
+    pub fn set_postal_code(&mut self, postal_code: Data) -> Result<(), EMVOError> {
+        self.postal_code = Some(EMVQRFieldDataObject::checked(
             ID_POSTAL_CODE,
-            Box::new(EMVAlphanumbericSpecial::try_from(postal_code).unwrap()),
+            Box::new(EMVAlphanumbericSpecial::try_from(postal_code)?),
             10,
-        ))
+        )?);
+        Ok(())
     }
-    pub fn set_rfu(&mut self, tag_id: TagID, rfu_for_emvco: Data) {
-        if tag_id.is_between(ID_RFU_FOR_EMVCO_RANGE_START, ID_RFU_FOR_EMVCO_RANGE_END) {
-            if self.rfu_for_emvcos.is_none() {
-                let rfu_for_emvcos: Vec<EMVQRFieldDataObject> = Vec::new();
-                self.rfu_for_emvcos = Some(rfu_for_emvcos);
-            }
-            let item = EMVQRFieldDataObject::new(
-                tag_id,
-                Box::new(EMVString::try_from(rfu_for_emvco).unwrap()),
-                99,
-            );
-            self.rfu_for_emvcos.as_mut().unwrap().push(item);
+    pub fn set_additional_data_field_template(&mut self, template: AdditionalDataFieldTemplate) {
+        self.additional_data_field_template = Some(EMVQRFieldDataObject::new(
+            ID_ADDITIONAL_DATA_FIELD_TEMPLATE,
+            Box::new(template),
+            99,
+        ));
+    }
+    pub fn set_merchant_information_language_template(
+        &mut self,
+        template: MerchantInformationLanguageTemplate,
+    ) {
+        self.merchant_information_language_template = Some(EMVQRFieldDataObject::new(
+            ID_MERCHANT_INFORMATION_LANGUAGE_TEMPLATE,
+            Box::new(template),
+            99,
+        ));
+    }
+    /// Adds or replaces the unreserved template with the template's ID.
+    pub fn set_unreserved_template(&mut self, template: UnreservedTemplate) {
+        let tag_id = template.tag_id();
+        self.other_data_objects.insert(
+            tag_id,
+            EMVQRFieldDataObject::new(tag_id, Box::new(template), 99),
+        );
+    }
+    /// Adds or replaces the RFU data object (IDs 65-79) with the given ID.
+    pub fn set_rfu(&mut self, tag_id: TagID, rfu_for_emvco: Data) -> Result<(), EMVOError> {
+        if !tag_id.is_between(ID_RFU_FOR_EMVCO_RANGE_START, ID_RFU_FOR_EMVCO_RANGE_END) {
+            let msg = format!("Tag Id {} Is Not RFU for EMVCo", tag_id);
+            return Err(EMVOError::new(msg.as_str()));
         }
+        let item =
+            EMVQRFieldDataObject::new(tag_id, Box::new(EMVString::try_from(rfu_for_emvco)?), 99);
+        self.other_data_objects.insert(tag_id, item);
+        Ok(())
     }
-    pub fn generate_pay_load(&mut self) -> Result<String, EMVOError> {
-        let mut builder = EMVQFieldDataObjectListBuilder::new();
-        let mut merchant_list: Vec<EMVQRFieldDataObject> = vec![];
-        if self.payload_format_indicator.is_some() {
-            builder.add_field_object(self.payload_format_indicator.as_ref().unwrap());
-        }
-        if self.point_of_initiation_method.is_some() {
-            builder.add_field_object(self.point_of_initiation_method.as_ref().unwrap());
-        }
-        if self.merchant_account_information.is_some() {
-            for (k, v) in self.merchant_account_information.as_ref().unwrap() {
-                //let item = EMVQRFieldDataObject::new(k,v,99);
-                let data = v.value();
-                merchant_list.push(EMVQRFieldDataObject::new(
-                    k,
-                    Box::new(EMVAlphanumbericSpecial::try_from(data).unwrap()),
-                    99,
-                ));
+    /// Sets a top level data object by its ID, e.g. when building from parsed
+    /// or JSON input. IDs without a slot of their own are passed through.
+    pub fn set_field_data_object(&mut self, field: EMVQRFieldDataObject) -> Result<(), EMVOError> {
+        let slot = match field.tag_id() {
+            ID_PAYLOAD_FORMAT_INDICATOR => &mut self.payload_format_indicator,
+            ID_POINT_OF_INITIATION_METHOD => &mut self.point_of_initiation_method,
+            tag_id
+                if tag_id.is_between(
+                    ID_MERCHANT_ACCOUNT_INFORMATION_RANGE_START,
+                    ID_MERCHANT_ACCOUNT_INFORMATION_RANGE_END,
+                ) =>
+            {
+                self.merchant_account_information
+                    .get_or_insert_with(BTreeMap::new)
+                    .insert(tag_id, field);
+                return Ok(());
             }
-            for (index, _) in merchant_list.iter().enumerate() {
-                builder.add_field_object(merchant_list.get(index).unwrap());
+            ID_MERCHANT_CATEGORY_CODE => &mut self.merchant_category_code,
+            ID_TRANSACTION_CURRENCY => &mut self.transaction_currency,
+            ID_TRANSACTION_AMOUNT => &mut self.transaction_amount,
+            ID_TIP_OR_CONVENIENCE_INDICATOR => &mut self.tip_or_convenience_indicator,
+            ID_VALUE_OF_CONVENIENCE_FEE_FIXED => &mut self.value_of_convenience_fee_fixed,
+            ID_VALUE_OF_CONVENIENCE_FEE_PERCENTAGE => &mut self.value_of_convenience_fee_percentage,
+            ID_COUNTRY_CODE => &mut self.country_code,
+            ID_MERCHANT_NAME => &mut self.merchant_name,
+            ID_MERCHANT_CITY => &mut self.merchant_city,
+            ID_POSTAL_CODE => &mut self.postal_code,
+            ID_ADDITIONAL_DATA_FIELD_TEMPLATE => &mut self.additional_data_field_template,
+            ID_CRC => return Err(EMVOError::new("CRC is computed when encoding")),
+            ID_MERCHANT_INFORMATION_LANGUAGE_TEMPLATE => {
+                &mut self.merchant_information_language_template
             }
+            tag_id => {
+                self.other_data_objects.insert(tag_id, field);
+                return Ok(());
+            }
+        };
+        *slot = Some(field);
+        Ok(())
+    }
+    pub fn generate_pay_load(&mut self) -> Result<String, EMVOError> {
+        let pay_load = self.to_pay_load()?;
+        let (_, crc) = pay_load.split_at(pay_load.len() - 4);
+        self.crc = Some(EMVQRFieldDataObject::new(
+            ID_CRC,
+            Box::new(EMVAlphanumbericSpecial::try_from(crc.to_string())?),
+            4,
+        ));
+        Ok(pay_load)
+    }
+    /// Top level data objects in payload order, without the CRC.
+    pub fn data_objects(&self) -> impl Iterator<Item = &EMVQRFieldDataObject> {
+        let merchant_account_information = self
+            .merchant_account_information
+            .iter()
+            .flat_map(|accounts| accounts.values());
+        IntoIterator::into_iter([
+            &self.payload_format_indicator,
+            &self.point_of_initiation_method,
+        ])
+        .flatten()
+        .chain(merchant_account_information)
+        .chain(
+            IntoIterator::into_iter([
+                &self.merchant_category_code,
+                &self.transaction_currency,
+                &self.transaction_amount,
+                &self.tip_or_convenience_indicator,
+                &self.value_of_convenience_fee_fixed,
+                &self.value_of_convenience_fee_percentage,
+                &self.country_code,
+                &self.merchant_name,
+                &self.merchant_city,
+                &self.postal_code,
+                &self.additional_data_field_template,
+                &self.merchant_information_language_template,
+            ])
+            .flatten(),
+        )
+        .chain(self.other_data_objects.values())
+    }
+    /// Length in characters of the payload, CRC included.
+    pub fn encoded_len(&self) -> Result<usize, EMVOError> {
+        let mut length = 8;
+        for data_object in self.data_objects() {
+            length += data_object.encoded_len()?;
         }
-        if self.merchant_category_code.is_some() {
-            builder.add_field_object(self.merchant_category_code.as_ref().unwrap());
-        }
-        if self.transaction_currency.is_some() {
-            builder.add_field_object(self.transaction_currency.as_ref().unwrap());
-        }
-        if self.transaction_amount.is_some() {
-            builder.add_field_object(self.transaction_amount.as_ref().unwrap());
-        }
-        if self.tip_or_convenience_indicator.is_some() {
-            builder.add_field_object(self.tip_or_convenience_indicator.as_ref().unwrap());
-        }
-        if self.value_of_convenience_fee_fixed.is_some() {
-            builder.add_field_object(self.value_of_convenience_fee_fixed.as_ref().unwrap());
-        }
-        if self.value_of_convenience_fee_percentage.is_some() {
-            builder.add_field_object(self.value_of_convenience_fee_percentage.as_ref().unwrap());
-        }
-        if self.country_code.is_some() {
-            builder.add_field_object(self.country_code.as_ref().unwrap());
-        }
-        if self.merchant_name.is_some() {
-            builder.add_field_object(self.merchant_name.as_ref().unwrap());
-        }
-        if self.merchant_city.is_some() {
-            builder.add_field_object(self.merchant_city.as_ref().unwrap());
-        }
-        if self.postal_code.is_some() {
-            builder.add_field_object(self.postal_code.as_ref().unwrap());
+        Ok(length)
+    }
+    /// Checks every data object, then streams the payload and its CRC into
+    /// `out`. Nothing is written when a data object is invalid.
+    pub fn encode_into<W: fmt::Write>(&self, out: &mut W) -> Result<(), EMVOError> {
+        self.encoded_len()?;
+        self.write_into(out)?;
+        Ok(())
+    }
+    fn write_into<W: fmt::Write>(&self, out: &mut W) -> fmt::Result {
+        let mut writer = CrcWriter::new(out, crc16_digest());
+        for data_object in self.data_objects() {
+            data_object.write_into(&mut writer)?;
         }
-        let result = builder.to_string();
-        let mut crc_value = String::from(result.unwrap().clone());
-        crc_value.push_str(ID_CRC);
-        crc_value.push_str("04");
+        write!(writer, "{}04", ID_CRC)?;
+        writer.finish()
+    }
+    pub fn to_pay_load(&self) -> Result<String, EMVOError> {
+        let mut pay_load = String::with_capacity(self.encoded_len()?);
+        self.write_into(&mut pay_load)?;
+        Ok(pay_load)
+    }
+}
 
-        // use custom algorithm
-        const CUSTOM_ALG: Algorithm<u16> = Algorithm {
-            width: 16,
-            poly: 0x1021,
-            init: 0xffff,
-            refin: false,
-            refout: false,
-            xorout: 0x0000,
-            check: 0x29B1,
-            residue: 0x0000,
-        };
+pub fn encode_data_object(tag_id: TagID, value: &str) -> Result<String, EMVOError> {
+    let length = value.chars().count();
+    if length > 99 {
+        let msg = format!("Tag Id {} Data Length Invalid", tag_id);
+        return Err(EMVOError::new(msg.as_str()));
+    }
+    Ok(format!("{}{:02}{}", tag_id, length, value))
+}
 
-        let crc = Crc::<u16>::new(&CUSTOM_ALG);
-        let mut digest = crc.digest();
-        digest.update(crc_value.into_bytes().as_slice());
-        let crc_value_string = format!("{:04x}", digest.finalize());
+/// Replaces the value of `tag_id`, or inserts it before the first higher ID
+/// so the other data objects keep their order.
+pub fn upsert_data_object(objects: &mut Vec<(TagID, Data)>, tag_id: TagID, value: Data) {
+    if let Some(object) = objects.iter_mut().find(|(id, _)| *id == tag_id) {
+        object.1 = value;
+    } else {
+        let index = objects
+            .iter()
+            .position(|(id, _)| *id > tag_id)
+            .unwrap_or(objects.len());
+        objects.insert(index, (tag_id, value));
+    }
+}
 
-        info!("crc value {}", crc_value_string);
+/// Encodes top level data objects, as returned by [`parse_pay_load`], and
+/// appends the CRC.
+pub fn encode_pay_load(objects: &[(TagID, Data)]) -> Result<String, EMVOError> {
+    let mut result = String::new();
+    for (id, value) in objects {
+        result.push_str(&encode_data_object(*id, value)?);
+    }
+    result.push_str(&ID_CRC.to_string());
+    result.push_str("04");
+    let crc = crc16(&result);
+    result.push_str(&crc);
+    Ok(result)
+}
 
-        self.crc = Some(EMVQRFieldDataObject::new(
-            ID_CRC,
-            Box::new(EMVAlphanumbericSpecial::try_from(crc_value_string.to_uppercase()).unwrap()),
-            4,
-        ));
-        builder.add_field_object(self.crc.as_ref().unwrap());
-        builder.to_string()
+/// Splits a payload into its top level data objects after checking that it
+/// ends with a matching CRC, which is not returned.
+pub fn parse_pay_load(pay_load: &str) -> Result<Vec<(TagID, Data)>, EMVOError> {
+    let pay_load = pay_load.trim();
+    let mut objects = parse_data_objects(pay_load)?;
+    match objects.pop() {
+        Some((id, crc)) if id == ID_CRC && crc.len() == 4 => {
+            let signed: String = pay_load
+                .chars()
+                .take(pay_load.chars().count() - crc.len())
+                .collect();
+            if !crc.eq_ignore_ascii_case(&crc16(&signed)) {
+                return Err(EMVOError::new("CRC mismatch"));
+            }
+        }
+        _ => return Err(EMVOError::new("CRC missing")),
+    }
+    Ok(objects)
+}
+
+/// Turns a static payload (e.g. a bank-issued PromptPay QR) into a dynamic
+/// one: tag 01 becomes "12", tag 54 is set to the amount and, with a
+/// reference, tag 62 gets it as Reference Label. Every other data object is
+/// copied unchanged and the CRC is recomputed.
+pub fn convert_to_dynamic(
+    pay_load: &str,
+    transaction_amount: f32,
+    reference: Option<&str>,
+) -> Result<String, EMVOError> {
+    let mut objects = parse_pay_load(pay_load)?;
+    if objects.first().map(|(id, _)| *id) != Some(ID_PAYLOAD_FORMAT_INDICATOR) {
+        return Err(EMVOError::new("Payload Format Indicator missing"));
+    }
+
+    if !transaction_amount.is_finite() || transaction_amount <= 0.0 {
+        return Err(EMVOError::new("Transaction Amount Invalid"));
     }
+    let amount = EMVAlphanumbericSpecial::try_from(transaction_amount.to_string())?.value();
+    if amount.len() > 13 {
+        return Err(EMVOError::new("Transaction Amount Invalid"));
+    }
+    upsert_data_object(
+        &mut objects,
+        ID_POINT_OF_INITIATION_METHOD,
+        EMVQR::default().get_point_types(DYNAMIC_POINT).value(),
+    );
+    upsert_data_object(&mut objects, ID_TRANSACTION_AMOUNT, amount);
+
+    if let Some(reference) = reference {
+        let reference = EMVAlphanumbericSpecial::try_from(reference.to_string())?.value();
+        if reference.is_empty() || reference.len() > 25 {
+            return Err(EMVOError::new("Reference Label Invalid"));
+        }
+        let mut additional_data = match objects
+            .iter()
+            .find(|(id, _)| *id == ID_ADDITIONAL_DATA_FIELD_TEMPLATE)
+        {
+            Some((_, template)) => parse_data_objects(template)?,
+            None => vec![],
+        };
+        upsert_data_object(
+            &mut additional_data,
+            ADDITIONAL_ID_REFERENCE_LABEL,
+            reference,
+        );
+        let mut template = String::new();
+        for (id, value) in &additional_data {
+            template.push_str(&encode_data_object(*id, value)?);
+        }
+        upsert_data_object(&mut objects, ID_ADDITIONAL_DATA_FIELD_TEMPLATE, template);
+    }
+
+    encode_pay_load(&objects)
 }
@@ -3,26 +3,36 @@ use std::convert::*;
 use std::error::Error;
 use std::fmt;
 use std::fmt::Formatter;
+use std::sync::OnceLock;
 
 use regex::Regex;
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
 
-pub const TYPE_NAME_NUMERIC: &'static str = "numeric";
-pub const TYPE_NAME_ALPHA_NUMERIC: &'static str = "alpha_numeric";
-pub const TYPE_NAME_STR: &'static str = "str";
+pub const TYPE_NAME_NUMERIC: &str = "numeric";
+pub const TYPE_NAME_ALPHA_NUMERIC: &str = "alpha_numeric";
+pub const TYPE_NAME_STR: &str = "str";
+pub const TYPE_NAME_TEMPLATE: &str = "template";
 
 pub type Data = String;
 
-pub type TagID = &'static str;
 pub type CurrencyCode = &'static str;
 pub type CountryCode = &'static str;
 pub type LanguageCode = &'static str;
 pub type PointType = usize;
 
+/// A data object ID: two decimal digits, "00" to "99".
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct TagID(u8);
+
 pub trait EMVOData {
     fn len(&self) -> usize;
     fn valid(&self) -> bool;
     fn value(&self) -> Data;
     fn type_name(&self) -> &str;
+    /// Writes the value without building it as a `String` first.
+    fn encode_into(&self, out: &mut dyn fmt::Write) -> fmt::Result {
+        out.write_str(&self.value())
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -31,15 +41,15 @@ pub struct EMVOError {
 }
 #[derive(Debug, Clone)]
 pub struct EMVNumeric {
-    d: Option<Data>,
+    d: Data,
 }
 #[derive(Debug, Clone)]
 pub struct EMVAlphanumbericSpecial {
-    d: Option<Data>,
+    d: Data,
 }
 #[derive(Debug, Clone)]
 pub struct EMVString {
-    d: Option<Data>,
+    d: Data,
 }
 
 //Error
@@ -60,29 +70,48 @@ impl Error for EMVOError {
         &self.details
     }
 }
-////
+impl From<fmt::Error> for EMVOError {
+    fn from(_: fmt::Error) -> Self {
+        EMVOError::new("Write Error")
+    }
+}
+
+fn numeric_regex() -> &'static Regex {
+    static REGEX: OnceLock<Regex> = OnceLock::new();
+    REGEX.get_or_init(|| Regex::new(r"^[0-9]*$").unwrap())
+}
+fn alphanumeric_special_regex() -> &'static Regex {
+    static REGEX: OnceLock<Regex> = OnceLock::new();
+    REGEX.get_or_init(|| Regex::new(r"^[0-9a-zA-Z\s.!?\\-]*$").unwrap())
+}
+/// Whether `data` only holds characters allowed in alphanumeric special
+/// data objects.
+pub fn is_alphanumeric_special(data: &str) -> bool {
+    alphanumeric_special_regex().is_match(data)
+}
+//
 impl EMVOData for EMVNumeric {
     fn len(&self) -> usize {
-        self.d.clone().unwrap().len()
+        self.d.len()
     }
     fn valid(&self) -> bool {
-        let data = self.value();
-        let re = Regex::new(r"(^[0-9]*$)").unwrap();
-        let caps = re.captures_iter(data.as_str());
-        caps.count() > 0
+        numeric_regex().is_match(&self.d)
     }
     fn value(&self) -> Data {
-        self.d.clone().unwrap()
+        self.d.clone()
     }
 
     fn type_name(&self) -> &str {
         TYPE_NAME_NUMERIC
     }
+    fn encode_into(&self, out: &mut dyn fmt::Write) -> fmt::Result {
+        out.write_str(&self.d)
+    }
 }
 impl TryFrom<Data> for EMVNumeric {
     type Error = EMVOError;
     fn try_from(value: Data) -> Result<Self, Self::Error> {
-        let numeric = EMVNumeric { d: Some(value) };
+        let numeric = EMVNumeric { d: value };
         if numeric.valid() {
             Ok(numeric)
         } else {
@@ -93,25 +122,25 @@ impl TryFrom<Data> for EMVNumeric {
 /////
 impl EMVOData for EMVAlphanumbericSpecial {
     fn len(&self) -> usize {
-        self.d.clone().unwrap().len()
+        self.d.len()
     }
     fn valid(&self) -> bool {
-        let data = self.value();
-        let re = Regex::new(r"(^[0-9a-zA-Z\s.!?\\-]*$)").unwrap();
-        let caps = re.captures_iter(data.as_str());
-        caps.count() > 0
+        is_alphanumeric_special(&self.d)
     }
     fn value(&self) -> Data {
-        self.d.clone().unwrap()
+        self.d.clone()
     }
     fn type_name(&self) -> &str {
         TYPE_NAME_ALPHA_NUMERIC
     }
+    fn encode_into(&self, out: &mut dyn fmt::Write) -> fmt::Result {
+        out.write_str(&self.d)
+    }
 }
 impl TryFrom<Data> for EMVAlphanumbericSpecial {
     type Error = EMVOError;
     fn try_from(value: Data) -> Result<Self, Self::Error> {
-        let alpha_numeric_special = EMVAlphanumbericSpecial { d: Some(value) };
+        let alpha_numeric_special = EMVAlphanumbericSpecial { d: value };
         if alpha_numeric_special.valid() {
             Ok(alpha_numeric_special)
         } else {
@@ -119,26 +148,29 @@ impl TryFrom<Data> for EMVAlphanumbericSpecial {
         }
     }
 }
-////
+//
 impl EMVOData for EMVString {
     fn len(&self) -> usize {
-        self.d.clone().unwrap().chars().count()
+        self.d.chars().count()
     }
     fn valid(&self) -> bool {
-        std::str::from_utf8(self.value().as_bytes()).is_ok()
+        std::str::from_utf8(self.d.as_bytes()).is_ok()
     }
     fn value(&self) -> Data {
-        self.d.clone().unwrap()
+        self.d.clone()
     }
     fn type_name(&self) -> &str {
         TYPE_NAME_STR
     }
+    fn encode_into(&self, out: &mut dyn fmt::Write) -> fmt::Result {
+        out.write_str(&self.d)
+    }
 }
 impl TryFrom<Data> for EMVString {
     type Error = EMVOError;
 
     fn try_from(value: Data) -> Result<Self, Self::Error> {
-        let emv_str = EMVString { d: Some(value) };
+        let emv_str = EMVString { d: value };
         if emv_str.valid() {
             Ok(emv_str)
         } else {
@@ -147,14 +179,90 @@ impl TryFrom<Data> for EMVString {
     }
 }
 
-pub trait TagIDData {
-    fn is_between(&self, start: TagID, end: TagID) -> bool;
+/// Splits an "ID, length, value" string into its data objects. Lengths count
+/// characters, as they do when generating.
+pub fn parse_data_objects(data: &str) -> Result<Vec<(TagID, Data)>, EMVOError> {
+    let chars: Vec<char> = data.chars().collect();
+    let mut objects = vec![];
+    let mut index = 0;
+    while index < chars.len() {
+        if index + 4 > chars.len() {
+            return Err(EMVOError::new("Truncated data object"));
+        }
+        let id: String = chars[index..index + 2].iter().collect();
+        let id = TagID::try_from(id.as_str())?;
+        // `usize::parse` would also take a sign, as in "+5"
+        let length = &chars[index + 2..index + 4];
+        if !length.iter().all(|c| c.is_ascii_digit()) {
+            return Err(EMVOError::new("Data object length is not numeric"));
+        }
+        let length: usize = length.iter().collect::<String>().parse().unwrap();
+        let start = index + 4;
+        if start + length > chars.len() {
+            return Err(EMVOError::new("Data object longer than payload"));
+        }
+        objects.push((id, chars[start..start + length].iter().collect()));
+        index = start + length;
+    }
+    Ok(objects)
+}
+
+impl TagID {
+    /// For constants; IDs from input go through `TryFrom`/`parse`.
+    pub const fn new(id: u8) -> TagID {
+        assert!(id <= 99, "Tag Id must be between 00 and 99");
+        TagID(id)
+    }
+    pub fn value(self) -> u8 {
+        self.0
+    }
+    pub fn is_between(self, start: TagID, end: TagID) -> bool {
+        (start..=end).contains(&self)
+    }
+}
+impl TryFrom<u8> for TagID {
+    type Error = EMVOError;
+
+    fn try_from(id: u8) -> Result<Self, Self::Error> {
+        if id > 99 {
+            let msg = format!("Tag Id {} Invalid", id);
+            return Err(EMVOError::new(msg.as_str()));
+        }
+        Ok(TagID(id))
+    }
+}
+impl TryFrom<&str> for TagID {
+    type Error = EMVOError;
+
+    fn try_from(id: &str) -> Result<Self, Self::Error> {
+        if id.len() != 2 || !id.chars().all(|c| c.is_ascii_digit()) {
+            let msg = format!("Tag Id {} Invalid", id);
+            return Err(EMVOError::new(msg.as_str()));
+        }
+        Ok(TagID(id.parse().unwrap()))
+    }
+}
+impl std::str::FromStr for TagID {
+    type Err = EMVOError;
+
+    fn from_str(id: &str) -> Result<Self, Self::Err> {
+        TagID::try_from(id)
+    }
+}
+impl fmt::Display for TagID {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{:02}", self.0)
+    }
+}
+/// Serialized as the two-digit string, so tag IDs can be JSON object keys.
+impl Serialize for TagID {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
 }
-impl TagIDData for TagID {
-    fn is_between(&self, start: TagID, end: TagID) -> bool {
-        let current_tag: usize = self.to_string().parse().unwrap();
-        let start_tag: usize = start.to_string().parse().unwrap();
-        let end_tag: usize = end.to_string().parse().unwrap();
-        return (current_tag >= start_tag) && (current_tag <= end_tag);
+impl<'de> Deserialize<'de> for TagID {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let id = String::deserialize(deserializer)?;
+        TagID::try_from(id.as_str()).map_err(de::Error::custom)
     }
 }
@@ -1,20 +1,44 @@
 #![allow(dead_code)]
+#![allow(clippy::upper_case_acronyms)]
 
 use actix_files::NamedFile;
-use actix_web::{App, Error, HttpServer, web};
+use actix_web::{web, App, Error, HttpServer};
 use actix_web_opentelemetry::RequestTracing;
 use log::Level;
 use opentelemetry::{global, KeyValue};
 use opentelemetry_appender_log::OpenTelemetryLogBridge;
 use opentelemetry_sdk::logs::{BatchLogProcessor, LoggerProvider};
-use opentelemetry_sdk::Resource;
+use opentelemetry_sdk::metrics::{PeriodicReader, SdkMeterProvider};
+use opentelemetry_sdk::propagation::TraceContextPropagator;
 use opentelemetry_sdk::runtime::Tokio;
+use opentelemetry_sdk::Resource;
 use opentelemetry_semantic_conventions as semcov;
 
+mod admin_auth;
 mod apis;
+mod bank_notifications;
+mod card_networks;
+mod duitnow;
+mod emvo_builder;
+mod emvo_compiled;
+mod emvo_cpm;
+mod emvo_document;
 mod emvo_qrcode;
 mod emvo_types;
+mod merchant_registry;
+mod paynow;
 mod prompt_pay;
+mod prompt_pay_ota;
+mod qr_signature;
+mod qrcode_batch;
+mod qrcode_cache;
+mod qrcode_pdf;
+mod qris;
+mod reconciliation;
+mod slip_verification;
+mod storage;
+mod transactions;
+mod webhooks;
 
 //use opentelemetry::sdk::metrics::{controllers, processors, selectors};
 
@@ -22,10 +46,65 @@ pub async fn index() -> Result<NamedFile, Error> {
     Ok(NamedFile::open("./static/index.html")?)
 }
 
+/// `prompt_pay_service verify-signature <payload>` lets a field auditor check
+/// a sticker with the public keys in `QR_VERIFY_KEYS`. Prints the verification
+/// as JSON and exits with 0 only when the signature is valid.
+fn verify_signature_cli(pay_load: Option<&String>) -> i32 {
+    let pay_load = match pay_load {
+        Some(pay_load) => pay_load,
+        None => {
+            eprintln!("usage: prompt_pay_service verify-signature <payload>");
+            return 2;
+        }
+    };
+    let verification =
+        qr_signature::QRVerifyingKeys::from_env().and_then(|keys| keys.verify_pay_load(pay_load));
+    match verification {
+        Ok(verification) => {
+            println!(
+                "{}",
+                serde_json::to_string(&verification).expect("verification serializes")
+            );
+            if verification.status == qr_signature::SignatureStatus::Valid {
+                0
+            } else {
+                1
+            }
+        }
+        Err(e) => {
+            eprintln!("{}", e);
+            2
+        }
+    }
+}
+
+/// `prompt_pay_service public-keys` prints the `QR_VERIFY_KEYS` value for the
+/// configured `QR_SIGNING_KEYS`, to hand to auditors.
+fn public_keys_cli() -> i32 {
+    match qr_signature::QRSigningKeys::from_env() {
+        Ok(keys) => {
+            println!("{}", keys.verifying_keys().to_env_string());
+            0
+        }
+        Err(e) => {
+            eprintln!("{}", e);
+            2
+        }
+    }
+}
+
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
+    let args: Vec<String> = std::env::args().collect();
+    match args.get(1).map(String::as_str) {
+        Some("verify-signature") => std::process::exit(verify_signature_cli(args.get(2))),
+        Some("public-keys") => std::process::exit(public_keys_cli()),
+        _ => {}
+    }
+
     let client = reqwest::Client::new();
 
+    global::set_text_map_propagator(TraceContextPropagator::new());
     let _trace = opentelemetry_application_insights::new_pipeline_from_env()
         .expect("env var APPLICATIONINSIGHTS_CONNECTION_STRING is valid connection string")
         .with_client(client.clone())
@@ -34,10 +113,20 @@ async fn main() -> std::io::Result<()> {
 
     let connection_string = std::env::var("APPLICATIONINSIGHTS_CONNECTION_STRING").unwrap();
     let exporter = opentelemetry_application_insights::Exporter::new_from_connection_string(
-        connection_string,
-        client,
+        connection_string.clone(),
+        client.clone(),
     )
     .expect("connection string is valid");
+    let metrics_exporter =
+        opentelemetry_application_insights::Exporter::new_from_connection_string(
+            connection_string,
+            client,
+        )
+        .expect("connection string is valid");
+    let meter_provider = SdkMeterProvider::builder()
+        .with_reader(PeriodicReader::builder(metrics_exporter, Tokio).build())
+        .build();
+    global::set_meter_provider(meter_provider.clone());
     let logger_provider = LoggerProvider::builder()
         .with_log_processor(BatchLogProcessor::builder(exporter, Tokio).build())
         .with_config(
@@ -52,20 +141,98 @@ async fn main() -> std::io::Result<()> {
     log::set_boxed_logger(Box::new(otel_log_appender)).expect("Could not set logger");
     log::set_max_level(Level::Info.to_level_filter());
 
-    HttpServer::new(move || {
+    let qrcode_cache = web::Data::new(qrcode_cache::QRCodeCache::from_env());
+    let storage = web::Data::new(storage::Storage::from_env().expect("Could not open database"));
+    let admin_auth = web::Data::new(admin_auth::AdminAuth::from_env());
+    let bank_webhook_auth = web::Data::new(bank_notifications::BankWebhookAuth::from_env());
+    let ota_policy = web::Data::new(prompt_pay_ota::OtaPolicy::from_env());
+    let qr_signing_keys = web::Data::new(
+        qr_signature::QRSigningKeys::from_env().expect("QR_SIGNING_KEYS is invalid"),
+    );
+    webhooks::WebhookDispatcher::from_env(webhooks::webhook_client()).spawn(storage.clone());
+    transactions::ExpiryScheduler::from_env().spawn(storage.clone());
+
+    // Banks that authenticate with client certificates get their own listener
+    let bank_mtls_server = match bank_notifications::mtls_server_config_from_env()? {
+        Some(tls_config) => {
+            let storage = storage.clone();
+            let bind = std::env::var("BANK_WEBHOOK_MTLS_BIND")
+                .unwrap_or_else(|_| "0.0.0.0:8443".to_string());
+            Some(
+                HttpServer::new(move || {
+                    App::new()
+                        .app_data(storage.clone())
+                        .app_data(web::Data::new(
+                            bank_notifications::BankWebhookAuth::MutualTls,
+                        ))
+                        .wrap(actix_web::middleware::Logger::default())
+                        .wrap(RequestTracing::new())
+                        .service(apis::bank_notification)
+                })
+                .bind_rustls_0_23(bind, tls_config)?
+                .run(),
+            )
+        }
+        None => None,
+    };
+
+    let server = HttpServer::new(move || {
         App::new()
+            .app_data(qrcode_cache.clone())
+            .app_data(storage.clone())
+            .app_data(admin_auth.clone())
+            .app_data(bank_webhook_auth.clone())
+            .app_data(qr_signing_keys.clone())
+            .app_data(ota_policy.clone())
             .wrap(actix_web::middleware::Compress::default())
             .wrap(actix_web::middleware::Logger::default())
             .wrap(RequestTracing::new())
             .service(apis::qr_code_tag30)
+            .service(apis::qr_code_tag30_get)
+            .service(apis::qr_code_tag30_batch)
+            .service(apis::qr_code_to_dynamic)
+            .service(apis::emvco_encode)
+            .service(apis::emvco_decode)
+            .service(apis::emvco_verify_signature)
+            .service(apis::qris_validate)
+            .service(apis::create_merchant)
+            .service(apis::list_merchants)
+            .service(apis::get_merchant)
+            .service(apis::update_merchant)
+            .service(apis::delete_merchant)
+            .service(apis::merchant_qr_code)
+            .service(apis::create_customer)
+            .service(apis::customer_qr_code)
+            .service(apis::verify_customer_ota)
+            .service(apis::set_merchant_webhook)
+            .service(apis::get_merchant_webhook)
+            .service(apis::delete_merchant_webhook)
+            .service(apis::get_transaction)
+            .service(apis::transaction_events)
+            .service(apis::cancel_transaction)
+            .service(apis::bank_notification)
+            .service(apis::list_notification_exceptions)
+            .service(apis::reconcile_statement)
+            .service(apis::verify_slip)
+            .service(apis::list_dead_letters)
+            .service(apis::retry_dead_letter)
             .route("/", web::get().to(index))
     })
     .workers(10)
     .bind("0.0.0.0:8080")?
-    .run()
-    .await?;
+    .run();
+
+    match bank_mtls_server {
+        Some(bank_mtls_server) => {
+            futures_util::future::try_join(server, bank_mtls_server).await?;
+        }
+        None => server.await?,
+    }
 
     global::shutdown_tracer_provider();
+    meter_provider
+        .shutdown()
+        .expect("Failed to shutdown meter provider");
     logger_provider
         .shutdown()
         .expect("Failed to shutdown logger provider");
@@ -0,0 +1,451 @@
+#![allow(dead_code)]
+
+use std::collections::BTreeMap;
+
+use rusqlite::{params, OptionalExtension};
+use serde::{Deserialize, Serialize};
+
+use crate::apis::PromptPayServiceError;
+use crate::card_networks::{card_merchant_accounts, CardMerchantAccount};
+use crate::emvo_qrcode::*;
+use crate::emvo_types::*;
+use crate::prompt_pay::{
+    MerchantPromptPayCreditTransfer, ProxyType, BAHT, CUSTOMER_PRESENTED,
+    ID_PROMPT_PAY_CREDIT_TRANSFER, THAI,
+};
+use crate::storage::{unix_timestamp, Storage};
+use crate::transactions::validate_transaction_ttl;
+
+pub const MERCHANT_SCHEMA: &str = "CREATE TABLE IF NOT EXISTS merchants (
+    id TEXT PRIMARY KEY NOT NULL,
+    profile TEXT NOT NULL,
+    created_at INTEGER NOT NULL,
+    updated_at INTEGER NOT NULL
+)";
+
+pub const MAX_LENGTH_MERCHANT_ID: usize = 64;
+
+/// Merchant Information—Language Template (ID "64").
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LanguageTemplate {
+    pub language_preference: String,
+    pub merchant_name: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub merchant_city: Option<String>,
+}
+
+/// An Unreserved Template (IDs "80"-"99"), e.g. a loyalty partner's, with
+/// its context specific data keyed by ID "01"-"99".
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct UnreservedTemplateData {
+    pub tag_id: TagID,
+    pub globally_unique_identifier: String,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub context_specific_data: BTreeMap<TagID, String>,
+}
+
+/// Labels of the Additional Data Field Template (ID "62") that are fixed per
+/// merchant. The reference label is supplied per QR code.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct AdditionalDataLabels {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bill_number: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mobile_number: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub store_label: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub loyalty_number: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub customer_label: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub terminal_label: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub purpose_of_transaction: Option<String>,
+}
+
+/// Everything needed to build a merchant's PromptPay QR except the amount
+/// and the per-sale reference.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct MerchantProfile {
+    pub merchant_name: String,
+    pub merchant_city: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub postal_code: Option<String>,
+    pub merchant_category_code: String,
+    pub proxy_type: ProxyType,
+    pub proxy_value: String,
+    /// Card network merchant IDs accepted alongside PromptPay.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub merchant_accounts: Vec<CardMerchantAccount>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub language_template: Option<LanguageTemplate>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub additional_data: Option<AdditionalDataLabels>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub unreserved_templates: Vec<UnreservedTemplateData>,
+    /// How long this merchant's dynamic QRs stay payable, overriding
+    /// `TRANSACTION_TTL_SECONDS`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub transaction_ttl_seconds: Option<i64>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Merchant {
+    pub id: String,
+    #[serde(flatten)]
+    pub profile: MerchantProfile,
+    pub created_at: i64,
+    pub updated_at: i64,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CreateMerchantRq {
+    pub id: String,
+    #[serde(flatten)]
+    pub profile: MerchantProfile,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct MerchantQrCodeRq {
+    #[serde(default)]
+    pub transaction_amount: Option<f32>,
+    #[serde(default)]
+    pub reference: Option<String>,
+    #[serde(default)]
+    pub ttl_seconds: Option<i64>,
+}
+
+/// Merchant IDs end up in URLs and file names, so keep them to a safe charset.
+pub fn validate_merchant_id(merchant_id: &str) -> Result<(), String> {
+    if merchant_id.is_empty() {
+        return Err("merchant_id is empty".to_string());
+    }
+    if merchant_id.len() > MAX_LENGTH_MERCHANT_ID {
+        return Err(format!(
+            "merchant_id longer than {} characters",
+            MAX_LENGTH_MERCHANT_ID
+        ));
+    }
+    let valid = merchant_id
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+    if !valid {
+        return Err("merchant_id may only contain A-Z, a-z, 0-9, '-' and '_'".to_string());
+    }
+    Ok(())
+}
+
+impl MerchantProfile {
+    /// A profile is valid when it produces a static QR without an amount.
+    pub fn validate(&self) -> Result<(), EMVOError> {
+        if let Some(ttl_seconds) = self.transaction_ttl_seconds {
+            validate_transaction_ttl(ttl_seconds).map_err(|e| EMVOError::new(&e))?;
+        }
+        self.generate_pay_load(None, None).map(|_| ())
+    }
+
+    /// Builds the payload; a transaction amount switches the QR to dynamic.
+    pub fn generate_pay_load(
+        &self,
+        transaction_amount: Option<f32>,
+        reference: Option<&str>,
+    ) -> Result<String, EMVOError> {
+        let mut merchant_prompt_pay = MerchantPromptPayCreditTransfer::default();
+        merchant_prompt_pay.set_promptpay_presented_type(CUSTOMER_PRESENTED)?;
+        merchant_prompt_pay.set_proxy(self.proxy_type, &self.proxy_value)?;
+
+        let mut builder = EMVQR::builder()
+            .merchant_account_information(
+                ID_PROMPT_PAY_CREDIT_TRANSFER,
+                Box::new(merchant_prompt_pay),
+            )
+            .merchant_category_code(self.merchant_category_code.clone())
+            .transaction_currency(BAHT)
+            .country_code(THAI)
+            .merchant_name(self.merchant_name.clone())
+            .merchant_city(self.merchant_city.clone());
+        for (tag_id, merchant_id) in card_merchant_accounts(&self.merchant_accounts)? {
+            builder = builder.merchant_account_information(tag_id, merchant_id);
+        }
+        match transaction_amount {
+            Some(amount) => {
+                if !amount.is_finite() || amount <= 0.0 {
+                    return Err(EMVOError::new("Transaction Amount Invalid"));
+                }
+                builder = builder
+                    .point_of_initiation_method(DYNAMIC_POINT)
+                    .transaction_amount(amount.to_string());
+            }
+            None => builder = builder.point_of_initiation_method(STATIC_POINT),
+        }
+        if let Some(postal_code) = &self.postal_code {
+            builder = builder.postal_code(postal_code.clone());
+        }
+
+        let additional_data = self.additional_data.clone().unwrap_or_default();
+        if additional_data != AdditionalDataLabels::default() || reference.is_some() {
+            builder =
+                builder.additional_data_field_template(additional_data.to_template(reference)?);
+        }
+        if let Some(language_template) = &self.language_template {
+            let mut template = MerchantInformationLanguageTemplate::default();
+            template.set_language_preference(language_template.language_preference.clone())?;
+            template.set_merchant_name(language_template.merchant_name.clone())?;
+            if let Some(merchant_city) = &language_template.merchant_city {
+                template.set_merchant_city(merchant_city.clone())?;
+            }
+            builder = builder.merchant_information_language_template(template);
+        }
+        for unreserved_template in &self.unreserved_templates {
+            builder = builder.unreserved_template(unreserved_template.to_template()?);
+        }
+        Ok(builder.build()?.into_pay_load())
+    }
+}
+
+impl UnreservedTemplateData {
+    fn to_template(&self) -> Result<UnreservedTemplate, EMVOError> {
+        let mut template =
+            UnreservedTemplate::new(self.tag_id, self.globally_unique_identifier.clone())?;
+        for (tag_id, data) in &self.context_specific_data {
+            template.set_context_specific_data(*tag_id, data.clone())?;
+        }
+        Ok(template)
+    }
+}
+
+impl AdditionalDataLabels {
+    fn to_template(
+        &self,
+        reference: Option<&str>,
+    ) -> Result<AdditionalDataFieldTemplate, EMVOError> {
+        let mut template = AdditionalDataFieldTemplate::default();
+        if let Some(bill_number) = &self.bill_number {
+            template.set_bill_number(bill_number.clone())?;
+        }
+        if let Some(mobile_number) = &self.mobile_number {
+            template.set_mobile_number(mobile_number.clone())?;
+        }
+        if let Some(store_label) = &self.store_label {
+            template.set_store_label(store_label.clone())?;
+        }
+        if let Some(loyalty_number) = &self.loyalty_number {
+            template.set_loyalty_number(loyalty_number.clone())?;
+        }
+        if let Some(reference) = reference {
+            template.set_reference_label(reference.to_string())?;
+        }
+        if let Some(customer_label) = &self.customer_label {
+            template.set_customer_label(customer_label.clone())?;
+        }
+        if let Some(terminal_label) = &self.terminal_label {
+            template.set_terminal_label(terminal_label.clone())?;
+        }
+        if let Some(purpose_of_transaction) = &self.purpose_of_transaction {
+            template.set_purpose_of_transaction(purpose_of_transaction.clone())?;
+        }
+        Ok(template)
+    }
+}
+
+fn merchant_from_row(row: &rusqlite::Row) -> rusqlite::Result<(String, String, i64, i64)> {
+    Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?))
+}
+
+fn to_merchant(
+    (id, profile, created_at, updated_at): (String, String, i64, i64),
+) -> Result<Merchant, PromptPayServiceError> {
+    let profile: MerchantProfile = serde_json::from_str(&profile).map_err(|e| {
+        log::error!("Merchant {} has an unreadable profile: {}", id, e);
+        PromptPayServiceError::InternalError
+    })?;
+    Ok(Merchant {
+        id,
+        profile,
+        created_at,
+        updated_at,
+    })
+}
+
+fn profile_json(profile: &MerchantProfile) -> Result<String, PromptPayServiceError> {
+    serde_json::to_string(profile).map_err(|_| PromptPayServiceError::InternalError)
+}
+
+impl Storage {
+    pub fn insert_merchant(
+        &self,
+        id: &str,
+        profile: &MerchantProfile,
+    ) -> Result<Merchant, PromptPayServiceError> {
+        let now = unix_timestamp();
+        let inserted = self.connection().execute(
+            "INSERT OR IGNORE INTO merchants (id, profile, created_at, updated_at) VALUES (?1, ?2, ?3, ?3)",
+            params![id, profile_json(profile)?, now],
+        )?;
+        if inserted == 0 {
+            return Err(PromptPayServiceError::Conflict);
+        }
+        Ok(Merchant {
+            id: id.to_string(),
+            profile: profile.clone(),
+            created_at: now,
+            updated_at: now,
+        })
+    }
+
+    pub fn update_merchant(
+        &self,
+        id: &str,
+        profile: &MerchantProfile,
+    ) -> Result<Merchant, PromptPayServiceError> {
+        let updated = self.connection().execute(
+            "UPDATE merchants SET profile = ?2, updated_at = ?3 WHERE id = ?1",
+            params![id, profile_json(profile)?, unix_timestamp()],
+        )?;
+        if updated == 0 {
+            return Err(PromptPayServiceError::NotFound);
+        }
+        self.get_merchant(id)
+    }
+
+    pub fn get_merchant(&self, id: &str) -> Result<Merchant, PromptPayServiceError> {
+        let row = self
+            .connection()
+            .query_row(
+                "SELECT id, profile, created_at, updated_at FROM merchants WHERE id = ?1",
+                params![id],
+                merchant_from_row,
+            )
+            .optional()?;
+        match row {
+            Some(row) => to_merchant(row),
+            None => Err(PromptPayServiceError::NotFound),
+        }
+    }
+
+    pub fn list_merchants(&self) -> Result<Vec<Merchant>, PromptPayServiceError> {
+        let conn = self.connection();
+        let mut statement =
+            conn.prepare("SELECT id, profile, created_at, updated_at FROM merchants ORDER BY id")?;
+        let rows = statement
+            .query_map([], merchant_from_row)?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        rows.into_iter().map(to_merchant).collect()
+    }
+
+    pub fn delete_merchant(&self, id: &str) -> Result<(), PromptPayServiceError> {
+        let deleted = self
+            .connection()
+            .execute("DELETE FROM merchants WHERE id = ?1", params![id])?;
+        if deleted == 0 {
+            return Err(PromptPayServiceError::NotFound);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn profile() -> MerchantProfile {
+        MerchantProfile {
+            merchant_name: "test".to_string(),
+            merchant_city: "Bangkok".to_string(),
+            postal_code: Some("10240".to_string()),
+            merchant_category_code: "5311".to_string(),
+            proxy_type: ProxyType::MobileNumber,
+            proxy_value: "0809729900".to_string(),
+            merchant_accounts: vec![],
+            language_template: None,
+            additional_data: None,
+            unreserved_templates: vec![],
+            transaction_ttl_seconds: None,
+        }
+    }
+
+    #[test]
+    fn test_merchant_profile_pay_load_matches_direct_api() {
+        let pay_load = profile().generate_pay_load(Some(50.0), None).unwrap();
+        // Same as the direct API apart from tag 01 (dynamic) and the CRC
+        assert!(pay_load.starts_with("000202010212"));
+        assert!(pay_load.contains("29370016A00000067701011401130000809729900"));
+        assert!(pay_load.contains("5402505802TH5904test6007Bangkok610510240"));
+    }
+
+    #[test]
+    fn test_merchant_profile_with_card_merchant_accounts() {
+        let mut profile = profile();
+        profile.merchant_accounts = serde_json::from_str(
+            r#"[{"network": "mastercard", "merchant_id": "5555555555554444"},
+                {"network": "visa", "merchant_id": "4111111111111111"}]"#,
+        )
+        .unwrap();
+        let pay_load = profile.generate_pay_load(None, None).unwrap();
+        assert!(pay_load.starts_with(
+            "0002020102110216411111111111111104165555555555554444\
+             29370016A00000067701011401130000809729900"
+        ));
+
+        profile.merchant_accounts[0].merchant_id = "5555555555554445".to_string();
+        assert!(profile.validate().is_err());
+    }
+
+    #[test]
+    fn test_merchant_profile_with_unreserved_template() {
+        let mut profile = profile();
+        profile.unreserved_templates = serde_json::from_str(
+            r#"[{"tag_id": "81", "globally_unique_identifier": "com.partner.loyalty",
+                 "context_specific_data": {"01": "MEMBER42"}}]"#,
+        )
+        .unwrap();
+        let pay_load = profile.generate_pay_load(None, None).unwrap();
+        assert!(pay_load.contains("81350019com.partner.loyalty0108MEMBER426304"));
+
+        profile.unreserved_templates[0].tag_id = TagID::new(79);
+        assert!(profile.validate().is_err());
+    }
+
+    #[test]
+    fn test_merchant_profile_with_reference_and_language_template() {
+        let mut profile = profile();
+        profile.additional_data = Some(AdditionalDataLabels {
+            terminal_label: Some("POS01".to_string()),
+            ..Default::default()
+        });
+        profile.language_template = Some(LanguageTemplate {
+            language_preference: "TH".to_string(),
+            merchant_name: "ร้านทดสอบ".to_string(),
+            merchant_city: None,
+        });
+        let pay_load = profile
+            .generate_pay_load(Some(10.0), Some("INV0001"))
+            .unwrap();
+        assert!(pay_load.contains("62200507INV00010705POS01"));
+        assert!(pay_load.contains("64190002TH0109ร้านทดสอบ6304"));
+    }
+
+    #[test]
+    fn test_merchant_crud() {
+        let storage = Storage::open_in_memory().unwrap();
+        let created = storage.insert_merchant("M001", &profile()).unwrap();
+        assert!(matches!(
+            storage.insert_merchant("M001", &profile()),
+            Err(PromptPayServiceError::Conflict)
+        ));
+        assert_eq!(storage.get_merchant("M001").unwrap(), created);
+
+        let mut updated_profile = profile();
+        updated_profile.merchant_name = "renamed".to_string();
+        let updated = storage.update_merchant("M001", &updated_profile).unwrap();
+        assert_eq!(updated.profile.merchant_name, "renamed");
+        assert_eq!(storage.list_merchants().unwrap().len(), 1);
+
+        storage.delete_merchant("M001").unwrap();
+        assert!(matches!(
+            storage.get_merchant("M001"),
+            Err(PromptPayServiceError::NotFound)
+        ));
+    }
+}
@@ -1,6 +1,9 @@
 #![allow(dead_code)]
 
 use std::convert::TryFrom;
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
 
 use crate::emvo_qrcode::*;
 use crate::emvo_types::*;
@@ -9,28 +12,31 @@ pub const BAHT: CurrencyCode = "764";
 pub const THAI: CountryCode = "TH";
 pub const LANG_TH: LanguageCode = "TH";
 
-pub const ID_PROMPT_PAY_CREDIT_TRANSFER_AID: TagID = "00";
-pub const ID_PROMPT_PAY_CREDIT_TRANSFER_MOBILE_NUMBER: TagID = "01";
-pub const ID_PROMPT_PAY_CREDIT_TRANSFER_NATIONAL_ID: TagID = "02";
-pub const ID_PROMPT_PAY_CREDIT_TRANSFER_EWALLET_ID: TagID = "03";
-pub const ID_PROMPT_PAY_CREDIT_TRANSFER_BANK_ACCOUNT: TagID = "04";
-pub const ID_PROMPT_PAY_CREDIT_TRANSFER_OTA: TagID = "05";
+pub const ID_PROMPT_PAY_CREDIT_TRANSFER: TagID = TagID::new(29);
+pub const ID_PROMPT_PAY_BILL_PAYMENT: TagID = TagID::new(30);
+
+pub const ID_PROMPT_PAY_CREDIT_TRANSFER_AID: TagID = TagID::new(0);
+pub const ID_PROMPT_PAY_CREDIT_TRANSFER_MOBILE_NUMBER: TagID = TagID::new(1);
+pub const ID_PROMPT_PAY_CREDIT_TRANSFER_NATIONAL_ID: TagID = TagID::new(2);
+pub const ID_PROMPT_PAY_CREDIT_TRANSFER_EWALLET_ID: TagID = TagID::new(3);
+pub const ID_PROMPT_PAY_CREDIT_TRANSFER_BANK_ACCOUNT: TagID = TagID::new(4);
+pub const ID_PROMPT_PAY_CREDIT_TRANSFER_OTA: TagID = TagID::new(5);
 
-pub const ID_PROMPT_PAY_BILL_PAYMENT_AID: TagID = "00";
-pub const ID_PROMPT_PAY_BILL_PAYMENT_BILLER_ID: TagID = "01";
-pub const ID_PROMPT_PAY_BILL_PAYMENT_REF1: TagID = "02";
-pub const ID_PROMPT_PAY_BILL_PAYMENT_REF2: TagID = "03";
+pub const ID_PROMPT_PAY_BILL_PAYMENT_AID: TagID = TagID::new(0);
+pub const ID_PROMPT_PAY_BILL_PAYMENT_BILLER_ID: TagID = TagID::new(1);
+pub const ID_PROMPT_PAY_BILL_PAYMENT_REF1: TagID = TagID::new(2);
+pub const ID_PROMPT_PAY_BILL_PAYMENT_REF2: TagID = TagID::new(3);
 
 pub type PromptPayPresentedType = usize;
 pub const MERCHANT_PRESENTED: PromptPayPresentedType = 0;
 pub const CUSTOMER_PRESENTED: PromptPayPresentedType = 1;
 
 const MAX_LENGTH_AID: usize = 16;
-const MAX_LENGTH_MOBILE_NO: usize = 13;
+pub const MAX_LENGTH_MOBILE_NO: usize = 13;
 const MAX_LENGTH_NATIONAL_ID: usize = 13;
 const MAX_LENGTH_EWALLET_ID: usize = 15;
 const MAX_LENGTH_BANK_ACCOUNT: usize = 43;
-const MAX_LENGTH_OTA: usize = 10;
+pub const MAX_LENGTH_OTA: usize = 10;
 const MAX_LENGTH_BILLER_ID: usize = 15;
 const MAX_LENGTH_REF1: usize = 20;
 const MAX_LENGTH_REF2: usize = 20;
@@ -39,114 +45,187 @@ const MAX_LENGTH_SERVICE_PROFIDER_ID: usize = 3;
 const MAX_LENGTH_TRANSACTION_REF: usize = 25;
 const MAX_LENGTH_ACQUIRER_ID: usize = 3;
 
-const PROMPTPAY_PRESENTED_TYPE_ARRAY: [&'static str; 2] = ["A000000677010111", "A000000677010114"];
+/// PromptPay proxy (account identifier) carried in the credit transfer template.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ProxyType {
+    MobileNumber,
+    NationalId,
+    EWalletId,
+    BankAccount,
+}
+
+impl ProxyType {
+    /// Credit transfer sub-tag carrying the proxy.
+    pub fn tag_id(self) -> TagID {
+        match self {
+            ProxyType::MobileNumber => ID_PROMPT_PAY_CREDIT_TRANSFER_MOBILE_NUMBER,
+            ProxyType::NationalId => ID_PROMPT_PAY_CREDIT_TRANSFER_NATIONAL_ID,
+            ProxyType::EWalletId => ID_PROMPT_PAY_CREDIT_TRANSFER_EWALLET_ID,
+            ProxyType::BankAccount => ID_PROMPT_PAY_CREDIT_TRANSFER_BANK_ACCOUNT,
+        }
+    }
+    pub fn from_tag_id(tag_id: TagID) -> Option<Self> {
+        match tag_id {
+            ID_PROMPT_PAY_CREDIT_TRANSFER_MOBILE_NUMBER => Some(ProxyType::MobileNumber),
+            ID_PROMPT_PAY_CREDIT_TRANSFER_NATIONAL_ID => Some(ProxyType::NationalId),
+            ID_PROMPT_PAY_CREDIT_TRANSFER_EWALLET_ID => Some(ProxyType::EWalletId),
+            ID_PROMPT_PAY_CREDIT_TRANSFER_BANK_ACCOUNT => Some(ProxyType::BankAccount),
+            _ => None,
+        }
+    }
+    /// Proxies are numeric and zero-padded to this length when encoded.
+    pub fn max_length(self) -> usize {
+        match self {
+            ProxyType::MobileNumber => MAX_LENGTH_MOBILE_NO,
+            ProxyType::NationalId => MAX_LENGTH_NATIONAL_ID,
+            ProxyType::EWalletId => MAX_LENGTH_EWALLET_ID,
+            ProxyType::BankAccount => MAX_LENGTH_BANK_ACCOUNT,
+        }
+    }
+}
+
+const PROMPTPAY_PRESENTED_TYPE_ARRAY: [&str; 2] = ["A000000677010111", "A000000677010114"];
+
+/// The presented type identified by a credit transfer AID.
+pub fn promptpay_presented_type(aid: &str) -> Option<PromptPayPresentedType> {
+    PROMPTPAY_PRESENTED_TYPE_ARRAY
+        .iter()
+        .position(|item| *item == aid)
+}
 
-#[derive(Default)]
 pub struct MerchantPromptPayCreditTransfer {
-    aid: Option<EMVQRFieldDataObject>,
-    mobile: Option<EMVQRFieldDataObject>,
-    national_id: Option<EMVQRFieldDataObject>,
-    e_wallet_id: Option<EMVQRFieldDataObject>,
-    bank_account: Option<EMVQRFieldDataObject>,
-    ota: Option<EMVQRFieldDataObject>,
+    template: EMVTemplate,
+    presented_type: Option<PromptPayPresentedType>,
+    ota: Option<Data>,
+}
+
+impl Default for MerchantPromptPayCreditTransfer {
+    fn default() -> Self {
+        MerchantPromptPayCreditTransfer {
+            template: EMVTemplate::new(ID_PROMPT_PAY_CREDIT_TRANSFER).unwrap(),
+            presented_type: None,
+            ota: None,
+        }
+    }
 }
 
 impl EMVOData for MerchantPromptPayCreditTransfer {
     fn len(&self) -> usize {
-        let data = self.value();
-        EMVAlphanumbericSpecial::try_from(data).unwrap().len()
+        self.template.len()
     }
 
     fn valid(&self) -> bool {
-        let data = self.value();
-        EMVAlphanumbericSpecial::try_from(data).unwrap().valid()
+        self.template.valid()
     }
 
     fn value(&self) -> Data {
-        let mut builder = EMVQFieldDataObjectListBuilder::new();
-        if self.aid.is_some() {
-            let aid_ref = self.aid.as_ref().unwrap();
-            builder.add_field_object(aid_ref);
-        }
-        if self.mobile.is_some() {
-            builder.add_field_object(self.mobile.as_ref().unwrap());
-        }
-        if self.national_id.is_some() {
-            builder.add_field_object(self.national_id.as_ref().unwrap());
-        }
-        if self.e_wallet_id.is_some() {
-            builder.add_field_object(self.e_wallet_id.as_ref().unwrap());
-        }
-        if self.bank_account.is_some() {
-            builder.add_field_object(self.bank_account.as_ref().unwrap());
-        }
-        if self.aid.is_some() {
-            let data = self.aid.as_ref().unwrap().value().unwrap();
-            if data.eq(&PROMPTPAY_PRESENTED_TYPE_ARRAY[CUSTOMER_PRESENTED].to_string()) {
-                if self.ota.is_some() {
-                    builder.add_field_object(self.ota.as_ref().unwrap());
-                }
-            }
-        }
-        builder.to_string().unwrap()
+        self.template.value()
     }
 
     fn type_name(&self) -> &str {
-        TYPE_NAME_ALPHA_NUMERIC
+        TYPE_NAME_TEMPLATE
+    }
+
+    fn encode_into(&self, out: &mut dyn fmt::Write) -> fmt::Result {
+        self.template.encode_into(out)
     }
 }
 
 impl MerchantPromptPayCreditTransfer {
+    /// The encoded sub-tags, or why they cannot be encoded.
+    pub fn to_list_string(&self) -> Result<String, EMVOError> {
+        self.template.to_list_string()
+    }
+    fn set_numeric(&mut self, tag_id: TagID, data: &Data, length: usize) -> Result<(), EMVOError> {
+        self.template.set(EMVQRFieldDataObject::checked(
+            tag_id,
+            Box::new(EMVNumeric::try_from(data.clone())?),
+            length,
+        )?)
+    }
+    /// The OTA is only carried by customer presented QRs.
+    fn update_ota(&mut self) -> Result<(), EMVOError> {
+        match self.ota.clone() {
+            Some(ota) if self.presented_type == Some(CUSTOMER_PRESENTED) => {
+                self.set_numeric(ID_PROMPT_PAY_CREDIT_TRANSFER_OTA, &ota, MAX_LENGTH_OTA)
+            }
+            _ => {
+                self.template.remove(ID_PROMPT_PAY_CREDIT_TRANSFER_OTA);
+                Ok(())
+            }
+        }
+    }
     pub fn set_promptpay_presented_type(
         &mut self,
         prompt_pay_presented_type: PromptPayPresentedType,
-    ) {
-        let data = &PROMPTPAY_PRESENTED_TYPE_ARRAY[prompt_pay_presented_type];
-        self.aid = Some(EMVQRFieldDataObject::new(
+    ) -> Result<(), EMVOError> {
+        let data = PROMPTPAY_PRESENTED_TYPE_ARRAY
+            .get(prompt_pay_presented_type)
+            .ok_or_else(|| EMVOError::new("PromptPay Presented Type Invalid"))?;
+        self.template.set_field(
             ID_PROMPT_PAY_CREDIT_TRANSFER_AID,
-            Box::new(EMVAlphanumbericSpecial::try_from(data.to_string()).unwrap()),
+            Box::new(EMVAlphanumbericSpecial::try_from(data.to_string())?),
             MAX_LENGTH_AID,
-        ));
+        )?;
+        self.presented_type = Some(prompt_pay_presented_type);
+        self.update_ota()
     }
-    pub fn set_mobile_number(&mut self, mobile_no: &Data) {
-        self.mobile = Some(EMVQRFieldDataObject::new(
+    pub fn set_mobile_number(&mut self, mobile_no: &Data) -> Result<(), EMVOError> {
+        self.set_numeric(
             ID_PROMPT_PAY_CREDIT_TRANSFER_MOBILE_NUMBER,
-            Box::new(EMVNumeric::try_from(mobile_no.clone()).unwrap()),
+            mobile_no,
             MAX_LENGTH_MOBILE_NO,
-        ));
+        )
     }
-    pub fn set_national_id(&mut self, national_id: &Data) {
-        self.national_id = Some(EMVQRFieldDataObject::new(
+    pub fn set_national_id(&mut self, national_id: &Data) -> Result<(), EMVOError> {
+        self.set_numeric(
             ID_PROMPT_PAY_CREDIT_TRANSFER_NATIONAL_ID,
-            Box::new(EMVNumeric::try_from(national_id.clone()).unwrap()),
+            national_id,
             MAX_LENGTH_NATIONAL_ID,
-        ));
+        )
     }
-    pub fn set_e_wallet_id(&mut self, e_wallet_id: &Data) {
-        self.e_wallet_id = Some(EMVQRFieldDataObject::new(
+    pub fn set_e_wallet_id(&mut self, e_wallet_id: &Data) -> Result<(), EMVOError> {
+        self.set_numeric(
             ID_PROMPT_PAY_CREDIT_TRANSFER_EWALLET_ID,
-            Box::new(EMVNumeric::try_from(e_wallet_id.clone()).unwrap()),
+            e_wallet_id,
             MAX_LENGTH_EWALLET_ID,
-        ));
+        )
     }
-    pub fn set_bank_account(&mut self, bank_account: &Data) {
-        self.bank_account = Some(EMVQRFieldDataObject::new(
+    pub fn set_bank_account(&mut self, bank_account: &Data) -> Result<(), EMVOError> {
+        self.set_numeric(
             ID_PROMPT_PAY_CREDIT_TRANSFER_BANK_ACCOUNT,
-            Box::new(EMVNumeric::try_from(bank_account.clone()).unwrap()),
+            bank_account,
             MAX_LENGTH_BANK_ACCOUNT,
-        ));
+        )
     }
-    pub fn set_ota(&mut self, ota: &Data) {
-        self.ota = Some(EMVQRFieldDataObject::new(
-            ID_PROMPT_PAY_CREDIT_TRANSFER_OTA,
-            Box::new(EMVNumeric::try_from(ota.clone()).unwrap()),
-            MAX_LENGTH_OTA,
-        ));
+    /// Sets the proxy for `proxy_type`, returning an error instead of panicking
+    /// when the value is not numeric or too long.
+    pub fn set_proxy(&mut self, proxy_type: ProxyType, value: &Data) -> Result<(), EMVOError> {
+        if value.is_empty() || value.len() > proxy_type.max_length() {
+            return Err(EMVOError::new("Proxy Length Invalid"));
+        }
+        EMVNumeric::try_from(value.clone())?;
+        match proxy_type {
+            ProxyType::MobileNumber => self.set_mobile_number(value),
+            ProxyType::NationalId => self.set_national_id(value),
+            ProxyType::EWalletId => self.set_e_wallet_id(value),
+            ProxyType::BankAccount => self.set_bank_account(value),
+        }
+    }
+    pub fn set_ota(&mut self, ota: &Data) -> Result<(), EMVOError> {
+        if ota.is_empty() || ota.len() > MAX_LENGTH_OTA {
+            return Err(EMVOError::new("OTA Length Invalid"));
+        }
+        EMVNumeric::try_from(ota.clone())?;
+        self.ota = Some(ota.clone());
+        self.update_ota()
     }
 }
+#[cfg(test)]
 mod test {
-    use base64::Engine;
     use base64::engine::general_purpose;
+    use base64::Engine;
     use qrcode_generator::QrCodeEcc;
 
     use super::*;
@@ -154,22 +233,32 @@ mod test {
     #[test]
     fn test_merchant_prompt_pay_credit_transfer_pay_load() {
         let mut emvo = EMVQR::default();
-        let result = emvo
-            .set_payload_format_indicator("02".to_string())
+        emvo.set_payload_format_indicator("02".to_string())
             .expect("Error");
         let mut merchant_prompt_pay = MerchantPromptPayCreditTransfer::default();
-        merchant_prompt_pay.set_promptpay_presented_type(CUSTOMER_PRESENTED);
-        merchant_prompt_pay.set_mobile_number(&"0809729900".to_string());
+        merchant_prompt_pay
+            .set_promptpay_presented_type(CUSTOMER_PRESENTED)
+            .unwrap();
+        merchant_prompt_pay
+            .set_mobile_number(&"0809729900".to_string())
+            .unwrap();
 
         emvo.set_point_types(STATIC_POINT).expect("Error");
-        emvo.set_transaction_currency(BAHT);
-        emvo.set_transaction_amount("50".to_string());
-        emvo.set_merchant_name("test".to_string());
-        emvo.set_merchant_category_code("5311".to_string());
-        emvo.set_merchant_account_information("29", Box::new(merchant_prompt_pay));
-        emvo.set_merchant_city("Bangkok".to_string());
-        emvo.set_postal_code("10240".to_string());
-        emvo.set_country_code(THAI);
+        emvo.set_transaction_currency(BAHT).expect("Error");
+        emvo.set_transaction_amount("50".to_string())
+            .expect("Error");
+        emvo.set_merchant_name("test".to_string()).expect("Error");
+        emvo.set_merchant_category_code("5311".to_string())
+            .expect("Error");
+        emvo.set_merchant_account_information(
+            ID_PROMPT_PAY_CREDIT_TRANSFER,
+            Box::new(merchant_prompt_pay),
+        )
+        .expect("Error");
+        emvo.set_merchant_city("Bangkok".to_string())
+            .expect("Error");
+        emvo.set_postal_code("10240".to_string()).expect("Error");
+        emvo.set_country_code(THAI).expect("Error");
 
         let result = emvo.generate_pay_load().expect("Error");
         let expected_result = "00020201021129370016A000000677010114011300008097299005204531153037645402505802TH5904test6007Bangkok610510240630443DC";
@@ -178,23 +267,33 @@ mod test {
     #[test]
     fn test_merchant_prompt_pay_credit_transfer() {
         let mut emvo = EMVQR::default();
-        let result = emvo
-            .set_payload_format_indicator("02".to_string())
+        emvo.set_payload_format_indicator("02".to_string())
             .expect("Error");
         let mut merchant_prompt_pay = MerchantPromptPayCreditTransfer::default();
 
-        merchant_prompt_pay.set_promptpay_presented_type(CUSTOMER_PRESENTED);
-        merchant_prompt_pay.set_mobile_number(&"0809729900".to_string());
+        merchant_prompt_pay
+            .set_promptpay_presented_type(CUSTOMER_PRESENTED)
+            .unwrap();
+        merchant_prompt_pay
+            .set_mobile_number(&"0809729900".to_string())
+            .unwrap();
 
         emvo.set_point_types(STATIC_POINT).expect("Error");
-        emvo.set_transaction_currency(BAHT);
-        emvo.set_transaction_amount("50".to_string());
-        emvo.set_merchant_name("test".to_string());
-        emvo.set_merchant_category_code("5311".to_string());
-        emvo.set_merchant_account_information("29", Box::new(merchant_prompt_pay));
-        emvo.set_merchant_city("Bangkok".to_string());
-        emvo.set_postal_code("10240".to_string());
-        emvo.set_country_code(THAI);
+        emvo.set_transaction_currency(BAHT).expect("Error");
+        emvo.set_transaction_amount("50".to_string())
+            .expect("Error");
+        emvo.set_merchant_name("test".to_string()).expect("Error");
+        emvo.set_merchant_category_code("5311".to_string())
+            .expect("Error");
+        emvo.set_merchant_account_information(
+            ID_PROMPT_PAY_CREDIT_TRANSFER,
+            Box::new(merchant_prompt_pay),
+        )
+        .expect("Error");
+        emvo.set_merchant_city("Bangkok".to_string())
+            .expect("Error");
+        emvo.set_postal_code("10240".to_string()).expect("Error");
+        emvo.set_country_code(THAI).expect("Error");
 
         let result = emvo.generate_pay_load().expect("Error");
         let result: Vec<u8> =
@@ -204,4 +303,236 @@ mod test {
         assert_eq!(str_b64, expected_qr_code);
     }
 
+    #[test]
+    fn test_setters_report_invalid_values() {
+        let mut merchant_prompt_pay = MerchantPromptPayCreditTransfer::default();
+        assert!(merchant_prompt_pay.set_promptpay_presented_type(2).is_err());
+        merchant_prompt_pay
+            .set_promptpay_presented_type(MERCHANT_PRESENTED)
+            .unwrap();
+        assert!(merchant_prompt_pay
+            .set_mobile_number(&"08O9729900".to_string())
+            .is_err());
+        assert!(merchant_prompt_pay
+            .set_national_id(&"1".repeat(MAX_LENGTH_NATIONAL_ID + 1))
+            .is_err());
+        assert!(merchant_prompt_pay
+            .set_e_wallet_id(&"-1".to_string())
+            .is_err());
+        assert!(merchant_prompt_pay
+            .set_bank_account(&"12 34".to_string())
+            .is_err());
+        // only the AID was set
+        assert_eq!(
+            merchant_prompt_pay.to_list_string().unwrap(),
+            "0016A000000677010111"
+        );
+    }
+
+    #[test]
+    fn test_convert_static_to_dynamic() {
+        let static_pay_load = "00020101021129370016A000000677010114011300008097299005204531153037645802TH5904test6007Bangkok610510240";
+        let mut static_pay_load = format!("{}6304", static_pay_load);
+        static_pay_load.push_str(&crc16(&static_pay_load));
+
+        let result = convert_to_dynamic(&static_pay_load, 50.25, Some("INV0001")).unwrap();
+        let expected = "00020101021229370016A00000067701011401130000809729900520453115303764540550.255802TH5904test6007Bangkok61051024062110507INV00016304";
+        assert!(result.starts_with(expected));
+        assert_eq!(&result[expected.len()..], crc16(expected));
+
+        // converting again replaces the amount and reference in place
+        let again = convert_to_dynamic(&result, 10.0, Some("INV0002")).unwrap();
+        assert_eq!(
+            again,
+            convert_to_dynamic(&static_pay_load, 10.0, Some("INV0002")).unwrap()
+        );
+
+        let corrupted = static_pay_load.replace("Bangkok", "Bangkom");
+        assert!(convert_to_dynamic(&corrupted, 50.0, None).is_err());
+        assert!(convert_to_dynamic(&static_pay_load, -1.0, None).is_err());
+    }
+
+    #[test]
+    fn test_encode_into() {
+        let mut language = MerchantInformationLanguageTemplate::default();
+        language.set_language_preference("TH".to_string()).unwrap();
+        language.set_merchant_name("ร้านทดสอบ".to_string()).unwrap();
+        let mut merchant_prompt_pay = MerchantPromptPayCreditTransfer::default();
+        merchant_prompt_pay
+            .set_promptpay_presented_type(MERCHANT_PRESENTED)
+            .unwrap();
+        merchant_prompt_pay
+            .set_mobile_number(&"0809729900".to_string())
+            .unwrap();
+        let mut emvo = EMVQR::default();
+        emvo.set_payload_format_indicator("02".to_string()).unwrap();
+        emvo.set_merchant_account_information(
+            ID_PROMPT_PAY_CREDIT_TRANSFER,
+            Box::new(merchant_prompt_pay),
+        )
+        .unwrap();
+        emvo.set_merchant_category_code("5311".to_string()).unwrap();
+        emvo.set_merchant_information_language_template(language);
+
+        let mut pay_load = String::new();
+        emvo.encode_into(&mut pay_load).unwrap();
+        assert_eq!(pay_load, emvo.to_pay_load().unwrap());
+        assert_eq!(emvo.encoded_len().unwrap(), pay_load.chars().count());
+        assert!(pay_load.starts_with("00020229370016A00000067701011101130000809729900520453116419"));
+        let (data, crc) = pay_load.split_at(pay_load.len() - 4);
+        assert_eq!(crc, crc16(data));
+
+        assert!(emvo
+            .set_merchant_name("a name that is longer than 25".to_string())
+            .is_err());
+    }
+
+    /// Numeric data counting how often it is validated.
+    struct CountingData(std::rc::Rc<std::cell::Cell<usize>>);
+
+    impl EMVOData for CountingData {
+        fn len(&self) -> usize {
+            2
+        }
+        fn valid(&self) -> bool {
+            self.0.set(self.0.get() + 1);
+            true
+        }
+        fn value(&self) -> Data {
+            "42".to_string()
+        }
+        fn type_name(&self) -> &str {
+            TYPE_NAME_NUMERIC
+        }
+    }
+
+    #[test]
+    fn test_nested_templates_are_validated_once() {
+        let validations = std::rc::Rc::new(std::cell::Cell::new(0));
+        let mut inner = EMVTemplate::new(TagID::new(50)).unwrap();
+        inner
+            .set_field(
+                TagID::new(1),
+                Box::new(CountingData(validations.clone())),
+                2,
+            )
+            .unwrap();
+        let mut outer = EMVTemplate::new(ID_ADDITIONAL_DATA_FIELD_TEMPLATE).unwrap();
+        outer.set(inner.into_field_data_object()).unwrap();
+        let mut emvo = EMVQR::default();
+        emvo.set_payload_format_indicator("02".to_string()).unwrap();
+        emvo.set_field_data_object(outer.into_field_data_object())
+            .unwrap();
+
+        let pay_load = emvo.to_pay_load().unwrap();
+        assert!(pay_load.starts_with("0002026210500601024263"));
+        assert_eq!(validations.get(), 1);
+    }
+
+    #[test]
+    fn test_unreserved_template() {
+        assert!(UnreservedTemplate::new(TagID::new(79), "com.partner".to_string()).is_err());
+        assert!(UnreservedTemplate::new(TagID::new(80), "".to_string()).is_err());
+        assert!(UnreservedTemplate::new(TagID::new(80), "A".repeat(33)).is_err());
+        let mut template =
+            UnreservedTemplate::new(TagID::new(80), "A0000006770101".to_string()).unwrap();
+        assert!(template
+            .set_context_specific_data(
+                UNRESERVED_TEMPLATE_ID_GLOBALLY_UNIQUE_IDENTIFIER,
+                "X".to_string()
+            )
+            .is_err());
+        template
+            .set_context_specific_data(TagID::new(2), "POINTS".to_string())
+            .unwrap();
+
+        let mut emvo = EMVQR::default();
+        emvo.set_payload_format_indicator("02".to_string()).unwrap();
+        emvo.set_unreserved_template(template);
+        emvo.set_rfu(TagID::new(65), "RFU".to_string()).unwrap();
+        let pay_load = emvo.generate_pay_load().unwrap();
+        assert!(pay_load.starts_with("0002026503RFU80280014A00000067701010206POINTS6304"));
+    }
+
+    #[test]
+    fn test_rfu_is_replaced_and_ordered() {
+        let mut emvo = EMVQR::default();
+        emvo.set_payload_format_indicator("02".to_string()).unwrap();
+        emvo.set_rfu(TagID::new(70), "B".to_string()).unwrap();
+        emvo.set_rfu(TagID::new(65), "OLD".to_string()).unwrap();
+        emvo.set_rfu(TagID::new(65), "NEW".to_string()).unwrap();
+        emvo.set_field_data_object(EMVQRFieldDataObject::new(
+            TagID::new(70),
+            Box::new(EMVString::try_from("C".to_string()).unwrap()),
+            99,
+        ))
+        .unwrap();
+        let pay_load = emvo.to_pay_load().unwrap();
+        assert!(pay_load.starts_with("0002026503NEW7001C6304"));
+    }
+
+    #[test]
+    fn test_emv_template() {
+        assert!(EMVTemplate::new(ID_MERCHANT_CATEGORY_CODE).is_err());
+        assert!(EMVTemplate::new(TagID::new(25)).is_err());
+
+        let mut template = EMVTemplate::new(ID_ADDITIONAL_DATA_FIELD_TEMPLATE).unwrap();
+        assert!(!template.valid());
+        let label =
+            |data: &str| Box::new(EMVAlphanumbericSpecial::try_from(data.to_string()).unwrap());
+        assert!(template.set_field(TagID::new(0), label("X"), 25).is_err());
+        template
+            .set_field(ADDITIONAL_ID_TERMINAL_LABEL, label("T1"), 25)
+            .unwrap();
+        template
+            .set_field(ADDITIONAL_ID_BILL_NUMBER, label("B1"), 25)
+            .unwrap();
+        template
+            .set_field(ADDITIONAL_ID_TERMINAL_LABEL, label("T2"), 25)
+            .unwrap();
+        assert_eq!(template.value(), "0102B10702T2");
+        assert_eq!(
+            template.into_field_data_object().value().unwrap(),
+            "62120102B10702T2"
+        );
+
+        let mut language = MerchantInformationLanguageTemplate::default();
+        language.set_language_preference("TH".to_string()).unwrap();
+        language.set_merchant_name("ร้านทดสอบ".to_string()).unwrap();
+        let mut emvo = EMVQR::default();
+        emvo.set_payload_format_indicator("02".to_string()).unwrap();
+        emvo.set_merchant_information_language_template(language);
+        let pay_load = emvo.generate_pay_load().unwrap();
+        assert!(pay_load.starts_with("0002026419"));
+        assert!(pay_load.contains("0002TH0109ร้านทดสอบ"));
+    }
+
+    #[test]
+    fn test_tag_id() {
+        let tag_id: TagID = "29".parse().unwrap();
+        assert_eq!(tag_id, ID_PROMPT_PAY_CREDIT_TRANSFER);
+        assert_eq!(TagID::try_from(5).unwrap().to_string(), "05");
+        assert!(TagID::try_from(100).is_err());
+        assert!("2".parse::<TagID>().is_err());
+        assert!("a9".parse::<TagID>().is_err());
+        assert!(parse_data_objects("0002TH").is_ok());
+        assert!(parse_data_objects("00+2TH").is_err());
+        assert!(parse_data_objects("00 2TH").is_err());
+        assert!(ID_CRC < ID_MERCHANT_INFORMATION_LANGUAGE_TEMPLATE);
+        assert!(tag_id.is_between(
+            ID_MERCHANT_ACCOUNT_INFORMATION_RANGE_START,
+            ID_MERCHANT_ACCOUNT_INFORMATION_RANGE_END
+        ));
+
+        let mut emvo = EMVQR::default();
+        let merchant_prompt_pay = MerchantPromptPayCreditTransfer::default();
+        assert!(emvo
+            .set_merchant_account_information(
+                ID_MERCHANT_CATEGORY_CODE,
+                Box::new(merchant_prompt_pay)
+            )
+            .is_err());
+        assert!(emvo.set_rfu(ID_CRC, "X".to_string()).is_err());
+        assert!(emvo.set_rfu(TagID::new(65), "X".to_string()).is_ok());
+    }
 }
@@ -0,0 +1,489 @@
+#![allow(dead_code)]
+
+use hmac::{Hmac, Mac};
+use rusqlite::{params, OptionalExtension};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::apis::PromptPayServiceError;
+use crate::emvo_qrcode::*;
+use crate::emvo_types::*;
+use crate::prompt_pay::*;
+use crate::qr_signature::constant_time_eq;
+use crate::storage::{unix_timestamp, Storage};
+use crate::webhooks::generate_secret;
+
+pub const CUSTOMER_SCHEMA: &str = "CREATE TABLE IF NOT EXISTS customers (
+    id TEXT PRIMARY KEY NOT NULL,
+    proxy TEXT NOT NULL UNIQUE,
+    proxy_value TEXT NOT NULL,
+    ota_secret TEXT NOT NULL,
+    access_token_hash TEXT NOT NULL,
+    last_ota_counter INTEGER,
+    created_at INTEGER NOT NULL,
+    updated_at INTEGER NOT NULL
+)";
+
+pub const MAX_LENGTH_CUSTOMER_ID: usize = 64;
+pub const DEFAULT_OTA_TIME_STEP_SECONDS: i64 = 30;
+pub const DEFAULT_OTA_WINDOW_STEPS: i64 = 1;
+
+/// How long an OTA lives: codes are derived from `unix time / time step`
+/// and accepted up to `window_steps` steps either side of the verifier's
+/// clock, to allow for clock drift and the time it takes to scan.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct OtaPolicy {
+    pub time_step_seconds: i64,
+    pub window_steps: i64,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CreateCustomerRq {
+    pub id: String,
+    pub proxy_type: ProxyType,
+    pub proxy_value: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Customer {
+    pub id: String,
+    pub proxy_type: ProxyType,
+    pub proxy_value: String,
+    #[serde(skip)]
+    pub ota_secret: String,
+    pub created_at: i64,
+    pub updated_at: i64,
+}
+
+/// Returned once when the customer is created, the only time the OTA secret
+/// and the access token are shown. Wallets that generate codes offline keep
+/// the secret; the others send the token to have codes issued.
+#[derive(Debug, Serialize)]
+pub struct CustomerRs {
+    #[serde(flatten)]
+    pub customer: Customer,
+    pub ota_secret: String,
+    pub access_token: String,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct CustomerQrCodeRs {
+    pub pay_load: String,
+    pub qrcode_base64: String,
+    pub expires_at: i64,
+}
+
+#[derive(Debug, PartialEq, Deserialize, Serialize)]
+pub struct OtaVerification {
+    pub valid: bool,
+    pub customer_id: Option<String>,
+}
+
+impl Default for OtaPolicy {
+    fn default() -> Self {
+        OtaPolicy {
+            time_step_seconds: DEFAULT_OTA_TIME_STEP_SECONDS,
+            window_steps: DEFAULT_OTA_WINDOW_STEPS,
+        }
+    }
+}
+
+impl OtaPolicy {
+    /// Reads `OTA_TIME_STEP_SECONDS` (default 30) and `OTA_WINDOW_STEPS`
+    /// (default 1).
+    pub fn from_env() -> Self {
+        let time_step_seconds = std::env::var("OTA_TIME_STEP_SECONDS")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .filter(|seconds: &i64| *seconds > 0)
+            .unwrap_or(DEFAULT_OTA_TIME_STEP_SECONDS);
+        let window_steps = std::env::var("OTA_WINDOW_STEPS")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .filter(|steps: &i64| *steps >= 0)
+            .unwrap_or(DEFAULT_OTA_WINDOW_STEPS);
+        OtaPolicy {
+            time_step_seconds,
+            window_steps,
+        }
+    }
+
+    pub fn counter(&self, timestamp: i64) -> i64 {
+        timestamp.div_euclid(self.time_step_seconds)
+    }
+
+    /// When the OTA for `counter` stops being accepted.
+    pub fn expires_at(&self, counter: i64) -> i64 {
+        (counter + 1 + self.window_steps) * self.time_step_seconds
+    }
+}
+
+/// HOTP (RFC 4226) over HMAC-SHA256, as the 10 digit OTA of a customer
+/// presented QR. Dynamic truncation yields 31 bits, so the code is that
+/// number zero-padded rather than reduced.
+pub fn generate_ota(secret: &[u8], counter: i64) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret).expect("HMAC accepts keys of any length");
+    mac.update(&counter.to_be_bytes());
+    let hash = mac.finalize().into_bytes();
+    let offset = usize::from(hash[hash.len() - 1] & 0x0F);
+    let code = u32::from_be_bytes([
+        hash[offset] & 0x7F,
+        hash[offset + 1],
+        hash[offset + 2],
+        hash[offset + 3],
+    ]);
+    format!("{:0width$}", code, width = MAX_LENGTH_OTA)
+}
+
+pub fn validate_customer_id(customer_id: &str) -> Result<(), String> {
+    if customer_id.is_empty() || customer_id.len() > MAX_LENGTH_CUSTOMER_ID {
+        return Err(format!(
+            "id must be 1 to {} characters",
+            MAX_LENGTH_CUSTOMER_ID
+        ));
+    }
+    let valid = customer_id
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+    if !valid {
+        return Err("id may only contain A-Z, a-z, 0-9, '-' and '_'".to_string());
+    }
+    Ok(())
+}
+
+/// The proxy as it is encoded in the credit transfer template, sub-tag
+/// followed by the zero-padded value, which is how scanned QRs are matched.
+fn encoded_proxy(proxy_type: ProxyType, proxy_value: &str) -> String {
+    format!(
+        "{}{:0>width$}",
+        proxy_type.tag_id(),
+        proxy_value,
+        width = proxy_type.max_length()
+    )
+}
+
+impl CreateCustomerRq {
+    pub fn validate(&self) -> Result<(), EMVOError> {
+        validate_customer_id(&self.id).map_err(|e| EMVOError::new(e.as_str()))?;
+        MerchantPromptPayCreditTransfer::default().set_proxy(self.proxy_type, &self.proxy_value)
+    }
+}
+
+impl Customer {
+    /// A dynamic customer presented PromptPay QR carrying `ota`.
+    pub fn generate_pay_load(&self, ota: &str) -> Result<String, EMVOError> {
+        let mut prompt_pay = MerchantPromptPayCreditTransfer::default();
+        prompt_pay.set_promptpay_presented_type(CUSTOMER_PRESENTED)?;
+        prompt_pay.set_proxy(self.proxy_type, &self.proxy_value)?;
+        prompt_pay.set_ota(&ota.to_string())?;
+
+        let mut emvo = EMVQR::default();
+        emvo.set_payload_format_indicator("02".to_string())?;
+        emvo.set_point_types(DYNAMIC_POINT)?;
+        emvo.set_merchant_account_information(ID_PROMPT_PAY_CREDIT_TRANSFER, Box::new(prompt_pay))?;
+        emvo.set_transaction_currency(BAHT)?;
+        emvo.set_country_code(THAI)?;
+        emvo.generate_pay_load()
+    }
+}
+
+/// The encoded proxy and OTA of a scanned customer presented QR.
+fn scanned_proxy_and_ota(pay_load: &str) -> Result<(String, String), EMVOError> {
+    let objects = parse_pay_load(pay_load)?;
+    let template = objects
+        .iter()
+        .find(|(tag_id, _)| *tag_id == ID_PROMPT_PAY_CREDIT_TRANSFER)
+        .map(|(_, template)| parse_data_objects(template))
+        .transpose()?
+        .unwrap_or_default();
+    let field = |tag_id: TagID| {
+        template
+            .iter()
+            .find(|(id, _)| *id == tag_id)
+            .map(|(_, value)| value.clone())
+    };
+    let aid = field(ID_PROMPT_PAY_CREDIT_TRANSFER_AID).unwrap_or_default();
+    if promptpay_presented_type(&aid) != Some(CUSTOMER_PRESENTED) {
+        return Err(EMVOError::new("Not A Customer Presented PromptPay QR"));
+    }
+    let ota =
+        field(ID_PROMPT_PAY_CREDIT_TRANSFER_OTA).ok_or_else(|| EMVOError::new("OTA Missing"))?;
+    let proxy = template
+        .iter()
+        .find(|(tag_id, _)| ProxyType::from_tag_id(*tag_id).is_some())
+        .map(|(tag_id, value)| format!("{}{}", tag_id, value))
+        .ok_or_else(|| EMVOError::new("Proxy Missing"))?;
+    Ok((proxy, ota))
+}
+
+type CustomerRow = (String, String, String, String, i64, i64);
+
+fn customer_from_row(row: &rusqlite::Row) -> rusqlite::Result<CustomerRow> {
+    Ok((
+        row.get(0)?,
+        row.get(1)?,
+        row.get(2)?,
+        row.get(3)?,
+        row.get(4)?,
+        row.get(5)?,
+    ))
+}
+
+fn to_customer(
+    (id, proxy, proxy_value, ota_secret, created_at, updated_at): CustomerRow,
+) -> Result<Customer, PromptPayServiceError> {
+    let proxy_type = proxy
+        .get(..2)
+        .and_then(|tag_id| tag_id.parse().ok())
+        .and_then(ProxyType::from_tag_id)
+        .ok_or_else(|| {
+            log::error!("Customer {} has an unreadable proxy", id);
+            PromptPayServiceError::InternalError
+        })?;
+    Ok(Customer {
+        id,
+        proxy_type,
+        proxy_value,
+        ota_secret,
+        created_at,
+        updated_at,
+    })
+}
+
+const CUSTOMER_COLUMNS: &str = "id, proxy, proxy_value, ota_secret, created_at, updated_at";
+
+/// Only the digest of a customer access token is stored.
+fn access_token_hash(access_token: &str) -> String {
+    hex::encode(Sha256::digest(access_token.as_bytes()))
+}
+
+impl Storage {
+    /// Fails with `Conflict` when the ID or the proxy is already registered.
+    pub fn insert_customer(
+        &self,
+        rq: &CreateCustomerRq,
+    ) -> Result<CustomerRs, PromptPayServiceError> {
+        let now = unix_timestamp();
+        let ota_secret = generate_secret();
+        let access_token = generate_secret();
+        let inserted = self.connection().execute(
+            "INSERT OR IGNORE INTO customers (id, proxy, proxy_value, ota_secret, access_token_hash, created_at, updated_at)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?6)",
+            params![
+                rq.id,
+                encoded_proxy(rq.proxy_type, &rq.proxy_value),
+                rq.proxy_value,
+                ota_secret,
+                access_token_hash(&access_token),
+                now
+            ],
+        )?;
+        if inserted == 0 {
+            return Err(PromptPayServiceError::Conflict);
+        }
+        Ok(CustomerRs {
+            customer: Customer {
+                id: rq.id.clone(),
+                proxy_type: rq.proxy_type,
+                proxy_value: rq.proxy_value.clone(),
+                ota_secret: ota_secret.clone(),
+                created_at: now,
+                updated_at: now,
+            },
+            ota_secret,
+            access_token,
+        })
+    }
+
+    /// The customer `id` if `access_token` is theirs. An unknown ID and a
+    /// wrong token are both `Unauthorized`, so IDs cannot be probed.
+    pub fn authenticate_customer(
+        &self,
+        id: &str,
+        access_token: &str,
+    ) -> Result<Customer, PromptPayServiceError> {
+        let row = self
+            .connection()
+            .query_row(
+                &format!(
+                    "SELECT {} FROM customers WHERE id = ?1 AND access_token_hash = ?2",
+                    CUSTOMER_COLUMNS
+                ),
+                params![id, access_token_hash(access_token)],
+                customer_from_row,
+            )
+            .optional()?;
+        match row {
+            Some(row) => to_customer(row),
+            None => Err(PromptPayServiceError::Unauthorized),
+        }
+    }
+
+    pub fn get_customer(&self, id: &str) -> Result<Customer, PromptPayServiceError> {
+        let row = self
+            .connection()
+            .query_row(
+                &format!("SELECT {} FROM customers WHERE id = ?1", CUSTOMER_COLUMNS),
+                params![id],
+                customer_from_row,
+            )
+            .optional()?;
+        match row {
+            Some(row) => to_customer(row),
+            None => Err(PromptPayServiceError::NotFound),
+        }
+    }
+
+    /// Checks the OTA of a scanned customer presented QR against the
+    /// customer owning its proxy. An accepted OTA (and any older one) cannot
+    /// be used again. The customer is only named when the OTA is valid.
+    pub fn verify_customer_ota(
+        &self,
+        pay_load: &str,
+        policy: &OtaPolicy,
+        now: i64,
+    ) -> Result<OtaVerification, PromptPayServiceError> {
+        let (proxy, ota) = scanned_proxy_and_ota(pay_load).map_err(|e| {
+            log::error!("Bad request: {}", e);
+            PromptPayServiceError::BadClientData
+        })?;
+        let conn = self.connection();
+        let row = conn
+            .query_row(
+                "SELECT id, ota_secret, last_ota_counter FROM customers WHERE proxy = ?1",
+                params![proxy],
+                |row| {
+                    Ok((
+                        row.get::<_, String>(0)?,
+                        row.get::<_, String>(1)?,
+                        row.get::<_, Option<i64>>(2)?,
+                    ))
+                },
+            )
+            .optional()?;
+        let (customer_id, ota_secret, last_counter) = match row {
+            Some(row) => row,
+            None => {
+                return Ok(OtaVerification {
+                    valid: false,
+                    customer_id: None,
+                })
+            }
+        };
+        let current = policy.counter(now);
+        let counter = (current - policy.window_steps..=current + policy.window_steps)
+            .filter(|counter| last_counter.is_none_or(|last| *counter > last))
+            .find(|counter| {
+                constant_time_eq(
+                    generate_ota(ota_secret.as_bytes(), *counter).as_bytes(),
+                    ota.as_bytes(),
+                )
+            });
+        if let Some(counter) = counter {
+            conn.execute(
+                "UPDATE customers SET last_ota_counter = ?2, updated_at = ?3 WHERE id = ?1",
+                params![customer_id, counter, now],
+            )?;
+        }
+        Ok(OtaVerification {
+            valid: counter.is_some(),
+            customer_id: counter.map(|_| customer_id),
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn customer_rq() -> CreateCustomerRq {
+        CreateCustomerRq {
+            id: "C001".to_string(),
+            proxy_type: ProxyType::MobileNumber,
+            proxy_value: "0809729900".to_string(),
+        }
+    }
+
+    #[test]
+    fn test_generate_ota() {
+        let ota = generate_ota(b"12345678901234567890", 1);
+        assert_eq!(ota.len(), MAX_LENGTH_OTA);
+        assert!(ota.chars().all(|c| c.is_ascii_digit()));
+        assert_eq!(ota, generate_ota(b"12345678901234567890", 1));
+        assert_ne!(ota, generate_ota(b"12345678901234567890", 2));
+
+        let policy = OtaPolicy::default();
+        assert_eq!(policy.counter(59), 1);
+        assert_eq!(policy.expires_at(1), 90);
+    }
+
+    #[test]
+    fn test_customer_presented_ota() {
+        let storage = Storage::open_in_memory().unwrap();
+        let registered = storage.insert_customer(&customer_rq()).unwrap();
+        let customer = registered.customer;
+        assert!(matches!(
+            storage.insert_customer(&customer_rq()),
+            Err(PromptPayServiceError::Conflict)
+        ));
+        assert_eq!(storage.get_customer("C001").unwrap(), customer);
+        assert_eq!(
+            storage
+                .authenticate_customer("C001", &registered.access_token)
+                .unwrap(),
+            customer
+        );
+        for (id, access_token) in [("C001", registered.ota_secret.as_str()), ("C002", "")] {
+            assert!(matches!(
+                storage.authenticate_customer(id, access_token),
+                Err(PromptPayServiceError::Unauthorized)
+            ));
+        }
+
+        let policy = OtaPolicy::default();
+        let now = 1_700_000_000;
+        let ota = generate_ota(customer.ota_secret.as_bytes(), policy.counter(now));
+        let pay_load = customer.generate_pay_load(&ota).unwrap();
+        assert!(pay_load.starts_with("00020201021229510016A00000067701011401130000809729900"));
+        assert!(pay_load.contains(&format!("0510{}5303764", ota)));
+
+        // outside the window
+        let late = storage
+            .verify_customer_ota(&pay_load, &policy, now + 2 * policy.time_step_seconds)
+            .unwrap();
+        assert_eq!(
+            late,
+            OtaVerification {
+                valid: false,
+                customer_id: None,
+            }
+        );
+
+        let verification = storage
+            .verify_customer_ota(&pay_load, &policy, now + policy.time_step_seconds)
+            .unwrap();
+        assert_eq!(
+            verification,
+            OtaVerification {
+                valid: true,
+                customer_id: Some("C001".to_string()),
+            }
+        );
+        // one time
+        assert_eq!(
+            storage
+                .verify_customer_ota(&pay_load, &policy, now)
+                .unwrap(),
+            OtaVerification {
+                valid: false,
+                customer_id: None,
+            }
+        );
+
+        let data = pay_load[..pay_load.len() - 4].replace("A000000677010114", "A000000677010111");
+        let merchant_presented = format!("{}{}", data, crc16(&data));
+        assert!(storage
+            .verify_customer_ota(&merchant_presented, &policy, now)
+            .is_err());
+    }
+}
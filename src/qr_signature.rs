@@ -0,0 +1,402 @@
+#![allow(dead_code)]
+
+use std::collections::BTreeMap;
+use std::convert::TryInto;
+
+use ed25519_dalek::{Signature, Signer, SigningKey, VerifyingKey};
+use serde::{Deserialize, Serialize};
+
+use crate::emvo_qrcode::*;
+use crate::emvo_types::*;
+
+// Unreserved templates (IDs 80-99) carrying our signature. A full Ed25519
+// signature does not fit one template, so it is split over two with the same
+// identifier: the first holds the key ID and sub-tag 02, the second sub-tag 03
+pub const SIGNATURE_GLOBALLY_UNIQUE_IDENTIFIER: &str = "TH.QRTAG30.SIG";
+pub const SIGNATURE_ID_KEY_ID: TagID = TagID::new(1); // (M) Key ID
+pub const SIGNATURE_ID_SIGNATURE: TagID = TagID::new(2); // (M) Ed25519 Signature, first part
+pub const SIGNATURE_ID_SIGNATURE_CONTINUED: TagID = TagID::new(3); // (M) Ed25519 Signature, rest
+
+/// Signature bytes in sub-tag 02, hex encoded. Sized so the first template
+/// still fits 99 characters with the longest key ID; the rest go in 03.
+pub const SIGNATURE_FIRST_PART_BYTES: usize = 28;
+const KEY_ID_MAX_LEN: usize = 16;
+
+/// The service-held Ed25519 keys QR payloads are signed with, by key ID.
+/// Retired keys stay configured so the stickers they signed still verify.
+#[derive(Default)]
+pub struct QRSigningKeys {
+    keys: BTreeMap<String, SigningKey>,
+    active: Option<String>,
+    verifying_keys: QRVerifyingKeys,
+}
+
+/// Ed25519 public keys by key ID. Enough to verify a sticker, never to sign
+/// one, so this is all an offline verifier needs.
+#[derive(Default)]
+pub struct QRVerifyingKeys {
+    keys: BTreeMap<String, VerifyingKey>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum SignatureStatus {
+    /// Issued by us and unchanged since.
+    Valid,
+    /// Carries our signature template, but the payload was altered or the
+    /// signature was not made by the named key.
+    Invalid,
+    /// Signed with a key ID this service does not hold.
+    UnknownKey,
+    /// No signature template; not a sticker we issued.
+    Unsigned,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SignatureVerification {
+    pub status: SignatureStatus,
+    pub key_id: Option<String>,
+}
+
+/// Splits `<key id>:<hex 32 bytes>` pairs separated by commas.
+fn parse_key_entries(keys: &str, name: &str) -> Result<Vec<(String, [u8; 32])>, EMVOError> {
+    let mut entries = Vec::new();
+    for entry in keys.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+        let (key_id, key) = entry.split_once(':').ok_or_else(|| {
+            let msg = format!("{} Must Be <key id>:<hex key>", name);
+            EMVOError::new(msg.as_str())
+        })?;
+        if key_id.is_empty() || key_id.len() > KEY_ID_MAX_LEN || !is_alphanumeric_special(key_id) {
+            let msg = format!("{} Id {} Invalid", name, key_id);
+            return Err(EMVOError::new(msg.as_str()));
+        }
+        let key: [u8; 32] = hex::decode(key)
+            .ok()
+            .and_then(|key| key.try_into().ok())
+            .ok_or_else(|| {
+                let msg = format!("{} {} Must Be 32 Hex Bytes", name, key_id);
+                EMVOError::new(msg.as_str())
+            })?;
+        entries.push((key_id.to_string(), key));
+    }
+    Ok(entries)
+}
+
+impl QRSigningKeys {
+    /// Parses `<key id>:<hex Ed25519 seed>` pairs separated by commas. The
+    /// active key defaults to the last one listed.
+    pub fn parse(keys: &str, active: Option<&str>) -> Result<Self, EMVOError> {
+        let mut signing_keys = QRSigningKeys::default();
+        for (key_id, seed) in parse_key_entries(keys, "QR Signing Key")? {
+            let key = SigningKey::from_bytes(&seed);
+            signing_keys
+                .verifying_keys
+                .keys
+                .insert(key_id.clone(), key.verifying_key());
+            signing_keys.keys.insert(key_id.clone(), key);
+            signing_keys.active = Some(key_id);
+        }
+        if let Some(active) = active.filter(|a| !a.is_empty()) {
+            if !signing_keys.keys.contains_key(active) {
+                let msg = format!("QR Signing Key Id {} Not Configured", active);
+                return Err(EMVOError::new(msg.as_str()));
+            }
+            signing_keys.active = Some(active.to_string());
+        }
+        Ok(signing_keys)
+    }
+
+    /// Reads `QR_SIGNING_KEYS` and `QR_SIGNING_KEY_ID`. Without keys nothing
+    /// is signed and every signature verifies as an unknown key.
+    pub fn from_env() -> Result<Self, EMVOError> {
+        let keys = std::env::var("QR_SIGNING_KEYS").unwrap_or_default();
+        let active = std::env::var("QR_SIGNING_KEY_ID").ok();
+        QRSigningKeys::parse(&keys, active.as_deref())
+    }
+
+    pub fn active_key_id(&self) -> Option<&str> {
+        self.active.as_deref()
+    }
+
+    /// The public halves of the configured keys.
+    pub fn verifying_keys(&self) -> &QRVerifyingKeys {
+        &self.verifying_keys
+    }
+
+    /// Adds (or replaces) the signature templates with ones made by the
+    /// active key and recomputes the CRC. Returns the payload unchanged when
+    /// no key is configured.
+    pub fn sign_pay_load(&self, pay_load: &str) -> Result<String, EMVOError> {
+        let (key_id, key) = match self.active.as_ref() {
+            Some(key_id) => (key_id, &self.keys[key_id]),
+            None => return Ok(pay_load.to_string()),
+        };
+        let mut objects = parse_pay_load(pay_load)?;
+        objects.retain(|(tag_id, value)| !is_signature_template(*tag_id, value));
+        let mut free_tag_ids = (ID_UNRESERVED_TEMPLATES_RANGE_START.value()
+            ..=ID_UNRESERVED_TEMPLATES_RANGE_END.value())
+            .rev()
+            .map(TagID::new)
+            .filter(|tag_id| objects.iter().all(|(id, _)| id != tag_id));
+        let (first_tag_id, second_tag_id) = match (free_tag_ids.next(), free_tag_ids.next()) {
+            (Some(first), Some(second)) => (first, second),
+            _ => {
+                return Err(EMVOError::new(
+                    "No Unreserved Templates Left For The Signature",
+                ))
+            }
+        };
+
+        let signature = key.sign(signed_message(&objects)?.as_bytes()).to_bytes();
+        let (first_part, rest) = signature.split_at(SIGNATURE_FIRST_PART_BYTES);
+        let mut first = UnreservedTemplate::new(
+            first_tag_id,
+            SIGNATURE_GLOBALLY_UNIQUE_IDENTIFIER.to_string(),
+        )?;
+        first.set_context_specific_data(SIGNATURE_ID_KEY_ID, key_id.clone())?;
+        first.set_context_specific_data(SIGNATURE_ID_SIGNATURE, hex::encode_upper(first_part))?;
+        let mut second = UnreservedTemplate::new(
+            second_tag_id,
+            SIGNATURE_GLOBALLY_UNIQUE_IDENTIFIER.to_string(),
+        )?;
+        second
+            .set_context_specific_data(SIGNATURE_ID_SIGNATURE_CONTINUED, hex::encode_upper(rest))?;
+        upsert_data_object(&mut objects, first_tag_id, first.value());
+        upsert_data_object(&mut objects, second_tag_id, second.value());
+        encode_pay_load(&objects)
+    }
+
+    /// Fails only when the payload itself cannot be read (e.g. bad CRC).
+    pub fn verify_pay_load(&self, pay_load: &str) -> Result<SignatureVerification, EMVOError> {
+        self.verifying_keys.verify_pay_load(pay_load)
+    }
+}
+
+impl QRVerifyingKeys {
+    /// Parses `<key id>:<hex Ed25519 public key>` pairs separated by commas.
+    pub fn parse(keys: &str) -> Result<Self, EMVOError> {
+        let mut verifying_keys = QRVerifyingKeys::default();
+        for (key_id, key) in parse_key_entries(keys, "QR Verifying Key")? {
+            let key = VerifyingKey::from_bytes(&key).map_err(|_| {
+                let msg = format!("QR Verifying Key {} Is Not An Ed25519 Public Key", key_id);
+                EMVOError::new(msg.as_str())
+            })?;
+            verifying_keys.keys.insert(key_id, key);
+        }
+        Ok(verifying_keys)
+    }
+
+    /// Reads `QR_VERIFY_KEYS`.
+    pub fn from_env() -> Result<Self, EMVOError> {
+        QRVerifyingKeys::parse(&std::env::var("QR_VERIFY_KEYS").unwrap_or_default())
+    }
+
+    /// The keys in the `QR_VERIFY_KEYS` format.
+    pub fn to_env_string(&self) -> String {
+        self.keys
+            .iter()
+            .map(|(key_id, key)| format!("{}:{}", key_id, hex::encode(key.as_bytes())))
+            .collect::<Vec<_>>()
+            .join(",")
+    }
+
+    /// Fails only when the payload itself cannot be read (e.g. bad CRC).
+    pub fn verify_pay_load(&self, pay_load: &str) -> Result<SignatureVerification, EMVOError> {
+        let mut objects = parse_pay_load(pay_load)?;
+        let mut fields = Vec::new();
+        let mut signed = false;
+        for (tag_id, value) in objects.iter() {
+            if is_signature_template(*tag_id, value) {
+                fields.extend(parse_data_objects(value)?);
+                signed = true;
+            }
+        }
+        if !signed {
+            return Ok(SignatureVerification {
+                status: SignatureStatus::Unsigned,
+                key_id: None,
+            });
+        }
+        objects.retain(|(tag_id, value)| !is_signature_template(*tag_id, value));
+        let field = |tag_id: TagID| {
+            fields
+                .iter()
+                .find(|(id, _)| *id == tag_id)
+                .map(|(_, value)| value.as_str())
+        };
+        let key_id = field(SIGNATURE_ID_KEY_ID).map(str::to_string);
+        let status = match key_id.as_ref().map(|key_id| self.keys.get(key_id)) {
+            None => SignatureStatus::Invalid,
+            Some(None) => SignatureStatus::UnknownKey,
+            Some(Some(key)) => {
+                let signature = field(SIGNATURE_ID_SIGNATURE)
+                    .zip(field(SIGNATURE_ID_SIGNATURE_CONTINUED))
+                    .and_then(|(first, rest)| hex::decode(format!("{}{}", first, rest)).ok())
+                    .and_then(|bytes| Signature::from_slice(&bytes).ok());
+                let message = signed_message(&objects)?;
+                match signature {
+                    Some(signature)
+                        if key.verify_strict(message.as_bytes(), &signature).is_ok() =>
+                    {
+                        SignatureStatus::Valid
+                    }
+                    _ => SignatureStatus::Invalid,
+                }
+            }
+        };
+        Ok(SignatureVerification { status, key_id })
+    }
+}
+
+fn is_signature_template(tag_id: TagID, value: &str) -> bool {
+    tag_id.is_between(
+        ID_UNRESERVED_TEMPLATES_RANGE_START,
+        ID_UNRESERVED_TEMPLATES_RANGE_END,
+    ) && parse_data_objects(value)
+        .map(|fields| {
+            fields.first().map(|(id, guid)| (*id, guid.as_str()))
+                == Some((
+                    UNRESERVED_TEMPLATE_ID_GLOBALLY_UNIQUE_IDENTIFIER,
+                    SIGNATURE_GLOBALLY_UNIQUE_IDENTIFIER,
+                ))
+        })
+        .unwrap_or(false)
+}
+
+/// Every data object except the signature templates and the CRC, in payload
+/// order, as they are encoded.
+fn signed_message(objects: &[(TagID, Data)]) -> Result<String, EMVOError> {
+    let mut message = String::new();
+    for (tag_id, value) in objects {
+        message.push_str(&encode_data_object(*tag_id, value)?);
+    }
+    Ok(message)
+}
+
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const PAY_LOAD: &str = "00020101021129370016A000000677010111011300668123456785802TH53037646304";
+
+    fn pay_load() -> String {
+        format!("{}{}", PAY_LOAD, crc16(PAY_LOAD))
+    }
+
+    fn keys(active: &str) -> QRSigningKeys {
+        QRSigningKeys::parse(
+            &format!("K1:{},K2:{}", "11".repeat(32), "22".repeat(32)),
+            Some(active),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_sign_and_verify_pay_load() {
+        let signed = keys("K1").sign_pay_load(&pay_load()).unwrap();
+        let objects = parse_pay_load(&signed).unwrap();
+        let (tag_id, template) = &objects[objects.len() - 1];
+        assert_eq!(*tag_id, TagID::new(99));
+        assert!(template.starts_with("0014TH.QRTAG30.SIG0102K10256"));
+        let (tag_id, template) = &objects[objects.len() - 2];
+        assert_eq!(*tag_id, TagID::new(98));
+        assert!(template.starts_with("0014TH.QRTAG30.SIG0372"));
+        assert!(objects.iter().all(|(_, value)| value.chars().count() <= 99));
+
+        // K1 is retired but still configured
+        let verification = keys("K2").verify_pay_load(&signed).unwrap();
+        assert_eq!(verification.status, SignatureStatus::Valid);
+        assert_eq!(verification.key_id.as_deref(), Some("K1"));
+
+        // re-signing replaces the template instead of adding another one
+        let resigned = keys("K2").sign_pay_load(&signed).unwrap();
+        assert_eq!(parse_pay_load(&resigned).unwrap().len(), objects.len());
+        assert_eq!(
+            keys("K1")
+                .verify_pay_load(&resigned)
+                .unwrap()
+                .key_id
+                .as_deref(),
+            Some("K2")
+        );
+    }
+
+    #[test]
+    fn test_verify_with_public_keys_only() {
+        let signed = keys("K1").sign_pay_load(&pay_load()).unwrap();
+        let public_keys = keys("K1").verifying_keys().to_env_string();
+        assert!(!public_keys.contains(&"11".repeat(32)));
+        let verifying_keys = QRVerifyingKeys::parse(&public_keys).unwrap();
+        assert_eq!(
+            verifying_keys.verify_pay_load(&signed).unwrap().status,
+            SignatureStatus::Valid
+        );
+
+        // a signature that is cut short no longer verifies
+        let objects: Vec<_> = parse_pay_load(&signed)
+            .unwrap()
+            .into_iter()
+            .filter(|(tag_id, _)| *tag_id != TagID::new(98))
+            .collect();
+        assert_eq!(
+            verifying_keys
+                .verify_pay_load(&encode_pay_load(&objects).unwrap())
+                .unwrap()
+                .status,
+            SignatureStatus::Invalid
+        );
+        assert!(QRVerifyingKeys::parse(&format!("K1:{}", "11".repeat(31))).is_err());
+    }
+
+    #[test]
+    fn test_verify_rejects_tampered_pay_load() {
+        let signed = keys("K1").sign_pay_load(&pay_load()).unwrap();
+        let mut objects = parse_pay_load(&signed).unwrap();
+        upsert_data_object(
+            &mut objects,
+            TagID::new(29),
+            "0016A00000067701011101130066898765432".to_string(),
+        );
+        let tampered = encode_pay_load(&objects).unwrap();
+        assert_eq!(
+            keys("K1").verify_pay_load(&tampered).unwrap().status,
+            SignatureStatus::Invalid
+        );
+        assert_eq!(
+            QRSigningKeys::default()
+                .verify_pay_load(&signed)
+                .unwrap()
+                .status,
+            SignatureStatus::UnknownKey
+        );
+        assert_eq!(
+            keys("K1").verify_pay_load(&pay_load()).unwrap().status,
+            SignatureStatus::Unsigned
+        );
+        assert_eq!(
+            QRSigningKeys::default().sign_pay_load(&pay_load()).unwrap(),
+            pay_load()
+        );
+    }
+
+    #[test]
+    fn test_parse_signing_keys() {
+        assert_eq!(keys("K1").active_key_id(), Some("K1"));
+        assert_eq!(
+            QRSigningKeys::parse(&format!("K1:{}", "11".repeat(32)), None)
+                .unwrap()
+                .active_key_id(),
+            Some("K1")
+        );
+        assert!(QRSigningKeys::parse("K1:1234", None).is_err());
+        assert!(QRSigningKeys::parse(&format!("K1:{}", "11".repeat(32)), Some("K3")).is_err());
+        assert!(QRSigningKeys::parse("", None)
+            .unwrap()
+            .active_key_id()
+            .is_none());
+    }
+}
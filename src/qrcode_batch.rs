@@ -0,0 +1,310 @@
+#![allow(dead_code)]
+
+use std::collections::HashSet;
+use std::io::{Cursor, Write};
+
+use log::error;
+use qrcode_generator::QrCodeEcc;
+use serde::{Deserialize, Serialize};
+use zip::write::SimpleFileOptions;
+use zip::ZipWriter;
+
+use crate::apis::{generate_prompt_pay_pay_load, GenerateQrCodeRq, PromptPayServiceError};
+use crate::merchant_registry::validate_merchant_id;
+use crate::qrcode_cache::QRCodeRenderOptions;
+use crate::qrcode_pdf::{PdfQrDocument, PdfQrPage};
+
+pub const MAX_BATCH_ROWS: usize = 5000;
+pub const BATCH_MANIFEST_FILE_NAME: &str = "manifest.json";
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct BatchMerchantRq {
+    pub merchant_id: String,
+    pub mobile_number: String,
+    pub merchant_name: String,
+    pub transaction_amount: f32,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BatchFormat {
+    #[default]
+    Png,
+    Svg,
+    Pdf,
+}
+
+/// A merchant row that produced a valid payload, ready to be rendered.
+#[derive(Debug, Clone)]
+pub struct BatchQrCode {
+    pub merchant_id: String,
+    pub merchant_name: String,
+    pub pay_load: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct BatchRowError {
+    pub row: usize,
+    pub merchant_id: Option<String>,
+    pub error: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct BatchManifestItem {
+    pub merchant_id: String,
+    pub file: String,
+    pub pay_load: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct BatchManifest {
+    pub items: Vec<BatchManifestItem>,
+    pub errors: Vec<BatchRowError>,
+}
+
+/// Result of generating payloads for a batch. Rows are numbered from 1 in
+/// input order (the CSV header line is not counted).
+#[derive(Debug, Default)]
+pub struct BatchResult {
+    pub qr_codes: Vec<BatchQrCode>,
+    pub errors: Vec<BatchRowError>,
+}
+
+impl BatchFormat {
+    pub fn file_extension(&self) -> &'static str {
+        match self {
+            BatchFormat::Png => "png",
+            BatchFormat::Svg => "svg",
+            BatchFormat::Pdf => "pdf",
+        }
+    }
+}
+
+impl BatchMerchantRq {
+    fn to_generate_rq(&self) -> GenerateQrCodeRq {
+        GenerateQrCodeRq {
+            transaction_amount: self.transaction_amount,
+            mobile_number: self.mobile_number.clone(),
+            merchant_name: self.merchant_name.clone(),
+            ..Default::default()
+        }
+    }
+}
+
+impl BatchResult {
+    fn push_error(&mut self, row: usize, merchant_id: Option<String>, error: String) {
+        self.errors.push(BatchRowError {
+            row,
+            merchant_id,
+            error,
+        });
+    }
+}
+
+/// Parses a merchant CSV with a `merchant_id,mobile_number,merchant_name,transaction_amount`
+/// header. Rows that fail to parse are reported in the returned errors.
+pub fn parse_csv(data: &[u8]) -> (Vec<(usize, BatchMerchantRq)>, Vec<BatchRowError>) {
+    let mut reader = csv::ReaderBuilder::new()
+        .trim(csv::Trim::All)
+        .from_reader(data);
+    let mut rows = vec![];
+    let mut errors = vec![];
+    for (index, record) in reader.deserialize::<BatchMerchantRq>().enumerate() {
+        match record {
+            Ok(rq) => rows.push((index + 1, rq)),
+            Err(e) => errors.push(BatchRowError {
+                row: index + 1,
+                merchant_id: None,
+                error: e.to_string(),
+            }),
+        }
+    }
+    (rows, errors)
+}
+
+pub fn generate_batch(rows: Vec<(usize, BatchMerchantRq)>) -> BatchResult {
+    let mut result = BatchResult::default();
+    let mut merchant_ids: HashSet<String> = HashSet::new();
+    for (row, rq) in rows {
+        let merchant_id = Some(rq.merchant_id.clone());
+        if let Err(e) = validate_merchant_id(&rq.merchant_id) {
+            result.push_error(row, merchant_id, e);
+            continue;
+        }
+        if !merchant_ids.insert(rq.merchant_id.clone()) {
+            result.push_error(row, merchant_id, "duplicate merchant_id".to_string());
+            continue;
+        }
+        let generate_rq = rq.to_generate_rq();
+        if let Err(e) = generate_rq.validate() {
+            result.push_error(row, merchant_id, e.to_string());
+            continue;
+        }
+        match generate_prompt_pay_pay_load(&generate_rq) {
+            Ok(pay_load) => result.qr_codes.push(BatchQrCode {
+                merchant_id: rq.merchant_id,
+                merchant_name: rq.merchant_name,
+                pay_load,
+            }),
+            Err(e) => result.push_error(row, merchant_id, e.to_string()),
+        }
+    }
+    result
+}
+
+/// Builds a ZIP with one image per merchant, named `<merchant_id>.<ext>`, and a
+/// `manifest.json` listing every payload and row error.
+pub fn build_zip(
+    batch: BatchResult,
+    format: BatchFormat,
+    options: QRCodeRenderOptions,
+) -> Result<Vec<u8>, PromptPayServiceError> {
+    let mut zip = ZipWriter::new(Cursor::new(Vec::new()));
+    let file_options = SimpleFileOptions::default();
+    let mut manifest = BatchManifest {
+        items: vec![],
+        errors: batch.errors,
+    };
+    for qr_code in batch.qr_codes {
+        let file = format!("{}.{}", qr_code.merchant_id, format.file_extension());
+        let image = render_image(&qr_code.pay_load, format, options)?;
+        zip.start_file(file.as_str(), file_options)
+            .and_then(|_| zip.write_all(&image).map_err(Into::into))
+            .map_err(zip_error)?;
+        manifest.items.push(BatchManifestItem {
+            merchant_id: qr_code.merchant_id,
+            file,
+            pay_load: qr_code.pay_load,
+        });
+    }
+    let manifest = serde_json::to_vec_pretty(&manifest).map_err(|e| {
+        error!("Batch manifest error: {}", e);
+        PromptPayServiceError::InternalError
+    })?;
+    zip.start_file(BATCH_MANIFEST_FILE_NAME, file_options)
+        .and_then(|_| zip.write_all(&manifest).map_err(Into::into))
+        .map_err(zip_error)?;
+    let cursor = zip.finish().map_err(zip_error)?;
+    Ok(cursor.into_inner())
+}
+
+/// Builds a printable PDF with one QR per page. Row errors are listed on a
+/// final page so the operator can see which merchants were skipped.
+pub fn build_pdf(batch: BatchResult, ecc: QrCodeEcc) -> Result<Vec<u8>, PromptPayServiceError> {
+    let mut document = PdfQrDocument::new();
+    for qr_code in batch.qr_codes {
+        let matrix = qrcode_generator::to_matrix_from_str(&qr_code.pay_load, ecc).map_err(|e| {
+            error!("QRCode render error: {}", e);
+            PromptPayServiceError::InternalError
+        })?;
+        document.add_qr_page(&PdfQrPage {
+            matrix,
+            captions: vec![qr_code.merchant_name, qr_code.merchant_id],
+        });
+    }
+    if !batch.errors.is_empty() {
+        let mut lines = vec![format!(
+            "{} row(s) could not be generated:",
+            batch.errors.len()
+        )];
+        for e in batch.errors {
+            lines.push(format!(
+                "row {} {}: {}",
+                e.row,
+                e.merchant_id.unwrap_or_default(),
+                e.error
+            ));
+        }
+        document.add_text_page(&lines);
+    }
+    Ok(document.to_bytes())
+}
+
+fn render_image(
+    pay_load: &str,
+    format: BatchFormat,
+    options: QRCodeRenderOptions,
+) -> Result<Vec<u8>, PromptPayServiceError> {
+    let result = match format {
+        BatchFormat::Svg => qrcode_generator::to_svg_to_string_from_str(
+            pay_load,
+            options.ecc,
+            options.size,
+            None::<&str>,
+        )
+        .map(String::into_bytes),
+        _ => qrcode_generator::to_png_to_vec_from_str(pay_load, options.ecc, options.size),
+    };
+    result.map_err(|e| {
+        error!("QRCode render error: {}", e);
+        PromptPayServiceError::InternalError
+    })
+}
+
+fn zip_error(e: zip::result::ZipError) -> PromptPayServiceError {
+    error!("Batch zip error: {}", e);
+    PromptPayServiceError::InternalError
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Read;
+
+    use zip::ZipArchive;
+
+    use super::*;
+
+    const CSV: &str = "merchant_id,mobile_number,merchant_name,transaction_amount
+M001,0809729900,test,50
+M002,08097X9900,bad mobile,50
+M001,0809729900,duplicate,50
+M003,0809729901,another,not-a-number
+M004,0809729902,second,20
+";
+
+    #[test]
+    fn test_batch_reports_row_errors() {
+        let (rows, errors) = parse_csv(CSV.as_bytes());
+        let mut batch = generate_batch(rows);
+        batch.errors.extend(errors);
+        batch.errors.sort_by_key(|e| e.row);
+
+        let ids: Vec<&str> = batch
+            .qr_codes
+            .iter()
+            .map(|q| q.merchant_id.as_str())
+            .collect();
+        assert_eq!(ids, vec!["M001", "M004"]);
+        let rows: Vec<usize> = batch.errors.iter().map(|e| e.row).collect();
+        assert_eq!(rows, vec![2, 3, 4]);
+        assert_eq!(
+            batch.qr_codes[0].pay_load,
+            "00020201021129370016A000000677010114011300008097299005204531153037645402505802TH5904test6007Bangkok610510240630443DC"
+        );
+    }
+
+    #[test]
+    fn test_batch_zip_contains_images_and_manifest() {
+        let (rows, _) = parse_csv(CSV.as_bytes());
+        let batch = generate_batch(rows);
+        let bytes = build_zip(batch, BatchFormat::Svg, QRCodeRenderOptions::default()).unwrap();
+
+        let mut archive = ZipArchive::new(Cursor::new(bytes)).unwrap();
+        let mut names: Vec<String> = archive.file_names().map(String::from).collect();
+        names.sort();
+        assert_eq!(
+            names,
+            vec!["M001.svg", "M004.svg", BATCH_MANIFEST_FILE_NAME]
+        );
+
+        let mut manifest = String::new();
+        archive
+            .by_name(BATCH_MANIFEST_FILE_NAME)
+            .unwrap()
+            .read_to_string(&mut manifest)
+            .unwrap();
+        let manifest: serde_json::Value = serde_json::from_str(&manifest).unwrap();
+        assert_eq!(manifest["items"].as_array().unwrap().len(), 2);
+        assert_eq!(manifest["errors"].as_array().unwrap().len(), 2);
+    }
+}
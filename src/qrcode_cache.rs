@@ -0,0 +1,233 @@
+#![allow(dead_code)]
+
+use std::hash::{Hash, Hasher};
+use std::sync::{Arc, Mutex};
+
+use crc::{Crc, CRC_64_ECMA_182};
+use lru::LruCache;
+use opentelemetry::global;
+use opentelemetry::metrics::Counter;
+use opentelemetry::KeyValue;
+use qrcode_generator::QrCodeEcc;
+
+pub const DEFAULT_QRCODE_CACHE_MAX_BYTES: usize = 64 * 1024 * 1024;
+pub const DEFAULT_QRCODE_SIZE: usize = 320;
+
+const ETAG_CRC: Crc<u64> = Crc::<u64>::new(&CRC_64_ECMA_182);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct QRCodeRenderOptions {
+    pub ecc: QrCodeEcc,
+    pub size: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct QRCodeCacheKey {
+    payload: String,
+    options: QRCodeRenderOptions,
+}
+
+#[derive(Debug)]
+pub struct CachedQRCode {
+    pub qrcode_base64: String,
+    pub etag: String,
+}
+
+/// LRU cache of rendered QR images, bounded by the total size of the cached
+/// base64 bodies rather than by entry count.
+pub struct QRCodeCache {
+    entries: Mutex<CacheEntries>,
+    max_bytes: usize,
+    hits: Counter<u64>,
+    misses: Counter<u64>,
+}
+
+struct CacheEntries {
+    lru: LruCache<QRCodeCacheKey, Arc<CachedQRCode>>,
+    bytes: usize,
+}
+
+impl Default for QRCodeRenderOptions {
+    fn default() -> Self {
+        QRCodeRenderOptions {
+            ecc: QrCodeEcc::Low,
+            size: DEFAULT_QRCODE_SIZE,
+        }
+    }
+}
+impl Hash for QRCodeRenderOptions {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        (self.ecc as u8).hash(state);
+        self.size.hash(state);
+    }
+}
+
+impl QRCodeCacheKey {
+    pub fn new(payload: String, options: QRCodeRenderOptions) -> Self {
+        QRCodeCacheKey { payload, options }
+    }
+    pub fn payload(&self) -> &str {
+        &self.payload
+    }
+    pub fn options(&self) -> QRCodeRenderOptions {
+        self.options
+    }
+    /// Opaque strong entity tag derived from the payload and render options,
+    /// so it can be computed before the image is rendered.
+    pub fn etag(&self) -> String {
+        let mut digest = ETAG_CRC.digest();
+        digest.update(self.payload.as_bytes());
+        digest.update(&[self.options.ecc as u8]);
+        digest.update(&(self.options.size as u64).to_be_bytes());
+        format!("{:016x}", digest.finalize())
+    }
+}
+
+impl CachedQRCode {
+    fn weight(&self) -> usize {
+        self.qrcode_base64.len() + self.etag.len()
+    }
+}
+
+impl QRCodeCache {
+    pub fn new(max_bytes: usize) -> Self {
+        let meter = global::meter("prompt_pay_service");
+        QRCodeCache {
+            entries: Mutex::new(CacheEntries {
+                lru: LruCache::unbounded(),
+                bytes: 0,
+            }),
+            max_bytes,
+            hits: meter
+                .u64_counter("qrcode_cache_hits")
+                .with_description("Rendered QR code cache hits")
+                .init(),
+            misses: meter
+                .u64_counter("qrcode_cache_misses")
+                .with_description("Rendered QR code cache misses")
+                .init(),
+        }
+    }
+
+    pub fn from_env() -> Self {
+        let max_bytes = std::env::var("QRCODE_CACHE_MAX_BYTES")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(DEFAULT_QRCODE_CACHE_MAX_BYTES);
+        QRCodeCache::new(max_bytes)
+    }
+
+    pub fn get(&self, key: &QRCodeCacheKey) -> Option<Arc<CachedQRCode>> {
+        let result = self.entries.lock().unwrap().lru.get(key).cloned();
+        let attributes = [KeyValue::new("size", key.options.size as i64)];
+        if result.is_some() {
+            self.hits.add(1, &attributes);
+        } else {
+            self.misses.add(1, &attributes);
+        }
+        result
+    }
+
+    /// Returns the cached image for `key`, rendering it with `render` on a miss.
+    /// Rendering happens outside the lock so concurrent misses don't serialize.
+    pub fn get_or_render<F, E>(
+        &self,
+        key: QRCodeCacheKey,
+        render: F,
+    ) -> Result<Arc<CachedQRCode>, E>
+    where
+        F: FnOnce(&str, QRCodeRenderOptions) -> Result<String, E>,
+    {
+        if let Some(cached) = self.get(&key) {
+            return Ok(cached);
+        }
+        let cached = Arc::new(CachedQRCode {
+            qrcode_base64: render(key.payload(), key.options())?,
+            etag: key.etag(),
+        });
+        self.insert(key, cached.clone());
+        Ok(cached)
+    }
+
+    fn insert(&self, key: QRCodeCacheKey, value: Arc<CachedQRCode>) {
+        let weight = value.weight() + key.payload.len();
+        if weight > self.max_bytes {
+            return;
+        }
+        let mut entries = self.entries.lock().unwrap();
+        if let Some((old_key, old_value)) = entries.lru.push(key, value) {
+            entries.bytes -= old_value.weight() + old_key.payload.len();
+        }
+        entries.bytes += weight;
+        while entries.bytes > self.max_bytes {
+            match entries.lru.pop_lru() {
+                Some((k, v)) => entries.bytes -= v.weight() + k.payload.len(),
+                None => break,
+            }
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.lock().unwrap().lru.len()
+    }
+
+    pub fn size_in_bytes(&self) -> usize {
+        self.entries.lock().unwrap().bytes
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn render(payload: &str, _options: QRCodeRenderOptions) -> Result<String, ()> {
+        Ok(payload.repeat(4))
+    }
+
+    #[test]
+    fn test_cache_hit_returns_same_image() {
+        let cache = QRCodeCache::new(1024);
+        let key = QRCodeCacheKey::new("0002010102".to_string(), QRCodeRenderOptions::default());
+        let first = cache.get_or_render(key.clone(), render).unwrap();
+        let second = cache
+            .get_or_render(key, |_, _| -> Result<String, ()> {
+                panic!("rendered twice")
+            })
+            .unwrap();
+        assert!(Arc::ptr_eq(&first, &second));
+        assert_eq!(cache.len(), 1);
+    }
+
+    #[test]
+    fn test_cache_evicts_least_recently_used_by_size() {
+        let cache = QRCodeCache::new(300);
+        let options = QRCodeRenderOptions::default();
+        let a = QRCodeCacheKey::new("a".repeat(20), options);
+        let b = QRCodeCacheKey::new("b".repeat(20), options);
+        let c = QRCodeCacheKey::new("c".repeat(20), options);
+        cache.get_or_render(a.clone(), render).unwrap();
+        cache.get_or_render(b.clone(), render).unwrap();
+        cache.get(&a);
+        cache.get_or_render(c.clone(), render).unwrap();
+
+        assert!(cache.get(&a).is_some());
+        assert!(cache.get(&b).is_none());
+        assert!(cache.get(&c).is_some());
+        assert!(cache.size_in_bytes() <= 300);
+    }
+
+    #[test]
+    fn test_etag_depends_on_render_options() {
+        let payload = "0002010102".to_string();
+        let small = QRCodeCacheKey::new(payload.clone(), QRCodeRenderOptions::default());
+        let large = QRCodeCacheKey::new(
+            payload,
+            QRCodeRenderOptions {
+                ecc: QrCodeEcc::Low,
+                size: 640,
+            },
+        );
+        assert_ne!(small.etag(), large.etag());
+        assert_eq!(small.etag(), small.clone().etag());
+    }
+}
@@ -0,0 +1,188 @@
+#![allow(dead_code)]
+
+use std::fmt::Write;
+
+// A4 in PDF points (1/72 inch)
+const PAGE_WIDTH: f32 = 595.0;
+const PAGE_HEIGHT: f32 = 842.0;
+const QR_SIZE: f32 = 360.0;
+const QR_QUIET_ZONE_MODULES: usize = 4;
+const FONT_SIZE: f32 = 14.0;
+const LINE_HEIGHT: f32 = 20.0;
+
+/// One printable page: a QR code matrix with caption lines underneath.
+pub struct PdfQrPage {
+    pub matrix: Vec<Vec<bool>>,
+    pub captions: Vec<String>,
+}
+
+/// Minimal PDF 1.4 writer for printable QR sheets. QR modules are drawn as
+/// filled rectangles so the output stays vector and needs no image codec.
+pub struct PdfQrDocument {
+    pages: Vec<String>,
+}
+
+impl PdfQrDocument {
+    pub fn new() -> Self {
+        PdfQrDocument { pages: vec![] }
+    }
+
+    pub fn add_qr_page(&mut self, page: &PdfQrPage) {
+        let mut content = String::new();
+        let modules = page.matrix.len() + QR_QUIET_ZONE_MODULES * 2;
+        if !page.matrix.is_empty() {
+            let module_size = QR_SIZE / modules as f32;
+            let origin_x = (PAGE_WIDTH - QR_SIZE) / 2.0;
+            let origin_y = PAGE_HEIGHT - 120.0;
+            content.push_str("0 g\n");
+            for (y, row) in page.matrix.iter().enumerate() {
+                for (x, dark) in row.iter().enumerate() {
+                    if *dark {
+                        let _ = writeln!(
+                            content,
+                            "{:.3} {:.3} {:.3} {:.3} re",
+                            origin_x + (x + QR_QUIET_ZONE_MODULES) as f32 * module_size,
+                            origin_y - (y + QR_QUIET_ZONE_MODULES + 1) as f32 * module_size,
+                            module_size,
+                            module_size
+                        );
+                    }
+                }
+            }
+            content.push_str("f\n");
+        }
+        self.push_text_page(
+            content,
+            &page.captions,
+            PAGE_HEIGHT - 120.0 - QR_SIZE - 40.0,
+        );
+    }
+
+    pub fn add_text_page(&mut self, lines: &[String]) {
+        self.push_text_page(String::new(), lines, PAGE_HEIGHT - 72.0);
+    }
+
+    fn push_text_page(&mut self, mut content: String, lines: &[String], top: f32) {
+        if !lines.is_empty() {
+            let _ = writeln!(content, "BT\n/F1 {} Tf\n72 {:.3} Td", FONT_SIZE, top);
+            for (index, line) in lines.iter().enumerate() {
+                if index > 0 {
+                    let _ = writeln!(content, "0 {:.3} Td", -LINE_HEIGHT);
+                }
+                let _ = writeln!(content, "({}) Tj", escape_text(line));
+            }
+            content.push_str("ET\n");
+        }
+        self.pages.push(content);
+    }
+
+    pub fn page_count(&self) -> usize {
+        self.pages.len()
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        // Object layout: 1 catalog, 2 page tree, 3 font, then a page object
+        // followed by its content stream for every page.
+        let mut out: Vec<u8> = b"%PDF-1.4\n".to_vec();
+        let mut offsets: Vec<usize> = vec![];
+        let kids: Vec<String> = (0..self.pages.len())
+            .map(|index| format!("{} 0 R", 4 + index * 2))
+            .collect();
+
+        let mut objects: Vec<String> = vec![
+            "<< /Type /Catalog /Pages 2 0 R >>".to_string(),
+            format!(
+                "<< /Type /Pages /Kids [{}] /Count {} >>",
+                kids.join(" "),
+                self.pages.len()
+            ),
+            "<< /Type /Font /Subtype /Type1 /BaseFont /Helvetica >>".to_string(),
+        ];
+        for (index, content) in self.pages.iter().enumerate() {
+            objects.push(format!(
+                "<< /Type /Page /Parent 2 0 R /MediaBox [0 0 {} {}] /Resources << /Font << /F1 3 0 R >> >> /Contents {} 0 R >>",
+                PAGE_WIDTH,
+                PAGE_HEIGHT,
+                5 + index * 2
+            ));
+            objects.push(format!(
+                "<< /Length {} >>\nstream\n{}endstream",
+                content.len(),
+                content
+            ));
+        }
+
+        for (index, object) in objects.iter().enumerate() {
+            offsets.push(out.len());
+            out.extend_from_slice(format!("{} 0 obj\n{}\nendobj\n", index + 1, object).as_bytes());
+        }
+
+        let xref_offset = out.len();
+        let mut trailer = format!("xref\n0 {}\n0000000000 65535 f \n", objects.len() + 1);
+        for offset in offsets {
+            let _ = writeln!(trailer, "{:010} 00000 n ", offset);
+        }
+        let _ = write!(
+            trailer,
+            "trailer\n<< /Size {} /Root 1 0 R >>\nstartxref\n{}\n%%EOF\n",
+            objects.len() + 1,
+            xref_offset
+        );
+        out.extend_from_slice(trailer.as_bytes());
+        out
+    }
+}
+
+impl Default for PdfQrDocument {
+    fn default() -> Self {
+        PdfQrDocument::new()
+    }
+}
+
+// Helvetica here only covers WinAnsi, anything else is replaced.
+fn escape_text(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '(' | ')' | '\\' => {
+                escaped.push('\\');
+                escaped.push(c);
+            }
+            ' '..='~' => escaped.push(c),
+            _ => escaped.push('?'),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_pdf_has_one_page_per_qr_code() {
+        let mut document = PdfQrDocument::new();
+        for name in ["A (1)", "B"] {
+            document.add_qr_page(&PdfQrPage {
+                matrix: vec![vec![true, false], vec![false, true]],
+                captions: vec![name.to_string()],
+            });
+        }
+        let pdf = String::from_utf8(document.to_bytes()).unwrap();
+        assert!(pdf.starts_with("%PDF-1.4"));
+        assert!(pdf.contains("/Count 2"));
+        assert!(pdf.contains("(A \\(1\\)) Tj"));
+        assert!(pdf.trim_end().ends_with("%%EOF"));
+
+        let xref_offset: usize = pdf
+            .rsplit("startxref\n")
+            .next()
+            .unwrap()
+            .lines()
+            .next()
+            .unwrap()
+            .parse()
+            .unwrap();
+        assert!(pdf[xref_offset..].starts_with("xref"));
+    }
+}
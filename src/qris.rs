@@ -0,0 +1,610 @@
+#![allow(dead_code)]
+
+use std::convert::TryFrom;
+
+use serde::{Deserialize, Serialize};
+
+use crate::emvo_qrcode::*;
+use crate::emvo_types::*;
+
+pub const IDR: CurrencyCode = "360";
+pub const INDONESIA: CountryCode = "ID";
+
+/// QRIS merchant accounts (IDs 26-45), one per payment provider (PJSP).
+pub const ID_QRIS_MERCHANT_ACCOUNT_RANGE_START: TagID = TagID::new(26);
+pub const ID_QRIS_MERCHANT_ACCOUNT_RANGE_END: TagID = TagID::new(45);
+/// The QRIS domestic central repository template carrying the NMID.
+pub const ID_QRIS_NATIONAL_MERCHANT_ID: TagID = TagID::new(51);
+pub const QRIS_GLOBALLY_UNIQUE_IDENTIFIER: &str = "ID.CO.QRIS.WWW";
+
+pub const ID_QRIS_GLOBALLY_UNIQUE_IDENTIFIER: TagID = TagID::new(0);
+pub const ID_QRIS_MERCHANT_PAN: TagID = TagID::new(1);
+pub const ID_QRIS_MERCHANT_ID: TagID = TagID::new(2);
+pub const ID_QRIS_MERCHANT_CRITERIA: TagID = TagID::new(3);
+/// Tag 51 keeps the NMID in sub-tag 02 and leaves 01 unused.
+pub const ID_QRIS_NMID: TagID = TagID::new(2);
+
+const MAX_LENGTH_GLOBALLY_UNIQUE_IDENTIFIER: usize = 32;
+const MAX_LENGTH_MERCHANT_ID: usize = 15;
+
+/// Bank Indonesia's merchant size classes: micro, small, medium and large.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "UPPERCASE")]
+pub enum QrisMerchantCriteria {
+    Umi,
+    Uke,
+    Ume,
+    Ube,
+}
+
+/// A payment provider's account for the merchant (IDs 26-45).
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct QrisMerchantAccount {
+    /// The provider's reverse domain, e.g. `ID.CO.BANKXYZ.WWW`.
+    pub globally_unique_identifier: String,
+    /// Merchant PAN, 16 to 19 digits.
+    pub merchant_pan: String,
+    pub merchant_id: String,
+}
+
+/// Tip or convenience fee (IDs 55-57). Fees are in whole rupiah.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum QrisConvenienceFee {
+    /// The payer is prompted to enter a tip.
+    Prompt,
+    Fixed(String),
+    Percentage(String),
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct QrisProfile {
+    pub merchant_accounts: Vec<QrisMerchantAccount>,
+    /// National Merchant ID, `ID` and 13 digits.
+    pub national_merchant_id: String,
+    pub merchant_criteria: QrisMerchantCriteria,
+    pub merchant_category_code: String,
+    pub merchant_name: String,
+    pub merchant_city: String,
+    pub postal_code: String,
+    #[serde(default)]
+    pub transaction_amount: Option<String>,
+    #[serde(default)]
+    pub convenience_fee: Option<QrisConvenienceFee>,
+}
+
+/// One QRIS rule a payload breaks, with the data object it concerns.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct QrisViolation {
+    pub tag_id: Option<TagID>,
+    pub message: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct QrisValidation {
+    pub compliant: bool,
+    pub violations: Vec<QrisViolation>,
+}
+
+impl QrisMerchantCriteria {
+    pub fn value(self) -> &'static str {
+        match self {
+            QrisMerchantCriteria::Umi => "UMI",
+            QrisMerchantCriteria::Uke => "UKE",
+            QrisMerchantCriteria::Ume => "UME",
+            QrisMerchantCriteria::Ube => "UBE",
+        }
+    }
+    pub fn from_value(value: &str) -> Option<Self> {
+        match value {
+            "UMI" => Some(QrisMerchantCriteria::Umi),
+            "UKE" => Some(QrisMerchantCriteria::Uke),
+            "UME" => Some(QrisMerchantCriteria::Ume),
+            "UBE" => Some(QrisMerchantCriteria::Ube),
+            _ => None,
+        }
+    }
+}
+
+fn set_text(
+    template: &mut EMVTemplate,
+    tag_id: TagID,
+    value: &str,
+    length: usize,
+) -> Result<(), EMVOError> {
+    template.set_field(
+        tag_id,
+        Box::new(EMVAlphanumbericSpecial::try_from(value.to_string())?),
+        length,
+    )
+}
+
+impl QrisMerchantAccount {
+    pub fn to_template(
+        &self,
+        tag_id: TagID,
+        criteria: QrisMerchantCriteria,
+    ) -> Result<EMVTemplate, EMVOError> {
+        let mut template = EMVTemplate::new(tag_id)?;
+        set_text(
+            &mut template,
+            ID_QRIS_GLOBALLY_UNIQUE_IDENTIFIER,
+            &self.globally_unique_identifier,
+            MAX_LENGTH_GLOBALLY_UNIQUE_IDENTIFIER,
+        )?;
+        template.set_field(
+            ID_QRIS_MERCHANT_PAN,
+            Box::new(EMVNumeric::try_from(self.merchant_pan.clone())?),
+            19,
+        )?;
+        set_text(
+            &mut template,
+            ID_QRIS_MERCHANT_ID,
+            &self.merchant_id,
+            MAX_LENGTH_MERCHANT_ID,
+        )?;
+        set_text(
+            &mut template,
+            ID_QRIS_MERCHANT_CRITERIA,
+            criteria.value(),
+            3,
+        )?;
+        Ok(template)
+    }
+}
+
+impl QrisProfile {
+    /// Generates the payload and refuses it unless it passes
+    /// [`validate_pay_load`].
+    pub fn generate_pay_load(&self) -> Result<String, EMVOError> {
+        let account_count = ID_QRIS_MERCHANT_ACCOUNT_RANGE_END.value()
+            - ID_QRIS_MERCHANT_ACCOUNT_RANGE_START.value()
+            + 1;
+        if self.merchant_accounts.len() > account_count as usize {
+            return Err(EMVOError::new("QRIS Allows At Most 20 Merchant Accounts"));
+        }
+        let mut national_merchant_id = EMVTemplate::new(ID_QRIS_NATIONAL_MERCHANT_ID)?;
+        set_text(
+            &mut national_merchant_id,
+            ID_QRIS_GLOBALLY_UNIQUE_IDENTIFIER,
+            QRIS_GLOBALLY_UNIQUE_IDENTIFIER,
+            MAX_LENGTH_GLOBALLY_UNIQUE_IDENTIFIER,
+        )?;
+        set_text(
+            &mut national_merchant_id,
+            ID_QRIS_NMID,
+            &self.national_merchant_id,
+            15,
+        )?;
+        set_text(
+            &mut national_merchant_id,
+            ID_QRIS_MERCHANT_CRITERIA,
+            self.merchant_criteria.value(),
+            3,
+        )?;
+        let point_type = match self.transaction_amount {
+            Some(_) => DYNAMIC_POINT,
+            None => STATIC_POINT,
+        };
+        let mut builder = EMVQR::builder()
+            .payload_format_indicator("01")
+            .point_of_initiation_method(point_type)
+            .merchant_account_information(
+                ID_QRIS_NATIONAL_MERCHANT_ID,
+                Box::new(national_merchant_id),
+            );
+        for (index, account) in self.merchant_accounts.iter().enumerate() {
+            let tag_id = TagID::new(ID_QRIS_MERCHANT_ACCOUNT_RANGE_START.value() + index as u8);
+            builder = builder.merchant_account_information(
+                tag_id,
+                Box::new(account.to_template(tag_id, self.merchant_criteria)?),
+            );
+        }
+        let mut builder = builder
+            .merchant_category_code(self.merchant_category_code.clone())
+            .transaction_currency(IDR)
+            .country_code(INDONESIA)
+            .merchant_name(self.merchant_name.clone())
+            .merchant_city(self.merchant_city.clone())
+            .postal_code(self.postal_code.clone());
+        if let Some(amount) = &self.transaction_amount {
+            builder = builder.transaction_amount(amount.clone());
+        }
+        builder = match &self.convenience_fee {
+            None => builder,
+            Some(QrisConvenienceFee::Prompt) => builder.tip_or_convenience_indicator("01"),
+            Some(QrisConvenienceFee::Fixed(fee)) => builder
+                .tip_or_convenience_indicator("02")
+                .value_of_convenience_fee_fixed(fee.clone()),
+            Some(QrisConvenienceFee::Percentage(fee)) => builder
+                .tip_or_convenience_indicator("03")
+                .value_of_convenience_fee_percentage(fee.clone()),
+        };
+        let pay_load = builder.build()?.into_pay_load();
+        if let Some(violation) = validate_pay_load(&pay_load)?.violations.first() {
+            return Err(EMVOError::new(violation.message.as_str()));
+        }
+        Ok(pay_load)
+    }
+}
+
+struct Violations(Vec<QrisViolation>);
+
+impl Violations {
+    fn flag(&mut self, tag_id: Option<TagID>, message: &str) {
+        self.0.push(QrisViolation {
+            tag_id,
+            message: message.to_string(),
+        });
+    }
+}
+
+fn find(objects: &[(TagID, Data)], tag_id: TagID) -> Option<&str> {
+    objects
+        .iter()
+        .find(|(id, _)| *id == tag_id)
+        .map(|(_, value)| value.as_str())
+}
+
+fn is_digits(value: &str) -> bool {
+    !value.is_empty() && value.chars().all(|c| c.is_ascii_digit())
+}
+
+/// Reverse domain names such as `ID.CO.QRIS.WWW`: at least two uppercase
+/// alphanumeric labels separated by dots.
+fn is_reverse_domain(value: &str) -> bool {
+    value.len() <= MAX_LENGTH_GLOBALLY_UNIQUE_IDENTIFIER
+        && value.split('.').count() >= 2
+        && value.split('.').all(|label| {
+            !label.is_empty()
+                && label
+                    .chars()
+                    .all(|c| c.is_ascii_uppercase() || c.is_ascii_digit() || c == '-')
+        })
+}
+
+/// Rupiah amounts have no minor units, so only whole numbers are accepted.
+fn is_whole_rupiah(value: &str) -> bool {
+    is_digits(value) && !value.trim_start_matches('0').is_empty()
+}
+
+fn is_percentage(value: &str) -> bool {
+    match value.parse::<f64>() {
+        Ok(percentage) => {
+            value.chars().all(|c| c.is_ascii_digit() || c == '.')
+                && percentage > 0.0
+                && percentage < 100.0
+        }
+        Err(_) => false,
+    }
+}
+
+fn check_criteria(
+    violations: &mut Violations,
+    tag_id: TagID,
+    template: &[(TagID, Data)],
+) -> Option<QrisMerchantCriteria> {
+    let criteria = find(template, ID_QRIS_MERCHANT_CRITERIA);
+    let parsed = criteria.and_then(QrisMerchantCriteria::from_value);
+    if parsed.is_none() {
+        violations.flag(
+            Some(tag_id),
+            "Merchant Criteria Must Be UMI, UKE, UME Or UBE",
+        );
+    }
+    parsed
+}
+
+/// Checks a payload against the QRIS rules and lists every rule it breaks.
+/// Fails only when the payload itself cannot be read (e.g. bad CRC).
+pub fn validate_pay_load(pay_load: &str) -> Result<QrisValidation, EMVOError> {
+    let objects = parse_pay_load(pay_load)?;
+    let mut violations = Violations(Vec::new());
+
+    if find(&objects, ID_PAYLOAD_FORMAT_INDICATOR) != Some("01") {
+        violations.flag(
+            Some(ID_PAYLOAD_FORMAT_INDICATOR),
+            "Payload Format Indicator Must Be 01",
+        );
+    }
+    let point_of_initiation = find(&objects, ID_POINT_OF_INITIATION_METHOD);
+    let amount = find(&objects, ID_TRANSACTION_AMOUNT);
+    match (point_of_initiation, amount) {
+        (Some("11"), Some(_)) => violations.flag(
+            Some(ID_TRANSACTION_AMOUNT),
+            "Static QRIS Must Not Carry An Amount",
+        ),
+        (Some("12"), None) => violations.flag(
+            Some(ID_TRANSACTION_AMOUNT),
+            "Dynamic QRIS Must Carry An Amount",
+        ),
+        (Some("11"), None) | (Some("12"), Some(_)) => {}
+        _ => violations.flag(
+            Some(ID_POINT_OF_INITIATION_METHOD),
+            "Point Of Initiation Method Must Be 11 Or 12",
+        ),
+    }
+
+    let mut criteria = Vec::new();
+    let accounts: Vec<_> = objects
+        .iter()
+        .filter(|(tag_id, _)| {
+            tag_id.is_between(
+                ID_QRIS_MERCHANT_ACCOUNT_RANGE_START,
+                ID_QRIS_MERCHANT_ACCOUNT_RANGE_END,
+            )
+        })
+        .collect();
+    if accounts.is_empty() {
+        violations.flag(
+            None,
+            "At Least One Merchant Account (IDs 26-45) Is Required",
+        );
+    }
+    for (tag_id, value) in accounts {
+        let template = match parse_data_objects(value) {
+            Ok(template) => template,
+            Err(_) => {
+                violations.flag(Some(*tag_id), "Merchant Account Is Not A Template");
+                continue;
+            }
+        };
+        let guid = find(&template, ID_QRIS_GLOBALLY_UNIQUE_IDENTIFIER).unwrap_or_default();
+        if !is_reverse_domain(guid) {
+            violations.flag(
+                Some(*tag_id),
+                "Globally Unique Identifier Must Be A Reverse Domain",
+            );
+        }
+        let pan = find(&template, ID_QRIS_MERCHANT_PAN).unwrap_or_default();
+        if !is_digits(pan) || !(16..=19).contains(&pan.len()) {
+            violations.flag(Some(*tag_id), "Merchant PAN Must Be 16 To 19 Digits");
+        }
+        let merchant_id = find(&template, ID_QRIS_MERCHANT_ID).unwrap_or_default();
+        if merchant_id.is_empty() || merchant_id.len() > MAX_LENGTH_MERCHANT_ID {
+            violations.flag(Some(*tag_id), "Merchant ID Must Be 1 To 15 Characters");
+        }
+        criteria.extend(check_criteria(&mut violations, *tag_id, &template));
+    }
+
+    match find(&objects, ID_QRIS_NATIONAL_MERCHANT_ID).map(parse_data_objects) {
+        None => violations.flag(
+            Some(ID_QRIS_NATIONAL_MERCHANT_ID),
+            "National Merchant ID Template Is Required",
+        ),
+        Some(Err(_)) => violations.flag(
+            Some(ID_QRIS_NATIONAL_MERCHANT_ID),
+            "National Merchant ID Template Is Not A Template",
+        ),
+        Some(Ok(template)) => {
+            if find(&template, ID_QRIS_GLOBALLY_UNIQUE_IDENTIFIER)
+                != Some(QRIS_GLOBALLY_UNIQUE_IDENTIFIER)
+            {
+                violations.flag(
+                    Some(ID_QRIS_NATIONAL_MERCHANT_ID),
+                    "Globally Unique Identifier Must Be ID.CO.QRIS.WWW",
+                );
+            }
+            let nmid = find(&template, ID_QRIS_NMID).unwrap_or_default();
+            if nmid.len() != 15 || !nmid.starts_with("ID") || !is_digits(&nmid[2..]) {
+                violations.flag(
+                    Some(ID_QRIS_NATIONAL_MERCHANT_ID),
+                    "National Merchant ID Must Be ID And 13 Digits",
+                );
+            }
+            criteria.extend(check_criteria(
+                &mut violations,
+                ID_QRIS_NATIONAL_MERCHANT_ID,
+                &template,
+            ));
+        }
+    }
+    if criteria.windows(2).any(|pair| pair[0] != pair[1]) {
+        violations.flag(None, "Merchant Criteria Differ Between Templates");
+    }
+
+    if !find(&objects, ID_MERCHANT_CATEGORY_CODE)
+        .is_some_and(|mcc| is_digits(mcc) && mcc.len() == 4)
+    {
+        violations.flag(
+            Some(ID_MERCHANT_CATEGORY_CODE),
+            "Merchant Category Code Must Be 4 Digits",
+        );
+    }
+    if find(&objects, ID_TRANSACTION_CURRENCY) != Some(IDR) {
+        violations.flag(
+            Some(ID_TRANSACTION_CURRENCY),
+            "Transaction Currency Must Be 360",
+        );
+    }
+    if let Some(amount) = amount {
+        if !is_whole_rupiah(amount) {
+            violations.flag(
+                Some(ID_TRANSACTION_AMOUNT),
+                "Transaction Amount Must Be Whole Rupiah",
+            );
+        }
+    }
+
+    // The indicator decides which fee may be present: "01" prompts for a
+    // tip, "02" needs a fixed fee and "03" a percentage, never both
+    let fee_fixed = find(&objects, ID_VALUE_OF_CONVENIENCE_FEE_FIXED);
+    let fee_percentage = find(&objects, ID_VALUE_OF_CONVENIENCE_FEE_PERCENTAGE);
+    match find(&objects, ID_TIP_OR_CONVENIENCE_INDICATOR) {
+        None | Some("01") => {
+            if fee_fixed.is_some() || fee_percentage.is_some() {
+                violations.flag(
+                    Some(ID_TIP_OR_CONVENIENCE_INDICATOR),
+                    "Convenience Fee Requires Indicator 02 Or 03",
+                );
+            }
+        }
+        Some("02") => {
+            if !fee_fixed.is_some_and(is_whole_rupiah) {
+                violations.flag(
+                    Some(ID_VALUE_OF_CONVENIENCE_FEE_FIXED),
+                    "Fixed Convenience Fee Must Be Whole Rupiah",
+                );
+            }
+            if fee_percentage.is_some() {
+                violations.flag(
+                    Some(ID_VALUE_OF_CONVENIENCE_FEE_PERCENTAGE),
+                    "Indicator 02 Does Not Allow A Percentage Fee",
+                );
+            }
+        }
+        Some("03") => {
+            if !fee_percentage.is_some_and(is_percentage) {
+                violations.flag(
+                    Some(ID_VALUE_OF_CONVENIENCE_FEE_PERCENTAGE),
+                    "Percentage Convenience Fee Must Be Between 0 And 100",
+                );
+            }
+            if fee_fixed.is_some() {
+                violations.flag(
+                    Some(ID_VALUE_OF_CONVENIENCE_FEE_FIXED),
+                    "Indicator 03 Does Not Allow A Fixed Fee",
+                );
+            }
+        }
+        Some(_) => violations.flag(
+            Some(ID_TIP_OR_CONVENIENCE_INDICATOR),
+            "Tip Or Convenience Indicator Must Be 01, 02 Or 03",
+        ),
+    }
+
+    if find(&objects, ID_COUNTRY_CODE) != Some(INDONESIA) {
+        violations.flag(Some(ID_COUNTRY_CODE), "Country Code Must Be ID");
+    }
+    for (tag_id, name) in [
+        (ID_MERCHANT_NAME, "Merchant Name Is Required"),
+        (ID_MERCHANT_CITY, "Merchant City Is Required"),
+        (ID_POSTAL_CODE, "Postal Code Is Required"),
+    ] {
+        if find(&objects, tag_id).is_none_or(str::is_empty) {
+            violations.flag(Some(tag_id), name);
+        }
+    }
+
+    Ok(QrisValidation {
+        compliant: violations.0.is_empty(),
+        violations: violations.0,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn profile() -> QrisProfile {
+        QrisProfile {
+            merchant_accounts: vec![QrisMerchantAccount {
+                globally_unique_identifier: "ID.CO.BANKXYZ.WWW".to_string(),
+                merchant_pan: "9360001234567890123".to_string(),
+                merchant_id: "000123456789".to_string(),
+            }],
+            national_merchant_id: "ID1020021181745".to_string(),
+            merchant_criteria: QrisMerchantCriteria::Umi,
+            merchant_category_code: "5812".to_string(),
+            merchant_name: "WARUNG BU SRI".to_string(),
+            merchant_city: "JAKARTA".to_string(),
+            postal_code: "10110".to_string(),
+            transaction_amount: None,
+            convenience_fee: None,
+        }
+    }
+
+    fn messages(pay_load: &str) -> Vec<String> {
+        validate_pay_load(pay_load)
+            .unwrap()
+            .violations
+            .into_iter()
+            .map(|violation| violation.message)
+            .collect()
+    }
+
+    #[test]
+    fn test_qris_pay_load() {
+        let data = "00020101021126670017ID.CO.BANKXYZ.WWW011993600012345678901230212000123456789\
+                    0303UMI51440014ID.CO.QRIS.WWW0215ID10200211817450303UMI5204581253033605802ID\
+                    5913WARUNG BU SRI6007JAKARTA6105101106304";
+        let sample = format!("{}{}", data, crc16(data));
+        assert_eq!(profile().generate_pay_load().unwrap(), sample);
+        assert_eq!(
+            validate_pay_load(&sample).unwrap(),
+            QrisValidation {
+                compliant: true,
+                violations: vec![],
+            }
+        );
+
+        let dynamic = QrisProfile {
+            transaction_amount: Some("15000".to_string()),
+            convenience_fee: Some(QrisConvenienceFee::Fixed("1000".to_string())),
+            ..profile()
+        };
+        let pay_load = dynamic.generate_pay_load().unwrap();
+        assert!(pay_load.starts_with("000201010212"));
+        assert!(pay_load.contains("540515000550202560410005802ID"));
+    }
+
+    #[test]
+    fn test_qris_generate_rejects_non_compliant_profile() {
+        let fractional = QrisProfile {
+            transaction_amount: Some("15000.50".to_string()),
+            ..profile()
+        };
+        assert!(fractional.generate_pay_load().is_err());
+        let nmid = QrisProfile {
+            national_merchant_id: "1020021181745".to_string(),
+            ..profile()
+        };
+        assert!(nmid.generate_pay_load().is_err());
+        let percentage = QrisProfile {
+            convenience_fee: Some(QrisConvenienceFee::Percentage("150".to_string())),
+            ..profile()
+        };
+        assert!(percentage.generate_pay_load().is_err());
+    }
+
+    #[test]
+    fn test_qris_validator_flags_violations() {
+        let mut objects = parse_pay_load(&profile().generate_pay_load().unwrap()).unwrap();
+        upsert_data_object(&mut objects, ID_TRANSACTION_CURRENCY, "764".to_string());
+        upsert_data_object(&mut objects, ID_TRANSACTION_AMOUNT, "100.50".to_string());
+        upsert_data_object(
+            &mut objects,
+            ID_VALUE_OF_CONVENIENCE_FEE_FIXED,
+            "500".to_string(),
+        );
+        upsert_data_object(
+            &mut objects,
+            ID_QRIS_NATIONAL_MERCHANT_ID,
+            "0014ID.CO.QRIS.WWW0215ID10200211817450303UXX".to_string(),
+        );
+        objects.retain(|(tag_id, _)| *tag_id != ID_POSTAL_CODE);
+        let pay_load = encode_pay_load(&objects).unwrap();
+        assert_eq!(
+            messages(&pay_load),
+            vec![
+                "Static QRIS Must Not Carry An Amount",
+                "Merchant Criteria Must Be UMI, UKE, UME Or UBE",
+                "Transaction Currency Must Be 360",
+                "Transaction Amount Must Be Whole Rupiah",
+                "Convenience Fee Requires Indicator 02 Or 03",
+                "Postal Code Is Required",
+            ]
+        );
+
+        let duitnow = "00020101021126400014A000000615000101068900530208M0001234\
+                       5204581253034585802MY5902AB6002KL6304";
+        let duitnow = format!("{}{}", duitnow, crc16(duitnow));
+        let validation = validate_pay_load(&duitnow).unwrap();
+        assert!(!validation.compliant);
+        assert!(validation
+            .violations
+            .iter()
+            .any(|violation| violation.tag_id == Some(ID_QRIS_NATIONAL_MERCHANT_ID)));
+        assert!(validate_pay_load("00020101021163041234").is_err());
+    }
+}
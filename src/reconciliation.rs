@@ -0,0 +1,562 @@
+#![allow(dead_code)]
+
+use std::collections::HashSet;
+
+use log::error;
+use quick_xml::events::Event;
+use quick_xml::Reader;
+use serde::{Deserialize, Serialize};
+
+use crate::apis::PromptPayServiceError;
+use crate::storage::Storage;
+use crate::transactions::{to_satang, TransactionStatus};
+
+const THB: &str = "THB";
+const NOT_PROVIDED: &str = "NOTPROVIDED";
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum StatementFormat {
+    #[default]
+    Camt054,
+    Csv,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ReportFormat {
+    #[default]
+    Json,
+    Csv,
+}
+
+/// One credit on the bank statement. `entry` numbers statement credits from 1
+/// (camt.054 transaction details, or CSV data rows).
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct StatementEntry {
+    pub entry: usize,
+    pub bank_reference: Option<String>,
+    pub references: Vec<String>,
+    pub amount: f64,
+    pub currency: Option<String>,
+    pub booking_date: Option<String>,
+}
+
+/// Column names of a bank's CSV export. Every column but the amount is
+/// optional; rows are matched on any of `reference_columns`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CsvStatementFormat {
+    pub delimiter: u8,
+    pub amount_column: String,
+    pub reference_columns: Vec<String>,
+    pub bank_reference_column: Option<String>,
+    pub date_column: Option<String>,
+    pub currency_column: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ReconciliationCategory {
+    Matched,
+    Unmatched,
+    OverPaid,
+    UnderPaid,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ReconciliationItem {
+    #[serde(flatten)]
+    pub entry: StatementEntry,
+    pub matched_reference: Option<String>,
+    pub merchant_id: Option<String>,
+    pub expected_amount: Option<f64>,
+    pub difference: Option<f64>,
+    pub transaction_status: Option<TransactionStatus>,
+    pub note: Option<String>,
+}
+
+#[derive(Debug, Default, PartialEq, Serialize)]
+pub struct ReconciliationSummary {
+    pub entries: usize,
+    pub matched: usize,
+    pub unmatched: usize,
+    pub over_paid: usize,
+    pub under_paid: usize,
+    pub total_amount: f64,
+}
+
+#[derive(Debug, Default, PartialEq, Serialize)]
+pub struct ReconciliationReport {
+    pub summary: ReconciliationSummary,
+    pub matched: Vec<ReconciliationItem>,
+    pub unmatched: Vec<ReconciliationItem>,
+    pub over_paid: Vec<ReconciliationItem>,
+    pub under_paid: Vec<ReconciliationItem>,
+}
+
+/// Flat row of the CSV report, one per statement entry.
+#[derive(Debug, Serialize)]
+struct ReportRow<'a> {
+    category: ReconciliationCategory,
+    entry: usize,
+    bank_reference: Option<&'a str>,
+    references: String,
+    amount: f64,
+    currency: Option<&'a str>,
+    booking_date: Option<&'a str>,
+    matched_reference: Option<&'a str>,
+    merchant_id: Option<&'a str>,
+    expected_amount: Option<f64>,
+    difference: Option<f64>,
+    transaction_status: Option<TransactionStatus>,
+    note: Option<&'a str>,
+}
+
+impl Default for CsvStatementFormat {
+    fn default() -> Self {
+        CsvStatementFormat {
+            delimiter: b',',
+            amount_column: "amount".to_string(),
+            reference_columns: vec!["ref1".to_string(), "ref2".to_string(), "ref3".to_string()],
+            bank_reference_column: Some("transaction_id".to_string()),
+            date_column: Some("date".to_string()),
+            currency_column: None,
+        }
+    }
+}
+
+impl StatementEntry {
+    fn add_reference(&mut self, reference: &str) {
+        let reference = reference.trim();
+        if !reference.is_empty()
+            && reference != NOT_PROVIDED
+            && !self.references.iter().any(|r| r == reference)
+        {
+            self.references.push(reference.to_string());
+        }
+    }
+}
+
+fn parse_amount(text: &str) -> Result<f64, String> {
+    text.trim()
+        .replace(',', "")
+        .parse::<f64>()
+        .map_err(|_| format!("invalid amount {}", text))
+}
+
+#[derive(Default)]
+struct CamtEntry {
+    credit: bool,
+    amount: Option<f64>,
+    currency: Option<String>,
+    booking_date: Option<String>,
+    bank_reference: Option<String>,
+    details: Vec<StatementEntry>,
+}
+
+fn path_ends_with(path: &[String], suffix: &[&str]) -> bool {
+    path.len() >= suffix.len()
+        && path[path.len() - suffix.len()..]
+            .iter()
+            .zip(suffix)
+            .all(|(a, b)| a == b)
+}
+
+/// Extracts credit entries from an ISO 20022 camt.054 notification. Every
+/// transaction detail becomes one entry; an entry without details is kept
+/// whole. Debits are skipped.
+pub fn parse_camt054(data: &[u8]) -> Result<Vec<StatementEntry>, String> {
+    let mut reader = Reader::from_reader(data);
+    reader.config_mut().trim_text(true);
+    let mut buf = vec![];
+    let mut path: Vec<String> = vec![];
+    let mut currency: Option<String> = None;
+    let mut entry: Option<CamtEntry> = None;
+    let mut detail: Option<StatementEntry> = None;
+    let mut entries = vec![];
+
+    loop {
+        match reader.read_event_into(&mut buf) {
+            Err(e) => {
+                return Err(format!(
+                    "invalid camt.054 at {}: {}",
+                    reader.buffer_position(),
+                    e
+                ))
+            }
+            Ok(Event::Eof) if path.is_empty() => break,
+            Ok(Event::Eof) => {
+                return Err(format!("truncated camt.054, {} not closed", path.join("/")))
+            }
+            Ok(Event::Start(start)) => {
+                let name = String::from_utf8_lossy(start.local_name().as_ref()).to_string();
+                match name.as_str() {
+                    "Ntry" => entry = Some(CamtEntry::default()),
+                    "TxDtls" if entry.is_some() => detail = Some(StatementEntry::default()),
+                    "Amt" => {
+                        currency = start
+                            .try_get_attribute("Ccy")
+                            .ok()
+                            .flatten()
+                            .and_then(|a| a.unescape_value().ok())
+                            .map(|v| v.to_string())
+                    }
+                    _ => {}
+                }
+                path.push(name);
+            }
+            Ok(Event::End(_)) => {
+                let name = path.pop().unwrap_or_default();
+                match name.as_str() {
+                    "TxDtls" => {
+                        if let (Some(entry), Some(detail)) = (entry.as_mut(), detail.take()) {
+                            entry.details.push(detail);
+                        }
+                    }
+                    "Ntry" => {
+                        if let Some(entry) = entry.take() {
+                            if entry.credit {
+                                push_camt_entry(entry, &mut entries)?;
+                            }
+                        }
+                    }
+                    _ => {}
+                }
+            }
+            Ok(Event::Text(text)) => {
+                let text = text
+                    .unescape()
+                    .map_err(|e| format!("invalid camt.054 text: {}", e))?
+                    .to_string();
+                if let Some(detail) = detail.as_mut() {
+                    if path_ends_with(&path, &["TxDtls", "Amt"])
+                        || path_ends_with(&path, &["AmtDtls", "TxAmt", "Amt"])
+                    {
+                        detail.amount = parse_amount(&text)?;
+                        detail.currency = currency.clone();
+                    } else if path_ends_with(&path, &["Refs", "AcctSvcrRef"]) {
+                        detail.bank_reference = Some(text);
+                    } else if path_ends_with(&path, &["Refs", "EndToEndId"])
+                        || path_ends_with(&path, &["RmtInf", "Ustrd"])
+                        || path_ends_with(&path, &["CdtrRefInf", "Ref"])
+                    {
+                        detail.add_reference(&text);
+                    }
+                } else if let Some(entry) = entry.as_mut() {
+                    if path_ends_with(&path, &["Ntry", "Amt"]) {
+                        entry.amount = Some(parse_amount(&text)?);
+                        entry.currency = currency.clone();
+                    } else if path_ends_with(&path, &["Ntry", "CdtDbtInd"]) {
+                        entry.credit = text == "CRDT";
+                    } else if path_ends_with(&path, &["Ntry", "BookgDt", "Dt"])
+                        || path_ends_with(&path, &["Ntry", "BookgDt", "DtTm"])
+                    {
+                        entry.booking_date = Some(text);
+                    } else if path_ends_with(&path, &["Ntry", "AcctSvcrRef"]) {
+                        entry.bank_reference = Some(text);
+                    }
+                }
+            }
+            Ok(_) => {}
+        }
+        buf.clear();
+    }
+    Ok(entries)
+}
+
+fn push_camt_entry(entry: CamtEntry, entries: &mut Vec<StatementEntry>) -> Result<(), String> {
+    let amount = entry
+        .amount
+        .ok_or_else(|| "camt.054 entry without amount".to_string())?;
+    let mut details = entry.details;
+    if details.is_empty() {
+        details.push(StatementEntry::default());
+    }
+    // A single detail without its own amount carries the entry amount
+    let single = details.len() == 1;
+    for mut detail in details {
+        detail.entry = entries.len() + 1;
+        if single && detail.amount == 0.0 {
+            detail.amount = amount;
+            detail.currency = entry.currency.clone();
+        }
+        if detail.bank_reference.is_none() {
+            detail.bank_reference = entry.bank_reference.clone();
+        }
+        detail.booking_date = entry.booking_date.clone();
+        entries.push(detail);
+    }
+    Ok(())
+}
+
+/// Reads a bank CSV export described by `format`. Rows with an empty or
+/// negative amount are debits or balances and are skipped.
+pub fn parse_bank_csv(
+    data: &[u8],
+    format: &CsvStatementFormat,
+) -> Result<Vec<StatementEntry>, String> {
+    let mut reader = csv::ReaderBuilder::new()
+        .delimiter(format.delimiter)
+        .trim(csv::Trim::All)
+        .flexible(true)
+        .from_reader(data);
+    let headers = reader.headers().map_err(|e| e.to_string())?.clone();
+    let column = |name: &str| headers.iter().position(|h| h.eq_ignore_ascii_case(name));
+    let optional_column = |name: &Option<String>| name.as_deref().and_then(column);
+
+    let amount_column = column(&format.amount_column)
+        .ok_or_else(|| format!("missing amount column {}", format.amount_column))?;
+    let reference_columns: Vec<usize> = format
+        .reference_columns
+        .iter()
+        .filter_map(|name| column(name))
+        .collect();
+    if reference_columns.is_empty() {
+        return Err("none of the reference columns are present".to_string());
+    }
+    let bank_reference_column = optional_column(&format.bank_reference_column);
+    let date_column = optional_column(&format.date_column);
+    let currency_column = optional_column(&format.currency_column);
+
+    let mut entries = vec![];
+    for (index, record) in reader.records().enumerate() {
+        let record = record.map_err(|e| format!("row {}: {}", index + 1, e))?;
+        let field = |column: Option<usize>| {
+            column
+                .and_then(|c| record.get(c))
+                .filter(|v| !v.is_empty())
+                .map(String::from)
+        };
+        let amount = match record.get(amount_column).filter(|v| !v.is_empty()) {
+            Some(amount) => {
+                parse_amount(amount).map_err(|e| format!("row {}: {}", index + 1, e))?
+            }
+            None => continue,
+        };
+        if amount <= 0.0 {
+            continue;
+        }
+        let mut entry = StatementEntry {
+            entry: entries.len() + 1,
+            bank_reference: field(bank_reference_column),
+            references: vec![],
+            amount,
+            currency: field(currency_column),
+            booking_date: field(date_column),
+        };
+        for column in &reference_columns {
+            if let Some(reference) = record.get(*column) {
+                entry.add_reference(reference);
+            }
+        }
+        entries.push(entry);
+    }
+    Ok(entries)
+}
+
+impl ReconciliationReport {
+    fn push(&mut self, category: ReconciliationCategory, item: ReconciliationItem) {
+        self.summary.entries += 1;
+        self.summary.total_amount += item.entry.amount;
+        let (count, items) = match category {
+            ReconciliationCategory::Matched => (&mut self.summary.matched, &mut self.matched),
+            ReconciliationCategory::Unmatched => (&mut self.summary.unmatched, &mut self.unmatched),
+            ReconciliationCategory::OverPaid => (&mut self.summary.over_paid, &mut self.over_paid),
+            ReconciliationCategory::UnderPaid => {
+                (&mut self.summary.under_paid, &mut self.under_paid)
+            }
+        };
+        *count += 1;
+        items.push(item);
+    }
+
+    /// One CSV row per statement entry with its category in the first column.
+    pub fn to_csv(&self) -> Result<Vec<u8>, PromptPayServiceError> {
+        let mut writer = csv::Writer::from_writer(vec![]);
+        let categories = [
+            (ReconciliationCategory::Matched, &self.matched),
+            (ReconciliationCategory::Unmatched, &self.unmatched),
+            (ReconciliationCategory::OverPaid, &self.over_paid),
+            (ReconciliationCategory::UnderPaid, &self.under_paid),
+        ];
+        for (category, items) in categories.iter() {
+            for item in items.iter() {
+                writer
+                    .serialize(ReportRow {
+                        category: *category,
+                        entry: item.entry.entry,
+                        bank_reference: item.entry.bank_reference.as_deref(),
+                        references: item.entry.references.join("|"),
+                        amount: item.entry.amount,
+                        currency: item.entry.currency.as_deref(),
+                        booking_date: item.entry.booking_date.as_deref(),
+                        matched_reference: item.matched_reference.as_deref(),
+                        merchant_id: item.merchant_id.as_deref(),
+                        expected_amount: item.expected_amount,
+                        difference: item.difference,
+                        transaction_status: item.transaction_status,
+                        note: item.note.as_deref(),
+                    })
+                    .map_err(csv_error)?;
+            }
+        }
+        writer
+            .into_inner()
+            .map_err(|e| csv_error(e.into_error().into()))
+    }
+}
+
+fn csv_error(e: csv::Error) -> PromptPayServiceError {
+    error!("Reconciliation report error: {}", e);
+    PromptPayServiceError::InternalError
+}
+
+impl Storage {
+    /// Matches statement entries to issued dynamic QRs by any of their
+    /// references, then compares amounts. A second payment for an already
+    /// matched reference is reported as unmatched. Transactions are not
+    /// modified; this is a report for finance.
+    pub fn reconcile(
+        &self,
+        entries: Vec<StatementEntry>,
+    ) -> Result<ReconciliationReport, PromptPayServiceError> {
+        let mut report = ReconciliationReport::default();
+        let mut seen: HashSet<String> = HashSet::new();
+        for entry in entries {
+            let mut transaction = None;
+            for reference in &entry.references {
+                if let Some(found) = self.find_transaction(reference)? {
+                    transaction = Some(found);
+                    break;
+                }
+            }
+            let mut item = ReconciliationItem {
+                entry,
+                matched_reference: None,
+                merchant_id: None,
+                expected_amount: None,
+                difference: None,
+                transaction_status: None,
+                note: None,
+            };
+            let transaction = match transaction {
+                Some(transaction) => transaction,
+                None => {
+                    item.note = Some("no issued QR for these references".to_string());
+                    report.push(ReconciliationCategory::Unmatched, item);
+                    continue;
+                }
+            };
+
+            item.matched_reference = Some(transaction.reference.clone());
+            item.merchant_id = Some(transaction.merchant_id.clone());
+            item.expected_amount = Some(transaction.amount);
+            item.transaction_status = Some(transaction.status);
+            if !seen.insert(transaction.reference.clone()) {
+                item.note = Some("duplicate payment".to_string());
+                report.push(ReconciliationCategory::Unmatched, item);
+                continue;
+            }
+            if let Some(currency) = item.entry.currency.as_deref().filter(|c| *c != THB) {
+                item.note = Some(format!("currency {} is not THB", currency));
+                report.push(ReconciliationCategory::Unmatched, item);
+                continue;
+            }
+
+            let received = to_satang(item.entry.amount);
+            let difference = received - transaction.amount_satang;
+            item.difference = Some(difference as f64 / 100.0);
+            let category = match difference {
+                0 => ReconciliationCategory::Matched,
+                d if d > 0 => ReconciliationCategory::OverPaid,
+                _ => ReconciliationCategory::UnderPaid,
+            };
+            report.push(category, item);
+        }
+        report.summary.total_amount = to_satang(report.summary.total_amount) as f64 / 100.0;
+        Ok(report)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_camt054_credit_entries() {
+        let entries = parse_camt054(include_bytes!("../testdata/camt054.xml")).unwrap();
+        assert_eq!(entries.len(), 4);
+        assert_eq!(entries[0].references, vec!["INV0001"]);
+        assert_eq!(entries[0].amount, 50.25);
+        assert_eq!(entries[0].bank_reference.as_deref(), Some("BANK0000001"));
+        assert_eq!(entries[0].booking_date.as_deref(), Some("2024-06-10"));
+        assert_eq!(entries[1].references, vec!["INV0002"]);
+        assert_eq!(entries[1].amount, 20.0);
+        assert_eq!(entries[2].amount, 15.0);
+        assert_eq!(entries[2].bank_reference.as_deref(), Some("BANK0000003"));
+        assert!(entries[3].references.is_empty());
+        assert_eq!(entries[3].currency.as_deref(), Some(THB));
+    }
+
+    #[test]
+    fn test_parse_bank_csv_with_custom_columns() {
+        let csv = "Txn Date;Txn Ref;Reference 1;Reference 2;Credit\n\
+                   10/06/2024;B1;INV0001;M001;1,050.25\n\
+                   10/06/2024;B2;;INV0002;20.00\n\
+                   10/06/2024;B3;FEE;;\n";
+        let format = CsvStatementFormat {
+            delimiter: b';',
+            amount_column: "Credit".to_string(),
+            reference_columns: vec!["Reference 1".to_string(), "Reference 2".to_string()],
+            bank_reference_column: Some("Txn Ref".to_string()),
+            date_column: Some("Txn Date".to_string()),
+            currency_column: None,
+        };
+        let entries = parse_bank_csv(csv.as_bytes(), &format).unwrap();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].amount, 1050.25);
+        assert_eq!(entries[0].references, vec!["INV0001", "M001"]);
+        assert_eq!(entries[1].references, vec!["INV0002"]);
+        assert_eq!(entries[1].bank_reference.as_deref(), Some("B2"));
+        assert!(parse_bank_csv(csv.as_bytes(), &CsvStatementFormat::default()).is_err());
+    }
+
+    #[test]
+    fn test_reconcile_categories() {
+        let storage = Storage::open_in_memory().unwrap();
+        for (reference, amount) in [("INV0001", 50.25), ("INV0002", 25.0), ("INV0003", 10.0)] {
+            storage
+                .insert_transaction(reference, "M001", amount, "000201", 60)
+                .unwrap();
+        }
+        let mut entries = parse_camt054(include_bytes!("../testdata/camt054.xml")).unwrap();
+        let mut duplicate = entries[0].clone();
+        duplicate.entry = 5;
+        entries.push(duplicate);
+
+        let report = storage.reconcile(entries).unwrap();
+        assert_eq!(report.summary.matched, 1);
+        assert_eq!(report.summary.under_paid, 1);
+        assert_eq!(report.summary.over_paid, 1);
+        assert_eq!(report.summary.unmatched, 2);
+        assert_eq!(report.summary.total_amount, 234.5);
+        assert_eq!(report.under_paid[0].difference, Some(-5.0));
+        assert_eq!(report.over_paid[0].difference, Some(5.0));
+        assert_eq!(
+            report.unmatched[1].note.as_deref(),
+            Some("duplicate payment")
+        );
+
+        let csv = String::from_utf8(report.to_csv().unwrap()).unwrap();
+        let mut lines = csv.lines();
+        assert!(lines
+            .next()
+            .unwrap()
+            .starts_with("category,entry,bank_reference"));
+        assert!(lines
+            .next()
+            .unwrap()
+            .starts_with("matched,1,BANK0000001,INV0001,50.25"));
+        assert_eq!(csv.lines().count(), 6);
+    }
+}
@@ -0,0 +1,213 @@
+#![allow(dead_code)]
+
+use std::convert::TryFrom;
+
+use serde::{Deserialize, Serialize};
+
+use crate::apis::PromptPayServiceError;
+use crate::bank_notifications::{BankNotification, NotificationOutcome};
+use crate::emvo_qrcode::crc16;
+use crate::emvo_types::*;
+use crate::storage::Storage;
+
+// Slip verification mini-QR printed on Thai bank transfer slips
+pub const SLIP_ID_PAYLOAD: TagID = TagID::new(0); // (M) Slip data template
+pub const SLIP_ID_API_ID: TagID = TagID::new(0); // (M) API ID
+pub const SLIP_ID_SENDING_BANK: TagID = TagID::new(1); // (M) Sending bank code
+pub const SLIP_ID_TRANSACTION_REF: TagID = TagID::new(2); // (M) Transaction reference
+pub const SLIP_ID_COUNTRY_CODE: TagID = TagID::new(51); // (M) Country code
+pub const SLIP_ID_CRC: TagID = TagID::new(91); // (M) CRC
+
+pub const SLIP_COUNTRY_CODE: CountryCode = "TH";
+const MAX_LENGTH_TRANSACTION_REF: usize = 25;
+
+#[derive(Debug, Deserialize)]
+pub struct VerifySlipRq {
+    pub payload: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct SlipQr {
+    pub api_id: String,
+    pub sending_bank_code: String,
+    pub sending_bank: Option<&'static str>,
+    pub transaction_reference: String,
+    pub country_code: String,
+}
+
+/// `payment_received` is only true when the bank has notified us of this
+/// transfer and it matched a transaction; a well-formed slip alone proves
+/// nothing.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct SlipVerification {
+    #[serde(flatten)]
+    pub slip: SlipQr,
+    pub payment_received: bool,
+    pub notification: Option<BankNotification>,
+}
+
+/// Short name of a Thai bank by its BOT bank code.
+pub fn bank_name(code: &str) -> Option<&'static str> {
+    match code {
+        "002" => Some("BBL"),
+        "004" => Some("KBANK"),
+        "006" => Some("KTB"),
+        "011" => Some("TTB"),
+        "014" => Some("SCB"),
+        "017" => Some("CITI"),
+        "022" => Some("CIMBT"),
+        "024" => Some("UOBT"),
+        "025" => Some("BAY"),
+        "030" => Some("GSB"),
+        "033" => Some("GHB"),
+        "034" => Some("BAAC"),
+        "066" => Some("ISBT"),
+        "067" => Some("TISCO"),
+        "069" => Some("KKP"),
+        "070" => Some("ICBCT"),
+        "071" => Some("TCRB"),
+        "073" => Some("LHFG"),
+        "098" => Some("SME"),
+        _ => None,
+    }
+}
+
+/// Parses and checks the slip verification payload:
+/// `00` (API ID `00`, sending bank `01`, transaction reference `02`),
+/// country code `51` and CRC `91`.
+pub fn parse_slip_qr(payload: &str) -> Result<SlipQr, EMVOError> {
+    let payload = payload.trim();
+    if !payload.is_ascii() {
+        return Err(EMVOError::new("Slip payload is not ASCII"));
+    }
+    let objects = parse_data_objects(payload)?;
+    match objects.last() {
+        Some((id, crc)) if *id == SLIP_ID_CRC && crc.len() == 4 => {
+            let signed = &payload[..payload.len() - crc.len()];
+            if !crc.eq_ignore_ascii_case(&crc16(signed)) {
+                return Err(EMVOError::new("Slip CRC mismatch"));
+            }
+        }
+        _ => return Err(EMVOError::new("Slip CRC missing")),
+    }
+
+    let mut slip_data = None;
+    let mut country_code = None;
+    for (id, value) in &objects[..objects.len() - 1] {
+        match *id {
+            SLIP_ID_PAYLOAD => slip_data = Some(value),
+            SLIP_ID_COUNTRY_CODE => country_code = Some(value.clone()),
+            _ => {}
+        }
+    }
+    let country_code = country_code.ok_or_else(|| EMVOError::new("Slip country code missing"))?;
+    if country_code != SLIP_COUNTRY_CODE {
+        return Err(EMVOError::new("Slip country code is not TH"));
+    }
+
+    let mut api_id = None;
+    let mut sending_bank_code = None;
+    let mut transaction_reference = None;
+    let slip_data = slip_data.ok_or_else(|| EMVOError::new("Slip data missing"))?;
+    for (id, value) in parse_data_objects(slip_data)? {
+        match id {
+            SLIP_ID_API_ID => api_id = Some(EMVNumeric::try_from(value)?.value()),
+            SLIP_ID_SENDING_BANK => sending_bank_code = Some(EMVNumeric::try_from(value)?.value()),
+            SLIP_ID_TRANSACTION_REF => {
+                transaction_reference = Some(EMVAlphanumbericSpecial::try_from(value)?.value())
+            }
+            _ => {}
+        }
+    }
+    let sending_bank_code = sending_bank_code
+        .filter(|code| code.len() == 3)
+        .ok_or_else(|| EMVOError::new("Sending bank code missing"))?;
+    let transaction_reference = transaction_reference
+        .filter(|reference| !reference.is_empty() && reference.len() <= MAX_LENGTH_TRANSACTION_REF)
+        .ok_or_else(|| EMVOError::new("Transaction reference missing"))?;
+    Ok(SlipQr {
+        api_id: api_id.ok_or_else(|| EMVOError::new("API ID missing"))?,
+        sending_bank: bank_name(&sending_bank_code),
+        sending_bank_code,
+        transaction_reference,
+        country_code,
+    })
+}
+
+/// Slip images are decoded to the text of every QR they contain; anything
+/// that is not a PNG/JPEG is taken as the raw payload.
+pub fn slip_payloads_from_bytes(data: &[u8]) -> Result<Vec<String>, String> {
+    if image::guess_format(data).is_err() {
+        let payload = std::str::from_utf8(data).map_err(|_| "slip payload is not text")?;
+        return Ok(vec![payload.to_string()]);
+    }
+    let image = image::load_from_memory(data)
+        .map_err(|e| format!("unreadable slip image: {}", e))?
+        .to_luma8();
+    let mut prepared = rqrr::PreparedImage::prepare(image);
+    let payloads: Vec<String> = prepared
+        .detect_grids()
+        .iter()
+        .filter_map(|grid| grid.decode().ok())
+        .map(|(_, content)| content)
+        .collect();
+    if payloads.is_empty() {
+        return Err("no QR code found in slip image".to_string());
+    }
+    Ok(payloads)
+}
+
+impl Storage {
+    /// Looks the slip's transaction reference up among received bank
+    /// notifications.
+    pub fn verify_slip(&self, slip: SlipQr) -> Result<SlipVerification, PromptPayServiceError> {
+        let notification = self.get_bank_notification(&slip.transaction_reference)?;
+        Ok(SlipVerification {
+            slip,
+            payment_received: notification
+                .as_ref()
+                .map(|n| n.outcome == NotificationOutcome::Matched)
+                .unwrap_or(false),
+            notification,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn slip_payload(bank: &str, reference: &str, country: &str) -> String {
+        let data = format!(
+            "000600000101{:02}{}02{:02}{}",
+            bank.len(),
+            bank,
+            reference.len(),
+            reference
+        );
+        let mut payload = format!(
+            "00{:02}{}51{:02}{}9104",
+            data.len(),
+            data,
+            country.len(),
+            country
+        );
+        payload.push_str(&crc16(&payload));
+        payload
+    }
+
+    #[test]
+    fn test_parse_slip_qr() {
+        let slip = parse_slip_qr(&slip_payload("004", "014242082547BPM04988", "TH")).unwrap();
+        assert_eq!(slip.api_id, "000001");
+        assert_eq!(slip.sending_bank_code, "004");
+        assert_eq!(slip.sending_bank, Some("KBANK"));
+        assert_eq!(slip.transaction_reference, "014242082547BPM04988");
+
+        let mut tampered = slip_payload("004", "014242082547BPM04988", "TH");
+        tampered.replace_range(30..31, "9");
+        assert!(parse_slip_qr(&tampered).is_err());
+        assert!(parse_slip_qr(&slip_payload("004", "REF", "SG")).is_err());
+        assert!(parse_slip_qr("0041000600000101030040220").is_err());
+    }
+}
@@ -0,0 +1,88 @@
+#![allow(dead_code)]
+
+use std::sync::{Mutex, MutexGuard};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use actix_web::error::BlockingError;
+use log::error;
+use rusqlite::Connection;
+
+use crate::apis::PromptPayServiceError;
+use crate::bank_notifications::BANK_NOTIFICATION_SCHEMA;
+use crate::merchant_registry::MERCHANT_SCHEMA;
+use crate::prompt_pay_ota::CUSTOMER_SCHEMA;
+use crate::transactions::{TransactionEvents, TRANSACTION_SCHEMA};
+use crate::webhooks::WEBHOOK_SCHEMA;
+
+pub const DEFAULT_DB_PATH: &str = "promptpay.db";
+
+const SCHEMAS: &[&str] = &[
+    MERCHANT_SCHEMA,
+    TRANSACTION_SCHEMA,
+    BANK_NOTIFICATION_SCHEMA,
+    WEBHOOK_SCHEMA,
+    CUSTOMER_SCHEMA,
+];
+
+/// Embedded SQLite database shared by the registry and transaction modules.
+/// Each module adds its own `impl Storage` block and schema.
+pub struct Storage {
+    conn: Mutex<Connection>,
+    transaction_events: TransactionEvents,
+}
+
+impl Storage {
+    pub fn open(path: &str) -> rusqlite::Result<Self> {
+        Storage::migrate(Connection::open(path)?)
+    }
+
+    pub fn open_in_memory() -> rusqlite::Result<Self> {
+        Storage::migrate(Connection::open_in_memory()?)
+    }
+
+    pub fn from_env() -> rusqlite::Result<Self> {
+        let path =
+            std::env::var("PROMPTPAY_DB_PATH").unwrap_or_else(|_| DEFAULT_DB_PATH.to_string());
+        Storage::open(&path)
+    }
+
+    fn migrate(conn: Connection) -> rusqlite::Result<Self> {
+        conn.execute_batch("PRAGMA journal_mode = WAL; PRAGMA foreign_keys = ON;")?;
+        for schema in SCHEMAS {
+            conn.execute_batch(schema)?;
+        }
+        Ok(Storage {
+            conn: Mutex::new(conn),
+            transaction_events: TransactionEvents::new(),
+        })
+    }
+
+    pub(crate) fn connection(&self) -> MutexGuard<'_, Connection> {
+        self.conn.lock().unwrap()
+    }
+
+    pub fn transaction_events(&self) -> &TransactionEvents {
+        &self.transaction_events
+    }
+}
+
+pub fn unix_timestamp() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or_default()
+}
+
+impl From<rusqlite::Error> for PromptPayServiceError {
+    fn from(e: rusqlite::Error) -> Self {
+        error!("Storage error: {}", e);
+        PromptPayServiceError::InternalError
+    }
+}
+
+impl From<BlockingError> for PromptPayServiceError {
+    fn from(e: BlockingError) -> Self {
+        error!("Blocking task error: {}", e);
+        PromptPayServiceError::InternalError
+    }
+}
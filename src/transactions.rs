@@ -0,0 +1,621 @@
+#![allow(dead_code)]
+
+use std::fmt;
+use std::str::FromStr;
+use std::time::Duration;
+
+use actix_web::web::{self, Bytes, Data};
+use futures_util::Stream;
+use log::{error, info};
+use rand::distributions::Alphanumeric;
+use rand::Rng;
+use rusqlite::{params, Connection, OptionalExtension, TransactionBehavior};
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::time::{sleep_until, Instant};
+
+use crate::apis::PromptPayServiceError;
+use crate::storage::{unix_timestamp, Storage};
+use crate::webhooks::{insert_transaction_webhook, TraceContext, TRANSACTION_EXPIRED_EVENT};
+
+pub const TRANSACTION_SCHEMA: &str = "CREATE TABLE IF NOT EXISTS transactions (
+    reference TEXT PRIMARY KEY NOT NULL,
+    merchant_id TEXT NOT NULL,
+    amount_satang INTEGER NOT NULL,
+    pay_load TEXT NOT NULL,
+    status TEXT NOT NULL,
+    created_at INTEGER NOT NULL,
+    expires_at INTEGER NOT NULL,
+    updated_at INTEGER NOT NULL
+);
+CREATE INDEX IF NOT EXISTS transactions_status_expires_at ON transactions (status, expires_at)";
+
+pub const DEFAULT_TRANSACTION_TTL_SECONDS: i64 = 15 * 60;
+pub const MAX_TRANSACTION_TTL_SECONDS: i64 = 7 * 24 * 60 * 60;
+pub const DEFAULT_EXPIRY_SWEEP_INTERVAL_SECONDS: u64 = 5;
+const EXPIRY_SWEEP_BATCH_SIZE: usize = 500;
+const GENERATED_REFERENCE_LENGTH: usize = 20;
+const TRANSACTION_EVENTS_CAPACITY: usize = 1024;
+const SSE_KEEP_ALIVE: Duration = Duration::from_secs(15);
+
+/// Lifecycle of a dynamic QR. Only `Pending` transactions may change state.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "UPPERCASE")]
+pub enum TransactionStatus {
+    Pending,
+    Paid,
+    Expired,
+    Cancelled,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Transaction {
+    pub reference: String,
+    pub merchant_id: String,
+    pub amount: f64,
+    #[serde(skip)]
+    pub amount_satang: i64,
+    pub pay_load: String,
+    pub status: TransactionStatus,
+    pub created_at: i64,
+    pub expires_at: i64,
+    pub updated_at: i64,
+}
+
+/// In-process fan-out of transaction status changes to live subscribers
+/// (the SSE endpoint). Publishing without subscribers is a no-op.
+pub struct TransactionEvents {
+    sender: broadcast::Sender<Transaction>,
+}
+
+impl TransactionStatus {
+    pub fn is_final(&self) -> bool {
+        *self != TransactionStatus::Pending
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TransactionStatus::Pending => "PENDING",
+            TransactionStatus::Paid => "PAID",
+            TransactionStatus::Expired => "EXPIRED",
+            TransactionStatus::Cancelled => "CANCELLED",
+        }
+    }
+}
+impl fmt::Display for TransactionStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+impl FromStr for TransactionStatus {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "PENDING" => Ok(TransactionStatus::Pending),
+            "PAID" => Ok(TransactionStatus::Paid),
+            "EXPIRED" => Ok(TransactionStatus::Expired),
+            "CANCELLED" => Ok(TransactionStatus::Cancelled),
+            _ => Err(format!("unknown transaction status {}", s)),
+        }
+    }
+}
+
+impl TransactionEvents {
+    pub fn new() -> Self {
+        let (sender, _) = broadcast::channel(TRANSACTION_EVENTS_CAPACITY);
+        TransactionEvents { sender }
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<Transaction> {
+        self.sender.subscribe()
+    }
+
+    pub fn publish(&self, transaction: &Transaction) {
+        let _ = self.sender.send(transaction.clone());
+    }
+}
+
+impl Default for TransactionEvents {
+    fn default() -> Self {
+        TransactionEvents::new()
+    }
+}
+
+/// Amounts are kept in satang so matching against bank notifications never
+/// compares floats.
+pub fn to_satang(amount: f64) -> i64 {
+    (amount * 100.0).round() as i64
+}
+
+pub fn generate_reference() -> String {
+    rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(GENERATED_REFERENCE_LENGTH)
+        .map(|c| (c as char).to_ascii_uppercase())
+        .collect()
+}
+
+pub fn transaction_ttl_from_env() -> i64 {
+    std::env::var("TRANSACTION_TTL_SECONDS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .filter(|ttl: &i64| *ttl > 0)
+        .unwrap_or(DEFAULT_TRANSACTION_TTL_SECONDS)
+}
+
+pub fn validate_transaction_ttl(ttl_seconds: i64) -> Result<(), String> {
+    if ttl_seconds <= 0 || ttl_seconds > MAX_TRANSACTION_TTL_SECONDS {
+        return Err(format!(
+            "ttl must be between 1 and {} seconds",
+            MAX_TRANSACTION_TTL_SECONDS
+        ));
+    }
+    Ok(())
+}
+
+const TRANSACTION_COLUMNS: &str =
+    "reference, merchant_id, amount_satang, pay_load, status, created_at, expires_at, updated_at";
+
+fn transaction_from_row(row: &rusqlite::Row) -> rusqlite::Result<Transaction> {
+    let amount_satang: i64 = row.get(2)?;
+    let status: String = row.get(4)?;
+    Ok(Transaction {
+        reference: row.get(0)?,
+        merchant_id: row.get(1)?,
+        amount: amount_satang as f64 / 100.0,
+        amount_satang,
+        pay_load: row.get(3)?,
+        status: status.parse().map_err(|e: String| {
+            rusqlite::Error::FromSqlConversionFailure(4, rusqlite::types::Type::Text, e.into())
+        })?,
+        created_at: row.get(5)?,
+        expires_at: row.get(6)?,
+        updated_at: row.get(7)?,
+    })
+}
+
+/// Reads a transaction as stored on `conn`, without expiring it.
+pub(crate) fn select_transaction(
+    conn: &Connection,
+    reference: &str,
+) -> Result<Option<Transaction>, PromptPayServiceError> {
+    Ok(conn
+        .query_row(
+            &format!(
+                "SELECT {} FROM transactions WHERE reference = ?1",
+                TRANSACTION_COLUMNS
+            ),
+            params![reference],
+            transaction_from_row,
+        )
+        .optional()?)
+}
+
+/// Moves a pending transaction to `status` on `conn` without publishing the
+/// change, so callers can make it part of a larger SQL transaction. Fails
+/// with `Conflict` when the transaction already left the pending state, or
+/// is past its expiry time and `status` is not `Expired`.
+pub(crate) fn update_transaction_status(
+    conn: &Connection,
+    reference: &str,
+    status: TransactionStatus,
+) -> Result<Transaction, PromptPayServiceError> {
+    // A pending transaction past its expiry time can only become expired
+    let expiry_condition = if status == TransactionStatus::Expired {
+        "expires_at <= ?3"
+    } else {
+        "expires_at > ?3"
+    };
+    let updated = conn.execute(
+        &format!(
+            "UPDATE transactions SET status = ?2, updated_at = ?3 WHERE reference = ?1 AND status = ?4 AND {}",
+            expiry_condition
+        ),
+        params![
+            reference,
+            status.as_str(),
+            unix_timestamp(),
+            TransactionStatus::Pending.as_str()
+        ],
+    )?;
+    let transaction =
+        select_transaction(conn, reference)?.ok_or(PromptPayServiceError::NotFound)?;
+    if updated == 0 {
+        return Err(PromptPayServiceError::Conflict);
+    }
+    Ok(transaction)
+}
+
+/// Expires an overdue pending transaction and queues its `transaction.expired`
+/// webhook on `conn`, which callers run inside one SQLite transaction so the
+/// status change is never committed without its webhook.
+pub(crate) fn expire_overdue_transaction(
+    conn: &Connection,
+    reference: &str,
+) -> Result<Transaction, PromptPayServiceError> {
+    let transaction = update_transaction_status(conn, reference, TransactionStatus::Expired)?;
+    insert_transaction_webhook(
+        conn,
+        TRANSACTION_EXPIRED_EVENT,
+        &transaction,
+        &TraceContext::new(),
+    )?;
+    Ok(transaction)
+}
+
+impl Storage {
+    pub fn insert_transaction(
+        &self,
+        reference: &str,
+        merchant_id: &str,
+        amount: f64,
+        pay_load: &str,
+        ttl_seconds: i64,
+    ) -> Result<Transaction, PromptPayServiceError> {
+        let now = unix_timestamp();
+        let transaction = Transaction {
+            reference: reference.to_string(),
+            merchant_id: merchant_id.to_string(),
+            amount,
+            amount_satang: to_satang(amount),
+            pay_load: pay_load.to_string(),
+            status: TransactionStatus::Pending,
+            created_at: now,
+            expires_at: now + ttl_seconds,
+            updated_at: now,
+        };
+        let inserted = self.connection().execute(
+            &format!(
+                "INSERT OR IGNORE INTO transactions ({}) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+                TRANSACTION_COLUMNS
+            ),
+            params![
+                transaction.reference,
+                transaction.merchant_id,
+                transaction.amount_satang,
+                transaction.pay_load,
+                transaction.status.as_str(),
+                transaction.created_at,
+                transaction.expires_at,
+                transaction.updated_at
+            ],
+        )?;
+        if inserted == 0 {
+            return Err(PromptPayServiceError::Conflict);
+        }
+        Ok(transaction)
+    }
+
+    /// Reads a transaction as stored, without expiring it.
+    pub fn find_transaction(
+        &self,
+        reference: &str,
+    ) -> Result<Option<Transaction>, PromptPayServiceError> {
+        select_transaction(&self.connection(), reference)
+    }
+
+    /// Returns the transaction, expiring it first if it is still pending past
+    /// its expiry time.
+    pub fn get_transaction(&self, reference: &str) -> Result<Transaction, PromptPayServiceError> {
+        let transaction = self
+            .find_transaction(reference)?
+            .ok_or(PromptPayServiceError::NotFound)?;
+        if transaction.status == TransactionStatus::Pending
+            && transaction.expires_at <= unix_timestamp()
+        {
+            // Expiry is attempted once. Only losing the race to another status
+            // change is answered with the row as it is now
+            return match self.expire_transaction(reference) {
+                Err(PromptPayServiceError::Conflict) => self
+                    .find_transaction(reference)?
+                    .ok_or(PromptPayServiceError::NotFound),
+                result => result,
+            };
+        }
+        Ok(transaction)
+    }
+
+    /// Expires an overdue pending transaction and queues the merchant's
+    /// `transaction.expired` webhook.
+    pub fn expire_transaction(
+        &self,
+        reference: &str,
+    ) -> Result<Transaction, PromptPayServiceError> {
+        let transaction = {
+            let mut conn = self.connection();
+            let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+            let transaction = expire_overdue_transaction(&tx, reference)?;
+            tx.commit()?;
+            transaction
+        };
+        self.transaction_events().publish(&transaction);
+        Ok(transaction)
+    }
+
+    /// Expires up to `limit` pending transactions past their expiry time at
+    /// `now` and returns them.
+    pub fn expire_overdue_transactions(
+        &self,
+        now: i64,
+        limit: usize,
+    ) -> Result<Vec<Transaction>, PromptPayServiceError> {
+        let references = {
+            let conn = self.connection();
+            let mut stmt = conn.prepare(
+                "SELECT reference FROM transactions WHERE status = ?1 AND expires_at <= ?2 ORDER BY expires_at LIMIT ?3",
+            )?;
+            let rows = stmt.query_map(
+                params![TransactionStatus::Pending.as_str(), now, limit as i64],
+                |row| row.get::<_, String>(0),
+            )?;
+            rows.collect::<rusqlite::Result<Vec<_>>>()?
+        };
+        let mut expired = vec![];
+        for reference in references {
+            match self.expire_transaction(&reference) {
+                Ok(transaction) => expired.push(transaction),
+                // Paid or cancelled since the select
+                Err(PromptPayServiceError::Conflict) => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(expired)
+    }
+
+    /// Moves a pending transaction to `status` and publishes the change. Fails
+    /// with `Conflict` when the transaction already left the pending state.
+    pub fn transition_transaction(
+        &self,
+        reference: &str,
+        status: TransactionStatus,
+    ) -> Result<Transaction, PromptPayServiceError> {
+        let result = update_transaction_status(&self.connection(), reference, status);
+        match result {
+            Ok(transaction) => {
+                self.transaction_events().publish(&transaction);
+                Ok(transaction)
+            }
+            // Expire it if it is pending past its expiry time
+            Err(PromptPayServiceError::Conflict) if status != TransactionStatus::Expired => {
+                self.get_transaction(reference)?;
+                Err(PromptPayServiceError::Conflict)
+            }
+            Err(e) => Err(e),
+        }
+    }
+}
+
+/// Background task expiring overdue pending transactions, so merchants get
+/// their `transaction.expired` webhook even when nobody reads the transaction.
+pub struct ExpiryScheduler {
+    interval: Duration,
+}
+
+impl ExpiryScheduler {
+    pub fn new(interval: Duration) -> Self {
+        ExpiryScheduler { interval }
+    }
+
+    /// Reads `TRANSACTION_EXPIRY_SWEEP_SECONDS`, default 5.
+    pub fn from_env() -> Self {
+        let seconds = std::env::var("TRANSACTION_EXPIRY_SWEEP_SECONDS")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .filter(|seconds: &u64| *seconds > 0)
+            .unwrap_or(DEFAULT_EXPIRY_SWEEP_INTERVAL_SECONDS);
+        ExpiryScheduler::new(Duration::from_secs(seconds))
+    }
+
+    pub fn spawn(self, storage: Data<Storage>) {
+        actix_web::rt::spawn(async move {
+            let mut interval = actix_web::rt::time::interval(self.interval);
+            loop {
+                interval.tick().await;
+                if let Err(e) = ExpiryScheduler::sweep(&storage).await {
+                    error!("Transaction expiry sweep error: {}", e);
+                }
+            }
+        });
+    }
+
+    /// Expires everything overdue, a batch at a time, and returns the count.
+    pub async fn sweep(storage: &Data<Storage>) -> Result<usize, PromptPayServiceError> {
+        let mut count = 0;
+        loop {
+            let storage = storage.clone();
+            let expired = web::block(move || {
+                storage.expire_overdue_transactions(unix_timestamp(), EXPIRY_SWEEP_BATCH_SIZE)
+            })
+            .await??;
+            count += expired.len();
+            if expired.len() < EXPIRY_SWEEP_BATCH_SIZE {
+                break;
+            }
+        }
+        if count > 0 {
+            info!("Expired {} pending transaction(s)", count);
+        }
+        Ok(count)
+    }
+}
+
+fn status_event(transaction: &Transaction) -> Bytes {
+    let data = serde_json::to_string(transaction).unwrap_or_default();
+    Bytes::from(format!("event: status\ndata: {}\n\n", data))
+}
+
+async fn reload_transaction(storage: &Data<Storage>, reference: &str) -> Option<Transaction> {
+    let storage = storage.clone();
+    let reference = reference.to_string();
+    web::block(move || storage.get_transaction(&reference))
+        .await
+        .ok()?
+        .ok()
+}
+
+struct StatusStream {
+    storage: Data<Storage>,
+    receiver: broadcast::Receiver<Transaction>,
+    current: Transaction,
+    initial_sent: bool,
+}
+
+/// A second past `expires_at`, when a read of the transaction expires it.
+fn expiry_deadline(transaction: &Transaction) -> Instant {
+    let until_expiry = (transaction.expires_at - unix_timestamp()).max(0) as u64 + 1;
+    Instant::now() + Duration::from_secs(until_expiry)
+}
+
+/// Server-Sent Events for one transaction: the current status first, then
+/// every change until it reaches a final status. Nobody may read a pending
+/// transaction at its expiry time, so the stream reloads it then to expire it.
+pub fn status_event_stream(
+    storage: Data<Storage>,
+    receiver: broadcast::Receiver<Transaction>,
+    current: Transaction,
+) -> impl Stream<Item = Result<Bytes, PromptPayServiceError>> {
+    let state = StatusStream {
+        storage,
+        receiver,
+        current,
+        initial_sent: false,
+    };
+    futures_util::stream::unfold(Some(state), |state| async move {
+        let mut state = state?;
+        if state.initial_sent {
+            let keep_alive = sleep_until(Instant::now() + SSE_KEEP_ALIVE);
+            let expiry = sleep_until(expiry_deadline(&state.current));
+            tokio::pin!(keep_alive, expiry);
+            loop {
+                tokio::select! {
+                    received = state.receiver.recv() => match received {
+                        Ok(transaction) if transaction.reference == state.current.reference => {
+                            state.current = transaction;
+                            break;
+                        }
+                        Ok(_) => {}
+                        Err(RecvError::Lagged(_)) => {
+                            let transaction =
+                                reload_transaction(&state.storage, &state.current.reference).await?;
+                            if transaction.status != state.current.status {
+                                state.current = transaction;
+                                break;
+                            }
+                        }
+                        Err(RecvError::Closed) => return None,
+                    },
+                    _ = &mut expiry => {
+                        let transaction =
+                            reload_transaction(&state.storage, &state.current.reference).await?;
+                        if transaction.status != state.current.status {
+                            state.current = transaction;
+                            break;
+                        }
+                        expiry.as_mut().reset(expiry_deadline(&state.current));
+                    }
+                    _ = &mut keep_alive => {
+                        let keep_alive = Bytes::from_static(b": keep-alive\n\n");
+                        return Some((Ok(keep_alive), Some(state)));
+                    }
+                }
+            }
+        }
+        state.initial_sent = true;
+        let event = status_event(&state.current);
+        let next = if state.current.status.is_final() {
+            None
+        } else {
+            Some(state)
+        };
+        Some((Ok(event), next))
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_transaction_lifecycle() {
+        let storage = Storage::open_in_memory().unwrap();
+        let reference = generate_reference();
+        let created = storage
+            .insert_transaction(&reference, "M001", 50.25, "000201", 60)
+            .unwrap();
+        assert_eq!(created.status, TransactionStatus::Pending);
+        assert_eq!(created.amount_satang, 5025);
+        assert!(matches!(
+            storage.insert_transaction(&reference, "M001", 1.0, "000201", 60),
+            Err(PromptPayServiceError::Conflict)
+        ));
+
+        let paid = storage
+            .transition_transaction(&reference, TransactionStatus::Paid)
+            .unwrap();
+        assert_eq!(paid.status, TransactionStatus::Paid);
+        assert!(matches!(
+            storage.transition_transaction(&reference, TransactionStatus::Cancelled),
+            Err(PromptPayServiceError::Conflict)
+        ));
+        assert!(matches!(
+            storage.get_transaction("UNKNOWN"),
+            Err(PromptPayServiceError::NotFound)
+        ));
+    }
+
+    #[test]
+    fn test_pending_transaction_expires_on_read() {
+        let storage = Storage::open_in_memory().unwrap();
+        let mut events = storage.transaction_events().subscribe();
+        storage
+            .insert_transaction("REF1", "M001", 10.0, "000201", 0)
+            .unwrap();
+        let transaction = storage.get_transaction("REF1").unwrap();
+        assert_eq!(transaction.status, TransactionStatus::Expired);
+        assert_eq!(events.try_recv().unwrap(), transaction);
+    }
+
+    #[test]
+    fn test_expiry_error_is_not_swallowed() {
+        let storage = Storage::open_in_memory().unwrap();
+        storage
+            .insert_transaction("REF1", "M001", 10.0, "000201", 0)
+            .unwrap();
+        storage
+            .connection()
+            .execute_batch("DROP TABLE webhook_deliveries")
+            .unwrap();
+        assert!(matches!(
+            storage.get_transaction("REF1"),
+            Err(PromptPayServiceError::InternalError)
+        ));
+        // The status change is rolled back with the failed webhook insert
+        assert_eq!(
+            storage.find_transaction("REF1").unwrap().unwrap().status,
+            TransactionStatus::Pending
+        );
+    }
+
+    #[actix_web::test]
+    async fn test_expiry_sweep() {
+        let storage = Data::new(Storage::open_in_memory().unwrap());
+        storage
+            .insert_transaction("REF1", "M001", 10.0, "000201", 0)
+            .unwrap();
+        storage
+            .insert_transaction("REF2", "M001", 10.0, "000201", 60)
+            .unwrap();
+        let mut events = storage.transaction_events().subscribe();
+
+        assert_eq!(ExpiryScheduler::sweep(&storage).await.unwrap(), 1);
+        let expired = events.try_recv().unwrap();
+        assert_eq!(expired.reference, "REF1");
+        assert_eq!(expired.status, TransactionStatus::Expired);
+        assert_eq!(
+            storage.get_transaction("REF2").unwrap().status,
+            TransactionStatus::Pending
+        );
+        assert_eq!(ExpiryScheduler::sweep(&storage).await.unwrap(), 0);
+    }
+}
@@ -0,0 +1,853 @@
+#![allow(dead_code)]
+
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+
+use actix_web::web::{self, Data};
+use hyper::client::connect::dns::Name;
+use log::{error, info, warn};
+use opentelemetry::trace::{SpanKind, Status, TraceContextExt, Tracer};
+use opentelemetry::{global, Context, KeyValue};
+use rand::distributions::Alphanumeric;
+use rand::Rng;
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::{Deserialize, Serialize};
+
+use crate::apis::PromptPayServiceError;
+use crate::bank_notifications::sign_payload;
+use crate::storage::{unix_timestamp, Storage};
+use crate::transactions::Transaction;
+
+pub const WEBHOOK_SCHEMA: &str = "CREATE TABLE IF NOT EXISTS merchant_webhooks (
+    merchant_id TEXT PRIMARY KEY NOT NULL REFERENCES merchants (id) ON DELETE CASCADE,
+    url TEXT NOT NULL,
+    secret TEXT NOT NULL,
+    created_at INTEGER NOT NULL,
+    updated_at INTEGER NOT NULL
+);
+CREATE TABLE IF NOT EXISTS webhook_deliveries (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    merchant_id TEXT NOT NULL,
+    event TEXT NOT NULL,
+    payload TEXT NOT NULL,
+    trace_context TEXT NOT NULL,
+    status TEXT NOT NULL,
+    attempts INTEGER NOT NULL,
+    next_attempt_at INTEGER NOT NULL,
+    last_error TEXT,
+    created_at INTEGER NOT NULL,
+    updated_at INTEGER NOT NULL
+);
+CREATE INDEX IF NOT EXISTS webhook_deliveries_status_next_attempt_at ON webhook_deliveries (status, next_attempt_at)";
+
+pub const TRANSACTION_PAID_EVENT: &str = "transaction.paid";
+pub const TRANSACTION_EXPIRED_EVENT: &str = "transaction.expired";
+
+pub const WEBHOOK_SIGNATURE_HEADER: &str = "x-promptpay-signature";
+pub const WEBHOOK_EVENT_HEADER: &str = "x-promptpay-event";
+pub const WEBHOOK_DELIVERY_HEADER: &str = "x-promptpay-delivery";
+pub const WEBHOOK_TIMESTAMP_HEADER: &str = "x-promptpay-timestamp";
+
+pub const DEFAULT_WEBHOOK_MAX_ATTEMPTS: u32 = 8;
+const WEBHOOK_BASE_BACKOFF_SECONDS: i64 = 30;
+const WEBHOOK_MAX_BACKOFF_SECONDS: i64 = 60 * 60;
+const WEBHOOK_POLL_INTERVAL: Duration = Duration::from_secs(1);
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(10);
+const WEBHOOK_BATCH_SIZE: usize = 50;
+const GENERATED_SECRET_LENGTH: usize = 32;
+const MIN_SECRET_LENGTH: usize = 16;
+
+/// W3C trace context headers captured from the request that caused an event,
+/// stored with the delivery so retries after a restart still join the trace.
+pub type TraceContext = HashMap<String, String>;
+
+#[derive(Debug, Deserialize)]
+pub struct MerchantWebhookRq {
+    pub url: String,
+    pub secret: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct MerchantWebhook {
+    pub merchant_id: String,
+    pub url: String,
+    #[serde(skip)]
+    pub secret: String,
+    pub created_at: i64,
+    pub updated_at: i64,
+}
+
+/// Returned once when the webhook is set, the only time the secret is shown.
+#[derive(Debug, Serialize)]
+pub struct MerchantWebhookRs {
+    #[serde(flatten)]
+    pub webhook: MerchantWebhook,
+    pub secret: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "UPPERCASE")]
+pub enum DeliveryStatus {
+    Pending,
+    Delivered,
+    Dead,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct WebhookDelivery {
+    pub id: i64,
+    pub merchant_id: String,
+    pub event: String,
+    pub payload: serde_json::Value,
+    #[serde(skip)]
+    pub trace_context: TraceContext,
+    pub status: DeliveryStatus,
+    pub attempts: u32,
+    pub next_attempt_at: i64,
+    pub last_error: Option<String>,
+    pub created_at: i64,
+    pub updated_at: i64,
+}
+
+impl DeliveryStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DeliveryStatus::Pending => "PENDING",
+            DeliveryStatus::Delivered => "DELIVERED",
+            DeliveryStatus::Dead => "DEAD",
+        }
+    }
+
+    fn parse(s: &str) -> Option<Self> {
+        match s {
+            "PENDING" => Some(DeliveryStatus::Pending),
+            "DELIVERED" => Some(DeliveryStatus::Delivered),
+            "DEAD" => Some(DeliveryStatus::Dead),
+            _ => None,
+        }
+    }
+}
+
+impl MerchantWebhookRq {
+    pub fn validate(&self) -> Result<(), String> {
+        match reqwest::Url::parse(&self.url) {
+            Ok(url) if url.scheme() == "https" && url.host_str().is_some() => {}
+            _ => return Err("url must be an absolute https URL".to_string()),
+        }
+        match &self.secret {
+            Some(secret) if secret.len() < MIN_SECRET_LENGTH => Err(format!(
+                "secret must be at least {} characters",
+                MIN_SECRET_LENGTH
+            )),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Only globally routable unicast addresses may receive webhooks: never
+/// loopback, private, link-local (cloud metadata), CGNAT, unique-local,
+/// documentation, multicast or reserved ranges.
+pub fn is_public_address(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => is_public_ipv4(ip),
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => is_public_ipv4(ip),
+            None => is_public_ipv6(ip),
+        },
+    }
+}
+
+fn is_public_ipv4(ip: Ipv4Addr) -> bool {
+    let [a, b, c, _] = ip.octets();
+    !(ip.is_unspecified()
+        || ip.is_loopback()
+        || ip.is_private()
+        || ip.is_link_local()
+        || ip.is_broadcast()
+        || ip.is_multicast()
+        || ip.is_documentation()
+        || a == 0
+        || a >= 240
+        || (a == 100 && (64..128).contains(&b))
+        || (a == 192 && b == 0 && c == 0)
+        || (a == 198 && (18..20).contains(&b)))
+}
+
+fn is_public_ipv6(ip: Ipv6Addr) -> bool {
+    let segments = ip.segments();
+    !(ip.is_unspecified()
+        || ip.is_loopback()
+        || ip.is_multicast()
+        || (segments[0] & 0xfe00) == 0xfc00
+        || (segments[0] & 0xffc0) == 0xfe80
+        || (segments[0] == 0x2001 && segments[1] == 0x0db8)
+        || (segments[0] == 0x0064 && segments[1] == 0xff9b))
+}
+
+/// Resolves `host` and fails unless every address it resolves to is public,
+/// so a name with one internal record cannot be used to reach it.
+pub async fn resolve_public_host(host: &str, port: u16) -> Result<Vec<SocketAddr>, String> {
+    let addrs: Vec<SocketAddr> = tokio::net::lookup_host((host, port))
+        .await
+        .map_err(|e| format!("cannot resolve {}: {}", host, e))?
+        .collect();
+    if addrs.is_empty() {
+        return Err(format!("cannot resolve {}", host));
+    }
+    match addrs.iter().find(|addr| !is_public_address(addr.ip())) {
+        Some(addr) => Err(format!(
+            "{} resolves to non-public address {}",
+            host,
+            addr.ip()
+        )),
+        None => Ok(addrs),
+    }
+}
+
+/// Checks a validated webhook URL points at a public host.
+pub async fn check_webhook_destination(url: &str) -> Result<(), String> {
+    let url = reqwest::Url::parse(url).map_err(|e| e.to_string())?;
+    let port = url.port_or_known_default().unwrap_or(443);
+    let host = url.host_str().ok_or("url must have a host")?;
+    match host
+        .trim_matches(|c| c == '[' || c == ']')
+        .parse::<IpAddr>()
+    {
+        Ok(ip) if is_public_address(ip) => Ok(()),
+        Ok(ip) => Err(format!("url must not point at non-public address {}", ip)),
+        Err(_) => resolve_public_host(host, port).await.map(|_| ()),
+    }
+}
+
+/// DNS resolver for the webhook client. Checking again at connect time
+/// closes the gap where a name is re-pointed at an internal address after
+/// registration.
+struct PublicDnsResolver;
+
+impl reqwest::dns::Resolve for PublicDnsResolver {
+    fn resolve(&self, name: Name) -> reqwest::dns::Resolving {
+        Box::pin(async move {
+            let addrs = resolve_public_host(name.as_str(), 0).await?;
+            Ok(Box::new(addrs.into_iter()) as reqwest::dns::Addrs)
+        })
+    }
+}
+
+/// HTTP client for merchant webhooks: public addresses only, and no
+/// redirects, which could otherwise point the request anywhere.
+pub fn webhook_client() -> reqwest::Client {
+    reqwest::Client::builder()
+        .dns_resolver(Arc::new(PublicDnsResolver))
+        .redirect(reqwest::redirect::Policy::none())
+        .build()
+        .expect("webhook HTTP client builds")
+}
+
+/// `sha256=<hex>` HMAC-SHA256 of `{timestamp}.{body}`. The timestamp is sent
+/// in `X-PromptPay-Timestamp` so merchants can reject replayed deliveries.
+pub fn sign_webhook(secret: &[u8], timestamp: i64, body: &[u8]) -> String {
+    let mut signed = format!("{}.", timestamp).into_bytes();
+    signed.extend_from_slice(body);
+    sign_payload(secret, &signed)
+}
+
+pub fn generate_secret() -> String {
+    rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(GENERATED_SECRET_LENGTH)
+        .map(char::from)
+        .collect()
+}
+
+/// Seconds to wait before the next attempt: 30s doubling per failed attempt,
+/// capped at an hour.
+pub fn retry_backoff_seconds(attempts: u32) -> i64 {
+    let doublings = attempts.saturating_sub(1).min(16);
+    (WEBHOOK_BASE_BACKOFF_SECONDS << doublings).min(WEBHOOK_MAX_BACKOFF_SECONDS)
+}
+
+/// Serializes the OpenTelemetry context of the current request.
+pub fn current_trace_context() -> TraceContext {
+    let mut carrier = TraceContext::new();
+    global::get_text_map_propagator(|propagator| {
+        propagator.inject_context(&Context::current(), &mut carrier)
+    });
+    carrier
+}
+
+fn conversion_error(index: usize, e: String) -> rusqlite::Error {
+    rusqlite::Error::FromSqlConversionFailure(index, rusqlite::types::Type::Text, e.into())
+}
+
+fn webhook_from_row(row: &rusqlite::Row) -> rusqlite::Result<MerchantWebhook> {
+    Ok(MerchantWebhook {
+        merchant_id: row.get(0)?,
+        url: row.get(1)?,
+        secret: row.get(2)?,
+        created_at: row.get(3)?,
+        updated_at: row.get(4)?,
+    })
+}
+
+const DELIVERY_COLUMNS: &str = "id, merchant_id, event, payload, trace_context, status, attempts, next_attempt_at, last_error, created_at, updated_at";
+
+fn delivery_from_row(row: &rusqlite::Row) -> rusqlite::Result<WebhookDelivery> {
+    let payload: String = row.get(3)?;
+    let trace_context: String = row.get(4)?;
+    let status: String = row.get(5)?;
+    Ok(WebhookDelivery {
+        id: row.get(0)?,
+        merchant_id: row.get(1)?,
+        event: row.get(2)?,
+        payload: serde_json::from_str(&payload).map_err(|e| conversion_error(3, e.to_string()))?,
+        trace_context: serde_json::from_str(&trace_context)
+            .map_err(|e| conversion_error(4, e.to_string()))?,
+        status: DeliveryStatus::parse(&status)
+            .ok_or_else(|| conversion_error(5, format!("unknown delivery status {}", status)))?,
+        attempts: row.get(6)?,
+        next_attempt_at: row.get(7)?,
+        last_error: row.get(8)?,
+        created_at: row.get(9)?,
+        updated_at: row.get(10)?,
+    })
+}
+
+fn encode_error(e: serde_json::Error) -> PromptPayServiceError {
+    error!("Webhook encode error: {}", e);
+    PromptPayServiceError::InternalError
+}
+
+/// Queues `event` for the transaction's merchant on `conn`, so it commits or
+/// rolls back with the caller's status change. Returns the delivery ID, or
+/// `None` when the merchant has no webhook.
+pub(crate) fn insert_transaction_webhook(
+    conn: &Connection,
+    event: &str,
+    transaction: &Transaction,
+    trace_context: &TraceContext,
+) -> Result<Option<i64>, PromptPayServiceError> {
+    let payload = serde_json::json!({
+        "event": event,
+        "transaction": transaction,
+    });
+    let now = unix_timestamp();
+    let inserted = conn.execute(
+        "INSERT INTO webhook_deliveries (merchant_id, event, payload, trace_context, status, attempts, next_attempt_at, created_at, updated_at)
+         SELECT merchant_id, ?2, ?3, ?4, ?5, 0, ?6, ?6, ?6 FROM merchant_webhooks WHERE merchant_id = ?1",
+        params![
+            transaction.merchant_id,
+            event,
+            serde_json::to_string(&payload).map_err(encode_error)?,
+            serde_json::to_string(trace_context).map_err(encode_error)?,
+            DeliveryStatus::Pending.as_str(),
+            now
+        ],
+    )?;
+    Ok(if inserted == 0 {
+        None
+    } else {
+        Some(conn.last_insert_rowid())
+    })
+}
+
+impl Storage {
+    pub fn set_merchant_webhook(
+        &self,
+        merchant_id: &str,
+        url: &str,
+        secret: &str,
+    ) -> Result<MerchantWebhook, PromptPayServiceError> {
+        self.get_merchant(merchant_id)?;
+        self.connection().execute(
+            "INSERT INTO merchant_webhooks (merchant_id, url, secret, created_at, updated_at) VALUES (?1, ?2, ?3, ?4, ?4)
+             ON CONFLICT (merchant_id) DO UPDATE SET url = excluded.url, secret = excluded.secret, updated_at = excluded.updated_at",
+            params![merchant_id, url, secret, unix_timestamp()],
+        )?;
+        self.get_merchant_webhook(merchant_id)
+    }
+
+    pub fn get_merchant_webhook(
+        &self,
+        merchant_id: &str,
+    ) -> Result<MerchantWebhook, PromptPayServiceError> {
+        self.connection()
+            .query_row(
+                "SELECT merchant_id, url, secret, created_at, updated_at FROM merchant_webhooks WHERE merchant_id = ?1",
+                params![merchant_id],
+                webhook_from_row,
+            )
+            .optional()?
+            .ok_or(PromptPayServiceError::NotFound)
+    }
+
+    pub fn delete_merchant_webhook(&self, merchant_id: &str) -> Result<(), PromptPayServiceError> {
+        let deleted = self.connection().execute(
+            "DELETE FROM merchant_webhooks WHERE merchant_id = ?1",
+            params![merchant_id],
+        )?;
+        if deleted == 0 {
+            return Err(PromptPayServiceError::NotFound);
+        }
+        Ok(())
+    }
+
+    /// Queues `event` for the transaction's merchant. Merchants without a
+    /// webhook are skipped and `None` is returned.
+    pub fn enqueue_transaction_webhook(
+        &self,
+        event: &str,
+        transaction: &Transaction,
+        trace_context: &TraceContext,
+    ) -> Result<Option<i64>, PromptPayServiceError> {
+        insert_transaction_webhook(&self.connection(), event, transaction, trace_context)
+    }
+
+    /// Pending deliveries due at `now`, oldest first, with the merchant's
+    /// current webhook (`None` once it has been removed).
+    pub fn due_webhook_deliveries(
+        &self,
+        now: i64,
+        limit: usize,
+    ) -> Result<Vec<(WebhookDelivery, Option<MerchantWebhook>)>, PromptPayServiceError> {
+        let conn = self.connection();
+        let mut stmt = conn.prepare(&format!(
+            "SELECT {} FROM webhook_deliveries WHERE status = ?1 AND next_attempt_at <= ?2 ORDER BY next_attempt_at, id LIMIT ?3",
+            DELIVERY_COLUMNS
+        ))?;
+        let deliveries = stmt
+            .query_map(
+                params![DeliveryStatus::Pending.as_str(), now, limit as i64],
+                delivery_from_row,
+            )?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        drop(stmt);
+        drop(conn);
+        deliveries
+            .into_iter()
+            .map(|delivery| {
+                let webhook = match self.get_merchant_webhook(&delivery.merchant_id) {
+                    Ok(webhook) => Some(webhook),
+                    Err(PromptPayServiceError::NotFound) => None,
+                    Err(e) => return Err(e),
+                };
+                Ok((delivery, webhook))
+            })
+            .collect()
+    }
+
+    /// Records the outcome of one attempt. Failures are retried with
+    /// exponential backoff until `max_attempts`, then dead-lettered.
+    pub fn record_webhook_attempt(
+        &self,
+        id: i64,
+        result: Result<(), String>,
+        max_attempts: u32,
+    ) -> Result<WebhookDelivery, PromptPayServiceError> {
+        let delivery = self.get_webhook_delivery(id)?;
+        let attempts = delivery.attempts + 1;
+        let now = unix_timestamp();
+        let (status, next_attempt_at, last_error) = match result {
+            Ok(()) => (DeliveryStatus::Delivered, delivery.next_attempt_at, None),
+            Err(e) if attempts >= max_attempts => {
+                (DeliveryStatus::Dead, delivery.next_attempt_at, Some(e))
+            }
+            Err(e) => (
+                DeliveryStatus::Pending,
+                now + retry_backoff_seconds(attempts),
+                Some(e),
+            ),
+        };
+        self.connection().execute(
+            "UPDATE webhook_deliveries SET status = ?2, attempts = ?3, next_attempt_at = ?4, last_error = ?5, updated_at = ?6 WHERE id = ?1",
+            params![id, status.as_str(), attempts, next_attempt_at, last_error, now],
+        )?;
+        self.get_webhook_delivery(id)
+    }
+
+    pub fn get_webhook_delivery(&self, id: i64) -> Result<WebhookDelivery, PromptPayServiceError> {
+        self.connection()
+            .query_row(
+                &format!(
+                    "SELECT {} FROM webhook_deliveries WHERE id = ?1",
+                    DELIVERY_COLUMNS
+                ),
+                params![id],
+                delivery_from_row,
+            )
+            .optional()?
+            .ok_or(PromptPayServiceError::NotFound)
+    }
+
+    pub fn list_dead_letters(&self) -> Result<Vec<WebhookDelivery>, PromptPayServiceError> {
+        let conn = self.connection();
+        let mut stmt = conn.prepare(&format!(
+            "SELECT {} FROM webhook_deliveries WHERE status = ?1 ORDER BY updated_at, id",
+            DELIVERY_COLUMNS
+        ))?;
+        let rows = stmt.query_map(params![DeliveryStatus::Dead.as_str()], delivery_from_row)?;
+        Ok(rows.collect::<rusqlite::Result<Vec<_>>>()?)
+    }
+
+    /// Puts a dead letter back on the queue with a fresh attempt budget.
+    pub fn retry_dead_letter(&self, id: i64) -> Result<WebhookDelivery, PromptPayServiceError> {
+        let now = unix_timestamp();
+        let updated = self.connection().execute(
+            "UPDATE webhook_deliveries SET status = ?2, attempts = 0, next_attempt_at = ?3, updated_at = ?3 WHERE id = ?1 AND status = ?4",
+            params![
+                id,
+                DeliveryStatus::Pending.as_str(),
+                now,
+                DeliveryStatus::Dead.as_str()
+            ],
+        )?;
+        if updated == 0 {
+            return Err(PromptPayServiceError::NotFound);
+        }
+        self.get_webhook_delivery(id)
+    }
+}
+
+/// Sends queued merchant webhooks. The queue lives in SQLite, so deliveries
+/// pending at shutdown are picked up again on the next start (at least once).
+#[derive(Clone)]
+pub struct WebhookDispatcher {
+    client: reqwest::Client,
+    max_attempts: u32,
+}
+
+impl WebhookDispatcher {
+    pub fn new(client: reqwest::Client, max_attempts: u32) -> Self {
+        WebhookDispatcher {
+            client,
+            max_attempts,
+        }
+    }
+
+    /// Reads `WEBHOOK_MAX_ATTEMPTS`, default 8.
+    pub fn from_env(client: reqwest::Client) -> Self {
+        let max_attempts = std::env::var("WEBHOOK_MAX_ATTEMPTS")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .filter(|attempts: &u32| *attempts > 0)
+            .unwrap_or(DEFAULT_WEBHOOK_MAX_ATTEMPTS);
+        WebhookDispatcher::new(client, max_attempts)
+    }
+
+    pub fn spawn(self, storage: Data<Storage>) {
+        actix_web::rt::spawn(async move {
+            let mut interval = actix_web::rt::time::interval(WEBHOOK_POLL_INTERVAL);
+            loop {
+                interval.tick().await;
+                if let Err(e) = self.deliver_due(&storage).await {
+                    error!("Webhook delivery error: {}", e);
+                }
+            }
+        });
+    }
+
+    /// Attempts every delivery that is due and returns how many were tried.
+    pub async fn deliver_due(
+        &self,
+        storage: &Data<Storage>,
+    ) -> Result<usize, PromptPayServiceError> {
+        let due = {
+            let storage = storage.clone();
+            web::block(move || storage.due_webhook_deliveries(unix_timestamp(), WEBHOOK_BATCH_SIZE))
+                .await??
+        };
+        let count = due.len();
+        for (delivery, webhook) in due {
+            let result = match webhook {
+                Some(webhook) => self.send(&delivery, &webhook).await,
+                None => Err("merchant webhook removed".to_string()),
+            };
+            let storage = storage.clone();
+            let max_attempts = self.max_attempts;
+            let delivery = web::block(move || {
+                storage.record_webhook_attempt(delivery.id, result, max_attempts)
+            })
+            .await??;
+            match delivery.status {
+                DeliveryStatus::Delivered => info!("Webhook {} delivered", delivery.id),
+                DeliveryStatus::Dead => warn!(
+                    "Webhook {} dead-lettered after {} attempts",
+                    delivery.id, delivery.attempts
+                ),
+                DeliveryStatus::Pending => {}
+            }
+        }
+        Ok(count)
+    }
+
+    /// Posts the signed payload inside a client span that continues the trace
+    /// of the request which produced the event.
+    async fn send(
+        &self,
+        delivery: &WebhookDelivery,
+        webhook: &MerchantWebhook,
+    ) -> Result<(), String> {
+        let parent = global::get_text_map_propagator(|propagator| {
+            propagator.extract(&delivery.trace_context)
+        });
+        let tracer = global::tracer("prompt_pay_service");
+        let span = tracer
+            .span_builder(format!("webhook {}", delivery.event))
+            .with_kind(SpanKind::Client)
+            .with_attributes(vec![
+                KeyValue::new("http.request.method", "POST"),
+                KeyValue::new("url.full", webhook.url.clone()),
+                KeyValue::new("webhook.delivery_id", delivery.id),
+            ])
+            .start_with_context(&tracer, &parent);
+        let cx = parent.with_span(span);
+
+        let mut headers = TraceContext::new();
+        global::get_text_map_propagator(|propagator| propagator.inject_context(&cx, &mut headers));
+        let body = serde_json::to_vec(&delivery.payload).map_err(|e| e.to_string())?;
+        let timestamp = unix_timestamp();
+        let mut request = self
+            .client
+            .post(&webhook.url)
+            .timeout(WEBHOOK_TIMEOUT)
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .header(
+                WEBHOOK_SIGNATURE_HEADER,
+                sign_webhook(webhook.secret.as_bytes(), timestamp, &body),
+            )
+            .header(WEBHOOK_TIMESTAMP_HEADER, timestamp.to_string())
+            .header(WEBHOOK_EVENT_HEADER, delivery.event.as_str())
+            .header(WEBHOOK_DELIVERY_HEADER, delivery.id.to_string());
+        for (name, value) in headers {
+            request = request.header(name, value);
+        }
+
+        let result = match request.body(body).send().await {
+            Ok(response) if response.status().is_success() => Ok(()),
+            Ok(response) => Err(format!("merchant responded {}", response.status())),
+            Err(e) => Err(e.to_string()),
+        };
+        let span = cx.span();
+        if let Err(e) = &result {
+            span.set_status(Status::error(e.clone()));
+        }
+        span.end();
+        result
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use actix_web::{post, App, HttpRequest, HttpResponse, HttpServer};
+    use opentelemetry_sdk::propagation::TraceContextPropagator;
+    use std::sync::Mutex;
+
+    use super::*;
+    use crate::merchant_registry::MerchantProfile;
+    use crate::prompt_pay::ProxyType;
+
+    const TRACEPARENT: &str = "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01";
+
+    fn storage_with_merchant() -> Storage {
+        let storage = Storage::open_in_memory().unwrap();
+        storage
+            .insert_merchant(
+                "M001",
+                &MerchantProfile {
+                    merchant_name: "test".to_string(),
+                    merchant_city: "Bangkok".to_string(),
+                    postal_code: None,
+                    merchant_category_code: "5311".to_string(),
+                    proxy_type: ProxyType::MobileNumber,
+                    proxy_value: "0809729900".to_string(),
+                    merchant_accounts: vec![],
+                    language_template: None,
+                    additional_data: None,
+                    unreserved_templates: vec![],
+                    transaction_ttl_seconds: None,
+                },
+            )
+            .unwrap();
+        storage
+    }
+
+    #[test]
+    fn test_retry_backoff_is_exponential_and_capped() {
+        assert_eq!(retry_backoff_seconds(1), 30);
+        assert_eq!(retry_backoff_seconds(2), 60);
+        assert_eq!(retry_backoff_seconds(4), 240);
+        assert_eq!(retry_backoff_seconds(10), WEBHOOK_MAX_BACKOFF_SECONDS);
+        assert_eq!(retry_backoff_seconds(u32::MAX), WEBHOOK_MAX_BACKOFF_SECONDS);
+    }
+
+    #[test]
+    fn test_failed_deliveries_are_dead_lettered() {
+        let storage = storage_with_merchant();
+        let transaction = storage
+            .insert_transaction("INV0001", "M001", 10.0, "000201", 60)
+            .unwrap();
+        assert_eq!(
+            storage
+                .enqueue_transaction_webhook(
+                    TRANSACTION_PAID_EVENT,
+                    &transaction,
+                    &TraceContext::new()
+                )
+                .unwrap(),
+            None
+        );
+
+        storage
+            .set_merchant_webhook("M001", "http://127.0.0.1:1/hook", &generate_secret())
+            .unwrap();
+        let id = storage
+            .enqueue_transaction_webhook(TRANSACTION_PAID_EVENT, &transaction, &TraceContext::new())
+            .unwrap()
+            .unwrap();
+        let delivery = storage
+            .record_webhook_attempt(id, Err("refused".to_string()), 2)
+            .unwrap();
+        assert_eq!(delivery.status, DeliveryStatus::Pending);
+        assert!(delivery.next_attempt_at > unix_timestamp());
+        assert!(storage
+            .due_webhook_deliveries(unix_timestamp(), 10)
+            .unwrap()
+            .is_empty());
+
+        let delivery = storage
+            .record_webhook_attempt(id, Err("refused".to_string()), 2)
+            .unwrap();
+        assert_eq!(delivery.status, DeliveryStatus::Dead);
+        assert_eq!(storage.list_dead_letters().unwrap().len(), 1);
+
+        let delivery = storage.retry_dead_letter(id).unwrap();
+        assert_eq!(delivery.status, DeliveryStatus::Pending);
+        assert_eq!(delivery.attempts, 0);
+        assert_eq!(
+            storage
+                .due_webhook_deliveries(unix_timestamp(), 10)
+                .unwrap()
+                .len(),
+            1
+        );
+    }
+
+    #[actix_web::test]
+    async fn test_webhook_destination_must_be_public_https() {
+        let rq = |url: &str| MerchantWebhookRq {
+            url: url.to_string(),
+            secret: None,
+        };
+        assert!(rq("https://merchant.example/hook").validate().is_ok());
+        assert!(rq("http://merchant.example/hook").validate().is_err());
+        assert!(rq("merchant.example/hook").validate().is_err());
+
+        for url in [
+            "https://127.0.0.1/hook",
+            "https://10.1.2.3/hook",
+            "https://169.254.169.254/latest/meta-data",
+            "https://100.64.0.1/hook",
+            "https://[::1]/hook",
+            "https://[fd00::1]/hook",
+            "https://[::ffff:192.168.0.1]/hook",
+            "https://localhost/hook",
+        ] {
+            assert!(check_webhook_destination(url).await.is_err(), "{}", url);
+        }
+        assert!(check_webhook_destination("https://8.8.8.8/hook")
+            .await
+            .is_ok());
+        assert!(check_webhook_destination("https://[2606:4700::1111]/hook")
+            .await
+            .is_ok());
+    }
+
+    struct ReceivedWebhook {
+        signature: String,
+        timestamp: String,
+        event: String,
+        traceparent: String,
+        body: Vec<u8>,
+    }
+
+    #[derive(Default)]
+    struct Received(Mutex<Vec<ReceivedWebhook>>);
+
+    #[post("/hook")]
+    async fn hook(req: HttpRequest, body: web::Bytes, received: Data<Received>) -> HttpResponse {
+        let header = |name: &str| {
+            req.headers()
+                .get(name)
+                .and_then(|v| v.to_str().ok())
+                .unwrap_or_default()
+                .to_string()
+        };
+        received.0.lock().unwrap().push(ReceivedWebhook {
+            signature: header(WEBHOOK_SIGNATURE_HEADER),
+            timestamp: header(WEBHOOK_TIMESTAMP_HEADER),
+            event: header(WEBHOOK_EVENT_HEADER),
+            traceparent: header("traceparent"),
+            body: body.to_vec(),
+        });
+        HttpResponse::NoContent().finish()
+    }
+
+    #[actix_web::test]
+    async fn test_deliver_signed_webhook_with_trace_context() {
+        global::set_text_map_propagator(TraceContextPropagator::new());
+        let received = Data::new(Received::default());
+        let server = {
+            let received = received.clone();
+            HttpServer::new(move || App::new().app_data(received.clone()).service(hook))
+                .workers(1)
+                .bind(("127.0.0.1", 0))
+                .unwrap()
+        };
+        let addr = server.addrs()[0];
+        actix_web::rt::spawn(server.run());
+
+        let storage = Data::new(storage_with_merchant());
+        let secret = generate_secret();
+        storage
+            .set_merchant_webhook("M001", &format!("http://{}/hook", addr), &secret)
+            .unwrap();
+        let transaction = storage
+            .insert_transaction("INV0001", "M001", 10.0, "000201", 60)
+            .unwrap();
+        let trace_context: TraceContext =
+            vec![("traceparent".to_string(), TRACEPARENT.to_string())]
+                .into_iter()
+                .collect();
+        let id = storage
+            .enqueue_transaction_webhook(TRANSACTION_PAID_EVENT, &transaction, &trace_context)
+            .unwrap()
+            .unwrap();
+
+        let dispatcher = WebhookDispatcher::new(reqwest::Client::new(), 3);
+        assert_eq!(dispatcher.deliver_due(&storage).await.unwrap(), 1);
+        assert_eq!(
+            storage.get_webhook_delivery(id).unwrap().status,
+            DeliveryStatus::Delivered
+        );
+
+        let received = received.0.lock().unwrap();
+        let webhook = &received[0];
+        let timestamp: i64 = webhook.timestamp.parse().unwrap();
+        assert!((unix_timestamp() - timestamp).abs() <= 5);
+        assert_eq!(
+            webhook.signature,
+            sign_webhook(secret.as_bytes(), timestamp, &webhook.body)
+        );
+        assert_ne!(
+            webhook.signature,
+            sign_payload(secret.as_bytes(), &webhook.body)
+        );
+        assert_eq!(webhook.event, TRANSACTION_PAID_EVENT);
+        assert!(webhook
+            .traceparent
+            .contains("4bf92f3577b34da6a3ce929d0e0e4736"));
+        let body: serde_json::Value = serde_json::from_slice(&webhook.body).unwrap();
+        assert_eq!(body["transaction"]["reference"], "INV0001");
+    }
+}